@@ -1,10 +1,37 @@
+// Anchor's `Error` is as large as it is, the on-chain toolchain predates
+// `abs_diff` and `is_multiple_of`, and amounts are written as whole units
+// followed by their decimals.
+#![allow(
+    clippy::result_large_err,
+    clippy::manual_abs_diff,
+    clippy::manual_is_multiple_of,
+    clippy::inconsistent_digit_grouping,
+)]
+
+pub mod math;
 mod price;
 
 use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 use byteorder::ByteOrder;
+use price::TransactionAccount;
 
 declare_id!("EuKUep9dcVnTbXHoX3UxpBbrJXY3nVAz1THwwHjtuMp1");
 
+/// `msg!` when built with the `debug-log` feature, compiled out otherwise.
+/// Tests also collect the lines, see `tests::LOG`.
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug-log") {
+            let line = format!($($arg)*);
+            #[cfg(test)]
+            tests::LOG.with(|log| log.borrow_mut().push(line.clone()));
+            msg!("{}", line);
+        }
+    };
+}
+
 #[error_code]
 pub enum ProtocolError {
     #[msg("Invalid Price")]
@@ -31,96 +58,1232 @@ pub enum ProtocolError {
     InvalidEd25519Instruction,
     #[msg("Invalid Authority")]
     InvalidAuthority,
+    #[msg("Math Overflow")]
+    MathOverflow,
+    #[msg("Position Not Liquidated")]
+    PositionNotLiquidated,
+    #[msg("Stale Signature")]
+    StaleSignature,
+    #[msg("Insufficient Insurance Fund")]
+    InsufficientInsuranceFund,
+    #[msg("Paused")]
+    Paused,
+    #[msg("Invalid Trigger")]
+    InvalidTrigger,
+    #[msg("Trigger Not Reached")]
+    TriggerNotReached,
+    #[msg("Unsupported Version")]
+    UnsupportedVersion,
+    #[msg("Open Interest Cap Reached")]
+    OpenInterestCapReached,
+    #[msg("Max Notional Exceeded")]
+    MaxNotionalExceeded,
+    #[msg("Below Minimum Size")]
+    BelowMinimumSize,
+    #[msg("Minimum Hold Not Met")]
+    MinimumHoldNotMet,
+    #[msg("Pool Mismatch")]
+    PoolMismatch,
+    #[msg("Collateral Mode Mismatch")]
+    CollateralModeMismatch,
+    #[msg("Would Be Immediately Liquidated")]
+    WouldBeImmediatelyLiquidated,
+    #[msg("Position Index In Use")]
+    PositionIndexInUse,
+    #[msg("Position Not Expired")]
+    PositionNotExpired,
+    #[msg("Already Processed")]
+    AlreadyProcessed,
+    #[msg("Price A Stale")]
+    PriceAStale,
+    #[msg("Price B Stale")]
+    PriceBStale,
+    #[msg("Confidence Exceeds Price")]
+    ConfidenceExceedsPrice,
+    #[msg("Collateral Price Stale")]
+    CollateralPriceStale,
+    #[msg("Collateral Conversion Unsupported")]
+    CollateralConversionUnsupported,
+    #[msg("Insufficient Vault Balance")]
+    InsufficientVaultBalance,
+    #[msg("Collateral Mint Not Accepted")]
+    CollateralMintNotAccepted,
 }
 
+/// denominator of every rate numerator in the protocol: rates, fees and
+/// slippage are all given in basis points
+pub const RATE_DENOMINATOR: u64 = 10000;
+/// upper bound for the `max_leverage` of any pool
 pub const MAX_LEVERAGE: u64 = 100;
+/// share of the liquidation penalty paid to the keeper, over 10000; the rest
+/// goes to the pool's insurance fund
+pub const LIQUIDATION_BONUS_NUMERATOR: u64 = 5000;
+/// how many slots a signed `LiquidatedData` stays valid for
+pub const MAX_SIGNATURE_AGE_SLOTS: u64 = 150;
+/// layout of the signed message, as tagged by `SignedDomain`
+pub const SIGNED_DATA_VERSION: u8 = 1;
+/// layout version written into every new `Position`
+pub const POSITION_VERSION: u8 = 7;
+/// length of the day overnight fees are charged per
+pub const SECONDS_PER_DAY: u64 = 86400;
+/// how `Position::overnight_fee` charges the day in progress at close
+pub const OVERNIGHT_FEE_ROUNDING: DayRounding = DayRounding::Ceil;
+/// most keepers a pool's `liquidators` whitelist can hold
+pub const MAX_LIQUIDATORS: usize = 16;
+/// most collateral mints a pool's `collateral_feeds` can convert
+pub const MAX_COLLATERAL_FEEDS: usize = 8;
+/// most open positions a `CrossAccount` can back
+pub const MAX_CROSS_POSITIONS: usize = 8;
+/// funding accrues once per this many seconds
+pub const FUNDING_INTERVAL: u64 = 3600;
+/// scale of `PoolConfig::cumulative_funding`, per unit of notional
+pub const FUNDING_PRECISION: u64 = 1_000_000_000;
+/// most positions `process_positions` closes in one instruction
+pub const MAX_BATCH_POSITIONS: usize = 8;
+/// the `mint` of a position margined in native SOL, which has none
+pub const NATIVE_MINT: Pubkey = Pubkey::new_from_array([0; 32]);
+/// how far an opened size's notional may stray from the leveraged margin it
+/// was bought with, over 10000
+pub const ASSET_AMOUNT_TOLERANCE_BPS: u64 = 10;
 
 #[program]
 pub mod protocol {
     use super::*;
+    use anchor_lang::AccountsClose;
 
+    /// Opens an isolated position. When the pool converts the `mint` posted
+    /// as collateral, its feed is passed first in `remaining_accounts`.
     pub fn create(ctx: Context<Create>, index: u32, args: PositionArgs) -> Result<()> {
+        create_isolated(ctx.accounts, ctx.remaining_accounts, index, args)
+    }
+
+    /// What `create` would open for `args` at the current oracle price,
+    /// without writing anything. Fails wherever `create` would.
+    pub fn quote_open(ctx: Context<QuoteOpen>, args: PositionArgs) -> Result<OpenQuote> {
+        let pool_config = &ctx.accounts.pool_config;
+        let args = check_isolated_args(pool_config, args)?;
+        pool_config.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, args.decimals, pool_config.max_price_age, pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        quote_isolated(pool_config, &args, &current_price, &clock)
+    }
+
+    /// Creates the payer's `PositionCounter`, handing out indexes from
+    /// `start` so it can skip those already taken through `create`.
+    pub fn init_position_counter(ctx: Context<InitPositionCounter>, start: u32) -> Result<()> {
+        let counter = &mut ctx.accounts.position_counter;
+        counter.owner = ctx.accounts.payer.key();
+        counter.next_index = start;
+
+        Ok(())
+    }
+
+    /// `create` at the next index of the payer's `PositionCounter`, which is
+    /// then bumped. `index` must be that next index, read from the counter
+    /// beforehand, as the position's address is derived from it.
+    pub fn create_auto(ctx: Context<CreateAuto>, index: u32, args: PositionArgs) -> Result<()> {
+        ctx.accounts.position_counter.claim(index)?;
+        create_isolated(&mut ctx.accounts.create, ctx.remaining_accounts, index, args)
+    }
+
+    /// Opens an isolated position margined in native SOL, in a pool whose
+    /// `collateral_mode` is `CollateralMode::Native`. The margin and opening
+    /// fee are paid in lamports into the pool's native vaults; the position is
+    /// closed with `process_position_native`.
+    pub fn create_native(ctx: Context<CreateNative>, index: u32, args: PositionArgs) -> Result<()> {
+        let pool_config = &mut ctx.accounts.pool_config;
+        let args = check_native_args(pool_config, args)?;
+        let clock = Clock::get()?;
+        pool_config.update_funding(clock.unix_timestamp)?;
+
+        pool_config.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, args.decimals, pool_config.max_price_age, pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+
+        let (opened, trading_fee) = open_isolated(pool_config, ctx.accounts.payer.key(), NATIVE_MINT, index, &args, &current_price, &clock, 0)?;
+        opened.check_pool(&ctx.accounts.pool.key())?;
+        *ctx.accounts.position = opened;
+        let position = &ctx.accounts.position;
+
+        for (to, amount) in [
+            (&ctx.accounts.native_vault, position.margin),
+            (&ctx.accounts.native_fee, trading_fee),
+        ] {
+            transfer_lamports_to_vault(&ctx.accounts.system_program, &ctx.accounts.payer, to, amount)?;
+        }
+
+        emit!(PositionOpened {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            amount: position.amount,
+            margin: position.margin,
+        });
+
+        Ok(())
+    }
+
+    /// Creates a pool's vault, fee account and insurance fund for a mint; only
+    /// the protocol's governance authority may.
+    pub fn init_vault(_ctx: Context<InitVault>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Funds the native vaults of a pool with their rent-exempt minimum, so
+    /// they can receive and pay out any amount of lamports.
+    pub fn init_native_vault(ctx: Context<InitNativeVault>) -> Result<()> {
+        ctx.accounts.pool_config.check_collateral_mode(CollateralMode::Native)?;
+        let rent = Rent::get()?.minimum_balance(0);
+        for vault in [
+            &ctx.accounts.native_vault,
+            &ctx.accounts.native_fee,
+            &ctx.accounts.native_insurance,
+        ] {
+            let missing = rent.saturating_sub(vault.lamports());
+            transfer_lamports_to_vault(&ctx.accounts.system_program, &ctx.accounts.payer, vault, missing)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the protocol's governance account, held by the program's
+    /// upgrade authority, which alone may then set up pools.
+    pub fn init_admin(ctx: Context<InitAdmin>) -> Result<()> {
+        ctx.accounts.admin.authority = ctx.accounts.authority.key();
+
+        Ok(())
+    }
+
+    /// Creates the risk configuration of a pool, administered by the payer,
+    /// who must be the protocol's governance authority.
+    pub fn init_pool_config(ctx: Context<InitPoolConfig>, args: PoolConfigArgs) -> Result<()> {
+        args.validate()?;
+
+        let pool_config = &mut ctx.accounts.pool_config;
+        pool_config.pool = ctx.accounts.pool.key();
+        pool_config.admin = ctx.accounts.payer.key();
+        pool_config.pending_admin = Pubkey::default();
+        pool_config.price_a = ctx.accounts.price_a.key();
+        pool_config.price_b = ctx.accounts.price_b.key();
+        pool_config.quote_mint = ctx.accounts.quote_mint.key();
+        pool_config.long_oi = 0;
+        pool_config.short_oi = 0;
+        pool_config.cumulative_funding = 0;
+        pool_config.liquidators = Vec::new();
+        pool_config.collateral_feeds = Vec::new();
+        pool_config.last_funding_update = Clock::get()?.unix_timestamp;
+        pool_config.collateral_mode = args.collateral_mode;
+        pool_config.set(&args);
+
+        Ok(())
+    }
+
+    pub fn update_pool_config(ctx: Context<UpdatePoolConfig>, args: PoolConfigArgs) -> Result<()> {
+        args.validate()?;
+        ctx.accounts.pool_config.check_collateral_mode(args.collateral_mode)?;
+        ctx.accounts.pool_config.set(&args);
+
+        Ok(())
+    }
+
+    /// Halts or resumes new exposure on the pool. Positions can still be
+    /// closed, liquidated and have margin withdrawn while paused.
+    pub fn set_paused(ctx: Context<UpdatePoolConfig>, paused: bool) -> Result<()> {
+        ctx.accounts.pool_config.paused = paused;
+
+        Ok(())
+    }
+
+    /// Proposes `new_admin` to take over the config, which it does once it
+    /// signs `accept_admin`; until then the current admin stays in charge and
+    /// can propose another key, or `Pubkey::default()` to withdraw the offer.
+    pub fn transfer_admin(ctx: Context<UpdatePoolConfig>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.pool_config.propose_admin(new_admin);
+
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        ctx.accounts.pool_config.accept_admin(&ctx.accounts.new_admin.key())
+    }
+
+    /// Whitelists a keeper for pools without permissionless liquidation.
+    pub fn add_liquidator(ctx: Context<UpdatePoolConfig>, liquidator: Pubkey) -> Result<()> {
+        ctx.accounts.pool_config.add_liquidator(liquidator)
+    }
+
+    pub fn remove_liquidator(ctx: Context<UpdatePoolConfig>, liquidator: Pubkey) -> Result<()> {
+        ctx.accounts.pool_config.remove_liquidator(&liquidator)
+    }
+
+    /// Accepts `mint` as collateral for new positions, converted to the quote
+    /// through the Pyth feed `price`.
+    pub fn add_collateral_feed(ctx: Context<UpdatePoolConfig>, mint: Pubkey, price: Pubkey) -> Result<()> {
+        ctx.accounts.pool_config.add_collateral_feed(mint, price)
+    }
+
+    /// Stops accepting `mint` as collateral. Positions already holding it
+    /// keep their feed and close as before.
+    pub fn remove_collateral_feed(ctx: Context<UpdatePoolConfig>, mint: Pubkey) -> Result<()> {
+        ctx.accounts.pool_config.remove_collateral_feed(&mint)
+    }
+
+    /// Moves the pool onto the Pyth feeds passed as `price_a` and `price_b`
+    /// after the oracle migrates, along with every position passed in
+    /// `remaining_accounts`. Positions left out keep the old feeds and can't
+    /// be priced until a later call with the same feeds migrates them.
+    pub fn migrate_position_feeds<'info>(ctx: Context<'_, '_, '_, 'info, MigratePositionFeeds<'info>>) -> Result<()> {
+        if ctx.remaining_accounts.len() > MAX_BATCH_POSITIONS {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        for feed in [&ctx.accounts.price_a, &ctx.accounts.price_b] {
+            if feed.key() != Pubkey::default() {
+                pyth_sdk_solana::load_price_feed_from_account_info(feed)
+                    .map_err(|_| ProtocolError::InvalidPriceAccount)?;
+            }
+        }
+
+        let pool_config = &mut ctx.accounts.pool_config;
+        pool_config.price_a = ctx.accounts.price_a.key();
+        pool_config.price_b = ctx.accounts.price_b.key();
+
+        for info in ctx.remaining_accounts {
+            let mut position = Account::<Position>::try_from(info)?;
+            if !info.is_writable {
+                return err!(ProtocolError::InvalidAccountData);
+            }
+            position.migrate_feeds(pool_config)?;
+            position.exit(ctx.program_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the positions passed in `remaining_accounts` onto the pool's
+    /// current maintenance margin and overnight fee and re-derives their
+    /// liquidation prices. Rate changes made with `update_pool_config` only
+    /// reach positions opened afterwards; this makes them retroactive, from
+    /// now on, for the positions the admin chooses.
+    pub fn recompute_liquidations<'info>(ctx: Context<'_, '_, '_, 'info, UpdatePoolConfig<'info>>) -> Result<()> {
+        let count = ctx.remaining_accounts.len();
+        if count == 0 || count > MAX_BATCH_POSITIONS {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        let now = Clock::get()?.unix_timestamp;
+
+        for info in ctx.remaining_accounts {
+            let mut position = Account::<Position>::try_from(info)?;
+            if !info.is_writable {
+                return err!(ProtocolError::InvalidAccountData);
+            }
+            position.apply_pool_rates(&ctx.accounts.pool_config, now)?;
+            position.exit(ctx.program_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a position created at an older `POSITION_VERSION` in the
+    /// current layout, so that it can be used again without closing it. The
+    /// account grows to the current `Position::LEN` with the owner paying the
+    /// extra rent.
+    pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+        let info = ctx.accounts.position.to_account_info();
+        let position = decode_legacy_position(&info.try_borrow_data()?, ctx.accounts.pool_config.cumulative_funding)?;
+        require_eq!(position.owner, ctx.accounts.owner.key(), ProtocolError::InvalidAuthority);
+        position.check_pool(&ctx.accounts.pool.key())?;
+
+        let space = 8 + Position::LEN;
+        let missing = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(info.lamports());
+        transfer_lamports_to_vault(&ctx.accounts.system_program, &ctx.accounts.owner, &ctx.accounts.position, missing)?;
+        info.realloc(space, false)?;
+
+        let mut data = info.try_borrow_mut_data()?;
+        let mut writer: &mut [u8] = &mut data;
+        position.try_serialize(&mut writer)
+    }
+
+    pub fn init_cross_account(ctx: Context<InitCrossAccount>) -> Result<()> {
+        let cross_account = &mut ctx.accounts.cross_account;
+        cross_account.pool = ctx.accounts.pool.key();
+        cross_account.owner = ctx.accounts.payer.key();
+        cross_account.mint = ctx.accounts.mint.key();
+        cross_account.collateral = 0;
+        cross_account.used_margin = 0;
+        cross_account.positions = Vec::new();
+
+        Ok(())
+    }
+
+    /// Tops up the pool's insurance fund, which covers losses that exceed a
+    /// position's margin.
+    pub fn deposit_insurance(ctx: Context<DepositInsurance>, amount: u64) -> Result<()> {
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.insurance_fund,
+            &ctx.accounts.payer,
+            amount,
+        )
+    }
+
+    /// Collects `amount` of the trading and overnight fees the pool's fee
+    /// account has taken in, to a token account of the admin's choosing. Only
+    /// the fee account is drawn on; the vault and insurance fund are untouched.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        check_fee_withdrawal(ctx.accounts.fee_account.amount, amount)?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.destination,
+            &ctx.accounts.vault_authority,
+            &ctx.accounts.pool.key(),
+            *ctx.bumps.get("vault_authority").unwrap(),
+            amount,
+        )
+    }
+
+    pub fn deposit_cross_margin(ctx: Context<DepositCrossMargin>, amount: u64) -> Result<()> {
+        let cross_account = &mut ctx.accounts.cross_account;
+        cross_account.collateral = cross_account.collateral
+            .checked_add(amount)
+            .ok_or(ProtocolError::InvalidArgs)?;
+
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.payer,
+            amount,
+        )
+    }
+
+    /// Opens a cross-margin position whose margin is taken from the owner's
+    /// shared `CrossAccount` collateral instead of being posted per position.
+    pub fn create_cross(ctx: Context<CreateCross>, index: u32, args: PositionArgs) -> Result<()> {
+        if args.ptype != PositionType::Cross {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        let pool_config = &mut ctx.accounts.pool_config;
+        let mut args = args;
+        pool_config.check_args(&mut args)?;
+        pool_config.check_min_size(&args)?;
+        let clock = Clock::get()?;
+        pool_config.update_funding(clock.unix_timestamp)?;
+
+        pool_config.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, args.decimals, pool_config.max_price_age, pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+
+        let cross_account = &mut ctx.accounts.cross_account;
+        cross_account.allocate(args.margin()?)?;
+
+        let (opened, trading_fee) = open_isolated(pool_config, ctx.accounts.payer.key(), cross_account.mint, index, &args, &current_price, &clock, cross_account.free_collateral())?;
+        opened.check_pool(&ctx.accounts.pool.key())?;
+        cross_account.charge_fee(trading_fee)?;
         let position = &mut ctx.accounts.position;
-        position.status = PositionStatus::Open;
-        position.pool = ctx.accounts.pool.key();
-        position.owner = ctx.accounts.payer.key();
-        position.index = index;
-        position.margin = args.margin();
-        position.ptype = args.ptype;
-        position.direction = args.direction;
-        position.created_at = Clock::get()?.unix_timestamp;
-        position.slot = Clock::get()?.slot;
-        position.decimals = args.decimals;
-
-        unimplemented!();
-    }
-
-    /// TODO
-    pub fn netoff(ctx: Context<Netoff>, args: PositionArgs) -> Result<()> {
+        **position = opened;
+        cross_account.add_position(position.key())?;
+
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.vault_authority,
+            &position.pool,
+            *ctx.bumps.get("vault_authority").unwrap(),
+            trading_fee,
+        )?;
+
+        emit!(PositionOpened {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            amount: position.amount,
+            margin: position.margin,
+        });
+
+        Ok(())
+    }
+
+    /// Nets an order off against an existing position. Same-direction orders
+    /// are averaged into the position, opposite-direction orders reduce, close
+    /// or flip it; returns the updated margin. Each fill pays the taker fee,
+    /// as an open does. A closed position pays its margin out to the owner,
+    /// returns that instead, and refunds its rent.
+    pub fn netoff(ctx: Context<Netoff>, args: PositionArgs) -> Result<u64> {
         let position = &mut ctx.accounts.position;
 
-        if args.leverage > MAX_LEVERAGE {
-            return err!(ProtocolError::InvalidLeverage);
+        let mut args = args;
+        ctx.accounts.pool_config.check_args(&mut args)?;
+
+        position.check_version()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, args.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_at(price_to_u64(current_price.price)?, clock.unix_timestamp, ctx.accounts.pool_config.cumulative_funding)?;
+        let before = (position.direction, position.amount);
+        let (posted_margin, trading_fee) = match (args.direction, position.direction) {
+            (Direction::OpenLong, Direction::OpenLong)
+            | (Direction::OpenShort, Direction::OpenShort) => {
+                position.add(&args, &current_price)?
+            }
+            (Direction::OpenLong, Direction::OpenShort)
+            | (Direction::OpenShort, Direction::OpenLong) => {
+                position.reduce(&args, &current_price)?
+            }
+        };
+        let after = (position.direction, position.amount);
+        ctx.accounts.pool_config.move_open_interest(before, after)?;
+        if after.0 != before.0 || after.1 > before.1 {
+            ctx.accounts.pool_config.check_notional(position.entry_notional()?)?;
         }
 
-        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, args.decimals)?;
-        if position.is_liquidated(current_price.price as u64) {
-            return err!(ProtocolError::PositionLiquidated);
+        if position.status == PositionStatus::Processed {
+            let settlement = position.netted_off_settlement();
+            let vault_authority_bump = *ctx.bumps.get("vault_authority").unwrap();
+            for (to, amount) in [
+                (&ctx.accounts.payer_token_account, settlement.returned_margin),
+                (&ctx.accounts.fee_account, settlement.overnight_fee + trading_fee),
+            ] {
+                transfer_from_vault(
+                    &ctx.accounts.token_program,
+                    &ctx.accounts.vault,
+                    to,
+                    &ctx.accounts.vault_authority,
+                    &position.pool,
+                    vault_authority_bump,
+                    amount,
+                )?;
+            }
+            ctx.accounts.position.close(ctx.accounts.payer.to_account_info())?;
+            return Ok(settlement.returned_margin);
         }
 
-        unimplemented!()
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.payer,
+            posted_margin,
+        )?;
+        // the fill's fee, taken out of the margin now in the vault
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.vault_authority,
+            &ctx.accounts.position.pool,
+            *ctx.bumps.get("vault_authority").unwrap(),
+            trading_fee,
+        )?;
+
+        Ok(ctx.accounts.position.margin)
     }
 
+    /// Adds `amount` to the position's margin, in the quote. A position
+    /// margined in another collateral pays for it in that collateral at the
+    /// feed passed first in `remaining_accounts`.
     pub fn increase_margin(ctx: Context<IncreaseMargin>, amount: u64) -> Result<()> {
+        ctx.accounts.pool_config.check_not_paused()?;
+
+        let position = &mut ctx.accounts.position;
+
+        position.check_version()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_at(price_to_u64(current_price.price)?, clock.unix_timestamp, ctx.accounts.pool_config.cumulative_funding)?;
+        let posted = match position_collateral_price(ctx.remaining_accounts, position, &ctx.accounts.pool_config, clock.unix_timestamp)? {
+            Some(collateral_price) => collateral_for_quote(amount, &collateral_price, position.collateral_decimals)?,
+            None => amount,
+        };
+        let key = position.key();
+        let ((), margin_changed) = position.change_margin(key, MarginChangeReason::Deposit, |position| position.deposit(amount))?;
+
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.payer,
+            posted,
+        )?;
+
+        emit!(MarginIncreased {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            amount,
+            margin: position.margin,
+        });
+        emit!(margin_changed);
+
+        Ok(())
+    }
+
+    pub fn decrease_margin(ctx: Context<DecreaseMargin>, amount: u64) -> Result<u64> {
         let position = &mut ctx.accounts.position;
 
-        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals)?;
-        if position.is_liquidated(current_price.price as u64) {
+        position.check_version()?;
+        position.check_quote_collateral()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        let price = price_to_u64(current_price.price)?;
+        let now = clock.unix_timestamp;
+        ctx.accounts.pool_config.update_funding(now)?;
+        let cumulative_funding = ctx.accounts.pool_config.cumulative_funding;
+        position.settle_at(price, now, cumulative_funding)?;
+        let key = position.key();
+        let ((), margin_changed) = position.change_margin(key, MarginChangeReason::Withdraw, |position| position.withdraw(amount, price))?;
+        // the fees accrued and not yet realized still stand against the bond
+        if position.is_liquidated_at(price, now, cumulative_funding)? {
             return err!(ProtocolError::PositionLiquidated);
         }
-        position.margin += amount;
+        emit!(margin_changed);
 
-        position.liquidation = get_liquidation(
-            position.last_price,
-            position.bond(),
-            position.direction,
-        );
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.vault_authority,
+            &position.pool,
+            *ctx.bumps.get("vault_authority").unwrap(),
+            amount,
+        )?;
 
-        Ok(())
+        Ok(position.margin)
     }
 
-    pub fn process_position<'info>(
-        ctx: Context<'_, '_, '_, 'info, ProcessPosition<'info>>,
-    ) -> Result<u64> {
-        let authenticated = verify_and_extract(&ctx.accounts.instruction_sysvar_account_info)
-            .map_err(|_| ProtocolError::InvalidSignature)?;
+    /// Changes the leverage of an isolated position, holding its size constant:
+    /// lowering it pulls the extra margin from the owner, raising it pays the
+    /// freed margin back. Returns the new margin.
+    pub fn set_leverage(ctx: Context<SetLeverage>, leverage: u64) -> Result<u64> {
+        ctx.accounts.pool_config.check_not_paused()?;
+
+        let position = &mut ctx.accounts.position;
+        ctx.accounts.pool_config.check_leverage(leverage, position.direction)?;
 
+        position.check_version()?;
+        position.check_quote_collateral()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_at(price_to_u64(current_price.price)?, clock.unix_timestamp, ctx.accounts.pool_config.cumulative_funding)?;
+        let previous_margin = position.set_leverage(leverage, price_to_u64(current_price.price)?)?;
+
+        if position.margin > previous_margin {
+            transfer_to_vault(
+                &ctx.accounts.token_program,
+                &ctx.accounts.payer_token_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.payer,
+                position.margin - previous_margin,
+            )?;
+        } else if position.margin < previous_margin {
+            transfer_from_vault(
+                &ctx.accounts.token_program,
+                &ctx.accounts.vault,
+                &ctx.accounts.payer_token_account,
+                &ctx.accounts.vault_authority,
+                &position.pool,
+                *ctx.bumps.get("vault_authority").unwrap(),
+                previous_margin - position.margin,
+            )?;
+        }
+
+        Ok(position.margin)
+    }
+
+    /// Liquidates a position whose liquidation price has been crossed on-chain.
+    /// Callable by any keeper, who is rewarded with the position's rent and a
+    /// share of its remaining margin. A cross position is judged on the health
+    /// of its whole `CrossAccount`, passed first in `remaining_accounts` and
+    /// followed by every other position it backs; the member furthest
+    /// underwater goes first and closes at its own PnL, any loss past its
+    /// margin coming out of the shared collateral. An isolated one margined in
+    /// converted collateral passes its collateral feed there instead.
+    pub fn liquidate<'info>(ctx: Context<'_, '_, '_, 'info, Liquidate<'info>>) -> Result<LiquidateResult> {
+        ctx.accounts.pool_config.check_liquidator(&ctx.accounts.keeper.key())?;
+
+        let position = &mut ctx.accounts.position;
+
+        position.check_version()?;
+        position.check_open()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, ctx.accounts.pool_config.liquidation_price_source(), clock.unix_timestamp)?;
+        let collateral_price = position_collateral_price(ctx.remaining_accounts, position, &ctx.accounts.pool_config, clock.unix_timestamp)?;
+        // the funding owed is realized, and the fees accrued since the last
+        // settlement count, against the bond
+        let booked_margin = position.margin;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_funding(ctx.accounts.pool_config.cumulative_funding)?;
+        let cumulative_funding = ctx.accounts.pool_config.cumulative_funding;
+        let penalty_bps = ctx.accounts.pool_config.liquidation_penalty_bps;
+        let now = clock.unix_timestamp;
+        let (result, fees, bad_debt) = if position.ptype == PositionType::Cross {
+            let (mut cross_account, members) = load_cross_members(ctx.remaining_accounts, position)?;
+            if !cross_account.is_liquidated(&members, &current_price, now)? {
+                return err!(ProtocolError::PositionNotLiquidated);
+            }
+            CrossAccount::check_worst_member(&members, &current_price)?;
+            let settlement = position.settle(&current_price, now)?;
+            let bad_debt = cross_account.release(&position.key(), booked_margin, settlement.bad_debt)?;
+            cross_account.exit(ctx.program_id)?;
+            (split_penalty(settlement.returned_margin, penalty_bps)?, settlement.overnight_fee + settlement.trading_fee, bad_debt)
+        } else {
+            if !position.is_liquidated_at(price_to_u64(current_price.price)?, now, cumulative_funding)? {
+                return err!(ProtocolError::PositionNotLiquidated);
+            }
+            (position.liquidation_split(now, cumulative_funding, penalty_bps)?, position.overnight_fee(now)?, 0)
+        };
+        position.status = PositionStatus::Processed;
+        ctx.accounts.pool_config.remove_open_interest(position.direction, position.amount);
+
+        let vault_authority_bump = *ctx.bumps.get("vault_authority").unwrap();
+        // a loss past what the shared collateral covers is made good to the
+        // vault by the insurance fund
+        if bad_debt > 0 {
+            if ctx.accounts.insurance_fund.amount < bad_debt {
+                return err!(ProtocolError::InsufficientInsuranceFund);
+            }
+            transfer_from_vault(
+                &ctx.accounts.token_program,
+                &ctx.accounts.insurance_fund,
+                &ctx.accounts.vault,
+                &ctx.accounts.vault_authority,
+                &position.pool,
+                vault_authority_bump,
+                bad_debt,
+            )?;
+        }
+        for (to, amount) in [
+            (&ctx.accounts.owner_token_account, result.returned_margin),
+            (&ctx.accounts.keeper_token_account, result.keeper_bonus),
+            (&ctx.accounts.insurance_fund, result.insurance_fee),
+            (&ctx.accounts.fee_account, fees),
+        ] {
+            transfer_from_vault(
+                &ctx.accounts.token_program,
+                &ctx.accounts.vault,
+                to,
+                &ctx.accounts.vault_authority,
+                &position.pool,
+                vault_authority_bump,
+                payout_in_collateral(amount, collateral_price.as_ref(), position.collateral_decimals)?,
+            )?;
+        }
+
+        emit!(PositionLiquidated {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: position.margin,
+            returned_margin: result.returned_margin,
+            pnl: realized_pnl(position.margin, result.returned_margin)?,
+        });
+
+        Ok(result)
+    }
+
+    /// Realizes the overnight fees and funding of an isolated position out of
+    /// its margin, paying the fees to the pool's fee account; returns the
+    /// overnight fee realized. Callable by anyone, so long-lived positions
+    /// don't build up unsettled fees. Rejected when the smaller margin would
+    /// leave the position liquidatable, which `liquidate` handles instead.
+    pub fn settle_fees(ctx: Context<SettleFees>) -> Result<u64> {
+        ctx.accounts.pool_config.check_collateral_mode(CollateralMode::Token)?;
+        let position = &mut ctx.accounts.position;
+        position.check_version()?;
+        position.check_quote_collateral()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, ctx.accounts.pool_config.liquidation_price_source(), clock.unix_timestamp)?;
+        let price = price_to_u64(current_price.price)?;
+
+        let now = clock.unix_timestamp;
+        ctx.accounts.pool_config.update_funding(now)?;
+        let cumulative_funding = ctx.accounts.pool_config.cumulative_funding;
+        let key = position.key();
+        let (fee, margin_changed) = position.change_margin(key, MarginChangeReason::Fee, |position| position.realize_fees(now, cumulative_funding, price))?;
+        emit!(margin_changed);
+
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.vault_authority,
+            &position.pool,
+            *ctx.bumps.get("vault_authority").unwrap(),
+            fee,
+        )?;
+
+        Ok(fee)
+    }
+
+    pub fn set_triggers(ctx: Context<SetTriggers>, take_profit: Option<u64>, stop_loss: Option<u64>) -> Result<()> {
+        ctx.accounts.position.check_version()?;
+        ctx.accounts.position.set_triggers(take_profit, stop_loss)
+    }
+
+    /// Closes a position that was never filled nor funded and refunds its
+    /// rent to the owner.
+    pub fn cancel_position(ctx: Context<CancelPosition>) -> Result<()> {
+        let position = &ctx.accounts.position;
+        position.check_version()?;
+        position.check_open()?;
+        position.check_cancellable()
+    }
+
+    /// Sets the time after which `expire_position` may close the position,
+    /// or clears it with `None`.
+    pub fn set_expiry(ctx: Context<SetTriggers>, expiry: Option<i64>) -> Result<()> {
+        ctx.accounts.position.check_version()?;
+        ctx.accounts.position.set_expiry(expiry, Clock::get()?.unix_timestamp)
+    }
+
+    /// Closes a position at the live price on the owner's own initiative,
+    /// paying out at least `min_return`. A position margined in converted
+    /// collateral passes its collateral feed first in `remaining_accounts` and
+    /// is paid out, and returns, in that collateral; a cross position passes
+    /// its `CrossAccount` there, which gives up the margin it had booked.
+    pub fn close_position(ctx: Context<ClosePosition>, min_return: u64) -> Result<u64> {
+        let position = &mut ctx.accounts.position;
+        let booked_margin = position.margin;
+
+        position.check_version()?;
+        position.check_open()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        let collateral_price = position_collateral_price(ctx.remaining_accounts, position, &ctx.accounts.pool_config, clock.unix_timestamp)?;
+        ctx.accounts.pool_config.check_min_hold(position.created_at, clock.unix_timestamp, false)?;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_funding(ctx.accounts.pool_config.cumulative_funding)?;
+        let mut settlement = position.close_at(&current_price, clock.unix_timestamp)?;
+        cap_payout(&ctx.accounts.pool_config, &position.key(), &mut settlement, position.margin);
+        release_cross_member(ctx.remaining_accounts, position, &position.key(), booked_margin, &mut settlement, ctx.program_id)?;
+        let payout = settlement.in_collateral(collateral_price.as_ref(), position.collateral_decimals)?;
+        check_min_return(payout.returned_margin, min_return)?;
+        ctx.accounts.pool_config.remove_open_interest(position.direction, position.amount);
+
+        emit!(PositionProcessed {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: position.margin,
+            returned_margin: settlement.returned_margin,
+            pnl: realized_pnl(position.margin, settlement.returned_margin)?,
+        });
+
+        pay_out_settlement(
+            SettlementAccounts {
+                token_program: &ctx.accounts.token_program,
+                vault_authority: &ctx.accounts.vault_authority,
+                vault: &ctx.accounts.vault,
+                fee_account: &ctx.accounts.fee_account,
+                insurance_fund: &ctx.accounts.insurance_fund,
+                owner_token_account: &ctx.accounts.owner_token_account,
+                vault_authority_bump: *ctx.bumps.get("vault_authority").unwrap(),
+            },
+            &position.pool,
+            &payout,
+        )?;
+
+        Ok(payout.returned_margin)
+    }
+
+    /// Closes a position whose take-profit or stop-loss has been crossed, at
+    /// the maker fee. Callable by any keeper; the owner gets the position's
+    /// rent back. Converted collateral is priced, and a cross position
+    /// settled with its `CrossAccount`, as in `close_position`.
+    pub fn execute_trigger(ctx: Context<ExecuteTrigger>) -> Result<u64> {
         let position = &mut ctx.accounts.position;
+
+        position.check_version()?;
+        position.check_open()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        let collateral_price = position_collateral_price(ctx.remaining_accounts, position, &ctx.accounts.pool_config, clock.unix_timestamp)?;
+        if !position.is_triggered(&current_price)? {
+            return err!(ProtocolError::TriggerNotReached);
+        }
         position.status = PositionStatus::Processed;
+        let booked_margin = position.margin;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_funding(ctx.accounts.pool_config.cumulative_funding)?;
+        ctx.accounts.pool_config.remove_open_interest(position.direction, position.amount);
+
+        let mut settlement = position.settle_as(&current_price, clock.unix_timestamp, Liquidity::Maker)?;
+        cap_payout(&ctx.accounts.pool_config, &position.key(), &mut settlement, position.margin);
+        release_cross_member(ctx.remaining_accounts, position, &position.key(), booked_margin, &mut settlement, ctx.program_id)?;
+        emit!(PositionProcessed {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: position.margin,
+            returned_margin: settlement.returned_margin,
+            pnl: realized_pnl(position.margin, settlement.returned_margin)?,
+        });
+
+        let payout = settlement.in_collateral(collateral_price.as_ref(), position.collateral_decimals)?;
+        pay_out_settlement(
+            SettlementAccounts {
+                token_program: &ctx.accounts.token_program,
+                vault_authority: &ctx.accounts.vault_authority,
+                vault: &ctx.accounts.vault,
+                fee_account: &ctx.accounts.fee_account,
+                insurance_fund: &ctx.accounts.insurance_fund,
+                owner_token_account: &ctx.accounts.owner_token_account,
+                vault_authority_bump: *ctx.bumps.get("vault_authority").unwrap(),
+            },
+            &position.pool,
+            &payout,
+        )?;
+
+        Ok(payout.returned_margin)
+    }
+
+    /// Closes a position past its expiry at the live price, as the owner
+    /// closing it would. Callable by any keeper, so that abandoned positions
+    /// don't stay open; a liquidatable one is left to `liquidate`. Converted
+    /// collateral is priced, and a cross position settled with its
+    /// `CrossAccount`, as in `close_position`.
+    pub fn expire_position(ctx: Context<ExecuteTrigger>) -> Result<u64> {
+        let position = &mut ctx.accounts.position;
+
+        position.check_version()?;
+        position.check_open()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        position.check_expired(clock.unix_timestamp)?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        let collateral_price = position_collateral_price(ctx.remaining_accounts, position, &ctx.accounts.pool_config, clock.unix_timestamp)?;
+        let booked_margin = position.margin;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+        position.settle_funding(ctx.accounts.pool_config.cumulative_funding)?;
+        let mut settlement = position.close_at(&current_price, clock.unix_timestamp)?;
+        cap_payout(&ctx.accounts.pool_config, &position.key(), &mut settlement, position.margin);
+        ctx.accounts.pool_config.remove_open_interest(position.direction, position.amount);
+        release_cross_member(ctx.remaining_accounts, position, &position.key(), booked_margin, &mut settlement, ctx.program_id)?;
+
+        emit!(PositionProcessed {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: position.margin,
+            returned_margin: settlement.returned_margin,
+            pnl: realized_pnl(position.margin, settlement.returned_margin)?,
+        });
+
+        let payout = settlement.in_collateral(collateral_price.as_ref(), position.collateral_decimals)?;
+        pay_out_settlement(
+            SettlementAccounts {
+                token_program: &ctx.accounts.token_program,
+                vault_authority: &ctx.accounts.vault_authority,
+                vault: &ctx.accounts.vault,
+                fee_account: &ctx.accounts.fee_account,
+                insurance_fund: &ctx.accounts.insurance_fund,
+                owner_token_account: &ctx.accounts.owner_token_account,
+                vault_authority_bump: *ctx.bumps.get("vault_authority").unwrap(),
+            },
+            &position.pool,
+            &payout,
+        )?;
+
+        Ok(payout.returned_margin)
+    }
+
+    /// Read-only view of how close a position is to liquidation at the live price.
+    pub fn get_health(ctx: Context<GetHealth>) -> Result<Health> {
+        let position = &ctx.accounts.position;
+
+        position.check_version()?;
+        position.check_price_accounts(&ctx.accounts.price_a.key(), &ctx.accounts.price_b.key())?;
+        let clock = Clock::get()?;
+        let current_price = get_current_price(&ctx.accounts.price_a, &ctx.accounts.price_b, position.decimals, ctx.accounts.pool_config.max_price_age, ctx.accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        position.health(&current_price, clock.unix_timestamp)
+    }
+
+    /// Read-only view of the liquidation price the position would have after
+    /// adding `margin_delta` to its margin, or withdrawing it when negative.
+    pub fn preview_liquidation(ctx: Context<PreviewLiquidation>, margin_delta: i64) -> Result<u64> {
+        let position = &ctx.accounts.position;
+
+        position.check_version()?;
+        position.liquidation_after(margin_delta)
+    }
+
+    /// Closes `close_amount` of a position, paying out at least `min_return`
+    /// unless the signed data marks it liquidated. The account is only closed
+    /// once the whole size is; a liquidated position always closes in full.
+    /// The payout goes to the token account passed first in
+    /// `remaining_accounts`, if any, instead of the owner's; the rent is
+    /// still refunded to `payer`. A cross position also passes its
+    /// `CrossAccount` there.
+    pub fn process_position<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessPosition<'info>>,
+        min_return: u64,
+        close_amount: u64,
+    ) -> Result<CloseResult> {
+        ctx.accounts.pool_config.check_collateral_mode(CollateralMode::Token)?;
+        let (settlement, result) = process_signed(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.pool_config,
+            &ctx.accounts.price_a,
+            &ctx.accounts.price_b,
+            &ctx.accounts.instruction_sysvar_account_info,
+            ctx.remaining_accounts,
+            ctx.program_id,
+            min_return,
+            close_amount,
+        )?;
+        let position = &ctx.accounts.position;
+        let cross_account = (position.ptype == PositionType::Cross).then(|| cross_account_address(&position.owner, &position.pool, ctx.program_id));
+        let recipient = payout_recipient(ctx.remaining_accounts, &position.mint, cross_account)?;
+
+        pay_out_settlement(
+            SettlementAccounts {
+                token_program: &ctx.accounts.token_program,
+                vault_authority: &ctx.accounts.vault_authority,
+                vault: &ctx.accounts.vault,
+                fee_account: &ctx.accounts.fee_account,
+                insurance_fund: &ctx.accounts.insurance_fund,
+                owner_token_account: recipient.as_ref().unwrap_or(&ctx.accounts.owner_token_account),
+                vault_authority_bump: *ctx.bumps.get("vault_authority").unwrap(),
+            },
+            &position.pool,
+            &settlement,
+        )?;
+
+        if position.status == PositionStatus::Processed {
+            ctx.accounts.position.close(ctx.accounts.payer.to_account_info())?;
+        }
+
+        Ok(result)
+    }
+
+    /// `process_position` for a position margined in native SOL: the payout
+    /// is made in lamports from the pool's native vaults to the owner.
+    pub fn process_position_native<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessPositionNative<'info>>,
+        min_return: u64,
+        close_amount: u64,
+    ) -> Result<CloseResult> {
+        ctx.accounts.pool_config.check_collateral_mode(CollateralMode::Native)?;
+        let (settlement, result) = process_signed(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.pool_config,
+            &ctx.accounts.price_a,
+            &ctx.accounts.price_b,
+            &ctx.accounts.instruction_sysvar_account_info,
+            ctx.remaining_accounts,
+            ctx.program_id,
+            min_return,
+            close_amount,
+        )?;
+        let position = &ctx.accounts.position;
+
+        pay_out_native_settlement(
+            NativeSettlementAccounts {
+                system_program: &ctx.accounts.system_program,
+                native_vault: &ctx.accounts.native_vault,
+                native_fee: &ctx.accounts.native_fee,
+                native_insurance: &ctx.accounts.native_insurance,
+                owner: &ctx.accounts.payer,
+                native_vault_bump: *ctx.bumps.get("native_vault").unwrap(),
+                native_insurance_bump: *ctx.bumps.get("native_insurance").unwrap(),
+            },
+            &position.pool,
+            &settlement,
+        )?;
+
+        if position.status == PositionStatus::Processed {
+            ctx.accounts.position.close(ctx.accounts.payer.to_account_info())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Closes in full every position passed in `remaining_accounts`, each
+    /// authenticated by its own Ed25519 instruction; those sit right before
+    /// this one, in the same order as the positions. The payouts go out as
+    /// one aggregate transfer, with `min_return` bounding the total returned
+    /// margin. With `best_effort` a position that fails to verify or close is
+    /// left untouched and reported as skipped instead of failing the batch.
+    /// Cross positions settle with the payer's `CrossAccount`, passed anywhere
+    /// among the positions.
+    pub fn process_positions<'info>(
+        mut ctx: Context<'_, '_, '_, 'info, ProcessPositions<'info>>,
+        min_return: u64,
+        best_effort: bool,
+    ) -> Result<Vec<BatchStatus>> {
+        let cross_account = cross_account_address(&ctx.accounts.payer.key(), &ctx.accounts.pool.key(), ctx.program_id);
+        let positions: Vec<&AccountInfo<'info>> = ctx.remaining_accounts
+            .iter()
+            .filter(|info| *info.key != cross_account)
+            .collect();
+        let count = positions.len();
+        if count == 0 || count > MAX_BATCH_POSITIONS {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        ctx.accounts.pool_config.check_collateral_mode(CollateralMode::Token)?;
+        let clock = Clock::get()?;
+        ctx.accounts.pool_config.update_funding(clock.unix_timestamp)?;
+
+        let accounts = &mut ctx.accounts;
+        let mut closed: Vec<Account<Position>> = Vec::with_capacity(count);
+        // parsed for the first position closed at the live price, and reused
+        let mut feeds: Option<PoolFeeds> = None;
+        let (settlement, statuses) = run_batch(positions.into_iter().enumerate(), best_effort, |(i, info)| {
+            let mut position = Account::<Position>::try_from(info)?;
+            let key = position.key();
+            // a position listed twice would otherwise be paid out twice
+            if !info.is_writable
+                || position.owner != accounts.payer.key()
+                || position.mint != accounts.mint.key()
+                || closed.iter().any(|closed| closed.key() == key)
+            {
+                return err!(ProtocolError::InvalidAccountData);
+            }
+            position.check_pool(&accounts.pool.key())?;
+            position.check_quote_collateral()?;
+            let authenticated = verify_and_extract_at(&accounts.instruction_sysvar_account_info, count - i, &accounts.pool.key())
+                .map_err(|_| ProtocolError::InvalidSignature)?;
+
+            let cumulative_funding = accounts.pool_config.cumulative_funding;
+            let booked_margin = position.margin;
+            let (mut settlement, status) = close_signed(
+                &mut position,
+                &key,
+                &accounts.pool_config,
+                &authenticated,
+                clock.slot,
+                clock.unix_timestamp,
+                |position| {
+                    position.check_price_accounts(&accounts.price_a.key(), &accounts.price_b.key())?;
+                    if feeds.is_none() {
+                        feeds = Some(PoolFeeds::load(&accounts.price_a, &accounts.price_b)?);
+                    }
+                    let current_price = feeds
+                        .as_ref()
+                        .ok_or(ProtocolError::InvalidPriceAccount)?
+                        .price(position.decimals, accounts.pool_config.max_price_age, accounts.pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+                    position.settle_funding(cumulative_funding)?;
+                    Ok(current_price)
+                },
+            )?;
+            let pnl = realized_pnl(position.margin, settlement.returned_margin)?;
+            // the last step that can fail, as it writes the cross account
+            release_cross_member(ctx.remaining_accounts, &position, &key, booked_margin, &mut settlement, ctx.program_id)?;
+            accounts.pool_config.remove_open_interest(position.direction, position.amount);
 
-        require_eq!(authenticated.authority, position.authority, ProtocolError::InvalidAuthority);
+            if status == BatchStatus::Liquidated {
+                emit!(PositionLiquidated {
+                    position: key,
+                    owner: position.owner,
+                    direction: position.direction,
+                    entry_price: position.last_price,
+                    margin: position.margin,
+                    returned_margin: settlement.returned_margin,
+                    pnl,
+                });
+            } else {
+                emit!(PositionProcessed {
+                    position: key,
+                    owner: position.owner,
+                    direction: position.direction,
+                    entry_price: position.last_price,
+                    margin: position.margin,
+                    returned_margin: settlement.returned_margin,
+                    pnl,
+                });
+            }
+            closed.push(position);
+            Ok((settlement, status))
+        })?;
+        check_min_return(settlement.returned_margin, min_return)?;
+
+        pay_out_settlement(
+            SettlementAccounts {
+                token_program: &ctx.accounts.token_program,
+                vault_authority: &ctx.accounts.vault_authority,
+                vault: &ctx.accounts.vault,
+                fee_account: &ctx.accounts.fee_account,
+                insurance_fund: &ctx.accounts.insurance_fund,
+                owner_token_account: &ctx.accounts.owner_token_account,
+                vault_authority_bump: *ctx.bumps.get("vault_authority").unwrap(),
+            },
+            &ctx.accounts.pool.key(),
+            &settlement,
+        )?;
 
-        unimplemented!();
+        for position in closed {
+            position.close(ctx.accounts.payer.to_account_info())?;
+        }
+        Ok(statuses)
     }
 }
 
 #[derive(Debug, Clone, Copy, AnchorDeserialize, AnchorSerialize)]
 pub struct PositionArgs {
+    /// reference price for the slippage check, scaled by `10^-expo`
     pub price: u64,
+    /// must be `-decimals`; kept so the reference price reads like a Pyth one
     pub expo: i32,
+    /// the single source of scale: oracle prices are fetched at `-decimals`,
+    /// and the position's size and prices are stored with this many decimals
     pub decimals: u8,
     pub leverage_margin: u64,
     pub leverage: u64,
+    /// the leverage is `leverage / leverage_denominator`, so 3 over 2 opens
+    /// at 1.5x; 1 for whole-number leverage, which clients predating it send
+    /// as 0
+    pub leverage_denominator: u64,
     pub ptype: PositionType,
     pub direction: Direction,
     pub slippage_numerator: u64,
     pub margin_rate_numerator: u64,
 }
 impl PositionArgs {
-    pub fn margin(&self) -> u64 {
-        self.leverage_margin
-            .checked_div(self.leverage)
-            .unwrap()
+    /// Rejects an `expo` that disagrees with `decimals`, which would skew the
+    /// slippage check against the oracle price fetched at `-decimals`.
+    #[inline(always)]
+    pub fn check_scale(&self) -> Result<()> {
+        if self.expo != -(self.decimals as i32) {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        Ok(())
+    }
+
+    /// The margin implied by the leveraged size, rounded up when the size
+    /// isn't a multiple of the leverage so a position is never opened with
+    /// less collateral than its leverage requires.
+    pub fn margin(&self) -> Result<u64> {
+        leveraged_margin(self.leverage_margin, self.leverage, self.leverage_denominator)
+    }
+
+    /// Rejects args whose `leverage` isn't the one they're exposed at: the
+    /// margin they post must be what the size `leverage_margin` buys at the
+    /// reference `price` needs at that leverage, as
+    /// `Position::check_leverage_consistency` holds the opened position to.
+    pub fn check_leverage_consistency(&self) -> Result<()> {
+        let price = i64::try_from(self.price).map_err(|_| ProtocolError::InvalidPrice)?;
+        let amount = get_asset_amount(self.leverage_margin, self.price, self.decimals)?;
+        let required = required_initial_margin(amount, price, self.decimals, self.leverage, self.leverage_denominator)?;
+        check_margin_tolerance(self.margin()?, required)
     }
 }
 
@@ -131,30 +1294,139 @@ pub enum PositionType {
     // cross-margin
     Cross,
 }
+/// What a pool's positions are margined in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub enum CollateralMode {
+    /// an SPL token, held in the pool's token vaults
+    Token,
+    /// native SOL, held as lamports in the pool's native vaults
+    Native,
+}
+
+/// How a pool's positions fill around the oracle's confidence interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub enum ConfMode {
+    /// at the oracle price, ignoring the confidence
+    Mid,
+    /// at the edge of the interval against the trader: buying at `price + conf`,
+    /// selling at `price - conf`
+    Conservative,
+}
+
+impl ConfMode {
+    /// `price` as positions in this mode fill against it.
+    #[inline(always)]
+    pub fn quote(self, price: &pyth_sdk_solana::Price) -> pyth_sdk_solana::Price {
+        match self {
+            ConfMode::Mid => pyth_sdk_solana::Price { conf: 0, ..*price },
+            ConfMode::Conservative => *price,
+        }
+    }
+}
+
+/// The side a position was opened on; closing it trades the other way.
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
 pub enum Direction {
     OpenLong,
     OpenShort,
 }
 
+impl Direction {
+    #[inline(always)]
+    pub fn is_long(self) -> bool {
+        self == Direction::OpenLong
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
 pub struct Rate {
     pub numerator: u64,
     pub denominator: u64,
 }
 
-#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+impl Rate {
+    /// `value * numerator / denominator`, rounded down. `None` on a zero
+    /// denominator or when the result doesn't fit in a `u64`.
+    pub fn apply(&self, value: u64) -> Option<u64> {
+        if self.denominator == 0 {
+            return None;
+        }
+        (value as u128)
+            .checked_mul(self.numerator as u128)?
+            .checked_div(self.denominator as u128)?
+            .try_into()
+            .ok()
+    }
+
+    /// `apply` rounded up, for fees charged by the pool.
+    pub fn apply_up(&self, value: u64) -> Option<u64> {
+        round_up_to_u64(
+            (value as u128).checked_mul(self.numerator as u128)?,
+            self.denominator as u128,
+        )
+        .ok()
+    }
+}
+
+/// `value / divisor` rounded down, for amounts paid out of the pool.
+/// `MathOverflow` on a zero divisor or a result that doesn't fit in a `u64`.
+pub fn round_down_to_u64(value: u128, divisor: u128) -> Result<u64> {
+    value
+        .checked_div(divisor)
+        .and_then(|quotient| u64::try_from(quotient).ok())
+        .ok_or(ProtocolError::MathOverflow.into())
+}
+
+/// `value / divisor` rounded up, for fees charged by the pool.
+/// `MathOverflow` on a zero divisor or a result that doesn't fit in a `u64`.
+pub fn round_up_to_u64(value: u128, divisor: u128) -> Result<u64> {
+    let quotient = value
+        .checked_div(divisor)
+        .ok_or(ProtocolError::MathOverflow)?;
+    let quotient = if value % divisor == 0 { quotient } else { quotient + 1 };
+    u64::try_from(quotient)
+        .map_err(|_| ProtocolError::MathOverflow.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
 pub enum PositionStatus {
     Open,
     Processed,
 }
 
+impl PositionStatus {
+    /// The byte the status is stored as at `Position::STATUS_OFFSET`, for
+    /// `memcmp` filters. Fixed by the variant order, which must not change.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            PositionStatus::Open => 0,
+            PositionStatus::Processed => 1,
+        }
+    }
+}
+
+/// Layout, after the 8-byte discriminator, at `POSITION_VERSION` 7: the
+/// version byte, six pubkeys, the index, three one-byte enums and `decimals`,
+/// the `u64`/`i64` fields in declaration order, the two optional trigger
+/// prices, the funding index, the confidence mode, the optional expiry, the
+/// leverage denominator, the collateral feed and its decimals, then the
+/// maker fee.
+/// `version` stays the first field so that it can be read whatever follows it.
 #[account]
 #[derive(Debug)]
 pub struct Position {
+    pub version: u8,
     pub pool: Pubkey,
     pub owner: Pubkey,
+    /// the pool's `oracle_authority` when the position was opened, whose
+    /// signed data alone closes it through `process_position`
     pub authority: Pubkey,
+    /// mint of the collateral held in the pool's vault; `NATIVE_MINT` for a
+    /// position margined in native SOL
+    pub mint: Pubkey,
+    /// Pyth feeds the position is priced with, copied from the pool config
+    pub price_a: Pubkey,
+    pub price_b: Pubkey,
     pub index: u32,
     pub status: PositionStatus,
     pub ptype: PositionType,
@@ -166,277 +1438,8134 @@ pub struct Position {
     pub margin: u64,
     pub margin_rate_numerator: u64,
     pub overnight_fee_numerator: u64,
+    /// fee on the notional traded at open and at any close but a trigger's,
+    /// over 10000
+    pub taker_fee_bps: u64,
     pub liquidation: u64,
     pub created_at: i64,
     pub slot: u64,
+    /// asset size as a fixed-point integer scaled by `10^decimals`
     pub amount: u64,
+    /// the pool's oracle limits when the position was opened; it is priced
+    /// with the pool's current ones
+    pub max_price_age: u64,
+    pub max_conf_bps: u64,
+    /// time up to which overnight fees have been settled into `accrued_fee`
+    pub last_fee_settlement: i64,
+    /// overnight fees of whole days settled so far, charged when closing
+    pub accrued_fee: u64,
+    /// exit price closing the position in profit, set by the owner
+    pub take_profit: Option<u64>,
+    /// exit price closing the position at a loss, set by the owner
+    pub stop_loss: Option<u64>,
+    /// the pool's `cumulative_funding` when funding was last settled
+    pub funding_index: i64,
+    /// the pool's `conf_mode` when the position was created, applied at
+    /// entry and exit alike
+    pub conf_mode: ConfMode,
+    /// time after which anyone may close the position, set by the owner
+    pub expiry: Option<i64>,
+    /// the leverage is `leverage / leverage_denominator`
+    pub leverage_denominator: u64,
+    /// Pyth feed pricing the collateral `mint` in the quote, copied from the
+    /// pool's `collateral_feeds`; `Pubkey::default()` when the collateral is
+    /// the quote itself. `margin` is always held in the quote.
+    pub collateral_price: Pubkey,
+    /// decimals of the collateral `mint`, when it is converted
+    pub collateral_decimals: u8,
+    /// fee on the notional traded when a keeper fills a take-profit or
+    /// stop-loss, over 10000
+    pub maker_fee_bps: u64,
 }
 
 impl Position {
-    pub const LEN: usize = 32
-        + 32
-        + 32
-        + 4
-        + 1 + 1 + 1 + 1
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8
-        + 8;
+    /// Offsets of fields in the account data, discriminator included, for
+    /// `memcmp` filters. Every field up to `direction` has a fixed size, so
+    /// these hold whatever the version.
+    pub const POOL_OFFSET: usize = 8 // discriminator
+        + 1; // version
+    pub const OWNER_OFFSET: usize = Self::POOL_OFFSET + 32;
+    pub const STATUS_OFFSET: usize = Self::OWNER_OFFSET
+        + 32 * 5 // owner, authority, mint, price_a, price_b
+        + 4; // index
+    pub const DIRECTION_OFFSET: usize = Self::STATUS_OFFSET
+        + 1 // status
+        + 1; // ptype
 
+    /// Borsh size of every field in declaration order, with both triggers and the expiry set.
+    pub const LEN: usize = 1 // version
+        + 32 // pool
+        + 32 // owner
+        + 32 // authority
+        + 32 // mint
+        + 32 // price_a
+        + 32 // price_b
+        + 4 // index
+        + 1 // status
+        + 1 // ptype
+        + 1 // direction
+        + 1 // decimals
+        + 8 // leverage
+        + 8 // last_price
+        + 8 // last_conf
+        + 8 // margin
+        + 8 // margin_rate_numerator
+        + 8 // overnight_fee_numerator
+        + 8 // taker_fee_bps
+        + 8 // liquidation
+        + 8 // created_at
+        + 8 // slot
+        + 8 // amount
+        + 8 // max_price_age
+        + 8 // max_conf_bps
+        + 8 // last_fee_settlement
+        + 8 // accrued_fee
+        + 1 + 8 // take_profit
+        + 1 + 8 // stop_loss
+        + 8 // funding_index
+        + 1 // conf_mode
+        + 1 + 8 // expiry
+        + 8 // leverage_denominator
+        + 32 // collateral_price
+        + 1 // collateral_decimals
+        + 8; // maker_fee_bps
+
+    /// Whether the margin was posted in a collateral other than the quote.
     #[inline(always)]
-    pub fn is_liquidated(&self, price: u64) -> bool {
-        match self.direction {
-            Direction::OpenLong => price <= self.liquidation,
-            Direction::OpenShort => price >= self.liquidation,
+    pub fn converts_collateral(&self) -> bool {
+        self.collateral_price != Pubkey::default()
+    }
+
+    /// Rejects a position margined in converted collateral, on the paths that
+    /// move its margin without pricing the collateral.
+    #[inline(always)]
+    pub fn check_quote_collateral(&self) -> Result<()> {
+        if self.converts_collateral() {
+            return err!(ProtocolError::CollateralConversionUnsupported);
         }
+        Ok(())
     }
 
     #[inline(always)]
-    pub fn maintainance_margin(&self) -> u64 {
-        self.margin
-            .checked_mul(self.margin_rate_numerator).unwrap()
-            .checked_div(10000).unwrap()
+    pub fn is_liquidated(&self, price: u64) -> bool {
+        math::is_liquidated(price, self.liquidation, self.direction.is_long())
     }
 
+    /// Rejects accounts written with a layout this program doesn't know.
     #[inline(always)]
-    pub fn overnight_fee(&self, time: i64) -> u64 {
-        let days = time
-            .checked_sub(self.created_at).unwrap()
-            .checked_add(86400).unwrap()
-            .checked_div(86400).unwrap() as u64;
-        self.amount
-            .checked_mul(self.leverage).unwrap()
-            .checked_mul(days).unwrap()
-            .checked_mul(self.overnight_fee_numerator).unwrap()
-            .checked_div(10000).unwrap()
+    pub fn check_version(&self) -> Result<()> {
+        if self.version != POSITION_VERSION {
+            return err!(ProtocolError::UnsupportedVersion);
+        }
+        Ok(())
     }
 
+    /// Rejects a position that has already been closed, so that nothing can
+    /// settle it twice whether or not its account was closed along with it.
     #[inline(always)]
-    pub fn bond(&self) -> u64 {
-        self.margin - self.maintainance_margin()
+    pub fn check_open(&self) -> Result<()> {
+        if self.status != PositionStatus::Open {
+            return err!(ProtocolError::AlreadyProcessed);
+        }
+        Ok(())
     }
 
-    pub fn get_liquidated_margin(&self, time: i64) -> u64 {
-        let overnight_fee = self.overnight_fee(time);
-        self.maintainance_margin()
-            .checked_sub(overnight_fee as u64).unwrap()
+    /// Rejects data signed by anyone but the position's authority, and any
+    /// data at all for a position opened without one.
+    #[inline(always)]
+    pub fn check_authority(&self, signer: &Pubkey) -> Result<()> {
+        if self.authority == Pubkey::default() || *signer != self.authority {
+            return err!(ProtocolError::InvalidAuthority);
+        }
+        Ok(())
     }
-}
 
-#[derive(Accounts)]
-#[instruction(index: u32)]
-pub struct Create<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK:
-    pub pool: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_a: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_b: UncheckedAccount<'info>,
-    #[account(init,
-        seeds = [b"protocol", payer.key().as_ref(), index.to_le_bytes().as_ref()],
-        bump,
-        payer = payer,
-        space = 8 + Position::LEN,
-    )]
-    pub position: Account<'info, Position>,
-    pub system_program: Program<'info, System>,
-}
+    /// Rejects a `pool` other than the one the position was opened in.
+    #[inline(always)]
+    pub fn check_pool(&self, pool: &Pubkey) -> Result<()> {
+        if self.pool != *pool {
+            return err!(ProtocolError::PoolMismatch);
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-#[instruction(args: PositionArgs)]
-pub struct Netoff<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK:
-    pub price_a: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_b: UncheckedAccount<'info>,
-    #[account(mut,
-        constraint = position.owner == payer.key(),
-        constraint = position.ptype == PositionType::Isolated,
-        constraint = args.ptype == PositionType::Isolated,
-    )]
-    pub position: Account<'info, Position>,
-    pub system_program: Program<'info, System>,
-}
+    #[inline(always)]
+    pub fn check_price_accounts(&self, price_a: &Pubkey, price_b: &Pubkey) -> Result<()> {
+        if *price_a != self.price_a || *price_b != self.price_b {
+            return err!(ProtocolError::InvalidPriceAccount);
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct IncreaseMargin<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK:
-    pub price_a: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_b: UncheckedAccount<'info>,
-    #[account(mut,
-        constraint = position.owner == payer.key(),
-    )]
-    pub position: Account<'info, Position>,
-    pub system_program: Program<'info, System>,
-}
+    /// Points the position at the feeds its pool has migrated to.
+    pub fn migrate_feeds(&mut self, pool_config: &PoolConfig) -> Result<()> {
+        self.check_version()?;
+        self.check_pool(&pool_config.pool)?;
+        self.price_a = pool_config.price_a;
+        self.price_b = pool_config.price_b;
+        Ok(())
+    }
 
-#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
-pub struct LiquidatedData {
-    pub is_liquidated: bool,
-    pub price: u64,
-    pub time: i64,
-    pub slot: u64,
-}
+    /// Takes on the pool's current maintenance margin and overnight fee and
+    /// re-derives the liquidation price from the entry and the new bond. The
+    /// whole days elapsed by `time` are settled at the old fee first, so the
+    /// new one only runs from here. A position this leaves liquidatable is
+    /// left to keepers.
+    pub fn apply_pool_rates(&mut self, pool_config: &PoolConfig, time: i64) -> Result<()> {
+        self.check_version()?;
+        self.check_pool(&pool_config.pool)?;
+        self.settle_fees(time)?;
+        self.margin_rate_numerator = pool_config.maintenance_margin_bps;
+        self.overnight_fee_numerator = pool_config.overnight_fee_bps;
+        self.liquidation = get_liquidation(self.last_price, self.bond()?, self.direction)?;
+        Ok(())
+    }
 
-#[derive(Debug, Clone, Copy)]
-pub struct AuthenticatedData {
-    pub data: LiquidatedData,
-    pub authority: Pubkey,
-}
+    /// Sets the exit triggers. A long's stop-loss must sit below its entry and
+    /// its take-profit above, the other way round for a short.
+    pub fn set_triggers(&mut self, take_profit: Option<u64>, stop_loss: Option<u64>) -> Result<()> {
+        let entry = price_to_u64(self.last_price)?;
+        let (below, above) = match self.direction {
+            Direction::OpenLong => (stop_loss, take_profit),
+            Direction::OpenShort => (take_profit, stop_loss),
+        };
+        if matches!(below, Some(price) if price >= entry) || matches!(above, Some(price) if price <= entry) {
+            return err!(ProtocolError::InvalidTrigger);
+        }
 
-pub fn verify_and_extract(instruction_sysvar_account_info: &AccountInfo) -> Result<AuthenticatedData> {
-    use anchor_lang::solana_program;
-    let current_instruction = solana_program::sysvar::instructions::load_current_index_checked(instruction_sysvar_account_info)?;
-    if current_instruction == 0 {
-        return err!(ProtocolError::InstructionAtWrongIndex);
+        self.take_profit = take_profit;
+        self.stop_loss = stop_loss;
+        Ok(())
     }
 
-    let ed25519_ix_index = (current_instruction - 1) as usize;
-    let ed25519_ix = solana_program::sysvar::instructions::load_instruction_at_checked(
-        ed25519_ix_index,
-        instruction_sysvar_account_info,
-    )
-    .map_err(|_| ProtocolError::InvalidAccountData)?;
+    /// Rejects cancelling a position that was ever filled or funded: one
+    /// holds margin, or did before fees or funding took it, and has to be
+    /// closed through the usual paths to settle. A cross position is booked
+    /// in its `CrossAccount` from the start, which only a close releases.
+    #[inline(always)]
+    pub fn check_cancellable(&self) -> Result<()> {
+        let untouched = self.amount == 0
+            && self.last_price == 0
+            && self.margin == 0
+            && self.accrued_fee == 0;
+        if self.ptype == PositionType::Cross || !untouched {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        Ok(())
+    }
 
-    if ed25519_ix.program_id != solana_program::ed25519_program::id() {
-        return err!(ProtocolError::InvalidEd25519Instruction);
+    /// Sets or clears the expiry; a new one must lie after `now`.
+    pub fn set_expiry(&mut self, expiry: Option<i64>, now: i64) -> Result<()> {
+        if matches!(expiry, Some(expiry) if expiry <= now) {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        self.expiry = expiry;
+        Ok(())
     }
 
-    let sig_len = ed25519_ix.data[0];
-    if sig_len != 1 {
-        return err!(ProtocolError::InvalidEd25519Instruction);
+    /// Rejects force-closing the position before its expiry has passed at `time`.
+    #[inline(always)]
+    pub fn check_expired(&self, time: i64) -> Result<()> {
+        match self.expiry {
+            Some(expiry) if time >= expiry => Ok(()),
+            _ => err!(ProtocolError::PositionNotExpired),
+        }
     }
 
-    let mut index = 2;
-    let _sig_offset = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]) as usize;
-    index += 2;
-    let sig_ix = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]);
-    index += 2;
-    let pubkey_offset = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]) as usize;
-    index += 2;
-    let pubkey_ix = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]);
-    index += 2;
-    let data_offset = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]) as usize;
-    index += 2;
-    let data_size = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]) as usize;
-    index += 2;
-    let data_ix = byteorder::LE::read_u16(&ed25519_ix.data[index..index+2]);
+    /// The trading fee on `amount` of the asset traded at `price` on the
+    /// `liquidity` side.
+    pub fn trading_fee(&self, price: u64, amount: u64, liquidity: Liquidity) -> Result<u64> {
+        let price = i64::try_from(price)
+            .map_err(|_| ProtocolError::InvalidPrice)?;
+        let notional = notional(price, amount, self.decimals)?;
+        let numerator = match liquidity {
+            Liquidity::Maker => self.maker_fee_bps,
+            Liquidity::Taker => self.taker_fee_bps,
+        };
+        Rate {
+            numerator,
+            denominator: RATE_DENOMINATOR,
+        }
+        .apply_up(notional)
+        .ok_or(ProtocolError::MathOverflow.into())
+    }
 
-    if pubkey_ix != u16::MAX || data_ix != u16::MAX || sig_ix != u16::MAX {
-        return err!(ProtocolError::InvalidEd25519Instruction);
+    /// Takes the trading fee of opening out of the margin and derives the
+    /// liquidation price from what is left.
+    pub fn charge_open_fee(&mut self, shared_collateral: u64) -> Result<u64> {
+        let fee = self.trading_fee(self.entry_price()?, self.amount, Liquidity::Taker)?;
+        self.margin = self.margin
+            .checked_sub(fee)
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        self.liquidation = get_liquidation(
+            self.last_price,
+            self.bond()?
+                .checked_add(shared_collateral)
+                .ok_or(ProtocolError::MathOverflow)?,
+            self.direction,
+        )?;
+        Ok(fee)
     }
 
-    let authority = Pubkey::try_from_slice(&ed25519_ix.data[pubkey_offset..pubkey_offset + 32])?;
-    let data: LiquidatedData = AnchorDeserialize::try_from_slice(&ed25519_ix.data[data_offset..data_offset+data_size])?;
+    /// The price the position exits at: the bid for a long, the ask for a short.
+    #[inline(always)]
+    pub fn exit_price(&self, current_price: &pyth_sdk_solana::Price) -> Result<u64> {
+        let current_price = &self.conf_mode.quote(current_price);
+        match self.direction {
+            Direction::OpenLong => get_fill_price(current_price, Direction::OpenShort),
+            Direction::OpenShort => get_fill_price(current_price, Direction::OpenLong),
+        }
+    }
 
-    Ok(AuthenticatedData {
-        data,
-        authority,
-    })
-}
+    /// Whether the exit price has crossed either trigger.
+    pub fn is_triggered(&self, current_price: &pyth_sdk_solana::Price) -> Result<bool> {
+        let exit_price = self.exit_price(current_price)?;
+        Ok(match self.direction {
+            Direction::OpenLong => {
+                matches!(self.take_profit, Some(price) if exit_price >= price)
+                    || matches!(self.stop_loss, Some(price) if exit_price <= price)
+            }
+            Direction::OpenShort => {
+                matches!(self.take_profit, Some(price) if exit_price <= price)
+                    || matches!(self.stop_loss, Some(price) if exit_price >= price)
+            }
+        })
+    }
 
-#[derive(Accounts)]
-pub struct ProcessPosition<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK:
-    pub pool: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_a: UncheckedAccount<'info>,
-    /// CHECK:
-    pub price_b: UncheckedAccount<'info>,
-    #[account(mut,
-        close = payer,
-        constraint = position.owner == payer.key(),
-    )]
-    pub position: Account<'info, Position>,
-    pub system_program: Program<'info, System>,
-    /// CHECK:
-    #[account(
-        constraint = instruction_sysvar_account_info.key() == anchor_lang::solana_program::sysvar::instructions::id(),
-    )]
-    pub instruction_sysvar_account_info: UncheckedAccount<'info>,
-}
+    #[inline(always)]
+    pub fn margin_rate(&self) -> Rate {
+        Rate {
+            numerator: self.margin_rate_numerator,
+            denominator: RATE_DENOMINATOR,
+        }
+    }
 
-fn get_current_price<'a>(price_a: &'a UncheckedAccount, price_b: &'a UncheckedAccount, decimals: u8) -> Result<pyth_sdk_solana::Price> {
-    // price feed
-    let pfa = pyth_sdk_solana::load_price_feed_from_account_info(price_a)
-        .map_err(|_| ProtocolError::InvalidPriceAccount)?;
-    let pfb = pyth_sdk_solana::load_price_feed_from_account_info(price_b)
-        .map_err(|_| ProtocolError::InvalidPriceAccount)?;
-    // current price
-    let cpa = pfa
-        .get_current_price()
-        .ok_or(ProtocolError::InvalidPrice)?;
-    let cpb = pfb
-        .get_current_price()
-        .ok_or(ProtocolError::InvalidPrice)?;
-    let target_expo = (decimals as i32)
-        .checked_neg()
-        .ok_or(ProtocolError::InvalidArgs)?;
-    cpa.get_price_in_quote(&cpb, target_expo)
-        .ok_or(ProtocolError::InvalidPrice.into())
-}
+    #[inline(always)]
+    pub fn overnight_fee_rate(&self) -> Rate {
+        Rate {
+            numerator: self.overnight_fee_numerator,
+            denominator: RATE_DENOMINATOR,
+        }
+    }
 
-fn get_liquidation(price: i64, bond: u64, direction: Direction) -> u64 {
-    // the price
-    match direction {
-        Direction::OpenLong => {
-            (price as u64)
-                .checked_sub(bond).unwrap()
+    /// `margin_rate` of the margin the position's size needs at its entry
+    /// price and leverage. It follows the size, not the margin held, so fees,
+    /// funding, deposits and withdrawals move the bond and leave the
+    /// requirement where it was.
+    #[inline(always)]
+    pub fn maintainance_margin(&self) -> Result<u64> {
+        let basis = required_initial_margin(self.amount, self.last_price, self.decimals, self.leverage, self.leverage_denominator)?;
+        self.margin_rate()
+            .apply(basis)
+            .ok_or(ProtocolError::MathOverflow.into())
+    }
+
+    /// Overnight fees owed at `time`: those settled so far plus the days
+    /// since, counted by `OVERNIGHT_FEE_ROUNDING`. Under `Ceil` a position
+    /// closed in the second it was opened pays nothing, one held for a day
+    /// and a second pays for two.
+    #[inline(always)]
+    pub fn overnight_fee(&self, time: i64) -> Result<u64> {
+        let days = OVERNIGHT_FEE_ROUNDING.days(self.elapsed_since_settlement(time)?)?;
+        self.overnight_fee_of_days(days)?
+            .checked_add(self.accrued_fee)
+            .ok_or(ProtocolError::MathOverflow.into())
+    }
+
+    /// Moves the fees of the whole days elapsed by `time` into `accrued_fee`,
+    /// charged on the notional held during them. Called before the size or
+    /// margin changes; the day in progress keeps running and is charged on
+    /// whatever notional is held when it completes or the position closes.
+    pub fn settle_fees(&mut self, time: i64) -> Result<()> {
+        let days = DayRounding::Floor.days(self.elapsed_since_settlement(time)?)?;
+        self.accrued_fee = self.accrued_fee
+            .checked_add(self.overnight_fee_of_days(days)?)
+            .ok_or(ProtocolError::MathOverflow)?;
+        self.last_fee_settlement = days
+            .checked_mul(SECONDS_PER_DAY)
+            .and_then(|settled| i64::try_from(settled).ok())
+            .and_then(|settled| self.last_fee_settlement.checked_add(settled))
+            .ok_or(ProtocolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// The liquidation price at `time`, with the overnight fees owed by then
+    /// and the funding owed at `cumulative_funding` taken out of the bond as
+    /// `realize_fees` would take them out of the margin. The stored
+    /// `liquidation` only moves when fees and funding are realized, so
+    /// between settlements it trails this one.
+    pub fn liquidation_at(&self, time: i64, cumulative_funding: i64) -> Result<u64> {
+        let bond = math::fee_adjusted_bond(
+            self.margin,
+            self.maintainance_margin()?,
+            self.overnight_fee(time)?,
+            self.funding_owed(cumulative_funding)?,
+        );
+        get_liquidation(self.last_price, bond, self.direction)
+    }
+
+    /// `is_liquidated` against the liquidation price at `time` and
+    /// `cumulative_funding`.
+    #[inline(always)]
+    pub fn is_liquidated_at(&self, price: u64, time: i64, cumulative_funding: i64) -> Result<bool> {
+        Ok(math::is_liquidated(price, self.liquidation_at(time, cumulative_funding)?, self.direction.is_long()))
+    }
+
+    /// Brings the position up to `time` before its margin or size changes:
+    /// fails with `PositionLiquidated` if the fees and funding owed by then
+    /// leave it liquidatable at `price`, and otherwise settles them. A cross
+    /// member keeps its funding for the close that releases it, as its
+    /// `CrossAccount` books the margin it holds.
+    pub fn settle_at(&mut self, price: u64, time: i64, cumulative_funding: i64) -> Result<()> {
+        if self.is_liquidated_at(price, time, cumulative_funding)? {
+            return err!(ProtocolError::PositionLiquidated);
         }
-        Direction::OpenShort => {
-            (price as u64)
-                .checked_add(bond).unwrap()
+        self.settle_fees(time)?;
+        if self.ptype != PositionType::Cross {
+            self.settle_funding(cumulative_funding)?;
         }
+        Ok(())
     }
-}
 
-fn get_asset_amount(leverage_margin: u64, price: u64) -> u64 {
-    leverage_margin.checked_div(price).unwrap()
-}
+    /// Takes the overnight fees of the whole days elapsed by `time`, and the
+    /// funding owed at `cumulative_funding`, out of the margin and moves the
+    /// liquidation price to the smaller bond. Fails with `PositionLiquidated`
+    /// if that leaves the position liquidatable at `price`. Returns the
+    /// overnight fee taken, which leaves the vault.
+    pub fn realize_fees(&mut self, time: i64, cumulative_funding: i64, price: u64) -> Result<u64> {
+        self.settle_fees(time)?;
+        self.settle_funding(cumulative_funding)?;
+        let fee = self.accrued_fee;
+        self.margin = self.margin
+            .checked_sub(fee)
+            .ok_or(ProtocolError::PositionLiquidated)?;
+        self.accrued_fee = 0;
+        self.liquidation = get_liquidation(self.last_price, self.bond()?, self.direction)?;
+        if self.is_liquidated(price) {
+            return err!(ProtocolError::PositionLiquidated);
+        }
+        Ok(fee)
+    }
 
-fn check_slippage(price: u64, args: PositionArgs) -> Result<()> {
-    let price_before = args.price
-            .checked_div(
-                10u64
-                    .checked_pow(args.expo.abs() as u32)
-                    .ok_or(ProtocolError::InvalidPrice)?
-            )
+    fn elapsed_since_settlement(&self, time: i64) -> Result<u64> {
+        time
+            .checked_sub(self.last_fee_settlement)
+            .and_then(|elapsed| u64::try_from(elapsed).ok())
+            .ok_or(ProtocolError::MathOverflow.into())
+    }
+
+    fn overnight_fee_of_days(&self, days: u64) -> Result<u64> {
+        // the financing model of `TransactionAccount`: the daily rate as a
+        // yearly one on the leveraged notional, over `days / 365`
+        TransactionAccount::try_from(self)?
+            .financing_fee(days)
+            .ok_or(ProtocolError::MathOverflow.into())
+    }
+
+    /// Funding owed since the last settlement with the pool at
+    /// `cumulative_funding`; negative when the position is owed instead.
+    pub fn funding_owed(&self, cumulative_funding: i64) -> Result<i64> {
+        let entry_notional = self.entry_notional()?;
+        let owed = (cumulative_funding as i128)
+            .checked_sub(self.funding_index as i128)
+            .and_then(|index| index.checked_mul(entry_notional as i128))
+            .map(|owed| owed / FUNDING_PRECISION as i128)
+            .and_then(|owed| i64::try_from(owed).ok())
+            .ok_or(ProtocolError::MathOverflow)?;
+        match self.direction {
+            Direction::OpenLong => Ok(owed),
+            Direction::OpenShort => Ok(-owed),
+        }
+    }
+
+    /// Realizes the funding owed into `margin` and moves the liquidation
+    /// price with it. Funding owed beyond the margin leaves none, and the
+    /// position to `liquidate`, rather than failing every path that settles
+    /// it. Returns the funding paid.
+    pub fn settle_funding(&mut self, cumulative_funding: i64) -> Result<i64> {
+        let owed = self.funding_owed(cumulative_funding)?;
+        let margin = math::funded_margin(self.margin, owed);
+        let paid = (self.margin as i128 - margin as i128) as i64;
+        self.margin = margin;
+        self.funding_index = cumulative_funding;
+        self.liquidation = get_liquidation(
+            self.last_price,
+            self.bond()?,
+            self.direction,
+        )?;
+        Ok(paid)
+    }
+
+    /// Rejects a position whose margin doesn't cover its size at its leverage.
+    pub fn check_initial_margin(&self) -> Result<()> {
+        let required = required_initial_margin(self.amount, self.last_price, self.decimals, self.leverage, self.leverage_denominator)?;
+        if self.margin < required {
+            return err!(ProtocolError::InsufficientBalance);
+        }
+        Ok(())
+    }
+
+    /// Rejects a margin that strays more than `ASSET_AMOUNT_TOLERANCE_BPS`,
+    /// past a unit of rounding, from what the size needs at the stored
+    /// leverage, so the leverage recorded is the one the position is exposed at.
+    pub fn check_leverage_consistency(&self) -> Result<()> {
+        let required = required_initial_margin(self.amount, self.last_price, self.decimals, self.leverage, self.leverage_denominator)?;
+        check_margin_tolerance(self.margin, required)
+    }
+
+    fn entry_notional(&self) -> Result<u64> {
+        notional(self.last_price, self.amount, self.decimals)
+    }
+
+    /// The position's exposure at `price`, in collateral units: positive for
+    /// a long, negative for a short.
+    pub fn notional(&self, price: i64) -> Result<i128> {
+        let notional = notional(price, self.amount, self.decimals)? as i128;
+        Ok(match self.direction {
+            Direction::OpenLong => notional,
+            Direction::OpenShort => -notional,
+        })
+    }
+
+    /// `notional` over the position's leverage: the margin its exposure at
+    /// `price` would take at that leverage, signed like `notional`.
+    pub fn leverage_adjusted_exposure(&self, price: i64) -> Result<i128> {
+        if self.leverage == 0 || self.leverage_denominator == 0 {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+        Ok(self.notional(price)? * self.leverage_denominator as i128 / self.leverage as i128)
+    }
+
+    #[inline(always)]
+    fn entry_price(&self) -> Result<u64> {
+        price_to_u64(self.last_price)
+    }
+
+    /// The margin above the maintenance margin; zero once fees or funding
+    /// have taken the margin below it, which leaves the position liquidatable
+    /// at its entry price.
+    #[inline(always)]
+    pub fn bond(&self) -> Result<u64> {
+        Ok(self.margin.saturating_sub(self.maintainance_margin()?))
+    }
+
+    /// What is left to split on liquidation at `time`: the maintenance
+    /// margin, or the margin if less remains once the funding owed at
+    /// `cumulative_funding` is paid, net of overnight fees. Floors at zero
+    /// once the fees eat all of it.
+    pub fn get_liquidated_margin(&self, time: i64, cumulative_funding: i64) -> Result<u64> {
+        let overnight_fee = self.overnight_fee(time)?;
+        let margin = math::funded_margin(self.margin, self.funding_owed(cumulative_funding)?);
+        Ok(self.maintainance_margin()?
+            .min(margin)
+            .saturating_sub(overnight_fee))
+    }
+
+    /// What a position marked liquidated at `time` pays out. No keeper is
+    /// involved, so the whole penalty of `liquidation_split` goes to the
+    /// insurance fund.
+    pub fn liquidated_settlement(&self, time: i64, cumulative_funding: i64, penalty_bps: u64) -> Result<Settlement> {
+        let split = self.liquidation_split(time, cumulative_funding, penalty_bps)?;
+        Ok(Settlement {
+            returned_margin: split.returned_margin,
+            overnight_fee: self.overnight_fee(time)?,
+            trading_fee: 0,
+            bad_debt: 0,
+            insurance_fee: split.keeper_bonus + split.insurance_fee,
+        })
+    }
+
+    /// The position's margin ratio and distance to its liquidation price at
+    /// `current_price`.
+    pub fn health(&self, current_price: &pyth_sdk_solana::Price, time: i64) -> Result<Health> {
+        // an underwater position has no equity left, which `settle` returns
+        // as bad debt rather than an error
+        let equity = self.settle(current_price, time)?.returned_margin as u128 * 10000;
+        let maintenance_margin = self.maintainance_margin()? as u128;
+        // a position without a maintenance margin can't be liquidated
+        let margin_ratio_bps = match equity.checked_div(maintenance_margin) {
+            Some(ratio) => u64::try_from(ratio).unwrap_or(u64::MAX),
+            None => u64::MAX,
+        };
+
+        let price = price_to_u64(current_price.price)?;
+        let liquidation_distance = match self.direction {
+            Direction::OpenLong => price.saturating_sub(self.liquidation),
+            Direction::OpenShort => self.liquidation.saturating_sub(price),
+        };
+
+        Ok(Health {
+            margin_ratio_bps,
+            liquidation_distance,
+        })
+    }
+
+    /// Splits the margin left after a liquidation. `penalty_bps` of it is the
+    /// penalty, shared between the keeper and the insurance fund, and the
+    /// owner gets the rest back.
+    pub fn liquidation_split(&self, time: i64, cumulative_funding: i64, penalty_bps: u64) -> Result<LiquidateResult> {
+        split_penalty(self.get_liquidated_margin(time, cumulative_funding)?, penalty_bps)
+    }
+
+    /// The PnL of closing at `current_price`, selling a long at the bid or
+    /// buying back a short at the ask, as modelled by `TransactionAccount`.
+    pub fn unrealized_pnl(&self, current_price: &pyth_sdk_solana::Price) -> Result<i128> {
+        TransactionAccount::try_from(self)?
+            .gross_profit(&self.conf_mode.quote(current_price))
+            .ok_or(ProtocolError::InvalidPrice.into())
+    }
+
+    /// The margin returned to the owner when closing at `current_price`: the
+    /// margin less the overnight and closing fees, plus the PnL. Fails when the loss exceeds
+    /// the margin; see `settle` for closing such a position.
+    pub fn get_profit(&self, current_price: &pyth_sdk_solana::Price, time: i64) -> Result<u64> {
+        let trading_fee = self.trading_fee(self.exit_price(current_price)?, self.amount, Liquidity::Taker)?;
+        let margin = self.margin
+            .checked_sub(self.overnight_fee(time)?)
+            .and_then(|margin| margin.checked_sub(trading_fee))
             .ok_or(ProtocolError::InvalidPrice)?;
 
-    match args.direction {
-        Direction::OpenLong => {
-            // the real price is higher than the given price
-            let real_price = price_before
-                .checked_mul(args.slippage_numerator + 10000).unwrap()
-                .checked_div(10000).unwrap();
-            if price.cmp(&real_price).is_ge() {
-                return err!(ProtocolError::SlippageReached);
-            }
+        u64::try_from(margin as i128 + self.unrealized_pnl(current_price)?)
+            .map_err(|_| ProtocolError::InvalidPrice.into())
+    }
+
+    /// Closes a position that hasn't crossed its liquidation price, which is
+    /// left to keepers.
+    pub fn close_at(&mut self, current_price: &pyth_sdk_solana::Price, time: i64) -> Result<Settlement> {
+        if self.is_liquidated(price_to_u64(current_price.price)?) {
+            return err!(ProtocolError::PositionLiquidated);
         }
-        Direction::OpenShort => {
-            // the real price is lower than the given price
-            let real_price = price_before
-                .checked_mul(10000 - args.slippage_numerator).unwrap()
-                .checked_div(10000).unwrap();
-            if price.cmp(&real_price).is_le() {
-                return err!(ProtocolError::SlippageReached);
-            }
+        let settlement = self.settle(current_price, time)?;
+        self.status = PositionStatus::Processed;
+        Ok(settlement)
+    }
+
+    /// Closes `close_amount` of the position's size at `current_price`,
+    /// realizing the PnL and the overnight fees settled so far on that share.
+    /// Margin and size shrink in proportion, so the liquidation price of what
+    /// remains is unchanged. The day in progress is charged on the remaining
+    /// size only.
+    pub fn partial_close(&mut self, close_amount: u64, current_price: &pyth_sdk_solana::Price, time: i64) -> Result<Settlement> {
+        if close_amount == 0 || close_amount >= self.amount {
+            return err!(ProtocolError::InvalidArgs);
         }
+        self.settle_fees(time)?;
+
+        let share = |value: u64| -> Result<u64> {
+            Ok(((value as u128)
+                .checked_mul(close_amount as u128)
+                .ok_or(ProtocolError::MathOverflow)?
+                / self.amount as u128) as u64)
+        };
+        let closed_margin = share(self.margin)?;
+        let overnight_fee = share(self.accrued_fee)?;
+        let trading_fee = self.trading_fee(self.exit_price(current_price)?, close_amount, Liquidity::Taker)?;
+        let pnl = self.unrealized_pnl(current_price)?
+            .checked_mul(close_amount as i128)
+            .ok_or(ProtocolError::MathOverflow)?
+            / self.amount as i128;
+
+        let returned_margin = (closed_margin as i128 - overnight_fee as i128 - trading_fee as i128)
+            .checked_add(pnl)
+            .and_then(|returned| u64::try_from(returned).ok())
+            .ok_or(ProtocolError::PositionLiquidated)?;
+
+        self.margin -= closed_margin;
+        self.accrued_fee -= overnight_fee;
+        self.amount -= close_amount;
+
+        Ok(Settlement {
+            returned_margin,
+            overnight_fee,
+            trading_fee,
+            bad_debt: 0,
+            insurance_fee: 0,
+        })
     }
-    Ok(())
-}
\ No newline at end of file
+
+    /// Closes the position at `current_price` even when the loss exceeds the
+    /// margin. The overnight fee, then the closing fee, are taken first, as
+    /// far as the margin covers them; whatever the margin cannot cover is returned as bad debt and the
+    /// owner gets nothing back.
+    pub fn settle(&self, current_price: &pyth_sdk_solana::Price, time: i64) -> Result<Settlement> {
+        self.settle_as(current_price, time, Liquidity::Taker)
+    }
+
+    /// `settle`, with the closing fee charged on the `liquidity` side.
+    pub fn settle_as(&self, current_price: &pyth_sdk_solana::Price, time: i64, liquidity: Liquidity) -> Result<Settlement> {
+        let overnight_fee = self.overnight_fee(time)?.min(self.margin);
+        let trading_fee = self.trading_fee(self.exit_price(current_price)?, self.amount, liquidity)?
+            .min(self.margin - overnight_fee);
+        let equity = (self.margin - overnight_fee - trading_fee) as i128 + self.unrealized_pnl(current_price)?;
+
+        if equity >= 0 {
+            Ok(Settlement {
+                returned_margin: u64::try_from(equity).map_err(|_| ProtocolError::MathOverflow)?,
+                overnight_fee,
+                trading_fee,
+                bad_debt: 0,
+                insurance_fee: 0,
+            })
+        } else {
+            Ok(Settlement {
+                returned_margin: 0,
+                overnight_fee,
+                trading_fee,
+                bad_debt: u64::try_from(-equity).map_err(|_| ProtocolError::MathOverflow)?,
+                insurance_fee: 0,
+            })
+        }
+    }
+
+    /// Fills the position at the current ask (long) or bid (short) and derives
+    /// its size and liquidation price. `shared_collateral` is the free collateral
+    /// of the owner's `CrossAccount` backing the position, zero when isolated.
+    pub fn open(&mut self, args: &PositionArgs, current_price: &pyth_sdk_solana::Price, shared_collateral: u64) -> Result<()> {
+        // past a whole `RATE_DENOMINATOR` the maintenance margin outgrows the
+        // margin and a day's fee the notional
+        if args.margin_rate_numerator > RATE_DENOMINATOR || self.overnight_fee_numerator > RATE_DENOMINATOR {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        let current_price = &self.conf_mode.quote(current_price);
+        let ask_or_bid = get_fill_price(current_price, args.direction)?;
+        check_slippage(ask_or_bid, *args)?;
+
+        self.leverage = args.leverage;
+        self.leverage_denominator = args.leverage_denominator;
+        self.margin_rate_numerator = args.margin_rate_numerator;
+        self.last_price = ask_or_bid as i64;
+        self.last_conf = current_price.conf;
+        self.amount = get_asset_amount(args.leverage_margin, ask_or_bid, self.decimals)?;
+        check_asset_amount(self.amount, ask_or_bid, self.decimals, args.leverage_margin)?;
+        self.check_initial_margin()?;
+        self.liquidation = get_liquidation(
+            self.last_price,
+            self.bond()?
+                .checked_add(shared_collateral)
+                .ok_or(ProtocolError::MathOverflow)?,
+            self.direction,
+        )?;
+        // a wide confidence or a bond near zero can leave the fill already
+        // past the liquidation price
+        let price = price_to_u64(current_price.price)?;
+        if self.is_liquidated(price) {
+            return err!(ProtocolError::WouldBeImmediatelyLiquidated);
+        }
+
+        Ok(())
+    }
+
+    /// Averages a same-direction order into the position: the entry becomes the
+    /// size-weighted average of the old and new fills, size and margin are
+    /// summed, less the taker fee on the fill, and the leverage becomes that of
+    /// the combined notional over the combined margin. Returns the margin
+    /// posted by the order and the fee taken out of it.
+    pub fn add(&mut self, args: &PositionArgs, current_price: &pyth_sdk_solana::Price) -> Result<(u64, u64)> {
+        if args.direction != self.direction {
+            return err!(ProtocolError::InvalidArgs);
+        }
+
+        let current_price = &self.conf_mode.quote(current_price);
+        let ask_or_bid = get_fill_price(current_price, args.direction)?;
+        check_slippage(ask_or_bid, *args)?;
+
+        let amount = get_asset_amount(args.leverage_margin, ask_or_bid, self.decimals)?;
+        let trading_fee = self.trading_fee(ask_or_bid, amount, Liquidity::Taker)?;
+        let total_amount = self.amount
+            .checked_add(amount)
+            .ok_or(ProtocolError::MathOverflow)?;
+
+        // weighted in u128 so the products of price and size can't overflow
+        let weighted_price = (price_to_u64(self.last_price)? as u128)
+            .checked_mul(self.amount as u128)
+            .and_then(|old| {
+                (ask_or_bid as u128)
+                    .checked_mul(amount as u128)?
+                    .checked_add(old)
+            })
+            .and_then(|sum| sum.checked_div(total_amount as u128))
+            .ok_or(ProtocolError::MathOverflow)?;
+
+        self.last_price = i64::try_from(weighted_price)
+            .map_err(|_| ProtocolError::InvalidPrice)?;
+        self.last_conf = current_price.conf;
+        self.amount = total_amount;
+        self.margin = self.margin
+            .checked_add(args.margin()?)
+            .and_then(|margin| margin.checked_sub(trading_fee))
+            .ok_or(ProtocolError::MathOverflow)?;
+        // the orders may have been levered differently; the combined position
+        // is levered at its notional over its margin, kept as a reduced fraction
+        let notional = self.entry_notional()?;
+        let divisor = gcd(notional, self.margin);
+        if divisor == 0 {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+        self.leverage = notional / divisor;
+        self.leverage_denominator = self.margin / divisor;
+        self.liquidation = get_liquidation(
+            self.last_price,
+            self.bond()?,
+            self.direction,
+        )?;
+
+        Ok((args.margin()?, trading_fee))
+    }
+
+    /// Adds `amount` to the margin and moves the liquidation price to the
+    /// larger bond.
+    pub fn deposit(&mut self, amount: u64) -> Result<()> {
+        self.margin = self.margin
+            .checked_add(amount)
+            .ok_or(ProtocolError::MathOverflow)?;
+        self.liquidation = get_liquidation(self.last_price, self.bond()?, self.direction)?;
+        Ok(())
+    }
+
+    /// Runs `change` on the position, `key`, and returns what it returned
+    /// along with the `MarginChanged` logging it for `reason`.
+    pub fn change_margin<T>(&mut self, key: Pubkey, reason: MarginChangeReason, change: impl FnOnce(&mut Self) -> Result<T>) -> Result<(T, MarginChanged)> {
+        let old_margin = self.margin;
+        let result = change(self)?;
+        Ok((result, MarginChanged {
+            position: key,
+            old_margin,
+            new_margin: self.margin,
+            reason,
+        }))
+    }
+
+    /// Withdraws margin from the position, keeping at least the maintenance
+    /// margin and refusing withdrawals that would make it liquidatable at `price`.
+    pub fn withdraw(&mut self, amount: u64, price: u64) -> Result<()> {
+        let withdrawable = self.margin
+            .checked_sub(self.maintainance_margin()?)
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        if amount > withdrawable {
+            return err!(ProtocolError::InsufficientBalance);
+        }
+
+        let mut withdrawn = self.clone();
+        withdrawn.margin -= amount;
+        withdrawn.liquidation = get_liquidation(
+            withdrawn.last_price,
+            withdrawn.bond()?,
+            withdrawn.direction,
+        )?;
+        if withdrawn.is_liquidated(price) {
+            return err!(ProtocolError::PositionLiquidated);
+        }
+        *self = withdrawn;
+
+        Ok(())
+    }
+
+    /// The liquidation price after `margin_delta` is added to the margin,
+    /// without changing the position.
+    pub fn liquidation_after(&self, margin_delta: i64) -> Result<u64> {
+        let mut changed = self.clone();
+        changed.margin = (self.margin as i128)
+            .checked_add(margin_delta as i128)
+            .and_then(|margin| u64::try_from(margin).ok())
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        get_liquidation(changed.last_price, changed.bond()?, changed.direction)
+    }
+
+    /// Re-levers the position holding its size, and so its notional at entry,
+    /// constant: the margin becomes that notional over `leverage`, a whole
+    /// number, rounded up as at open, and the liquidation price moves with
+    /// it. Refuses a change that would make the position liquidatable at
+    /// `price`. Returns the margin before the change.
+    pub fn set_leverage(&mut self, leverage: u64, price: u64) -> Result<u64> {
+        if leverage == 0 || leverage > MAX_LEVERAGE {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+
+        let mut relevered = self.clone();
+        relevered.leverage = leverage;
+        relevered.leverage_denominator = 1;
+        relevered.margin = leveraged_margin(self.entry_notional()?, leverage, 1)?;
+        relevered.liquidation = get_liquidation(
+            relevered.last_price,
+            relevered.bond()?,
+            relevered.direction,
+        )?;
+        if relevered.is_liquidated(price) {
+            return err!(ProtocolError::PositionLiquidated);
+        }
+
+        let previous_margin = self.margin;
+        *self = relevered;
+        Ok(previous_margin)
+    }
+
+    /// What a position closed by `reduce` pays out: its margin, with the PnL
+    /// already realized into it, less the overnight fees settled so far.
+    pub fn netted_off_settlement(&self) -> Settlement {
+        let overnight_fee = self.accrued_fee.min(self.margin);
+        Settlement {
+            returned_margin: self.margin - overnight_fee,
+            overnight_fee,
+            ..Settlement::default()
+        }
+    }
+
+    /// Nets an opposite-direction order off against the position. The PnL of the
+    /// netted-off size is realized into `margin`. An order of exactly the
+    /// position's size closes it, leaving the margin to be paid out as
+    /// `netted_off_settlement`; a larger one flips it, opening the remainder in
+    /// the order's direction with the matching share of the order's margin.
+    /// The taker fee on the whole fill comes out of the margin. Returns the
+    /// margin posted by the order, zero unless the position flips, and the fee.
+    pub fn reduce(&mut self, args: &PositionArgs, current_price: &pyth_sdk_solana::Price) -> Result<(u64, u64)> {
+        if args.direction == self.direction {
+            return err!(ProtocolError::InvalidArgs);
+        }
+
+        // the order's fill is the position's exit: bid for longs, ask for shorts,
+        // bounded by the order's own slippage
+        let current_price = &self.conf_mode.quote(current_price);
+        let ask_or_bid = get_fill_price(current_price, args.direction)?;
+        check_slippage(ask_or_bid, *args)?;
+        let amount = get_asset_amount(args.leverage_margin, ask_or_bid, self.decimals)?;
+        let closed = amount.min(self.amount);
+        let trading_fee = self.trading_fee(ask_or_bid, amount, Liquidity::Taker)?;
+
+        let diff = match self.direction {
+            Direction::OpenLong => (ask_or_bid as i128)
+                .checked_sub(self.last_price as i128),
+            Direction::OpenShort => (self.last_price as i128)
+                .checked_sub(ask_or_bid as i128),
+        }
+        .ok_or(ProtocolError::InvalidPrice)?;
+        let realized = diff
+            .checked_mul(closed as i128)
+            .and_then(|pnl| pnl.checked_div(10i128.checked_pow(self.decimals as u32)?))
+            .and_then(|pnl| pnl.checked_add(self.margin as i128))
+            .and_then(|margin| margin.checked_sub(trading_fee as i128))
+            .ok_or(ProtocolError::InvalidPrice)?;
+        if realized < 0 {
+            return err!(ProtocolError::PositionLiquidated);
+        }
+
+        self.margin = u64::try_from(realized)
+            .map_err(|_| ProtocolError::InvalidPrice)?;
+        self.amount -= closed;
+        if self.amount == 0 && amount == closed {
+            // an order of exactly the position's size closes it
+            self.status = PositionStatus::Processed;
+        }
+
+        let remainder = amount - closed;
+        let mut remainder_margin = 0;
+        if remainder > 0 {
+            remainder_margin = (args.margin()? as u128)
+                .checked_mul(remainder as u128)
+                .and_then(|m| m.checked_div(amount as u128))
+                .ok_or(ProtocolError::InvalidArgs)? as u64;
+            self.margin = self.margin
+                .checked_add(remainder_margin)
+                .ok_or(ProtocolError::InvalidArgs)?;
+            self.direction = args.direction;
+            // triggers placed for the old direction no longer make sense
+            self.take_profit = None;
+            self.stop_loss = None;
+            self.leverage = args.leverage;
+            self.leverage_denominator = args.leverage_denominator;
+            self.last_price = ask_or_bid as i64;
+            self.last_conf = current_price.conf;
+            self.amount = remainder;
+            self.check_initial_margin()?;
+        }
+
+        self.liquidation = get_liquidation(
+            self.last_price,
+            self.bond()?,
+            self.direction,
+        )?;
+
+        Ok((remainder_margin, trading_fee))
+    }
+}
+
+/// How a span of seconds is counted in days for the overnight fee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DayRounding {
+    /// a started day counts as a full one; no time at all is no day
+    Ceil,
+    /// only completed days count
+    Floor,
+}
+
+impl DayRounding {
+    pub fn days(self, elapsed: u64) -> Result<u64> {
+        match self {
+            DayRounding::Ceil => elapsed
+                .checked_add(SECONDS_PER_DAY - 1)
+                .map(|elapsed| elapsed / SECONDS_PER_DAY)
+                .ok_or(ProtocolError::MathOverflow.into()),
+            DayRounding::Floor => Ok(elapsed / SECONDS_PER_DAY),
+        }
+    }
+}
+
+/// Which side of the book a fill is on, and so which trading fee it pays: a
+/// take-profit or stop-loss resting until a keeper fills it makes liquidity,
+/// every other open and close takes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Which of the oracle's prices an instruction reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceSource {
+    Spot,
+    /// the exponentially-weighted moving average, which smooths out wicks
+    Ema,
+}
+
+#[derive(Debug, Clone, Copy, AnchorDeserialize, AnchorSerialize)]
+pub struct PoolConfigArgs {
+    pub max_leverage: u64,
+    /// 0 leaves the direction at `max_leverage`
+    pub max_leverage_long: u64,
+    pub max_leverage_short: u64,
+    pub maintenance_margin_bps: u64,
+    pub max_slippage_bps: u64,
+    pub overnight_fee_bps: u64,
+    pub maker_fee_bps: u64,
+    pub taker_fee_bps: u64,
+    pub max_long_oi: u64,
+    pub max_short_oi: u64,
+    pub max_notional: u64,
+    pub funding_rate_bps: u64,
+    pub min_margin: u64,
+    pub min_notional: u64,
+    pub min_hold_seconds: u64,
+    pub liquidation_penalty_bps: u64,
+    /// fixed when the pool config is created
+    pub collateral_mode: CollateralMode,
+    pub conf_mode: ConfMode,
+    pub paused: bool,
+    pub liquidate_on_ema: bool,
+    pub permissionless_liquidation: bool,
+    /// 0 leaves payouts uncapped
+    pub max_payout_multiple: u64,
+    pub max_price_age: u64,
+    pub max_conf_bps: u64,
+    pub oracle_authority: Pubkey,
+}
+
+impl PoolConfigArgs {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_leverage == 0 || self.max_leverage > MAX_LEVERAGE {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+        if self.max_leverage_long > MAX_LEVERAGE || self.max_leverage_short > MAX_LEVERAGE {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+        if self.maintenance_margin_bps > RATE_DENOMINATOR
+            || self.max_slippage_bps >= RATE_DENOMINATOR
+            || self.overnight_fee_bps > RATE_DENOMINATOR
+            || self.maker_fee_bps > RATE_DENOMINATOR
+            || self.taker_fee_bps > RATE_DENOMINATOR
+            || self.funding_rate_bps > RATE_DENOMINATOR
+            || self.liquidation_penalty_bps > RATE_DENOMINATOR
+        {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        // every position is priced under these, so neither may leave the
+        // pool's feeds unusable; a confidence as wide as the price is
+        // refused anyway
+        if self.max_price_age == 0
+            || self.max_price_age > i64::MAX as u64
+            || self.max_conf_bps == 0
+            || self.max_conf_bps > RATE_DENOMINATOR
+        {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        // positions opened under the default key could never be closed
+        // through signed data
+        if self.oracle_authority == Pubkey::default() {
+            return err!(ProtocolError::InvalidAuthority);
+        }
+        Ok(())
+    }
+}
+
+/// Risk parameters of a pool. Orders are checked against these instead of
+/// trusting the values supplied in `PositionArgs`.
+#[account]
+#[derive(Debug)]
+pub struct PoolConfig {
+    pub pool: Pubkey,
+    /// the only key allowed to update the config
+    pub admin: Pubkey,
+    /// Pyth feeds of the base and quote asset every position is priced with;
+    /// `price_b` is `Pubkey::default()` when the quote is taken as exactly 1
+    pub price_a: Pubkey,
+    pub price_b: Pubkey,
+    pub max_leverage: u64,
+    /// caps on the leverage of longs and shorts; 0 falls back to
+    /// `max_leverage`
+    pub max_leverage_long: u64,
+    pub max_leverage_short: u64,
+    /// maintenance margin rate applied to new positions, over 10000; open
+    /// positions keep theirs until `recompute_liquidations`
+    pub maintenance_margin_bps: u64,
+    /// largest slippage an order may accept, over 10000
+    pub max_slippage_bps: u64,
+    /// daily overnight fee charged on new positions, over 10000; open
+    /// positions keep theirs until `recompute_liquidations`
+    pub overnight_fee_bps: u64,
+    /// fee on the notional traded when a keeper fills a take-profit or
+    /// stop-loss, over 10000
+    pub maker_fee_bps: u64,
+    /// fee on the notional traded at open and at every other close, over
+    /// 10000
+    pub taker_fee_bps: u64,
+    /// rejects new orders while set
+    pub paused: bool,
+    /// check liquidations against the EMA price instead of spot; entries
+    /// always use spot
+    pub liquidate_on_ema: bool,
+    /// caps on the summed size of all open longs and shorts, scaled like
+    /// `Position::amount`
+    pub max_long_oi: u64,
+    pub max_short_oi: u64,
+    /// cap on a single position's notional at entry, in collateral units
+    pub max_notional: u64,
+    /// summed size of all open longs and shorts
+    pub long_oi: u64,
+    pub short_oi: u64,
+    /// funding paid by the heavier side per `FUNDING_INTERVAL` when the book
+    /// is entirely one-sided, over 10000; scaled down with the skew
+    pub funding_rate_bps: u64,
+    /// funding paid by longs per unit of notional since the pool was
+    /// created, scaled by `FUNDING_PRECISION`; negative when shorts have paid
+    pub cumulative_funding: i64,
+    /// time up to which funding has been accrued
+    pub last_funding_update: i64,
+    /// smallest margin and leveraged notional a new position may open with,
+    /// in collateral units
+    pub min_margin: u64,
+    pub min_notional: u64,
+    /// how long a position must be held before the owner may close it;
+    /// liquidations are allowed at any time
+    pub min_hold_seconds: u64,
+    /// share of a liquidated position's remaining margin withheld from the
+    /// owner, over 10000; split between the keeper and the insurance fund
+    pub liquidation_penalty_bps: u64,
+    /// what positions in the pool are margined in; fixed once created
+    pub collateral_mode: CollateralMode,
+    /// how new positions fill around the oracle's confidence; positions keep
+    /// the mode they were created with
+    pub conf_mode: ConfMode,
+    /// lets any keeper liquidate; otherwise only those in `liquidators`
+    pub permissionless_liquidation: bool,
+    /// keepers allowed to liquidate, at most `MAX_LIQUIDATORS`
+    pub liquidators: Vec<Pubkey>,
+    /// cap on what a close pays out, as a multiple of the margin closed: a
+    /// circuit breaker against a manipulated oracle, 0 for none
+    pub max_payout_multiple: u64,
+    /// the key proposed by `transfer_admin`, which becomes `admin` once it
+    /// accepts; `Pubkey::default()` when there is none
+    pub pending_admin: Pubkey,
+    /// collateral mints accepted besides the quote, at most
+    /// `MAX_COLLATERAL_FEEDS`
+    pub collateral_feeds: Vec<CollateralFeed>,
+    /// maximum age in seconds of the oracle prices every position of the
+    /// pool is priced with, by its owner and by keepers alike
+    pub max_price_age: u64,
+    /// maximum oracle confidence interval, in basis points of the price
+    pub max_conf_bps: u64,
+    /// the mint taken as the quote itself, 1:1 and without a feed; any other
+    /// collateral must be listed in `collateral_feeds`
+    pub quote_mint: Pubkey,
+    /// key whose Ed25519 signatures over `LiquidatedData` close positions,
+    /// stamped into each position as it opens; positions keep the key they
+    /// were opened under
+    pub oracle_authority: Pubkey,
+}
+
+/// A mint a pool accepts as collateral and the Pyth feed pricing it in the
+/// pool's quote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub struct CollateralFeed {
+    pub mint: Pubkey,
+    pub price: Pubkey,
+}
+
+impl PoolConfig {
+    pub const LEN: usize = 32
+        + 32
+        + 32
+        + 32
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 1
+        + 1
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 1
+        + 1
+        + 1
+        + 4 + 32 * MAX_LIQUIDATORS
+        + 8
+        + 32
+        + 4 + (32 + 32) * MAX_COLLATERAL_FEEDS
+        + 8
+        + 8
+        + 32
+        + 32;
+
+    pub fn set(&mut self, args: &PoolConfigArgs) {
+        self.max_leverage = args.max_leverage;
+        self.max_leverage_long = args.max_leverage_long;
+        self.max_leverage_short = args.max_leverage_short;
+        self.maintenance_margin_bps = args.maintenance_margin_bps;
+        self.max_slippage_bps = args.max_slippage_bps;
+        self.overnight_fee_bps = args.overnight_fee_bps;
+        self.maker_fee_bps = args.maker_fee_bps;
+        self.taker_fee_bps = args.taker_fee_bps;
+        self.max_long_oi = args.max_long_oi;
+        self.max_short_oi = args.max_short_oi;
+        self.max_notional = args.max_notional;
+        self.funding_rate_bps = args.funding_rate_bps;
+        self.min_margin = args.min_margin;
+        self.min_notional = args.min_notional;
+        self.min_hold_seconds = args.min_hold_seconds;
+        self.liquidation_penalty_bps = args.liquidation_penalty_bps;
+        self.conf_mode = args.conf_mode;
+        self.paused = args.paused;
+        self.liquidate_on_ema = args.liquidate_on_ema;
+        self.permissionless_liquidation = args.permissionless_liquidation;
+        self.max_payout_multiple = args.max_payout_multiple;
+        self.max_price_age = args.max_price_age;
+        self.max_conf_bps = args.max_conf_bps;
+        self.oracle_authority = args.oracle_authority;
+    }
+
+    #[inline(always)]
+    pub fn propose_admin(&mut self, new_admin: Pubkey) {
+        self.pending_admin = new_admin;
+    }
+
+    /// Hands the config over to `new_admin` if it is the proposed admin.
+    pub fn accept_admin(&mut self, new_admin: &Pubkey) -> Result<()> {
+        if self.pending_admin == Pubkey::default() || self.pending_admin != *new_admin {
+            return err!(ProtocolError::InvalidAuthority);
+        }
+        self.admin = *new_admin;
+        self.pending_admin = Pubkey::default();
+        Ok(())
+    }
+
+    /// Holds `settlement`'s payout on closing `margin` to `max_payout_multiple`
+    /// times it, leaving the excess in the vault. Returns the cap if it bit.
+    pub fn cap_payout(&self, settlement: &mut Settlement, margin: u64) -> Option<u64> {
+        if self.max_payout_multiple == 0 {
+            return None;
+        }
+        let cap = margin.saturating_mul(self.max_payout_multiple);
+        if settlement.returned_margin <= cap {
+            return None;
+        }
+        settlement.returned_margin = cap;
+        Some(cap)
+    }
+
+    /// Rejects a voluntary close of a position opened at `created_at` before
+    /// the minimum hold has passed at `time`. Liquidations always go through.
+    #[inline(always)]
+    pub fn check_min_hold(&self, created_at: i64, time: i64, is_liquidation: bool) -> Result<()> {
+        if is_liquidation {
+            return Ok(());
+        }
+        let held = time.saturating_sub(created_at);
+        if held < 0 || (held as u64) < self.min_hold_seconds {
+            return err!(ProtocolError::MinimumHoldNotMet);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn check_liquidator(&self, keeper: &Pubkey) -> Result<()> {
+        if !self.permissionless_liquidation && !self.liquidators.contains(keeper) {
+            return err!(ProtocolError::InvalidAuthority);
+        }
+        Ok(())
+    }
+
+    pub fn add_liquidator(&mut self, liquidator: Pubkey) -> Result<()> {
+        if self.liquidators.len() >= MAX_LIQUIDATORS || self.liquidators.contains(&liquidator) {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        self.liquidators.push(liquidator);
+        Ok(())
+    }
+
+    pub fn remove_liquidator(&mut self, liquidator: &Pubkey) -> Result<()> {
+        let index = self.liquidators
+            .iter()
+            .position(|key| key == liquidator)
+            .ok_or(ProtocolError::InvalidArgs)?;
+        self.liquidators.swap_remove(index);
+        Ok(())
+    }
+
+    pub fn add_collateral_feed(&mut self, mint: Pubkey, price: Pubkey) -> Result<()> {
+        if self.collateral_feeds.len() >= MAX_COLLATERAL_FEEDS
+            || price == Pubkey::default()
+            || mint == self.quote_mint
+            || self.listed_collateral_feed(&mint).is_some()
+        {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        self.collateral_feeds.push(CollateralFeed { mint, price });
+        Ok(())
+    }
+
+    pub fn remove_collateral_feed(&mut self, mint: &Pubkey) -> Result<()> {
+        let index = self.collateral_feeds
+            .iter()
+            .position(|feed| feed.mint == *mint)
+            .ok_or(ProtocolError::InvalidArgs)?;
+        self.collateral_feeds.swap_remove(index);
+        Ok(())
+    }
+
+    /// The feed converting `mint` to the quote; `None` when `mint` is the
+    /// quote itself. Rejects a mint that is neither.
+    pub fn collateral_feed(&self, mint: &Pubkey) -> Result<Option<Pubkey>> {
+        if *mint == self.quote_mint {
+            return Ok(None);
+        }
+        self.listed_collateral_feed(mint)
+            .map(Some)
+            .ok_or_else(|| ProtocolError::CollateralMintNotAccepted.into())
+    }
+
+    fn listed_collateral_feed(&self, mint: &Pubkey) -> Option<Pubkey> {
+        self.collateral_feeds
+            .iter()
+            .find(|feed| feed.mint == *mint)
+            .map(|feed| feed.price)
+    }
+
+    #[inline(always)]
+    pub fn liquidation_price_source(&self) -> PriceSource {
+        if self.liquidate_on_ema {
+            PriceSource::Ema
+        } else {
+            PriceSource::Spot
+        }
+    }
+
+    #[inline(always)]
+    pub fn check_price_accounts(&self, price_a: &Pubkey, price_b: &Pubkey) -> Result<()> {
+        if *price_a != self.price_a || *price_b != self.price_b {
+            return err!(ProtocolError::InvalidPriceAccount);
+        }
+        Ok(())
+    }
+
+    /// Rejects an instruction moving collateral the pool isn't margined in,
+    /// and an update that would change the pool's collateral mode.
+    #[inline(always)]
+    pub fn check_collateral_mode(&self, mode: CollateralMode) -> Result<()> {
+        if self.collateral_mode != mode {
+            return err!(ProtocolError::CollateralModeMismatch);
+        }
+        Ok(())
+    }
+
+    /// Guards instructions that add exposure; closing stays possible while paused.
+    #[inline(always)]
+    pub fn check_not_paused(&self) -> Result<()> {
+        if self.paused {
+            return err!(ProtocolError::Paused);
+        }
+        Ok(())
+    }
+
+    /// The funding rate per interval at the current open interest: the full
+    /// `funding_rate_bps` when the book is one-sided, none when it's balanced.
+    pub fn funding_rate(&self) -> Rate {
+        let total = self.long_oi as u128 + self.short_oi as u128;
+        let skew_bps = match total {
+            0 => 0,
+            total => (self.long_oi.abs_diff(self.short_oi) as u128 * 10000 / total) as u64,
+        };
+        Rate {
+            numerator: self.funding_rate_bps * skew_bps,
+            denominator: 10000 * 10000,
+        }
+    }
+
+    /// Accrues funding for every whole interval up to `time` at the current
+    /// open interest. Longs pay when they outweigh shorts, shorts otherwise.
+    pub fn update_funding(&mut self, time: i64) -> Result<()> {
+        let intervals = time
+            .checked_sub(self.last_funding_update)
+            .and_then(|elapsed| u64::try_from(elapsed).ok())
+            .ok_or(ProtocolError::MathOverflow)?
+            / FUNDING_INTERVAL;
+        let accrued = self.funding_rate()
+            .apply(FUNDING_PRECISION)
+            .and_then(|per_interval| per_interval.checked_mul(intervals))
+            .and_then(|accrued| i64::try_from(accrued).ok())
+            .ok_or(ProtocolError::MathOverflow)?;
+        let accrued = if self.long_oi >= self.short_oi { accrued } else { -accrued };
+
+        self.cumulative_funding = self.cumulative_funding
+            .checked_add(accrued)
+            .ok_or(ProtocolError::MathOverflow)?;
+        self.last_funding_update += (intervals * FUNDING_INTERVAL) as i64;
+        Ok(())
+    }
+
+    /// Rejects new positions too small to be worth their rent and keeper fees.
+    #[inline(always)]
+    pub fn check_min_size(&self, args: &PositionArgs) -> Result<()> {
+        if args.margin()? < self.min_margin || args.leverage_margin < self.min_notional {
+            return err!(ProtocolError::BelowMinimumSize);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn check_notional(&self, notional: u64) -> Result<()> {
+        if notional > self.max_notional {
+            return err!(ProtocolError::MaxNotionalExceeded);
+        }
+        Ok(())
+    }
+
+    /// Adds `amount` of size to the `direction` side of the open interest,
+    /// refusing to grow it past its cap.
+    pub fn add_open_interest(&mut self, direction: Direction, amount: u64) -> Result<()> {
+        let (open_interest, cap) = match direction {
+            Direction::OpenLong => (&mut self.long_oi, self.max_long_oi),
+            Direction::OpenShort => (&mut self.short_oi, self.max_short_oi),
+        };
+        let grown = open_interest
+            .checked_add(amount)
+            .ok_or(ProtocolError::MathOverflow)?;
+        if grown > cap {
+            return err!(ProtocolError::OpenInterestCapReached);
+        }
+        *open_interest = grown;
+        Ok(())
+    }
+
+    /// Frees `amount` of size closed on the `direction` side.
+    pub fn remove_open_interest(&mut self, direction: Direction, amount: u64) {
+        let open_interest = match direction {
+            Direction::OpenLong => &mut self.long_oi,
+            Direction::OpenShort => &mut self.short_oi,
+        };
+        *open_interest = open_interest.saturating_sub(amount);
+    }
+
+    /// Moves a position's share of the open interest from its size `before`
+    /// a change to its size `after`. Only growth is checked against the caps,
+    /// so a position can always shrink.
+    pub fn move_open_interest(&mut self, before: (Direction, u64), after: (Direction, u64)) -> Result<()> {
+        if before.0 == after.0 && after.1 <= before.1 {
+            self.remove_open_interest(before.0, before.1 - after.1);
+            return Ok(());
+        }
+        let mut moved = self.clone();
+        moved.remove_open_interest(before.0, before.1);
+        moved.add_open_interest(after.0, after.1)?;
+        *self = moved;
+        Ok(())
+    }
+
+    /// The leverage cap of positions in `direction`.
+    #[inline(always)]
+    pub fn max_leverage_for(&self, direction: Direction) -> u64 {
+        let cap = match direction {
+            Direction::OpenLong => self.max_leverage_long,
+            Direction::OpenShort => self.max_leverage_short,
+        };
+        if cap == 0 {
+            self.max_leverage
+        } else {
+            cap
+        }
+    }
+
+    #[inline(always)]
+    pub fn check_leverage(&self, leverage: u64, direction: Direction) -> Result<()> {
+        self.check_fractional_leverage(leverage, 1, direction)
+    }
+
+    /// Rejects a leverage of `leverage / leverage_denominator` below 1x or
+    /// above the cap of `direction`.
+    pub fn check_fractional_leverage(&self, leverage: u64, leverage_denominator: u64, direction: Direction) -> Result<()> {
+        let cap = self.max_leverage_for(direction) as u128 * leverage_denominator as u128;
+        if leverage_denominator == 0 || leverage < leverage_denominator || leverage as u128 > cap {
+            return err!(ProtocolError::InvalidLeverage);
+        }
+        Ok(())
+    }
+
+    /// Rejects an order outside the pool's limits and replaces its maintenance
+    /// margin rate with the pool's.
+    pub fn check_args(&self, args: &mut PositionArgs) -> Result<()> {
+        self.check_not_paused()?;
+        args.check_scale()?;
+        if args.leverage_denominator == 0 {
+            args.leverage_denominator = 1;
+        }
+        self.check_fractional_leverage(args.leverage, args.leverage_denominator, args.direction)?;
+        args.check_leverage_consistency()?;
+        if args.slippage_numerator > self.max_slippage_bps {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        args.margin_rate_numerator = self.maintenance_margin_bps;
+        Ok(())
+    }
+}
+
+/// Tracks the next free position index of an owner, so clients don't have
+/// to guess one.
+#[account]
+#[derive(Debug)]
+pub struct PositionCounter {
+    pub owner: Pubkey,
+    pub next_index: u32,
+}
+
+impl PositionCounter {
+    pub const LEN: usize = 32 + 4;
+
+    /// Takes `index` if it is the next free one and moves past it.
+    pub fn claim(&mut self, index: u32) -> Result<u32> {
+        if index != self.next_index {
+            return err!(ProtocolError::PositionIndexInUse);
+        }
+        self.next_index = index
+            .checked_add(1)
+            .ok_or(ProtocolError::MathOverflow)?;
+        Ok(index)
+    }
+}
+
+/// The protocol-wide governance authority, at the `[b"admin"]` PDA.
+#[account]
+#[derive(Debug)]
+pub struct Admin {
+    /// the key allowed to create pool configs
+    pub authority: Pubkey,
+}
+
+impl Admin {
+    pub const LEN: usize = 32;
+}
+
+#[account]
+#[derive(Debug)]
+pub struct CrossAccount {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    /// mint of the collateral held in the pool's vault
+    pub mint: Pubkey,
+    /// total collateral shared by the owner's cross-margin positions in the pool
+    pub collateral: u64,
+    /// collateral already allocated as margin to open cross positions
+    pub used_margin: u64,
+    /// the open positions backed by the account, at most `MAX_CROSS_POSITIONS`
+    pub positions: Vec<Pubkey>,
+}
+
+impl CrossAccount {
+    pub const LEN: usize = 32
+        + 32
+        + 32
+        + 8
+        + 8
+        + 4 + 32 * MAX_CROSS_POSITIONS;
+
+    #[inline(always)]
+    pub fn free_collateral(&self) -> u64 {
+        self.collateral
+            .saturating_sub(self.used_margin)
+    }
+
+    /// Pays a fee out of collateral already allocated as margin.
+    pub fn charge_fee(&mut self, fee: u64) -> Result<()> {
+        self.collateral = self.collateral
+            .checked_sub(fee)
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        self.used_margin = self.used_margin
+            .checked_sub(fee)
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        Ok(())
+    }
+
+    pub fn allocate(&mut self, margin: u64) -> Result<()> {
+        if margin > self.free_collateral() {
+            return err!(ProtocolError::InsufficientBalance);
+        }
+        self.used_margin = self.used_margin
+            .checked_add(margin)
+            .ok_or(ProtocolError::InsufficientBalance)?;
+        Ok(())
+    }
+
+    pub fn add_position(&mut self, position: Pubkey) -> Result<()> {
+        if self.positions.len() >= MAX_CROSS_POSITIONS || self.positions.contains(&position) {
+            return err!(ProtocolError::InvalidArgs);
+        }
+        self.positions.push(position);
+        Ok(())
+    }
+
+    /// Gives up the `margin` a member had booked, which leaves the vault with
+    /// its payout, and covers out of the free collateral the `bad_debt` its
+    /// loss ran up past that margin, which then stays in the vault. Returns
+    /// the bad debt the collateral falls short of.
+    pub fn settle_member(&mut self, margin: u64, bad_debt: u64) -> u64 {
+        self.collateral = self.collateral.saturating_sub(margin);
+        self.used_margin = self.used_margin.saturating_sub(margin);
+        let covered = bad_debt.min(self.free_collateral());
+        self.collateral -= covered;
+        bad_debt - covered
+    }
+
+    /// Drops a closed position, settled as in `settle_member`.
+    pub fn release(&mut self, position: &Pubkey, margin: u64, bad_debt: u64) -> Result<u64> {
+        let index = self.positions
+            .iter()
+            .position(|key| key == position)
+            .ok_or(ProtocolError::InvalidArgs)?;
+        self.positions.swap_remove(index);
+        Ok(self.settle_member(margin, bad_debt))
+    }
+
+    /// Rejects `members` unless they are exactly the positions the account
+    /// backs, each once.
+    pub fn check_members(&self, members: &[Pubkey]) -> Result<()> {
+        let complete = members.len() == self.positions.len()
+            && self.positions.iter().all(|position| members.contains(position));
+        if !complete {
+            return err!(ProtocolError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    /// The collateral, with the unrealized PnL of every member at
+    /// `current_price` and the overnight fees they owe at `time`, less their
+    /// summed maintenance margins. The gains of one member cover the losses
+    /// of another; negative once the account as a whole is underwater.
+    pub fn health(&self, members: &[Position], current_price: &pyth_sdk_solana::Price, time: i64) -> Result<i128> {
+        members.iter().try_fold(self.collateral as i128, |health, member| {
+            let overnight_fee = member.overnight_fee(time)?;
+            let maintenance_margin = member.maintainance_margin()?;
+            health
+                .checked_add(member.unrealized_pnl(current_price)?)
+                .and_then(|health| health.checked_sub(overnight_fee as i128))
+                .and_then(|health| health.checked_sub(maintenance_margin as i128))
+                .ok_or(ProtocolError::MathOverflow.into())
+        })
+    }
+
+    /// Whether the members are liquidatable together: only the aggregate
+    /// health counts, not where any one of them stands on its own.
+    #[inline(always)]
+    pub fn is_liquidated(&self, members: &[Position], current_price: &pyth_sdk_solana::Price, time: i64) -> Result<bool> {
+        Ok(self.health(members, current_price, time)? < 0)
+    }
+
+    /// Rejects liquidating the first of `members`, the position being
+    /// liquidated, while another stands further underwater at
+    /// `current_price`: members are liquidated worst first, each at its own
+    /// PnL, for as long as the account stays unhealthy.
+    pub fn check_worst_member(members: &[Position], current_price: &pyth_sdk_solana::Price) -> Result<()> {
+        let equity = |member: &Position| -> Result<i128> {
+            Ok(member.margin as i128 + member.unrealized_pnl(current_price)?)
+        };
+        let (position, others) = members
+            .split_first()
+            .ok_or(ProtocolError::InvalidAccountData)?;
+        let worst = equity(position)?;
+        for other in others {
+            if equity(other)? < worst {
+                return err!(ProtocolError::PositionNotLiquidated);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitVault<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        seeds = [b"admin"],
+        bump,
+        constraint = admin.authority == payer.key() @ ProtocolError::InvalidAuthority,
+    )]
+    pub admin: Account<'info, Admin>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", pool.key().as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(init,
+        seeds = [b"vault", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+        payer = payer,
+        token::mint = mint,
+        token::authority = vault_authority,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(init,
+        seeds = [b"fee", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+        payer = payer,
+        token::mint = mint,
+        token::authority = vault_authority,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(init,
+        seeds = [b"insurance", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+        payer = payer,
+        token::mint = mint,
+        token::authority = vault_authority,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitAdmin<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(init,
+        seeds = [b"admin"],
+        bump,
+        payer = authority,
+        space = 8 + Admin::LEN,
+    )]
+    pub admin: Account<'info, Admin>,
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key()) @ ProtocolError::InvalidAccountData,
+    )]
+    pub program: Program<'info, crate::program::Protocol>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key()) @ ProtocolError::InvalidAuthority,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitPoolConfig<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        seeds = [b"admin"],
+        bump,
+        constraint = admin.authority == payer.key() @ ProtocolError::InvalidAuthority,
+    )]
+    pub admin: Account<'info, Admin>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK: the Pyth feed of the base asset, fixed for the pool
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK: the Pyth feed of the quote asset, fixed for the pool; the system
+    /// program when the base feed is already quoted in the collateral
+    pub price_b: UncheckedAccount<'info>,
+    /// the mint of the quote, which token positions post without conversion
+    pub quote_mint: Account<'info, Mint>,
+    #[account(init,
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + PoolConfig::LEN,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut,
+        constraint = pool_config.admin == admin.key() @ ProtocolError::InvalidAuthority,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    pub new_admin: Signer<'info>,
+    #[account(mut)]
+    pub pool_config: Account<'info, PoolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: an older layout `Account<Position>` can't read, decoded by
+    /// `decode_legacy_position`
+    #[account(mut, owner = crate::ID)]
+    pub position: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePositionFeeds<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut,
+        constraint = pool_config.admin == admin.key() @ ProtocolError::InvalidAuthority,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: the new base feed, loaded as a Pyth price feed
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK: the new quote feed, or the system program when there is none
+    pub price_b: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositInsurance<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut,
+        constraint = payer_token_account.mint == mint.key(),
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    pub admin: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+        constraint = pool_config.admin == admin.key() @ ProtocolError::InvalidAuthority,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    pub mint: Account<'info, Mint>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", pool.key().as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"fee", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        constraint = destination.mint == mint.key(),
+    )]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct Create<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut,
+        constraint = payer_token_account.mint == mint.key(),
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"vault", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(init,
+        seeds = [b"protocol", payer.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + Position::LEN,
+    )]
+    pub position: Account<'info, Position>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteOpen<'info> {
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct CreateNative<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: a system account holding the pool's native margin
+    #[account(mut,
+        seeds = [b"native_vault", pool.key().as_ref()],
+        bump,
+    )]
+    pub native_vault: UncheckedAccount<'info>,
+    /// CHECK: a system account collecting the pool's native fees
+    #[account(mut,
+        seeds = [b"native_fee", pool.key().as_ref()],
+        bump,
+    )]
+    pub native_fee: UncheckedAccount<'info>,
+    #[account(init,
+        seeds = [b"protocol", payer.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + Position::LEN,
+    )]
+    pub position: Account<'info, Position>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitNativeVault<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: a system account holding the pool's native margin
+    #[account(mut,
+        seeds = [b"native_vault", pool.key().as_ref()],
+        bump,
+    )]
+    pub native_vault: UncheckedAccount<'info>,
+    /// CHECK: a system account collecting the pool's native fees
+    #[account(mut,
+        seeds = [b"native_fee", pool.key().as_ref()],
+        bump,
+    )]
+    pub native_fee: UncheckedAccount<'info>,
+    /// CHECK: a system account covering the pool's native bad debt
+    #[account(mut,
+        seeds = [b"native_insurance", pool.key().as_ref()],
+        bump,
+    )]
+    pub native_insurance: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitPositionCounter<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(init,
+        seeds = [b"position_counter", payer.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + PositionCounter::LEN,
+    )]
+    pub position_counter: Account<'info, PositionCounter>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct CreateAuto<'info> {
+    pub create: Create<'info>,
+    #[account(mut,
+        seeds = [b"position_counter", create.payer.key().as_ref()],
+        bump,
+    )]
+    pub position_counter: Account<'info, PositionCounter>,
+}
+
+#[derive(Accounts)]
+pub struct InitCrossAccount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    #[account(init,
+        seeds = [b"cross", payer.key().as_ref(), pool.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + CrossAccount::LEN,
+    )]
+    pub cross_account: Account<'info, CrossAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCrossMargin<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut,
+        constraint = cross_account.owner == payer.key(),
+    )]
+    pub cross_account: Account<'info, CrossAccount>,
+    #[account(mut,
+        constraint = payer_token_account.mint == cross_account.mint,
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"vault", cross_account.pool.as_ref(), cross_account.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct CreateCross<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        seeds = [b"cross", payer.key().as_ref(), pool.key().as_ref()],
+        bump,
+        constraint = cross_account.owner == payer.key(),
+    )]
+    pub cross_account: Account<'info, CrossAccount>,
+    #[account(init,
+        seeds = [b"protocol", payer.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + Position::LEN,
+    )]
+    pub position: Account<'info, Position>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", pool.key().as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", pool.key().as_ref(), cross_account.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", pool.key().as_ref(), cross_account.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(args: PositionArgs)]
+pub struct Netoff<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+        constraint = position.ptype == PositionType::Isolated,
+        constraint = args.ptype == PositionType::Isolated,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = payer_token_account.mint == position.mint,
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct IncreaseMargin<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = payer_token_account.mint == position.mint,
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DecreaseMargin<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = payer_token_account.mint == position.mint,
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetLeverage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+        constraint = position.ptype == PositionType::Isolated,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = payer_token_account.mint == position.mint,
+        constraint = payer_token_account.owner == payer.key(),
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        close = keeper,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = keeper_token_account.mint == position.mint,
+    )]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        constraint = owner_token_account.mint == position.mint,
+        constraint = owner_token_account.owner == position.owner,
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFees<'info> {
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        constraint = position.ptype == PositionType::Isolated,
+        constraint = position.status == PositionStatus::Open,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut,
+        close = owner,
+        constraint = position.owner == owner.key(),
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SetTriggers<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut,
+        constraint = position.owner == owner.key(),
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        close = owner,
+        constraint = position.owner == owner.key(),
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = owner_token_account.mint == position.mint,
+        constraint = owner_token_account.owner == owner.key(),
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTrigger<'info> {
+    pub keeper: Signer<'info>,
+    /// CHECK: receives the position's rent
+    #[account(mut,
+        constraint = owner.key() == position.owner,
+    )]
+    pub owner: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        close = owner,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = owner_token_account.mint == position.mint,
+        constraint = owner_token_account.owner == position.owner,
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetHealth<'info> {
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    pub position: Account<'info, Position>,
+    #[account(
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewLiquidation<'info> {
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct Health {
+    /// equity over maintenance margin, in basis points
+    pub margin_ratio_bps: u64,
+    /// price move left before the position is liquidated
+    pub liquidation_distance: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct Settlement {
+    /// margin returned to the position owner
+    pub returned_margin: u64,
+    /// overnight fee paid to the pool's fee account
+    pub overnight_fee: u64,
+    /// closing fee paid to the pool's fee account
+    pub trading_fee: u64,
+    /// loss beyond the margin, covered by the insurance fund
+    pub bad_debt: u64,
+    /// liquidation penalty paid to the insurance fund
+    pub insurance_fee: u64,
+}
+
+impl Settlement {
+    /// Adds up the settlements of positions paid out together.
+    pub fn checked_add(&self, other: &Settlement) -> Option<Settlement> {
+        Some(Settlement {
+            returned_margin: self.returned_margin.checked_add(other.returned_margin)?,
+            overnight_fee: self.overnight_fee.checked_add(other.overnight_fee)?,
+            trading_fee: self.trading_fee.checked_add(other.trading_fee)?,
+            bad_debt: self.bad_debt.checked_add(other.bad_debt)?,
+            insurance_fee: self.insurance_fee.checked_add(other.insurance_fee)?,
+        })
+    }
+
+    /// The settlement paid out in collateral tokens at `collateral_price`;
+    /// itself when the collateral is the quote.
+    pub fn in_collateral(&self, collateral_price: Option<&pyth_sdk_solana::Price>, collateral_decimals: u8) -> Result<Settlement> {
+        let convert = |amount| payout_in_collateral(amount, collateral_price, collateral_decimals);
+        Ok(Settlement {
+            returned_margin: convert(self.returned_margin)?,
+            overnight_fee: convert(self.overnight_fee)?,
+            trading_fee: convert(self.trading_fee)?,
+            bad_debt: convert(self.bad_debt)?,
+            insurance_fee: convert(self.insurance_fee)?,
+        })
+    }
+}
+
+/// How `process_positions` dealt with each position of the batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub enum BatchStatus {
+    Closed,
+    Liquidated,
+    /// failed to verify or close; only reported with `best_effort`
+    Skipped,
+}
+
+/// What `quote_open` returns: the position `create` would open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub struct OpenQuote {
+    /// the ask for a long, the bid for a short
+    pub fill_price: u64,
+    pub amount: u64,
+    pub liquidation: u64,
+    /// the margin backing the position once the opening fee is taken out
+    pub initial_margin: u64,
+    pub trading_fee: u64,
+}
+
+/// What `process_position` returns to the caller.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct CloseResult {
+    /// margin returned to the position owner
+    pub returned_margin: u64,
+    /// PnL of the closed size before fees
+    pub gross_pnl: i64,
+    /// overnight fee paid to the pool's fee account
+    pub overnight_fee: u64,
+    /// closing fee paid to the pool's fee account
+    pub trading_fee: u64,
+    /// the signed price for a liquidation, otherwise the bid or ask closed at
+    pub exit_price: u64,
+    pub was_liquidated: bool,
+    /// the signed data the close was processed with, and its verified signer
+    pub authenticated: AuthenticatedData,
+}
+
+impl CloseResult {
+    /// `closed_margin` is the margin that backed the closed size.
+    pub fn new(settlement: &Settlement, closed_margin: u64, exit_price: u64, authenticated: &AuthenticatedData) -> Result<Self> {
+        let gross_pnl = (settlement.returned_margin as i128
+            + settlement.overnight_fee as i128
+            + settlement.trading_fee as i128
+            + settlement.insurance_fee as i128)
+            - settlement.bad_debt as i128
+            - closed_margin as i128;
+        Ok(CloseResult {
+            returned_margin: settlement.returned_margin,
+            gross_pnl: i64::try_from(gross_pnl).map_err(|_| ProtocolError::MathOverflow)?,
+            overnight_fee: settlement.overnight_fee,
+            trading_fee: settlement.trading_fee,
+            exit_price,
+            was_liquidated: authenticated.data.is_liquidated,
+            authenticated: *authenticated,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct LiquidateResult {
+    /// margin returned to the position owner
+    pub returned_margin: u64,
+    /// margin paid to the keeper that liquidated the position
+    pub keeper_bonus: u64,
+    /// margin paid to the pool's insurance fund
+    pub insurance_fee: u64,
+}
+
+/// Logged when a close pays out less than it came to because of the pool's
+/// `max_payout_multiple`, which only an extreme oracle price should cause.
+#[event]
+pub struct PayoutCapped {
+    pub position: Pubkey,
+    /// what the close came to before the cap
+    pub payout: u64,
+    pub cap: u64,
+}
+
+/// Logged by an order failing with `SlippageReached`, so the client can see
+/// how far the market moved before retrying.
+#[event]
+pub struct SlippageRejected {
+    pub direction: Direction,
+    /// the order's reference price, scaled by `10^-expo`
+    pub reference_price: u64,
+    pub expo: i32,
+    /// the ask or bid it would have filled at, scaled by `10^decimals`
+    pub oracle_price: u64,
+    pub decimals: u8,
+    /// distance of the fill from the reference, over 10000 of the reference
+    pub deviation_bps: u64,
+}
+
+#[event]
+pub struct PositionOpened {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub direction: Direction,
+    pub entry_price: i64,
+    pub amount: u64,
+    pub margin: u64,
+}
+
+#[event]
+pub struct MarginIncreased {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub direction: Direction,
+    pub entry_price: i64,
+    /// margin added
+    pub amount: u64,
+    /// margin after the increase
+    pub margin: u64,
+}
+
+/// What moved a position's margin in a `MarginChanged`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnchorDeserialize, AnchorSerialize)]
+pub enum MarginChangeReason {
+    /// posted by the owner
+    Deposit,
+    /// taken back by the owner
+    Withdraw,
+    /// overnight fees and funding realized out of it
+    Fee,
+    /// the share released by a partial close
+    RealizedPnl,
+}
+
+/// Logged on every change to an open position's margin, so that its history
+/// can be rebuilt in order from the transaction logs alone.
+#[event]
+pub struct MarginChanged {
+    pub position: Pubkey,
+    pub old_margin: u64,
+    pub new_margin: u64,
+    pub reason: MarginChangeReason,
+}
+
+#[event]
+pub struct PositionProcessed {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub direction: Direction,
+    pub entry_price: i64,
+    pub margin: u64,
+    pub returned_margin: u64,
+    /// realized PnL net of fees, `returned_margin - margin`
+    pub pnl: i64,
+}
+
+#[event]
+pub struct PositionLiquidated {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub direction: Direction,
+    pub entry_price: i64,
+    pub margin: u64,
+    pub returned_margin: u64,
+    /// realized PnL net of fees, `returned_margin - margin`
+    pub pnl: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct LiquidatedData {
+    /// the position the message was signed for
+    pub position: Pubkey,
+    pub is_liquidated: bool,
+    pub price: u64,
+    pub time: i64,
+    pub slot: u64,
+}
+
+/// Prefix of every signed message, ahead of its `LiquidatedData`. Binds the
+/// signature to this program and one pool, so that a message signed for
+/// another program or market doesn't verify here.
+#[derive(Debug, Clone, Copy, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct SignedDomain {
+    pub program_id: Pubkey,
+    pub pool: Pubkey,
+    pub version: u8,
+}
+
+impl SignedDomain {
+    pub fn new(pool: Pubkey) -> Self {
+        Self {
+            program_id: crate::ID,
+            pool,
+            version: SIGNED_DATA_VERSION,
+        }
+    }
+
+    /// The message an authority signs over `data`: this tag followed by the
+    /// data itself.
+    pub fn message(&self, data: &LiquidatedData) -> Vec<u8> {
+        let mut message = self.try_to_vec().unwrap();
+        message.extend_from_slice(&data.try_to_vec().unwrap());
+        message
+    }
+}
+
+/// A `LiquidatedData` and the key its Ed25519 signature was verified against.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, AnchorDeserialize, AnchorSerialize)]
+pub struct AuthenticatedData {
+    pub data: LiquidatedData,
+    pub authority: Pubkey,
+}
+
+/// Reads the signed data of the Ed25519 instruction right before the current
+/// one, which must be tagged for this program and `pool`.
+pub fn verify_and_extract(instruction_sysvar_account_info: &AccountInfo, pool: &Pubkey) -> Result<AuthenticatedData> {
+    verify_and_extract_at(instruction_sysvar_account_info, 1, pool)
+}
+
+/// Like `verify_and_extract`, for the Ed25519 instruction `distance`
+/// instructions before the current one.
+pub fn verify_and_extract_at(instruction_sysvar_account_info: &AccountInfo, distance: usize, pool: &Pubkey) -> Result<AuthenticatedData> {
+    use anchor_lang::solana_program;
+    let current_instruction = solana_program::sysvar::instructions::load_current_index_checked(instruction_sysvar_account_info)? as usize;
+    if distance == 0 || current_instruction < distance {
+        return err!(ProtocolError::InstructionAtWrongIndex);
+    }
+
+    let ed25519_ix_index = current_instruction - distance;
+    let ed25519_ix = solana_program::sysvar::instructions::load_instruction_at_checked(
+        ed25519_ix_index,
+        instruction_sysvar_account_info,
+    )
+    .map_err(|_| ProtocolError::InvalidAccountData)?;
+
+    if ed25519_ix.program_id != solana_program::ed25519_program::id() {
+        return err!(ProtocolError::InvalidEd25519Instruction);
+    }
+
+    extract_ed25519_data(&ed25519_ix.data, &SignedDomain::new(*pool))
+}
+
+/// Builds the Ed25519 program instruction a client places right before
+/// `process_position`, carrying `authority`'s `signature` over `data` tagged
+/// for `pool`. The signature must be over `SignedDomain::new(pool).message(data)`.
+#[cfg(any(test, feature = "client"))]
+pub fn build_ed25519_instruction(authority: &Pubkey, pool: &Pubkey, data: &LiquidatedData, signature: &[u8; 64]) -> anchor_lang::solana_program::instruction::Instruction {
+    let message = SignedDomain::new(*pool).message(data);
+    let sig_offset = ED25519_HEADER_LEN as u16;
+    let pubkey_offset = sig_offset + 64;
+    let data_offset = pubkey_offset + 32;
+
+    // one signature, with everything it covers inside this instruction
+    let mut ix_data = vec![1, 0];
+    for value in [
+        sig_offset,
+        u16::MAX,
+        pubkey_offset,
+        u16::MAX,
+        data_offset,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        ix_data.extend_from_slice(&value.to_le_bytes());
+    }
+    ix_data.extend_from_slice(signature);
+    ix_data.extend_from_slice(authority.as_ref());
+    ix_data.extend_from_slice(&message);
+
+    anchor_lang::solana_program::instruction::Instruction {
+        program_id: anchor_lang::solana_program::ed25519_program::id(),
+        accounts: vec![],
+        data: ix_data,
+    }
+}
+
+/// Rejects signed data meant for another position or signed more than
+/// `MAX_SIGNATURE_AGE_SLOTS` before `slot`, so a message can't be replayed.
+fn check_signed_data(data: &LiquidatedData, position: &Pubkey, slot: u64) -> Result<()> {
+    if data.position != *position {
+        return err!(ProtocolError::StaleSignature);
+    }
+    let age = slot
+        .checked_sub(data.slot)
+        .ok_or(ProtocolError::StaleSignature)?;
+    if age > MAX_SIGNATURE_AGE_SLOTS {
+        return err!(ProtocolError::StaleSignature);
+    }
+    Ok(())
+}
+
+/// Decodes a position account written at an older `POSITION_VERSION`. Fields
+/// have only ever been appended, so its data is a prefix of the current
+/// layout, followed by whatever a shorter trigger left behind. The fields
+/// added since are set to how the position behaved before them: funding
+/// runs from `cumulative_funding` on, fills stay conservative, the position
+/// doesn't expire, its leverage stays a whole number, its margin stays in
+/// the quote and its triggers are filled at the fee it was opened with.
+fn decode_legacy_position(data: &[u8], cumulative_funding: i64) -> Result<Position> {
+    use anchor_lang::Discriminator;
+    if data.len() < 9 || data[..8] != Position::discriminator() {
+        return err!(ProtocolError::InvalidAccountData);
+    }
+    let version = data[8];
+    if version == 0 || version >= POSITION_VERSION {
+        return err!(ProtocolError::UnsupportedVersion);
+    }
+
+    // the two optional triggers follow the fixed-size fields, and the
+    // fields appended since version 1 follow them
+    let body = &data[8..];
+    let mut end = Position::LEN - 2 * (1 + 8) - 8 - 1 - (1 + 8) - 8 - 32 - 1 - 8;
+    for _ in 0..2 {
+        end += match body.get(end) {
+            Some(0) => 1,
+            Some(1) => 1 + 8,
+            _ => return err!(ProtocolError::InvalidAccountData),
+        };
+    }
+    let mut current = body.get(..end)
+        .ok_or(ProtocolError::InvalidAccountData)?
+        .to_vec();
+    if version < 2 {
+        current.extend_from_slice(&cumulative_funding.to_le_bytes());
+    } else {
+        current.extend_from_slice(body.get(end..end + 8).ok_or(ProtocolError::InvalidAccountData)?);
+        end += 8;
+    }
+    if version < 3 {
+        current.extend_from_slice(
+            &ConfMode::Conservative
+                .try_to_vec()
+                .map_err(|_| ProtocolError::InvalidAccountData)?,
+        );
+    } else {
+        current.push(*body.get(end).ok_or(ProtocolError::InvalidAccountData)?);
+        end += 1;
+    }
+    if version < 4 {
+        current.extend_from_slice(
+            &None::<i64>
+                .try_to_vec()
+                .map_err(|_| ProtocolError::InvalidAccountData)?,
+        );
+    } else {
+        let expiry = match body.get(end) {
+            Some(0) => 1,
+            Some(1) => 1 + 8,
+            _ => return err!(ProtocolError::InvalidAccountData),
+        };
+        current.extend_from_slice(body.get(end..end + expiry).ok_or(ProtocolError::InvalidAccountData)?);
+        end += expiry;
+    }
+    if version < 5 {
+        current.extend_from_slice(&1u64.to_le_bytes());
+    } else {
+        current.extend_from_slice(body.get(end..end + 8).ok_or(ProtocolError::InvalidAccountData)?);
+        end += 8;
+    }
+    if version < 6 {
+        current.extend_from_slice(Pubkey::default().as_ref());
+        current.push(0);
+    } else {
+        current.extend_from_slice(body.get(end..end + 32 + 1).ok_or(ProtocolError::InvalidAccountData)?);
+    }
+    current.extend_from_slice(&0u64.to_le_bytes());
+
+    let mut position = Position::try_from_slice(&current)
+        .map_err(|_| ProtocolError::InvalidAccountData)?;
+    position.version = POSITION_VERSION;
+    position.maker_fee_bps = position.taker_fee_bps;
+    Ok(position)
+}
+
+/// The body of `create`, shared with `create_auto`. Takes the margin and the
+/// opening fee in the collateral `mint`, converted at the feed passed first in
+/// `remaining_accounts` if the pool converts it.
+fn create_isolated(accounts: &mut Create, remaining_accounts: &[AccountInfo], index: u32, args: PositionArgs) -> Result<()> {
+    let pool_config = &mut accounts.pool_config;
+    let collateral_feed = pool_config.collateral_feed(&accounts.mint.key())?;
+    let args = check_isolated_args(pool_config, args)?;
+    debug_log!("create: {:?} leverage {}/{} margin {}", args.direction, args.leverage, args.leverage_denominator, args.leverage_margin);
+    let clock = Clock::get()?;
+    pool_config.update_funding(clock.unix_timestamp)?;
+
+    pool_config.check_price_accounts(&accounts.price_a.key(), &accounts.price_b.key())?;
+    let current_price = get_current_price(&accounts.price_a, &accounts.price_b, args.decimals, pool_config.max_price_age, pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+
+    let (opened, trading_fee) = open_isolated(pool_config, accounts.payer.key(), accounts.mint.key(), index, &args, &current_price, &clock, 0)?;
+    opened.check_pool(&accounts.pool.key())?;
+    *accounts.position = opened;
+    let (margin, trading_fee) = match collateral_feed {
+        Some(feed) => {
+            let info = remaining_accounts.first().ok_or(ProtocolError::InvalidPriceAccount)?;
+            let collateral_price = get_collateral_price(info, &feed, args.decimals, pool_config.max_price_age, pool_config.max_conf_bps, clock.unix_timestamp)?;
+            accounts.position.collateral_price = feed;
+            accounts.position.collateral_decimals = accounts.mint.decimals;
+            (
+                collateral_for_quote(accounts.position.margin, &collateral_price, accounts.mint.decimals)?,
+                collateral_for_quote(trading_fee, &collateral_price, accounts.mint.decimals)?,
+            )
+        }
+        None => (accounts.position.margin, trading_fee),
+    };
+    let position = &accounts.position;
+
+    for (to, amount) in [
+        (&accounts.vault, margin),
+        (&accounts.fee_account, trading_fee),
+    ] {
+        transfer_to_vault(
+            &accounts.token_program,
+            &accounts.payer_token_account,
+            to,
+            &accounts.payer,
+            amount,
+        )?;
+    }
+
+    emit!(PositionOpened {
+        position: position.key(),
+        owner: position.owner,
+        direction: position.direction,
+        entry_price: position.last_price,
+        amount: position.amount,
+        margin: position.margin,
+    });
+
+    Ok(())
+}
+
+/// The checks `create` runs on an order before pricing it. Returns the order
+/// with the pool's maintenance margin in place of the client's.
+fn check_isolated_args(pool_config: &PoolConfig, args: PositionArgs) -> Result<PositionArgs> {
+    // cross-margin positions are opened against a `CrossAccount` via `create_cross`
+    if args.ptype != PositionType::Isolated {
+        return err!(ProtocolError::InvalidArgs);
+    }
+    pool_config.check_collateral_mode(CollateralMode::Token)?;
+    let mut args = args;
+    pool_config.check_args(&mut args)?;
+    pool_config.check_min_size(&args)?;
+    Ok(args)
+}
+
+/// `check_isolated_args` for a pool whose margin is paid in native SOL.
+fn check_native_args(pool_config: &PoolConfig, args: PositionArgs) -> Result<PositionArgs> {
+    if args.ptype != PositionType::Isolated {
+        return err!(ProtocolError::InvalidArgs);
+    }
+    pool_config.check_collateral_mode(CollateralMode::Native)?;
+    let mut args = args;
+    pool_config.check_args(&mut args)?;
+    pool_config.check_min_size(&args)?;
+    Ok(args)
+}
+
+/// Opens a position of checked `args` at `current_price` with the pool's
+/// feeds and rates: fills it, takes the opening fee out of its margin and
+/// books the open interest. Returns the position and the fee.
+/// `shared_collateral` is the free collateral of the `CrossAccount` backing a
+/// cross position, zero when isolated.
+#[allow(clippy::too_many_arguments)]
+fn open_isolated(
+    pool_config: &mut PoolConfig,
+    owner: Pubkey,
+    mint: Pubkey,
+    index: u32,
+    args: &PositionArgs,
+    current_price: &pyth_sdk_solana::Price,
+    clock: &Clock,
+    shared_collateral: u64,
+) -> Result<(Position, u64)> {
+    let mut position = Position {
+        version: POSITION_VERSION,
+        pool: pool_config.pool,
+        owner,
+        authority: pool_config.oracle_authority,
+        mint,
+        price_a: pool_config.price_a,
+        price_b: pool_config.price_b,
+        index,
+        status: PositionStatus::Open,
+        ptype: args.ptype,
+        direction: args.direction,
+        decimals: args.decimals,
+        leverage: 0,
+        last_price: 0,
+        last_conf: 0,
+        margin: args.margin()?,
+        margin_rate_numerator: 0,
+        overnight_fee_numerator: pool_config.overnight_fee_bps,
+        taker_fee_bps: pool_config.taker_fee_bps,
+        liquidation: 0,
+        created_at: clock.unix_timestamp,
+        slot: clock.slot,
+        amount: 0,
+        max_price_age: pool_config.max_price_age,
+        max_conf_bps: pool_config.max_conf_bps,
+        last_fee_settlement: clock.unix_timestamp,
+        accrued_fee: 0,
+        take_profit: None,
+        stop_loss: None,
+        funding_index: pool_config.cumulative_funding,
+        conf_mode: pool_config.conf_mode,
+        expiry: None,
+        leverage_denominator: 0,
+        collateral_price: Pubkey::default(),
+        collateral_decimals: 0,
+        maker_fee_bps: pool_config.maker_fee_bps,
+    };
+
+    position.open(args, current_price, shared_collateral)?;
+    position.check_leverage_consistency()?;
+    let trading_fee = position.charge_open_fee(shared_collateral)?;
+    pool_config.check_notional(position.entry_notional()?)?;
+    pool_config.add_open_interest(position.direction, position.amount)?;
+    Ok((position, trading_fee))
+}
+
+/// Runs `open_isolated` on a copy of the pool config and reports the result.
+fn quote_isolated(pool_config: &PoolConfig, args: &PositionArgs, current_price: &pyth_sdk_solana::Price, clock: &Clock) -> Result<OpenQuote> {
+    let (position, trading_fee) = open_isolated(&mut pool_config.clone(), Pubkey::default(), Pubkey::default(), 0, args, current_price, clock, 0)?;
+    Ok(OpenQuote {
+        fill_price: position.entry_price()?,
+        amount: position.amount,
+        liquidation: position.liquidation,
+        initial_margin: position.margin,
+        trading_fee,
+    })
+}
+
+/// Verifies the signed data of `process_position` and closes `close_amount`
+/// of the position, up to the payout, which depends on the collateral mode.
+/// A cross position settles with its `CrossAccount` among
+/// `remaining_accounts`.
+#[allow(clippy::too_many_arguments)]
+fn process_signed<'info>(
+    position: &mut Account<'info, Position>,
+    pool_config: &mut PoolConfig,
+    price_a: &UncheckedAccount<'info>,
+    price_b: &UncheckedAccount<'info>,
+    instruction_sysvar_account_info: &UncheckedAccount<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    program_id: &Pubkey,
+    min_return: u64,
+    close_amount: u64,
+) -> Result<(Settlement, CloseResult)> {
+    let authenticated = verify_and_extract(instruction_sysvar_account_info, &position.pool)
+        .map_err(|_| ProtocolError::InvalidSignature)?;
+
+    position.check_version()?;
+    position.check_quote_collateral()?;
+    position.check_open()?;
+
+    position.check_authority(&authenticated.authority)?;
+
+    let data = authenticated.data;
+    let clock = Clock::get()?;
+    check_signed_data(&data, &position.key(), clock.slot)?;
+    pool_config.check_min_hold(position.created_at, clock.unix_timestamp, data.is_liquidated)?;
+    pool_config.update_funding(clock.unix_timestamp)?;
+    let amount = position.amount;
+    let booked_margin = position.margin;
+    debug_log!("process: liquidated {} signed price {} close {}", data.is_liquidated, data.price, close_amount);
+    let (settlement, closed_margin, exit_price) = if data.is_liquidated {
+        position.status = PositionStatus::Processed;
+        let settlement = position.liquidated_settlement(data.time, pool_config.cumulative_funding, pool_config.liquidation_penalty_bps)?;
+        emit!(PositionLiquidated {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: position.margin,
+            returned_margin: settlement.returned_margin,
+            pnl: realized_pnl(position.margin, settlement.returned_margin)?,
+        });
+        (settlement, position.margin, data.price)
+    } else {
+        position.check_price_accounts(&price_a.key(), &price_b.key())?;
+        let current_price = get_current_price(price_a, price_b, position.decimals, pool_config.max_price_age, pool_config.max_conf_bps, PriceSource::Spot, clock.unix_timestamp)?;
+        position.settle_funding(pool_config.cumulative_funding)?;
+        let (mut settlement, closed_margin) = if close_amount < position.amount {
+            let key = position.key();
+            let (settlement, margin_changed) = position.change_margin(key, MarginChangeReason::RealizedPnl, |position| position.partial_close(close_amount, &current_price, clock.unix_timestamp))?;
+            emit!(margin_changed);
+            (settlement, margin_changed.old_margin - margin_changed.new_margin)
+        } else {
+            position.status = PositionStatus::Processed;
+            (position.settle(&current_price, clock.unix_timestamp)?, position.margin)
+        };
+        cap_payout(pool_config, &position.key(), &mut settlement, closed_margin);
+        check_min_return(settlement.returned_margin, min_return)?;
+        emit!(PositionProcessed {
+            position: position.key(),
+            owner: position.owner,
+            direction: position.direction,
+            entry_price: position.last_price,
+            margin: closed_margin,
+            returned_margin: settlement.returned_margin,
+            pnl: realized_pnl(closed_margin, settlement.returned_margin)?,
+        });
+        (settlement, closed_margin, position.exit_price(&current_price)?)
+    };
+    let closed_amount = if position.status == PositionStatus::Processed {
+        amount
+    } else {
+        amount - position.amount
+    };
+    pool_config.remove_open_interest(position.direction, closed_amount);
+    // the cross account booked the margin as it stood before any funding
+    let released_margin = if position.status == PositionStatus::Processed {
+        booked_margin
+    } else {
+        booked_margin.saturating_sub(position.margin)
+    };
+    let mut settlement = settlement;
+    release_cross_member(remaining_accounts, position, &position.key(), released_margin, &mut settlement, program_id)?;
+
+    Ok((settlement, CloseResult::new(&settlement, closed_margin, exit_price, &authenticated)?))
+}
+
+/// Verifies the signed data for `position` and settles it in full: at the
+/// liquidation margin when the data marks it liquidated, otherwise at the
+/// price `current_price` fetches for it.
+fn close_signed(
+    position: &mut Position,
+    key: &Pubkey,
+    pool_config: &PoolConfig,
+    authenticated: &AuthenticatedData,
+    slot: u64,
+    time: i64,
+    current_price: impl FnOnce(&mut Position) -> Result<pyth_sdk_solana::Price>,
+) -> Result<(Settlement, BatchStatus)> {
+    position.check_version()?;
+    position.check_open()?;
+    position.check_authority(&authenticated.authority)?;
+
+    let data = authenticated.data;
+    check_signed_data(&data, key, slot)?;
+    pool_config.check_min_hold(position.created_at, time, data.is_liquidated)?;
+    if data.is_liquidated {
+        position.status = PositionStatus::Processed;
+        return Ok((position.liquidated_settlement(data.time, pool_config.cumulative_funding, pool_config.liquidation_penalty_bps)?, BatchStatus::Liquidated));
+    }
+    let current_price = current_price(position)?;
+    let mut settlement = position.settle(&current_price, time)?;
+    cap_payout(pool_config, key, &mut settlement, position.margin);
+    position.status = PositionStatus::Processed;
+    Ok((settlement, BatchStatus::Closed))
+}
+
+/// Runs `close` over every item of a batch and adds up the settlements of
+/// those that close. With `best_effort` an item that fails is reported as
+/// `BatchStatus::Skipped`; otherwise its error fails the whole batch.
+fn run_batch<T>(
+    items: impl IntoIterator<Item = T>,
+    best_effort: bool,
+    mut close: impl FnMut(T) -> Result<(Settlement, BatchStatus)>,
+) -> Result<(Settlement, Vec<BatchStatus>)> {
+    let mut total = Settlement::default();
+    let mut statuses = Vec::new();
+    for item in items {
+        match close(item) {
+            Ok((settlement, status)) => {
+                total = total.checked_add(&settlement).ok_or(ProtocolError::MathOverflow)?;
+                statuses.push(status);
+            }
+            Err(_) if best_effort => statuses.push(BatchStatus::Skipped),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((total, statuses))
+}
+
+/// signature count, padding and one set of signature offsets
+const ED25519_HEADER_LEN: usize = 2 + 14;
+
+/// Reads the signer and the signed `LiquidatedData` out of the data of an
+/// Ed25519 program instruction holding a single signature. The message must
+/// be tagged with `domain`.
+fn extract_ed25519_data(ix_data: &[u8], domain: &SignedDomain) -> Result<AuthenticatedData> {
+    if ix_data.len() < ED25519_HEADER_LEN {
+        return err!(ProtocolError::InvalidEd25519Instruction);
+    }
+
+    let sig_len = ix_data[0];
+    if sig_len != 1 {
+        return err!(ProtocolError::InvalidEd25519Instruction);
+    }
+
+    let mut index = 2;
+    let _sig_offset = byteorder::LE::read_u16(&ix_data[index..index+2]) as usize;
+    index += 2;
+    let sig_ix = byteorder::LE::read_u16(&ix_data[index..index+2]);
+    index += 2;
+    let pubkey_offset = byteorder::LE::read_u16(&ix_data[index..index+2]) as usize;
+    index += 2;
+    let pubkey_ix = byteorder::LE::read_u16(&ix_data[index..index+2]);
+    index += 2;
+    let data_offset = byteorder::LE::read_u16(&ix_data[index..index+2]) as usize;
+    index += 2;
+    let data_size = byteorder::LE::read_u16(&ix_data[index..index+2]) as usize;
+    index += 2;
+    let data_ix = byteorder::LE::read_u16(&ix_data[index..index+2]);
+
+    if pubkey_ix != u16::MAX || data_ix != u16::MAX || sig_ix != u16::MAX {
+        return err!(ProtocolError::InvalidEd25519Instruction);
+    }
+
+    let pubkey = ix_data
+        .get(pubkey_offset..pubkey_offset + 32)
+        .ok_or(ProtocolError::InvalidEd25519Instruction)?;
+    let message = ix_data
+        .get(data_offset..data_offset + data_size)
+        .ok_or(ProtocolError::InvalidEd25519Instruction)?;
+
+    let authority = Pubkey::try_from_slice(pubkey)
+        .map_err(|_| ProtocolError::InvalidEd25519Instruction)?;
+    let mut message = message;
+    let tag = SignedDomain::deserialize(&mut message)
+        .map_err(|_| ProtocolError::InvalidEd25519Instruction)?;
+    let data = LiquidatedData::try_from_slice(message)
+        .map_err(|_| ProtocolError::InvalidEd25519Instruction)?;
+    if tag != *domain {
+        return err!(ProtocolError::InvalidSignature);
+    }
+
+    Ok(AuthenticatedData {
+        data,
+        authority,
+    })
+}
+
+#[derive(Accounts)]
+pub struct ProcessPosition<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    /// closed into `payer` once fully processed
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+        constraint = position.pool == pool.key() @ ProtocolError::PoolMismatch,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = owner_token_account.mint == position.mint,
+        constraint = owner_token_account.owner == position.owner,
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", position.pool.as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", position.pool.as_ref(), position.mint.as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// CHECK:
+    #[account(
+        constraint = instruction_sysvar_account_info.key() == anchor_lang::solana_program::sysvar::instructions::id(),
+    )]
+    pub instruction_sysvar_account_info: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProcessPositionNative<'info> {
+    /// the position's owner, paid out in lamports
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    /// closed into `payer` once fully processed
+    #[account(mut,
+        constraint = position.owner == payer.key(),
+        constraint = position.pool == pool.key() @ ProtocolError::PoolMismatch,
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut,
+        seeds = [b"pool_config", position.pool.as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    /// CHECK: a system account holding the pool's native margin
+    #[account(mut,
+        seeds = [b"native_vault", position.pool.as_ref()],
+        bump,
+    )]
+    pub native_vault: UncheckedAccount<'info>,
+    /// CHECK: a system account collecting the pool's native fees
+    #[account(mut,
+        seeds = [b"native_fee", position.pool.as_ref()],
+        bump,
+    )]
+    pub native_fee: UncheckedAccount<'info>,
+    /// CHECK: a system account covering the pool's native bad debt
+    #[account(mut,
+        seeds = [b"native_insurance", position.pool.as_ref()],
+        bump,
+    )]
+    pub native_insurance: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    /// CHECK:
+    #[account(
+        constraint = instruction_sysvar_account_info.key() == anchor_lang::solana_program::sysvar::instructions::id(),
+    )]
+    pub instruction_sysvar_account_info: UncheckedAccount<'info>,
+}
+
+/// The positions to close are passed in `remaining_accounts`; each must be
+/// writable, owned by `payer` and belong to `pool` and `mint`.
+#[derive(Accounts)]
+pub struct ProcessPositions<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK:
+    pub pool: UncheckedAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    /// CHECK:
+    pub price_a: UncheckedAccount<'info>,
+    /// CHECK:
+    pub price_b: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"pool_config", pool.key().as_ref()],
+        bump,
+    )]
+    pub pool_config: Account<'info, PoolConfig>,
+    #[account(mut,
+        constraint = owner_token_account.mint == mint.key(),
+        constraint = owner_token_account.owner == payer.key(),
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    /// CHECK: the PDA owning every vault of the pool
+    #[account(
+        seeds = [b"vault_authority", pool.key().as_ref()],
+        bump,
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    #[account(mut,
+        seeds = [b"vault", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"fee", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut,
+        seeds = [b"insurance", pool.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// CHECK:
+    #[account(
+        constraint = instruction_sysvar_account_info.key() == anchor_lang::solana_program::sysvar::instructions::id(),
+    )]
+    pub instruction_sysvar_account_info: UncheckedAccount<'info>,
+}
+
+/// Reads the price of `price_a` in the collateral. A pool whose base feed is
+/// already quoted in the collateral has no quote feed: it stores
+/// `Pubkey::default()`, the system program's address, as `price_b`, and
+/// callers pass the system program in its place. That also suits a USD feed
+/// margined in a USD stablecoin, saving the second feed's read and its
+/// rounding, but the pool then prices the stablecoin at 1 through a depeg:
+/// positions fill, close and liquidate at the USD price, not the collateral's.
+/// `now` is the caller's clock, read once per instruction.
+/// A feed that is stale, or has no current price, fails with the error
+/// naming it, so that keepers know which one to wait on.
+fn get_current_price<'a>(price_a: &'a UncheckedAccount, price_b: &'a UncheckedAccount, decimals: u8, max_age: u64, max_conf_bps: u64, source: PriceSource, now: i64) -> Result<pyth_sdk_solana::Price> {
+    PoolFeeds::load(price_a, price_b)?.price(decimals, max_age, max_conf_bps, source, now)
+}
+
+/// The base and quote feeds of a pool, parsed once so that an instruction
+/// pricing several positions doesn't parse them again for each.
+struct PoolFeeds {
+    feed_a: pyth_sdk_solana::PriceFeed,
+    feed_b: Option<pyth_sdk_solana::PriceFeed>,
+}
+
+impl PoolFeeds {
+    fn load(price_a: &UncheckedAccount, price_b: &UncheckedAccount) -> Result<Self> {
+        let feed_a = load_price_feed(price_a)?;
+        let feed_b = if price_b.key() == Pubkey::default() {
+            None
+        } else {
+            Some(load_price_feed(price_b)?)
+        };
+        Ok(Self { feed_a, feed_b })
+    }
+
+    /// The price `get_current_price` reads from the feeds.
+    fn price(&self, decimals: u8, max_age: u64, max_conf_bps: u64, source: PriceSource, now: i64) -> Result<pyth_sdk_solana::Price> {
+        check_feed_ages(self.feed_a.publish_time, self.feed_b.as_ref().map(|feed| feed.publish_time), now, max_age)?;
+        let cpa = select_price(&self.feed_a, Feed::A, source)?;
+        let cpb = match &self.feed_b {
+            Some(feed) => Some(select_price(feed, Feed::B, source)?),
+            None => None,
+        };
+        quote_price(&cpa, cpb.as_ref(), decimals, max_conf_bps)
+    }
+}
+
+/// The base and quote feeds a price is read from, and the feed converting a
+/// position's collateral to the quote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Feed {
+    A,
+    B,
+    Collateral,
+}
+
+impl Feed {
+    /// The error of the feed not giving a current price.
+    fn stale(self) -> Error {
+        match self {
+            Feed::A => ProtocolError::PriceAStale.into(),
+            Feed::B => ProtocolError::PriceBStale.into(),
+            Feed::Collateral => ProtocolError::CollateralPriceStale.into(),
+        }
+    }
+}
+
+fn load_price_feed(price_account: &AccountInfo) -> Result<pyth_sdk_solana::PriceFeed> {
+    pyth_sdk_solana::load_price_feed_from_account_info(price_account)
+        .map_err(|_| ProtocolError::InvalidPriceAccount.into())
+}
+
+/// Checks the publish times of the base feed and of the quote feed, if any,
+/// against `max_age`, the base feed first.
+fn check_feed_ages(publish_a: i64, publish_b: Option<i64>, now: i64, max_age: u64) -> Result<()> {
+    check_price_age(publish_a, now, max_age).map_err(|_| Feed::A.stale())?;
+    if let Some(publish_b) = publish_b {
+        check_price_age(publish_b, now, max_age).map_err(|_| Feed::B.stale())?;
+    }
+    Ok(())
+}
+
+fn select_price(price_feed: &pyth_sdk_solana::PriceFeed, feed: Feed, source: PriceSource) -> Result<pyth_sdk_solana::Price> {
+    match source {
+        PriceSource::Spot => price_feed.get_current_price(),
+        PriceSource::Ema => price_feed.get_ema_price(),
+    }
+    .ok_or_else(|| feed.stale())
+}
+
+/// Prices `price_a` in units of `price_b`, or on its own when there is no
+/// quote feed, scaled by `10^decimals`.
+fn quote_price(price_a: &pyth_sdk_solana::Price, price_b: Option<&pyth_sdk_solana::Price>, decimals: u8, max_conf_bps: u64) -> Result<pyth_sdk_solana::Price> {
+    let target_expo = (decimals as i32)
+        .checked_neg()
+        .ok_or(ProtocolError::InvalidArgs)?;
+    let price = match price_b {
+        Some(price_b) => price_a.get_price_in_quote(price_b, target_expo),
+        None => price_a.scale_to_exponent(target_expo),
+    }
+    .ok_or(ProtocolError::InvalidPrice)?;
+    debug_log!("oracle price {} conf {} expo {}", price.price, price.conf, price.expo);
+    check_confidence(&price, max_conf_bps)?;
+    Ok(price)
+}
+
+fn realized_pnl(margin: u64, returned_margin: u64) -> Result<i64> {
+    (returned_margin as i128)
+        .checked_sub(margin as i128)
+        .and_then(|pnl| i64::try_from(pnl).ok())
+        .ok_or(ProtocolError::MathOverflow.into())
+}
+
+/// Reads the price of a collateral in the quote from `info`, which must be
+/// the `expected` feed, at `-decimals` and under the age and confidence
+/// limits the pool prices its own feeds with.
+fn get_collateral_price(info: &AccountInfo, expected: &Pubkey, decimals: u8, max_age: u64, max_conf_bps: u64, now: i64) -> Result<pyth_sdk_solana::Price> {
+    if info.key != expected {
+        return err!(ProtocolError::InvalidPriceAccount);
+    }
+    let feed = load_price_feed(info)?;
+    check_price_age(feed.publish_time, now, max_age).map_err(|_| Feed::Collateral.stale())?;
+    let price = select_price(&feed, Feed::Collateral, PriceSource::Spot)?;
+    quote_price(&price, None, decimals, max_conf_bps)
+}
+
+/// The collateral price of `position`, from the feed passed first in
+/// `remaining_accounts` and under the pool's oracle limits; `None` when its
+/// margin is in the quote.
+fn position_collateral_price(remaining_accounts: &[AccountInfo], position: &Position, pool_config: &PoolConfig, now: i64) -> Result<Option<pyth_sdk_solana::Price>> {
+    if !position.converts_collateral() {
+        return Ok(None);
+    }
+    let info = remaining_accounts.first().ok_or(ProtocolError::InvalidPriceAccount)?;
+    get_collateral_price(info, &position.collateral_price, position.decimals, pool_config.max_price_age, pool_config.max_conf_bps, now).map(Some)
+}
+
+/// Collateral tokens of `collateral_decimals` worth `quote` at
+/// `collateral_price`, both scaled by the same `10^-expo`. Rounds up, as the
+/// protocol is the one taking them.
+fn collateral_for_quote(quote: u64, collateral_price: &pyth_sdk_solana::Price, collateral_decimals: u8) -> Result<u64> {
+    let (scaled, price) = collateral_ratio(quote, collateral_price, collateral_decimals)?;
+    let amount = scaled
+        .checked_add(price - 1)
+        .ok_or(ProtocolError::MathOverflow)?
+        / price;
+    u64::try_from(amount).map_err(|_| ProtocolError::MathOverflow.into())
+}
+
+/// Like `collateral_for_quote`, rounding down for a payout, and `quote` as is
+/// when there is no `collateral_price`.
+fn payout_in_collateral(quote: u64, collateral_price: Option<&pyth_sdk_solana::Price>, collateral_decimals: u8) -> Result<u64> {
+    let collateral_price = match collateral_price {
+        Some(price) => price,
+        None => return Ok(quote),
+    };
+    let (scaled, price) = collateral_ratio(quote, collateral_price, collateral_decimals)?;
+    u64::try_from(scaled / price).map_err(|_| ProtocolError::MathOverflow.into())
+}
+
+/// `quote` scaled up by `10^collateral_decimals`, and the collateral price to
+/// divide it by.
+fn collateral_ratio(quote: u64, collateral_price: &pyth_sdk_solana::Price, collateral_decimals: u8) -> Result<(u128, u128)> {
+    let price = price_to_u64(collateral_price.price)? as u128;
+    if price == 0 {
+        return err!(ProtocolError::InvalidPrice);
+    }
+    let scaled = 10u128
+        .checked_pow(collateral_decimals as u32)
+        .and_then(|scale| (quote as u128).checked_mul(scale))
+        .ok_or(ProtocolError::MathOverflow)?;
+    Ok((scaled, price))
+}
+
+fn check_price_age(publish_time: i64, now: i64, max_age: u64) -> Result<()> {
+    let age = now
+        .checked_sub(publish_time)
+        .ok_or(ProtocolError::InvalidPrice)?;
+    // a `max_age` past `i64::MAX` accepts any age rather than wrapping
+    if age > i64::try_from(max_age).unwrap_or(i64::MAX) {
+        return err!(ProtocolError::InvalidPrice);
+    }
+    Ok(())
+}
+
+/// Rejects prices whose confidence interval is wider than `max_conf_bps` of the price.
+fn check_confidence(price: &pyth_sdk_solana::Price, max_conf_bps: u64) -> Result<()> {
+    if price.price <= 0 {
+        return err!(ProtocolError::InvalidPrice);
+    }
+    let conf_bps = (price.conf as u128)
+        .checked_mul(10000)
+        .and_then(|conf| conf.checked_div(price.price as u128))
+        .ok_or(ProtocolError::InvalidPrice)?;
+    if conf_bps > max_conf_bps as u128 {
+        return err!(ProtocolError::InvalidPrice);
+    }
+    Ok(())
+}
+
+fn transfer_to_vault<'info>(
+    token_program: &Program<'info, Token>,
+    from: &Account<'info, TokenAccount>,
+    vault: &Account<'info, TokenAccount>,
+    authority: &Signer<'info>,
+    amount: u64,
+) -> Result<()> {
+    token::transfer(
+        CpiContext::new(
+            token_program.to_account_info(),
+            Transfer {
+                from: from.to_account_info(),
+                to: vault.to_account_info(),
+                authority: authority.to_account_info(),
+            },
+        ),
+        amount,
+    )
+}
+
+fn transfer_from_vault<'info>(
+    token_program: &Program<'info, Token>,
+    vault: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    vault_authority: &UncheckedAccount<'info>,
+    pool: &Pubkey,
+    vault_authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: vault.to_account_info(),
+                to: to.to_account_info(),
+                authority: vault_authority.to_account_info(),
+            },
+            &[&[b"vault_authority", pool.as_ref(), &[vault_authority_bump]]],
+        ),
+        amount,
+    )
+}
+
+/// Rejects collecting more fees than the fee account holds.
+fn check_fee_withdrawal(balance: u64, amount: u64) -> Result<()> {
+    if amount > balance {
+        return err!(ProtocolError::InsufficientBalance);
+    }
+    Ok(())
+}
+
+/// The `CrossAccount` backing `position` and every position it backs, read
+/// from `remaining_accounts`: the writable account first, then the positions
+/// other than `position` in any order.
+fn load_cross_members<'info>(remaining_accounts: &[AccountInfo<'info>], position: &Account<'info, Position>) -> Result<(Account<'info, CrossAccount>, Vec<Position>)> {
+    let (info, others) = remaining_accounts
+        .split_first()
+        .ok_or(ProtocolError::InvalidAccountData)?;
+    let cross_account = Account::<CrossAccount>::try_from(info)?;
+    if !info.is_writable || cross_account.owner != position.owner || cross_account.pool != position.pool {
+        return err!(ProtocolError::InvalidAccountData);
+    }
+
+    let mut keys = vec![position.key()];
+    let mut members = vec![(**position).clone()];
+    for info in others {
+        members.push((*Account::<Position>::try_from(info)?).clone());
+        keys.push(info.key());
+    }
+    cross_account.check_members(&keys)?;
+    Ok((cross_account, members))
+}
+
+/// The address of the `CrossAccount` backing the positions of `owner` in
+/// `pool`.
+fn cross_account_address(owner: &Pubkey, pool: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"cross", owner.as_ref(), pool.as_ref()], program_id).0
+}
+
+/// Settles a closing cross position with the owner's `CrossAccount`, found
+/// among `remaining_accounts` by its address: the `margin` it had booked
+/// leaves the account, as the returned margin is paid out of the vault, and
+/// the free collateral covers what it can of the `settlement`'s bad debt,
+/// leaving the rest to the insurance fund. A position closed in full stops
+/// being a member. Nothing to do for an isolated position.
+fn release_cross_member(remaining_accounts: &[AccountInfo], position: &Position, key: &Pubkey, margin: u64, settlement: &mut Settlement, program_id: &Pubkey) -> Result<()> {
+    if position.ptype != PositionType::Cross {
+        return Ok(());
+    }
+    let address = cross_account_address(&position.owner, &position.pool, program_id);
+    let info = remaining_accounts
+        .iter()
+        .find(|info| *info.key == address)
+        .ok_or(ProtocolError::InvalidAccountData)?;
+    if !info.is_writable {
+        return err!(ProtocolError::InvalidAccountData);
+    }
+    let mut cross_account = Account::<CrossAccount>::try_from(info)?;
+    settlement.bad_debt = if position.status == PositionStatus::Processed {
+        cross_account.release(key, margin, settlement.bad_debt)?
+    } else {
+        cross_account.settle_member(margin, settlement.bad_debt)
+    };
+    cross_account.exit(program_id)
+}
+
+/// Holds a close's payout to the pool's `max_payout_multiple` of the `margin`
+/// closed, logging a `PayoutCapped` when it bites.
+fn cap_payout(pool_config: &PoolConfig, position: &Pubkey, settlement: &mut Settlement, margin: u64) {
+    let payout = settlement.returned_margin;
+    if let Some(cap) = pool_config.cap_payout(settlement, margin) {
+        emit!(PayoutCapped {
+            position: *position,
+            payout,
+            cap,
+        });
+    }
+}
+
+/// The pool's token accounts a closing position is paid out of.
+struct SettlementAccounts<'a, 'info> {
+    token_program: &'a Program<'info, Token>,
+    vault_authority: &'a UncheckedAccount<'info>,
+    vault: &'a Account<'info, TokenAccount>,
+    fee_account: &'a Account<'info, TokenAccount>,
+    insurance_fund: &'a Account<'info, TokenAccount>,
+    owner_token_account: &'a Account<'info, TokenAccount>,
+    vault_authority_bump: u8,
+}
+
+/// The token account a close pays out to in place of the owner's: the first
+/// of `remaining_accounts` other than the `cross_account` of a cross
+/// position, which must be writable and hold `mint`.
+fn payout_recipient<'info>(remaining_accounts: &[AccountInfo<'info>], mint: &Pubkey, cross_account: Option<Pubkey>) -> Result<Option<Account<'info, TokenAccount>>> {
+    let info = match remaining_accounts.iter().find(|info| Some(*info.key) != cross_account) {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    let recipient = Account::<TokenAccount>::try_from(info)?;
+    if !info.is_writable || recipient.mint != *mint {
+        return err!(ProtocolError::InvalidAccountData);
+    }
+    Ok(Some(recipient))
+}
+
+fn pay_out_settlement(accounts: SettlementAccounts, pool: &Pubkey, settlement: &Settlement) -> Result<()> {
+    // a loss beyond the margin is made good to the vault by the insurance fund
+    if settlement.bad_debt > 0 {
+        if accounts.insurance_fund.amount < settlement.bad_debt {
+            return err!(ProtocolError::InsufficientInsuranceFund);
+        }
+        transfer_from_vault(
+            accounts.token_program,
+            accounts.insurance_fund,
+            accounts.vault,
+            accounts.vault_authority,
+            pool,
+            accounts.vault_authority_bump,
+            settlement.bad_debt,
+        )?;
+    }
+
+    // a loss stays in the vault, a profit is paid out of it
+    for (to, amount) in [
+        (accounts.owner_token_account, settlement.returned_margin),
+        (accounts.fee_account, settlement.overnight_fee + settlement.trading_fee),
+        (accounts.insurance_fund, settlement.insurance_fee),
+    ] {
+        transfer_from_vault(
+            accounts.token_program,
+            accounts.vault,
+            to,
+            accounts.vault_authority,
+            pool,
+            accounts.vault_authority_bump,
+            amount,
+        )?;
+    }
+    Ok(())
+}
+
+fn transfer_lamports_to_vault<'info>(
+    system_program: &Program<'info, System>,
+    from: &Signer<'info>,
+    vault: &UncheckedAccount<'info>,
+    amount: u64,
+) -> Result<()> {
+    system_program::transfer(
+        CpiContext::new(
+            system_program.to_account_info(),
+            system_program::Transfer {
+                from: from.to_account_info(),
+                to: vault.to_account_info(),
+            },
+        ),
+        amount,
+    )
+}
+
+/// Pays lamports out of the native vault seeded with `seed`, which signs for
+/// itself as it is owned by the system program.
+fn transfer_lamports_from_vault<'info>(
+    system_program: &Program<'info, System>,
+    vault: &UncheckedAccount<'info>,
+    to: AccountInfo<'info>,
+    seed: &[u8],
+    pool: &Pubkey,
+    vault_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    system_program::transfer(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Transfer {
+                from: vault.to_account_info(),
+                to,
+            },
+            &[&[seed, pool.as_ref(), &[vault_bump]]],
+        ),
+        amount,
+    )
+}
+
+/// The lamports of a native vault above the rent-exempt minimum it must keep.
+fn spendable_lamports(lamports: u64, rent_exempt_minimum: u64) -> u64 {
+    lamports.saturating_sub(rent_exempt_minimum)
+}
+
+/// The pool's native vaults a closing position is paid out of.
+struct NativeSettlementAccounts<'a, 'info> {
+    system_program: &'a Program<'info, System>,
+    native_vault: &'a UncheckedAccount<'info>,
+    native_fee: &'a UncheckedAccount<'info>,
+    native_insurance: &'a UncheckedAccount<'info>,
+    owner: &'a Signer<'info>,
+    native_vault_bump: u8,
+    native_insurance_bump: u8,
+}
+
+/// Rejects a native payout the vaults can't make without going under the
+/// rent-exempt minimum they must keep: the insurance fund first covers the
+/// bad debt into the vault, which then pays out everything else.
+fn check_native_payout(settlement: &Settlement, vault_lamports: u64, insurance_lamports: u64, rent_exempt_minimum: u64) -> Result<()> {
+    if spendable_lamports(insurance_lamports, rent_exempt_minimum) < settlement.bad_debt {
+        return err!(ProtocolError::InsufficientInsuranceFund);
+    }
+    let owed = [
+        settlement.returned_margin,
+        settlement.overnight_fee,
+        settlement.trading_fee,
+        settlement.insurance_fee,
+    ]
+    .iter()
+    .try_fold(0u64, |total, amount| total.checked_add(*amount))
+    .ok_or(ProtocolError::MathOverflow)?;
+    let available = spendable_lamports(vault_lamports, rent_exempt_minimum)
+        .checked_add(settlement.bad_debt)
+        .ok_or(ProtocolError::MathOverflow)?;
+    if available < owed {
+        return err!(ProtocolError::InsufficientVaultBalance);
+    }
+    Ok(())
+}
+
+/// `pay_out_settlement` in lamports.
+fn pay_out_native_settlement(accounts: NativeSettlementAccounts, pool: &Pubkey, settlement: &Settlement) -> Result<()> {
+    check_native_payout(
+        settlement,
+        accounts.native_vault.lamports(),
+        accounts.native_insurance.lamports(),
+        Rent::get()?.minimum_balance(0),
+    )?;
+    if settlement.bad_debt > 0 {
+        transfer_lamports_from_vault(
+            accounts.system_program,
+            accounts.native_insurance,
+            accounts.native_vault.to_account_info(),
+            b"native_insurance",
+            pool,
+            accounts.native_insurance_bump,
+            settlement.bad_debt,
+        )?;
+    }
+
+    for (to, amount) in [
+        (accounts.owner.to_account_info(), settlement.returned_margin),
+        (accounts.native_fee.to_account_info(), settlement.overnight_fee + settlement.trading_fee),
+        (accounts.native_insurance.to_account_info(), settlement.insurance_fee),
+    ] {
+        transfer_lamports_from_vault(
+            accounts.system_program,
+            accounts.native_vault,
+            to,
+            b"native_vault",
+            pool,
+            accounts.native_vault_bump,
+            amount,
+        )?;
+    }
+    Ok(())
+}
+
+/// Bounds the exit of a voluntary close the way `check_slippage` bounds an entry.
+fn check_min_return(returned_margin: u64, min_return: u64) -> Result<()> {
+    if returned_margin < min_return {
+        return err!(ProtocolError::SlippageReached);
+    }
+    Ok(())
+}
+
+/// Rejects a negative price, which a cast to `u64` would wrap into a huge one.
+#[inline(always)]
+fn require_nonnegative_price(price: i64) -> Result<()> {
+    if price < 0 {
+        return err!(ProtocolError::InvalidPrice);
+    }
+    Ok(())
+}
+
+/// An oracle or stored price as `u64`; the only way prices leave `i64`.
+#[inline(always)]
+fn price_to_u64(price: i64) -> Result<u64> {
+    require_nonnegative_price(price)?;
+    Ok(price as u64)
+}
+
+/// The price a new order fills at: the ask for longs, the bid for shorts.
+/// With no confidence interval both are the price itself, zero included. An
+/// interval as wide as the price leaves no bid, and is refused rather than
+/// filled at nothing.
+fn get_fill_price(current_price: &pyth_sdk_solana::Price, direction: Direction) -> Result<u64> {
+    let price = price_to_u64(current_price.price)?;
+    match direction {
+        Direction::OpenLong => price
+            .checked_add(current_price.conf)
+            .ok_or(ProtocolError::InvalidPrice.into()),
+        Direction::OpenShort => {
+            if current_price.conf > 0 && current_price.conf >= price {
+                return err!(ProtocolError::ConfidenceExceedsPrice);
+            }
+            Ok(price - current_price.conf)
+        }
+    }
+}
+
+/// The price a position entered at `price` with `bond` of margin above its
+/// maintenance margin is liquidated at. A long whose bond covers the whole
+/// price can't be liquidated and gets zero.
+fn get_liquidation(price: i64, bond: u64, direction: Direction) -> Result<u64> {
+    math::liquidation_price(price_to_u64(price)?, bond, direction.is_long())
+        .ok_or(ProtocolError::MathOverflow.into())
+}
+
+/// The value in collateral of `amount`, scaled by `10^decimals`, at `price`.
+/// Fails on a negative price rather than letting it wrap.
+fn notional(price: i64, amount: u64, decimals: u8) -> Result<u64> {
+    let price = price_to_u64(price)?;
+    (amount as u128)
+        .checked_mul(price as u128)
+        .and_then(|notional| notional.checked_div(10u128.checked_pow(decimals as u32)?))
+        .and_then(|notional| u64::try_from(notional).ok())
+        .ok_or(ProtocolError::MathOverflow.into())
+}
+
+/// The margin `amount` at `price` needs at `leverage / leverage_denominator`,
+/// rounded up like `PositionArgs::margin`.
+fn required_initial_margin(amount: u64, price: i64, decimals: u8, leverage: u64, leverage_denominator: u64) -> Result<u64> {
+    leveraged_margin(notional(price, amount, decimals)?, leverage, leverage_denominator)
+}
+
+/// Splits `penalty_bps` of the `remaining` margin of a liquidated position
+/// between the keeper and the insurance fund; the owner gets the rest back.
+fn split_penalty(remaining: u64, penalty_bps: u64) -> Result<LiquidateResult> {
+    let penalty = Rate {
+        numerator: penalty_bps,
+        denominator: RATE_DENOMINATOR,
+    }
+    .apply(remaining)
+    .ok_or(ProtocolError::MathOverflow)?;
+    let keeper_bonus = Rate {
+        numerator: LIQUIDATION_BONUS_NUMERATOR,
+        denominator: RATE_DENOMINATOR,
+    }
+    .apply(penalty)
+    .ok_or(ProtocolError::MathOverflow)?;
+
+    Ok(LiquidateResult {
+        returned_margin: remaining.checked_sub(penalty).ok_or(ProtocolError::MathOverflow)?,
+        keeper_bonus,
+        insurance_fee: penalty - keeper_bonus,
+    })
+}
+
+/// Rejects a `margin` more than `ASSET_AMOUNT_TOLERANCE_BPS`, past a unit of
+/// rounding, away from the `required` one.
+fn check_margin_tolerance(margin: u64, required: u64) -> Result<()> {
+    let diff = margin.abs_diff(required).saturating_sub(1) as u128;
+    if diff * RATE_DENOMINATOR as u128 > required as u128 * ASSET_AMOUNT_TOLERANCE_BPS as u128 {
+        return err!(ProtocolError::InvalidLeverage);
+    }
+    Ok(())
+}
+
+/// `notional` over a leverage of `leverage / leverage_denominator`, rounded
+/// up so that no position holds less collateral than its leverage requires.
+fn leveraged_margin(notional: u64, leverage: u64, leverage_denominator: u64) -> Result<u64> {
+    if leverage == 0 || leverage_denominator == 0 {
+        return err!(ProtocolError::InvalidLeverage);
+    }
+    let scaled = notional as u128 * leverage_denominator as u128;
+    let margin = scaled / leverage as u128 + u128::from(scaled % leverage as u128 != 0);
+    u64::try_from(margin).map_err(|_| ProtocolError::MathOverflow.into())
+}
+
+/// The greatest common divisor of `a` and `b`; zero only when both are.
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let rest = a % b;
+        a = b;
+        b = rest;
+    }
+    a
+}
+
+/// The asset size bought with `leverage_margin` at `price`, as a fixed-point
+/// integer scaled by `10^decimals`.
+fn get_asset_amount(leverage_margin: u64, price: u64, decimals: u8) -> Result<u64> {
+    let amount = (leverage_margin as u128)
+        .checked_mul(10u128.checked_pow(decimals as u32).ok_or(ProtocolError::InvalidArgs)?)
+        .and_then(|scaled| scaled.checked_div(price as u128))
+        .ok_or(ProtocolError::InvalidPrice)?;
+    u64::try_from(amount)
+        .map_err(|_| ProtocolError::InvalidArgs.into())
+}
+
+/// Rejects a size whose notional at `price` strays more than
+/// `ASSET_AMOUNT_TOLERANCE_BPS` from the `leverage_margin` it was bought with,
+/// as it does when `price` isn't scaled by `10^decimals`.
+fn check_asset_amount(amount: u64, price: u64, decimals: u8, leverage_margin: u64) -> Result<()> {
+    let price = i64::try_from(price).map_err(|_| ProtocolError::InvalidPrice)?;
+    let notional = notional(price, amount, decimals)?;
+    let diff = leverage_margin.abs_diff(notional) as u128;
+    if diff * 10000 > leverage_margin as u128 * ASSET_AMOUNT_TOLERANCE_BPS as u128 {
+        return err!(ProtocolError::InvalidArgs);
+    }
+    Ok(())
+}
+
+/// Checks the fill `price`, scaled by `10^decimals`, against the reference
+/// `args.price` scaled by `10^-expo`, logging a `SlippageRejected` with how
+/// far apart they were before failing.
+fn check_slippage(price: u64, args: PositionArgs) -> Result<()> {
+    if let Some(rejected) = slippage_rejection(price, args)? {
+        debug_log!("slippage: reference {} fill {} allowed {} bps", args.price, price, args.slippage_numerator);
+        emit!(rejected);
+        return err!(ProtocolError::SlippageReached);
+    }
+    Ok(())
+}
+
+/// The rejection of a fill at `price` for `args`, or `None` when it is within
+/// the order's slippage. The fill and the reference are both scaled by
+/// `10^-decimals`, which `check_scale` holds the order's `expo` to.
+fn slippage_rejection(price: u64, args: PositionArgs) -> Result<Option<SlippageRejected>> {
+    args.check_scale()?;
+    let fill = price as u128;
+    let price_before = args.price as u128;
+    let fill_bps = fill
+        .checked_mul(RATE_DENOMINATOR as u128)
+        .ok_or(ProtocolError::MathOverflow)?;
+
+    let rejected = match args.direction {
+        Direction::OpenLong => {
+            // the real price is higher than the given price
+            let real_price = price_before
+                .checked_mul(RATE_DENOMINATOR as u128 + args.slippage_numerator as u128)
+                .ok_or(ProtocolError::MathOverflow)?;
+            fill_bps.cmp(&real_price).is_ge()
+        }
+        Direction::OpenShort => {
+            // the real price is lower than the given price
+            let real_price = price_before
+                .checked_mul(
+                    (RATE_DENOMINATOR as u128)
+                        .checked_sub(args.slippage_numerator as u128)
+                        .ok_or(ProtocolError::InvalidArgs)?
+                )
+                .ok_or(ProtocolError::MathOverflow)?;
+            fill_bps.cmp(&real_price).is_le()
+        }
+    };
+    if !rejected {
+        return Ok(None);
+    }
+
+    // a zero reference is infinitely far from any fill
+    let deviation = if fill > price_before { fill - price_before } else { price_before - fill };
+    let deviation_bps = deviation
+        .checked_mul(RATE_DENOMINATOR as u128)
+        .and_then(|diff| diff.checked_div(price_before))
+        .map_or(u64::MAX, |bps| u64::try_from(bps).unwrap_or(u64::MAX));
+    Ok(Some(SlippageRejected {
+        direction: args.direction,
+        reference_price: args.price,
+        expo: args.expo,
+        oracle_price: price,
+        decimals: args.decimals,
+        deviation_bps,
+    }))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::{Discriminator, Event};
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// The lines `debug_log!` wrote on this test's thread.
+        pub static LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[cfg(feature = "debug-log")]
+    fn take_log() -> Vec<String> {
+        LOG.with(|log| log.borrow_mut().drain(..).collect())
+    }
+
+    /// The error `result` failed with, as the client receives it: Anchor's
+    /// `Error` can't be compared, and carries the line that raised it.
+    pub fn failure<T>(result: Result<T>) -> ProgramError {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(error) => error.into(),
+        }
+    }
+
+    /// `error` as the client receives it.
+    pub fn code(error: ProtocolError) -> ProgramError {
+        Error::from(error).into()
+    }
+
+    fn new_position(ptype: PositionType, direction: Direction, margin: u64) -> Position {
+        Position {
+            version: POSITION_VERSION,
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            authority: oracle(),
+            mint: Pubkey::default(),
+            price_a: Pubkey::default(),
+            price_b: Pubkey::default(),
+            index: 0,
+            status: PositionStatus::Open,
+            ptype,
+            direction,
+            decimals: 6,
+            leverage: 0,
+            last_price: 0,
+            last_conf: 0,
+            margin,
+            margin_rate_numerator: 0,
+            overnight_fee_numerator: 0,
+            taker_fee_bps: 0,
+            liquidation: 0,
+            created_at: 0,
+            slot: 0,
+            amount: 0,
+            max_price_age: 60,
+            max_conf_bps: 100,
+            last_fee_settlement: 0,
+            accrued_fee: 0,
+            take_profit: None,
+            stop_loss: None,
+            funding_index: 0,
+            conf_mode: ConfMode::Conservative,
+            expiry: None,
+            leverage_denominator: 1,
+            collateral_price: Pubkey::default(),
+            collateral_decimals: 0,
+            maker_fee_bps: 0,
+        }
+    }
+
+    fn new_args(ptype: PositionType, direction: Direction) -> PositionArgs {
+        PositionArgs {
+            price: 30000_000_000,
+            expo: -6,
+            decimals: 6,
+            leverage_margin: 1000_000_000,
+            leverage: 10,
+            leverage_denominator: 1,
+            ptype,
+            direction,
+            slippage_numerator: 100,
+            margin_rate_numerator: 500,
+        }
+    }
+
+    /// The key the test pool's positions are opened under and closed by.
+    fn oracle() -> Pubkey {
+        Pubkey::new_from_array([7; 32])
+    }
+
+    fn pool_config() -> PoolConfig {
+        PoolConfig {
+            pool: Pubkey::new_unique(),
+            admin: Pubkey::new_unique(),
+            price_a: Pubkey::new_unique(),
+            price_b: Pubkey::new_unique(),
+            max_leverage: 20,
+            max_leverage_long: 0,
+            max_leverage_short: 0,
+            maintenance_margin_bps: 800,
+            max_slippage_bps: 200,
+            overnight_fee_bps: 3,
+            maker_fee_bps: 5,
+            taker_fee_bps: 10,
+            paused: false,
+            liquidate_on_ema: false,
+            max_long_oi: 10_000_000,
+            max_short_oi: 10_000_000,
+            max_notional: 1000000_000_000,
+            long_oi: 0,
+            short_oi: 0,
+            funding_rate_bps: 10,
+            cumulative_funding: 0,
+            last_funding_update: 0,
+            min_margin: 10_000_000,
+            min_notional: 100_000_000,
+            min_hold_seconds: 60,
+            liquidation_penalty_bps: 2000,
+            collateral_mode: CollateralMode::Token,
+            conf_mode: ConfMode::Conservative,
+            permissionless_liquidation: true,
+            liquidators: Vec::new(),
+            max_payout_multiple: 0,
+            pending_admin: Pubkey::default(),
+            collateral_feeds: Vec::new(),
+            max_price_age: 60,
+            max_conf_bps: 100,
+            quote_mint: Pubkey::new_unique(),
+            oracle_authority: oracle(),
+        }
+    }
+
+    #[test]
+    fn test_leverage_cap_per_direction() {
+        let mut config = pool_config();
+        config.max_leverage_long = 50;
+        config.max_leverage_short = 5;
+
+        let mut long = new_args(PositionType::Isolated, Direction::OpenLong);
+        long.leverage = 50;
+        config.check_args(&mut long).unwrap();
+        long.leverage = 51;
+        assert_eq!(failure(config.check_args(&mut long)), code(ProtocolError::InvalidLeverage));
+
+        let mut short = new_args(PositionType::Isolated, Direction::OpenShort);
+        short.leverage = 5;
+        config.check_args(&mut short).unwrap();
+        // within the long cap and the pool-wide one, but not the short cap
+        short.leverage = 6;
+        assert_eq!(failure(config.check_args(&mut short)), code(ProtocolError::InvalidLeverage));
+
+        // re-levering is held to the position's side
+        config.check_leverage(50, Direction::OpenLong).unwrap();
+        assert_eq!(failure(config.check_leverage(50, Direction::OpenShort)), code(ProtocolError::InvalidLeverage));
+
+        // an unset cap falls back to the pool-wide one
+        config.max_leverage_short = 0;
+        config.check_leverage(20, Direction::OpenShort).unwrap();
+        assert_eq!(failure(config.check_leverage(21, Direction::OpenShort)), code(ProtocolError::InvalidLeverage));
+    }
+
+    #[test]
+    fn test_pool_config_overrides_args() {
+        let config = pool_config();
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        config.check_args(&mut args).unwrap();
+        // the pool's maintenance margin replaces the client-supplied one
+        assert_eq!(args.margin_rate_numerator, 800);
+
+
+        // within the crate-wide ceiling but above the pool's limit
+        args.leverage = 25;
+        assert!(args.leverage <= MAX_LEVERAGE);
+        assert_eq!(failure(config.check_args(&mut args)), code(ProtocolError::InvalidLeverage));
+
+        args.leverage = 0;
+        assert_eq!(failure(config.check_args(&mut args)), code(ProtocolError::InvalidLeverage));
+
+        // a client that predates fractional leverage sends no denominator
+        let mut whole = new_args(PositionType::Isolated, Direction::OpenLong);
+        let margin = whole.margin().unwrap();
+        whole.leverage_denominator = 0;
+        config.check_args(&mut whole).unwrap();
+        assert_eq!(whole.leverage_denominator, 1);
+        assert_eq!(whole.margin().unwrap(), margin);
+
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.slippage_numerator = 300;
+        assert_eq!(failure(config.check_args(&mut args)), code(ProtocolError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_price_accounts() {
+        let config = pool_config();
+        config.check_price_accounts(&config.price_a, &config.price_b).unwrap();
+
+        let mut position = new_position(PositionType::Isolated, Direction::OpenLong, 0);
+        position.price_a = config.price_a;
+        position.price_b = config.price_b;
+        position.check_price_accounts(&config.price_a, &config.price_b).unwrap();
+
+        // any other feed, or the right feeds swapped, is rejected
+        let wrong = Pubkey::new_unique();
+        for (price_a, price_b) in [
+            (&wrong, &config.price_b),
+            (&config.price_a, &wrong),
+            (&config.price_b, &config.price_a),
+        ] {
+            assert_eq!(
+                failure(config.check_price_accounts(price_a, price_b)),
+                code(ProtocolError::InvalidPriceAccount),
+            );
+            assert_eq!(
+                failure(position.check_price_accounts(price_a, price_b)),
+                code(ProtocolError::InvalidPriceAccount),
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate_feeds_then_close() {
+        let mut config = pool_config();
+        let mut long = open_long_of_three();
+        long.pool = config.pool;
+        long.price_a = config.price_a;
+        long.price_b = config.price_b;
+        let (old_a, old_b) = (config.price_a, config.price_b);
+
+        // the pool moves its base feed; the position follows
+        config.price_a = Pubkey::new_unique();
+        long.migrate_feeds(&config).unwrap();
+        assert_eq!(
+            failure(long.check_price_accounts(&old_a, &old_b)),
+            code(ProtocolError::InvalidPriceAccount),
+        );
+
+        // and closes against the new feed
+        let key = Pubkey::new_unique();
+        let time = long.created_at + 86400;
+        let price = flat_price(31000_000_000);
+        let expected = long.settle(&price, time).unwrap();
+        let signed = signed_close(&long, key, false);
+        let (settlement, status) = close_signed(&mut long, &key, &config, &signed, 1234, time, |position| {
+            position.check_price_accounts(&config.price_a, &config.price_b)?;
+            Ok(price)
+        })
+        .unwrap();
+        assert_eq!(status, BatchStatus::Closed);
+        assert_eq!(settlement, expected);
+
+        // a position of another pool isn't touched
+        let mut other = open_long_of_three();
+        other.pool = Pubkey::new_unique();
+        assert_eq!(failure(other.migrate_feeds(&config)), code(ProtocolError::PoolMismatch));
+        assert_eq!(other.price_a, Pubkey::default());
+    }
+
+    #[test]
+    fn test_recompute_liquidation_after_config_change() {
+        let mut config = pool_config();
+        let mut long = open_long_of_three();
+        long.pool = config.pool;
+        long.overnight_fee_numerator = 1;
+        assert_eq!(long.liquidation, 30000_000_000 - 8550_000_000);
+
+        // raising the maintenance margin from 5% to 8% moves the long's liquidation up
+        config.maintenance_margin_bps = 800;
+        config.overnight_fee_bps = 3;
+        let time = long.created_at + 2 * 86400 + 43200;
+        long.apply_pool_rates(&config, time).unwrap();
+        assert_eq!(long.margin_rate_numerator, 800);
+        assert_eq!(long.maintainance_margin().unwrap(), 720_000_000);
+        assert_eq!(long.liquidation, 30000_000_000 - 8280_000_000);
+
+        // the two days already held stay at the old fee, the third is at the new one
+        assert_eq!(long.accrued_fee, 2 * 9_000_000);
+        assert_eq!(long.overnight_fee(long.created_at + 3 * 86400).unwrap(), 2 * 9_000_000 + 27_000_000);
+
+        // lowering it moves a short's liquidation up as well
+        let mut short = open_short_of_three();
+        short.pool = config.pool;
+        config.maintenance_margin_bps = 300;
+        short.apply_pool_rates(&config, short.created_at).unwrap();
+        assert_eq!(short.liquidation, 30000_000_000 + 8730_000_000);
+
+        let mut other = open_long_of_three();
+        assert_eq!(failure(other.apply_pool_rates(&config, other.created_at)), code(ProtocolError::PoolMismatch));
+        assert_eq!(other.liquidation, 30000_000_000 - 8550_000_000);
+    }
+
+    /// The account data of `position` as written at `version`, without the
+    /// fields added since and with the stale bytes a cleared trigger leaves.
+    #[test]
+    fn test_status_byte() {
+        let mut long = open_long_of_three();
+        for (status, byte) in [(PositionStatus::Open, 0), (PositionStatus::Processed, 1)] {
+            long.status = status;
+            let mut data = Vec::new();
+            long.try_serialize(&mut data).unwrap();
+            assert_eq!(status.as_u8(), byte);
+            assert_eq!(data[Position::STATUS_OFFSET], byte);
+        }
+        assert_eq!(Position::STATUS_OFFSET, 205);
+    }
+
+    #[test]
+    fn test_filter_offsets() {
+        let mut short = open_short_of_three();
+        short.pool = Pubkey::new_unique();
+        short.owner = Pubkey::new_unique();
+        short.authority = Pubkey::new_unique();
+        let mut data = Vec::new();
+        short.try_serialize(&mut data).unwrap();
+
+        let at = |offset: usize, len: usize| &data[offset..offset + len];
+        assert_eq!(at(Position::POOL_OFFSET, 32), short.pool.as_ref());
+        assert_eq!(at(Position::OWNER_OFFSET, 32), short.owner.as_ref());
+        assert_eq!(at(Position::STATUS_OFFSET, 1), [PositionStatus::Open.as_u8()]);
+        assert_eq!(at(Position::DIRECTION_OFFSET, 1), [1]);
+        assert_eq!(
+            (Position::POOL_OFFSET, Position::OWNER_OFFSET, Position::STATUS_OFFSET, Position::DIRECTION_OFFSET),
+            (9, 41, 205, 207)
+        );
+    }
+
+    fn legacy_account(position: &Position, version: u8) -> Vec<u8> {
+        let mut legacy = position.clone();
+        legacy.version = version;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let added = match version {
+            1 => 8 + 1 + 1 + 8 + 32 + 1 + 8,
+            2 => 1 + 1 + 8 + 32 + 1 + 8,
+            3 => 1 + 8 + 32 + 1 + 8,
+            4 => 8 + 32 + 1 + 8,
+            5 => 32 + 1 + 8,
+            _ => 8,
+        };
+        data.truncate(data.len() - added);
+        data.extend_from_slice(&[0xff; 9]);
+        data
+    }
+
+    #[test]
+    fn test_migrate_legacy_position() {
+        let mut long = open_long_of_three();
+        long.stop_loss = Some(25000_000_000);
+        long.funding_index = 500;
+        long.conf_mode = ConfMode::Mid;
+        let expected = |funding_index| {
+            let mut expected = long.clone();
+            expected.version = POSITION_VERSION;
+            expected.funding_index = funding_index;
+            expected.conf_mode = ConfMode::Conservative;
+            expected.try_to_vec().unwrap()
+        };
+
+        // version 6 keeps its collateral and fills triggers at its taker fee
+        let mut converted = long.clone();
+        converted.taker_fee_bps = 10;
+        converted.collateral_price = Pubkey::new_unique();
+        converted.collateral_decimals = 9;
+        let migrated = decode_legacy_position(&legacy_account(&converted, 6), 1_000_000).unwrap();
+        assert_eq!(migrated.collateral_price, converted.collateral_price);
+        assert_eq!(migrated.collateral_decimals, 9);
+        assert_eq!(migrated.maker_fee_bps, 10);
+        assert_eq!(migrated.conf_mode, ConfMode::Mid);
+
+        // version 5 keeps its leverage and margins in the quote
+        let mut fractional = long.clone();
+        fractional.leverage_denominator = 2;
+        let migrated = decode_legacy_position(&legacy_account(&fractional, 5), 1_000_000).unwrap();
+        assert_eq!(migrated.leverage_denominator, 2);
+        assert_eq!(migrated.conf_mode, ConfMode::Mid);
+        assert!(!migrated.converts_collateral());
+        migrated.check_quote_collateral().unwrap();
+
+        // version 4 keeps its expiry and is levered in whole numbers
+        let mut expiring = long.clone();
+        expiring.expiry = Some(1_700_000_000);
+        expiring.leverage_denominator = 7;
+        let mut data = legacy_account(&expiring, 4);
+        data.truncate(data.len() - 9);
+        let migrated = decode_legacy_position(&data, 1_000_000).unwrap();
+        assert_eq!(migrated.expiry, Some(1_700_000_000));
+        assert_eq!(migrated.leverage_denominator, 1);
+        assert_eq!(migrated.conf_mode, ConfMode::Mid);
+
+        // version 3 keeps its confidence mode
+        let migrated = decode_legacy_position(&legacy_account(&long, 3), 1_000_000).unwrap();
+        assert_eq!(migrated.conf_mode, ConfMode::Mid);
+        assert_eq!(migrated.expiry, None);
+        assert_eq!(migrated.funding_index, 500);
+
+        // version 2 keeps its funding index
+        let migrated = decode_legacy_position(&legacy_account(&long, 2), 1_000_000).unwrap();
+        assert_eq!(migrated.try_to_vec().unwrap(), expected(500));
+
+        // version 1 had no funding and starts paying it from the pool's index
+        let mut migrated = decode_legacy_position(&legacy_account(&long, 1), 1_000_000).unwrap();
+        assert_eq!(migrated.try_to_vec().unwrap(), expected(1_000_000));
+        let mut data = Vec::new();
+        migrated.try_serialize(&mut data).unwrap();
+        assert!(data.len() <= 8 + Position::LEN);
+
+        // and can use it like any current position
+        migrated.check_version().unwrap();
+        assert_eq!(migrated.settle_funding(2_000_000).unwrap(), 90_000_000);
+
+        // a current account has nothing to migrate
+        let mut data = Vec::new();
+        long.try_serialize(&mut data).unwrap();
+        assert_eq!(failure(decode_legacy_position(&data, 0)), code(ProtocolError::UnsupportedVersion));
+
+        // nor has another account type
+        let mut data = legacy_account(&long, 1);
+        data[0] ^= 1;
+        assert_eq!(failure(decode_legacy_position(&data, 0)), code(ProtocolError::InvalidAccountData));
+    }
+
+    #[test]
+    fn test_quote_matches_create() {
+        let config = pool_config();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+
+        let quote = quote_isolated(&config, &args, &btc_usdc(), &clock).unwrap();
+        assert_eq!(quote.fill_price, 30005_000_000);
+        assert_eq!(quote.amount, 33327);
+        // 10 bps of the notional, rounded up
+        assert_eq!(quote.trading_fee, 999_977);
+        assert_eq!(config.long_oi, 0);
+
+        let mut created_config = config.clone();
+        let (position, trading_fee) = open_isolated(&mut created_config, Pubkey::new_unique(), Pubkey::new_unique(), 7, &args, &btc_usdc(), &clock, 0).unwrap();
+        assert_eq!(quote, OpenQuote {
+            fill_price: position.last_price as u64,
+            amount: position.amount,
+            liquidation: position.liquidation,
+            initial_margin: position.margin,
+            trading_fee,
+        });
+        assert_eq!(created_config.long_oi, position.amount);
+
+        // an order beyond its slippage fails the quote as it fails the create
+        let mut late = args;
+        late.price = 29000_000_000;
+        assert_eq!(
+            failure(quote_isolated(&config, &late, &btc_usdc(), &clock)),
+            code(ProtocolError::SlippageReached),
+        );
+        assert_eq!(
+            failure(open_isolated(&mut config.clone(), Pubkey::new_unique(), Pubkey::new_unique(), 7, &late, &btc_usdc(), &clock, 0)),
+            code(ProtocolError::SlippageReached),
+        );
+
+        // as does one past the pool's open interest cap
+        let mut full = config.clone();
+        full.max_long_oi = 33326;
+        assert_eq!(
+            failure(quote_isolated(&full, &args, &btc_usdc(), &clock)),
+            failure(open_isolated(&mut full.clone(), Pubkey::new_unique(), Pubkey::new_unique(), 7, &args, &btc_usdc(), &clock, 0)),
+        );
+    }
+
+    #[test]
+    fn test_admin_transfer() {
+        let mut config = pool_config();
+        let admin = Pubkey::new_unique();
+        let successor = Pubkey::new_unique();
+        config.admin = admin;
+
+        // nothing to accept before a proposal, and a proposal changes nothing yet
+        assert_eq!(failure(config.accept_admin(&successor)), code(ProtocolError::InvalidAuthority));
+        config.propose_admin(successor);
+        assert_eq!(config.admin, admin);
+
+        // only the proposed key can accept
+        assert_eq!(failure(config.accept_admin(&Pubkey::new_unique())), code(ProtocolError::InvalidAuthority));
+        config.accept_admin(&successor).unwrap();
+        assert_eq!(config.admin, successor);
+        assert_eq!(config.pending_admin, Pubkey::default());
+
+        // a withdrawn proposal can't be accepted
+        config.propose_admin(admin);
+        config.propose_admin(Pubkey::default());
+        assert_eq!(failure(config.accept_admin(&admin)), code(ProtocolError::InvalidAuthority));
+        assert_eq!(config.admin, successor);
+    }
+
+    #[test]
+    fn test_payout_capped() {
+        let mut config = pool_config();
+        config.max_payout_multiple = 3;
+        let long = open_long_of_three();
+
+        // a price doubled in a blink would pay the 9000 margin back elevenfold
+        let mut settlement = long.settle(&flat_price(60000_000_000), long.created_at).unwrap();
+        assert_eq!(settlement.returned_margin, 99000_000_000);
+        assert_eq!(config.cap_payout(&mut settlement, long.margin), Some(27000_000_000));
+        assert_eq!(settlement.returned_margin, 27000_000_000);
+
+        // an ordinary profit is left alone
+        let mut settlement = long.settle(&flat_price(33000_000_000), long.created_at).unwrap();
+        assert_eq!(config.cap_payout(&mut settlement, long.margin), None);
+        assert_eq!(settlement.returned_margin, 18000_000_000);
+
+        // and nothing is capped without a multiple
+        config.max_payout_multiple = 0;
+        let mut settlement = long.settle(&flat_price(60000_000_000), long.created_at).unwrap();
+        assert_eq!(config.cap_payout(&mut settlement, long.margin), None);
+        assert_eq!(settlement.returned_margin, 99000_000_000);
+    }
+
+    #[test]
+    fn test_pool_config_paused() {
+        let mut config = pool_config();
+        config.paused = true;
+
+        // opening, netting off and adding margin are blocked...
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        assert_eq!(failure(config.check_args(&mut args)), code(ProtocolError::Paused));
+        assert_eq!(failure(config.check_not_paused()), code(ProtocolError::Paused));
+
+        // ...while an open position can still be closed
+        let long = open_long_of_three();
+        let settlement = long.settle(&flat_price(31000_000_000), long.created_at).unwrap();
+        assert_eq!(settlement.returned_margin, 9000_000_000 + 1000_000_000 * 3);
+
+        config.paused = false;
+        config.check_not_paused().unwrap();
+        config.check_args(&mut args).unwrap();
+    }
+
+    #[test]
+    fn test_pool_config_args_validate() {
+        let args = PoolConfigArgs {
+            max_leverage: 20,
+            max_leverage_long: 0,
+            max_leverage_short: 0,
+            maintenance_margin_bps: 800,
+            max_slippage_bps: 200,
+            overnight_fee_bps: 3,
+            maker_fee_bps: 5,
+            taker_fee_bps: 10,
+            max_long_oi: 10_000_000,
+            max_short_oi: 10_000_000,
+            max_notional: 1000000_000_000,
+            funding_rate_bps: 10,
+            min_margin: 10_000_000,
+            min_notional: 100_000_000,
+            min_hold_seconds: 60,
+            liquidation_penalty_bps: 2000,
+            collateral_mode: CollateralMode::Token,
+            conf_mode: ConfMode::Conservative,
+            paused: false,
+            liquidate_on_ema: false,
+            permissionless_liquidation: true,
+            max_payout_multiple: 0,
+            max_price_age: 60,
+            max_conf_bps: 100,
+            oracle_authority: Pubkey::new_unique(),
+        };
+        args.validate().unwrap();
+
+        let mut config = pool_config();
+        config.set(&PoolConfigArgs { max_leverage: 50, ..args });
+        assert_eq!(config.max_leverage, 50);
+        assert_eq!(config.oracle_authority, args.oracle_authority);
+        assert_eq!(
+            failure(PoolConfigArgs { oracle_authority: Pubkey::default(), ..args }.validate()),
+            code(ProtocolError::InvalidAuthority),
+        );
+
+        assert_eq!(
+            failure(PoolConfigArgs { max_leverage: MAX_LEVERAGE + 1, ..args }.validate()),
+            code(ProtocolError::InvalidLeverage),
+        );
+        assert_eq!(
+            failure(PoolConfigArgs { max_leverage_short: MAX_LEVERAGE + 1, ..args }.validate()),
+            code(ProtocolError::InvalidLeverage),
+        );
+        assert_eq!(
+            failure(PoolConfigArgs { max_slippage_bps: 10000, ..args }.validate()),
+            code(ProtocolError::InvalidArgs),
+        );
+        assert_eq!(
+            failure(PoolConfigArgs { maintenance_margin_bps: 10001, ..args }.validate()),
+            code(ProtocolError::InvalidArgs),
+        );
+        assert_eq!(
+            failure(PoolConfigArgs { liquidation_penalty_bps: 10001, ..args }.validate()),
+            code(ProtocolError::InvalidArgs),
+        );
+        PoolConfigArgs { max_conf_bps: RATE_DENOMINATOR, ..args }.validate().unwrap();
+        for args in [
+            PoolConfigArgs { max_conf_bps: 0, ..args },
+            PoolConfigArgs { max_conf_bps: RATE_DENOMINATOR + 1, ..args },
+            PoolConfigArgs { max_price_age: 0, ..args },
+            PoolConfigArgs { max_price_age: u64::MAX, ..args },
+            PoolConfigArgs { maker_fee_bps: 10001, ..args },
+            PoolConfigArgs { taker_fee_bps: 10001, ..args },
+        ] {
+            assert_eq!(failure(args.validate()), code(ProtocolError::InvalidArgs));
+        }
+    }
+
+    #[test]
+    fn test_position_keeps_pool_conf_limit() {
+        let config = pool_config();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        // the oracle limits are the pool's, which the client has no say in
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+        let (position, _) = open_isolated(&mut config.clone(), Pubkey::new_unique(), Pubkey::new_unique(), 0, &args, &btc_usdc(), &clock, 0).unwrap();
+        assert_eq!((position.max_price_age, position.max_conf_bps), (config.max_price_age, config.max_conf_bps));
+        // and so are its fees on either side of a fill
+        assert_eq!((position.maker_fee_bps, position.taker_fee_bps), (5, 10));
+    }
+
+    #[test]
+    fn test_min_size() {
+        let config = pool_config();
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+
+        // at the minimum notional, and just above it
+        args.leverage_margin = 100_000_000;
+        config.check_min_size(&args).unwrap();
+        args.leverage_margin = 100_000_001;
+        config.check_min_size(&args).unwrap();
+
+        args.leverage_margin = 99_999_999;
+        assert_eq!(failure(config.check_min_size(&args)), code(ProtocolError::BelowMinimumSize));
+
+        // enough notional, but at 20x the margin falls short even rounded up
+        args.leverage_margin = 199_999_980;
+        args.leverage = 20;
+        assert_eq!(failure(config.check_min_size(&args)), code(ProtocolError::BelowMinimumSize));
+        args.leverage_margin = 200_000_000;
+        config.check_min_size(&args).unwrap();
+    }
+
+    #[test]
+    fn test_min_hold_close() {
+        let config = pool_config();
+        let long = open_long_of_three();
+        let opened = long.last_fee_settlement;
+
+        assert_eq!(
+            failure(config.check_min_hold(opened, opened + 59, false)),
+            code(ProtocolError::MinimumHoldNotMet),
+        );
+        config.check_min_hold(opened, opened + 60, false).unwrap();
+        config.check_min_hold(opened, opened + 3600, false).unwrap();
+    }
+
+    #[test]
+    fn test_min_hold_liquidation() {
+        let config = pool_config();
+        let long = open_long_of_three();
+        let opened = long.last_fee_settlement;
+
+        config.check_min_hold(opened, opened, true).unwrap();
+        config.check_min_hold(opened, opened + 59, true).unwrap();
+        config.check_min_hold(opened, opened + 60, true).unwrap();
+    }
+
+    #[test]
+    fn test_permissionless_liquidation() {
+        let mut config = pool_config();
+        let keeper = Pubkey::new_unique();
+        config.check_liquidator(&keeper).unwrap();
+
+        config.add_liquidator(Pubkey::new_unique()).unwrap();
+        config.check_liquidator(&keeper).unwrap();
+    }
+
+    #[test]
+    fn test_liquidator_whitelist() {
+        let mut config = pool_config();
+        config.permissionless_liquidation = false;
+        let whitelisted = Pubkey::new_unique();
+        let outsider = Pubkey::new_unique();
+
+        assert_eq!(failure(config.check_liquidator(&whitelisted)), code(ProtocolError::InvalidAuthority));
+        config.add_liquidator(whitelisted).unwrap();
+        config.check_liquidator(&whitelisted).unwrap();
+        assert_eq!(failure(config.check_liquidator(&outsider)), code(ProtocolError::InvalidAuthority));
+
+        config.remove_liquidator(&whitelisted).unwrap();
+        assert_eq!(failure(config.check_liquidator(&whitelisted)), code(ProtocolError::InvalidAuthority));
+        assert_eq!(failure(config.remove_liquidator(&whitelisted)), code(ProtocolError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_liquidator_whitelist_bounded() {
+        let mut config = pool_config();
+        let first = Pubkey::new_unique();
+        config.add_liquidator(first).unwrap();
+        assert_eq!(failure(config.add_liquidator(first)), code(ProtocolError::InvalidArgs));
+
+        for _ in 1..MAX_LIQUIDATORS {
+            config.add_liquidator(Pubkey::new_unique()).unwrap();
+        }
+        assert_eq!(failure(config.add_liquidator(Pubkey::new_unique())), code(ProtocolError::InvalidArgs));
+
+        // with the collateral feeds left empty
+        let mut data = Vec::new();
+        config.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 8 + PoolConfig::LEN - (32 + 32) * MAX_COLLATERAL_FEEDS);
+    }
+
+    #[test]
+    fn test_collateral_feeds() {
+        let mut config = pool_config();
+        let (sol, feed) = (Pubkey::new_unique(), Pubkey::new_unique());
+        // the quote needs no feed, and a mint that isn't listed is refused
+        assert_eq!(config.collateral_feed(&config.quote_mint).unwrap(), None);
+        assert_eq!(failure(config.collateral_feed(&sol)), code(ProtocolError::CollateralMintNotAccepted));
+
+        config.add_collateral_feed(sol, feed).unwrap();
+        assert_eq!(config.collateral_feed(&sol).unwrap(), Some(feed));
+        // one feed per mint, none for the quote, and never the default key meaning none
+        assert_eq!(failure(config.add_collateral_feed(sol, Pubkey::new_unique())), code(ProtocolError::InvalidArgs));
+        assert_eq!(failure(config.add_collateral_feed(config.quote_mint, Pubkey::new_unique())), code(ProtocolError::InvalidArgs));
+        assert_eq!(failure(config.add_collateral_feed(Pubkey::new_unique(), Pubkey::default())), code(ProtocolError::InvalidArgs));
+
+        for _ in 1..MAX_COLLATERAL_FEEDS {
+            config.add_collateral_feed(Pubkey::new_unique(), Pubkey::new_unique()).unwrap();
+        }
+        assert_eq!(failure(config.add_collateral_feed(Pubkey::new_unique(), Pubkey::new_unique())), code(ProtocolError::InvalidArgs));
+        for _ in 0..MAX_LIQUIDATORS {
+            config.add_liquidator(Pubkey::new_unique()).unwrap();
+        }
+        let mut data = Vec::new();
+        config.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 8 + PoolConfig::LEN);
+
+        config.remove_collateral_feed(&sol).unwrap();
+        assert_eq!(failure(config.collateral_feed(&sol)), code(ProtocolError::CollateralMintNotAccepted));
+        assert_eq!(failure(config.remove_collateral_feed(&sol)), code(ProtocolError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_collateral_conversion() {
+        // 1000 USDC of margin is 10 SOL at 100 USDC, with SOL's 9 decimals
+        let sol = flat_price(100_000_000);
+        assert_eq!(collateral_for_quote(1000_000_000, &sol, 9).unwrap(), 10_000_000_000);
+        assert_eq!(payout_in_collateral(1000_000_000, Some(&sol), 9).unwrap(), 10_000_000_000);
+
+        // what the protocol takes rounds up, what it pays out rounds down
+        let third = flat_price(3_000_000);
+        assert_eq!(collateral_for_quote(1, &third, 9).unwrap(), 334);
+        assert_eq!(payout_in_collateral(1, Some(&third), 9).unwrap(), 333);
+
+        // the quote itself isn't converted
+        assert_eq!(payout_in_collateral(1234, None, 9).unwrap(), 1234);
+
+        assert_eq!(failure(collateral_for_quote(1, &flat_price(0), 9)), code(ProtocolError::InvalidPrice));
+        assert_eq!(failure(collateral_for_quote(1, &flat_price(-1), 9)), code(ProtocolError::InvalidPrice));
+        assert_eq!(failure(collateral_for_quote(u64::MAX, &sol, 40)), code(ProtocolError::MathOverflow));
+    }
+
+    #[test]
+    fn test_close_in_collateral() {
+        let mut long = open_long_of_three();
+        long.collateral_price = Pubkey::new_unique();
+        long.collateral_decimals = 9;
+        assert!(long.converts_collateral());
+        assert_eq!(failure(long.check_quote_collateral()), code(ProtocolError::CollateralConversionUnsupported));
+
+        // the 9000 USDC margin was posted as 90 SOL at 100 USDC
+        assert_eq!(collateral_for_quote(long.margin, &flat_price(100_000_000), 9).unwrap(), 90_000_000_000);
+
+        // BTC up 1000 and SOL up to 125: the quote payout is paid at the new SOL price
+        let time = long.created_at + 86400;
+        let settlement = long.settle(&flat_price(31000_000_000), time).unwrap();
+        assert!(settlement.returned_margin > long.margin);
+        let paid = settlement.in_collateral(Some(&flat_price(125_000_000)), long.collateral_decimals).unwrap();
+        assert_eq!(paid, Settlement {
+            returned_margin: settlement.returned_margin * 8,
+            overnight_fee: settlement.overnight_fee * 8,
+            trading_fee: settlement.trading_fee * 8,
+            bad_debt: settlement.bad_debt * 8,
+            insurance_fee: settlement.insurance_fee * 8,
+        });
+        assert_eq!(settlement.in_collateral(None, 9).unwrap(), settlement);
+    }
+
+    #[test]
+    fn test_open_interest_cap() {
+        let mut config = pool_config();
+        for _ in 0..3 {
+            config.add_open_interest(Direction::OpenLong, 3_000_000).unwrap();
+        }
+        assert_eq!(
+            failure(config.add_open_interest(Direction::OpenLong, 3_000_000)),
+            code(ProtocolError::OpenInterestCapReached),
+        );
+        assert_eq!(config.long_oi, 9_000_000);
+
+        // the other side has its own cap
+        config.add_open_interest(Direction::OpenShort, 3_000_000).unwrap();
+        assert_eq!(config.short_oi, 3_000_000);
+
+        // closing frees capacity
+        config.remove_open_interest(Direction::OpenLong, 3_000_000);
+        config.add_open_interest(Direction::OpenLong, 3_000_000).unwrap();
+        assert_eq!(config.long_oi, 9_000_000);
+    }
+
+    #[test]
+    fn test_move_open_interest() {
+        let mut config = pool_config();
+        config.add_open_interest(Direction::OpenLong, 9_000_000).unwrap();
+
+        // growing past the cap fails and leaves the interest alone
+        assert_eq!(
+            failure(config.move_open_interest((Direction::OpenLong, 3_000_000), (Direction::OpenLong, 5_000_000))),
+            code(ProtocolError::OpenInterestCapReached),
+        );
+        assert_eq!(config.long_oi, 9_000_000);
+
+        // a flip frees the old side and takes the new one
+        config.move_open_interest((Direction::OpenLong, 3_000_000), (Direction::OpenShort, 1_000_000)).unwrap();
+        assert_eq!(config.long_oi, 6_000_000);
+        assert_eq!(config.short_oi, 1_000_000);
+
+        // shrinking always works, even over a lowered cap
+        config.max_short_oi = 0;
+        config.move_open_interest((Direction::OpenShort, 1_000_000), (Direction::OpenShort, 500_000)).unwrap();
+        assert_eq!(config.short_oi, 500_000);
+    }
+
+    #[test]
+    fn test_max_notional() {
+        let config = pool_config();
+        config.check_notional(1000000_000_000).unwrap();
+        assert_eq!(
+            failure(config.check_notional(1000000_000_001)),
+            code(ProtocolError::MaxNotionalExceeded),
+        );
+        assert!(config.check_notional(open_long_of_three().entry_notional().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_funding_long_heavy() {
+        let mut config = pool_config();
+        config.add_open_interest(Direction::OpenLong, 9_000_000).unwrap();
+        config.add_open_interest(Direction::OpenShort, 3_000_000).unwrap();
+        let mut long = open_long_of_three();
+        let mut short = open_short_of_three();
+
+        // half the book is skew: 5 bps of the rate's 10 per interval, for
+        // two intervals and part of a third
+        assert_eq!(config.funding_rate(), Rate { numerator: 50000, denominator: 100000000 });
+        config.update_funding(2 * 3600 + 1800).unwrap();
+        assert_eq!(config.cumulative_funding, 1_000_000);
+        assert_eq!(config.last_funding_update, 2 * 3600);
+
+        // longs pay 0.1% of their notional, shorts receive as much
+        assert_eq!(long.settle_funding(config.cumulative_funding).unwrap(), 90_000_000);
+        assert_eq!(long.margin, 9000_000_000 - 90_000_000);
+        assert_eq!(long.liquidation, get_liquidation(long.last_price, long.bond().unwrap(), long.direction).unwrap());
+        assert_eq!(short.settle_funding(config.cumulative_funding).unwrap(), -90_000_000);
+        assert_eq!(short.margin, 9000_000_000 + 90_000_000);
+
+        // nothing more is owed until the index moves again
+        assert_eq!(long.funding_owed(config.cumulative_funding).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_funding_short_heavy() {
+        let mut config = pool_config();
+        config.add_open_interest(Direction::OpenLong, 3_000_000).unwrap();
+        config.add_open_interest(Direction::OpenShort, 9_000_000).unwrap();
+        config.update_funding(2 * 3600).unwrap();
+        assert_eq!(config.cumulative_funding, -1_000_000);
+
+        let mut long = open_long_of_three();
+        let mut short = open_short_of_three();
+        assert_eq!(long.settle_funding(config.cumulative_funding).unwrap(), -90_000_000);
+        assert_eq!(short.settle_funding(config.cumulative_funding).unwrap(), 90_000_000);
+        assert_eq!(short.margin, 9000_000_000 - 90_000_000);
+    }
+
+    #[test]
+    fn test_funding_balanced_book() {
+        let mut config = pool_config();
+        config.update_funding(10 * 3600).unwrap();
+        assert_eq!(config.cumulative_funding, 0);
+
+        config.add_open_interest(Direction::OpenLong, 3_000_000).unwrap();
+        config.add_open_interest(Direction::OpenShort, 3_000_000).unwrap();
+        config.update_funding(20 * 3600).unwrap();
+        assert_eq!(config.cumulative_funding, 0);
+        assert_eq!(config.last_funding_update, 20 * 3600);
+    }
+
+    fn btc_usdc() -> pyth_sdk_solana::Price {
+        pyth_sdk_solana::Price {
+            price: 30000_000_000,
+            conf: 5_000_000,
+            expo: -6,
+        }
+    }
+
+    #[test]
+    fn test_cross_open_long_and_short() {
+        let mut cross_account = CrossAccount {
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            mint: Pubkey::default(),
+            collateral: 1000_000_000,
+            used_margin: 0,
+            positions: Vec::new(),
+        };
+
+        let args = new_args(PositionType::Cross, Direction::OpenLong);
+        cross_account.allocate(args.margin().unwrap()).unwrap();
+        assert_eq!(cross_account.free_collateral(), 900_000_000);
+
+        let mut long = new_position(PositionType::Cross, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &btc_usdc(), cross_account.free_collateral()).unwrap();
+        assert_eq!(long.last_price, 30005_000_000);
+        // the maintenance margin is on the 0.033327 BTC bought at the ask
+        assert_eq!(long.maintainance_margin().unwrap(), 4_999_883);
+        assert_eq!(long.bond().unwrap(), 95_000_117);
+        assert_eq!(long.liquidation, 30005_000_000 - 95_000_117 - 900_000_000);
+
+        let args = new_args(PositionType::Cross, Direction::OpenShort);
+        cross_account.allocate(args.margin().unwrap()).unwrap();
+        assert_eq!(cross_account.free_collateral(), 800_000_000);
+
+        let mut short = new_position(PositionType::Cross, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &btc_usdc(), cross_account.free_collateral()).unwrap();
+        assert_eq!(short.last_price, 29995_000_000);
+        assert_eq!(short.liquidation, 29995_000_000 + 95_000_134 + 800_000_000);
+    }
+
+    #[test]
+    fn test_cross_allocate_insufficient_collateral() {
+        let mut cross_account = CrossAccount {
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            mint: Pubkey::default(),
+            collateral: 150_000_000,
+            used_margin: 0,
+            positions: Vec::new(),
+        };
+
+        let args = new_args(PositionType::Cross, Direction::OpenLong);
+        assert!(cross_account.allocate(args.margin().unwrap()).is_ok());
+        assert_eq!(
+            failure(cross_account.allocate(args.margin().unwrap())),
+            code(ProtocolError::InsufficientBalance),
+        );
+        assert_eq!(cross_account.used_margin, 100_000_000);
+    }
+
+    #[test]
+    fn test_cross_health_offsets_members() {
+        let mut long = open_long_of_three();
+        let mut short = open_short_of_three();
+        long.ptype = PositionType::Cross;
+        short.ptype = PositionType::Cross;
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut cross_account = CrossAccount {
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            mint: Pubkey::default(),
+            collateral: long.margin + short.margin,
+            used_margin: long.margin + short.margin,
+            positions: Vec::new(),
+        };
+        for key in keys {
+            cross_account.add_position(key).unwrap();
+        }
+
+        // at 20000 the long has lost more than its margin and would be
+        // liquidated on its own, but the short's gain carries it
+        let price = flat_price(20000_000_000);
+        let time = long.created_at;
+        assert!(long.is_liquidated(20000_000_000));
+        let members = [long.clone(), short.clone()];
+        let maintenance = long.maintainance_margin().unwrap() + short.maintainance_margin().unwrap();
+        assert_eq!(
+            cross_account.health(&members, &price, time).unwrap(),
+            (18000_000_000 - maintenance) as i128
+        );
+        assert!(!cross_account.is_liquidated(&members, &price, time).unwrap());
+
+        // without the short behind it the account is underwater
+        let mut alone = CrossAccount {
+            collateral: long.margin,
+            used_margin: long.margin,
+            positions: vec![keys[0]],
+            ..cross_account.clone()
+        };
+        assert!(alone.is_liquidated(&[long.clone()], &price, time).unwrap());
+        assert_eq!(alone.release(&keys[0], long.margin, 0).unwrap(), 0);
+        assert!(alone.positions.is_empty());
+        assert_eq!((alone.collateral, alone.used_margin), (0, 0));
+
+        // every member must be passed, each once
+        cross_account.check_members(&[keys[1], keys[0]]).unwrap();
+        for members in [&[keys[0]][..], &[keys[0], keys[0]], &[keys[0], keys[1], Pubkey::new_unique()]] {
+            assert_eq!(failure(cross_account.check_members(members)), code(ProtocolError::InvalidAccountData));
+        }
+        assert_eq!(failure(cross_account.add_position(keys[0])), code(ProtocolError::InvalidArgs));
+    }
+
+    fn flat_price(price: i64) -> pyth_sdk_solana::Price {
+        pyth_sdk_solana::Price {
+            price,
+            conf: 0,
+            expo: -6,
+        }
+    }
+
+    #[test]
+    fn test_close_matches_transaction_account() {
+        for (position, price) in [
+            (open_long_of_three(), flat_price(33000_000_000)),
+            (open_long_of_three(), btc_usdc()),
+            (open_short_of_three(), flat_price(28500_000_000)),
+            (open_short_of_three(), btc_usdc()),
+        ] {
+            let account = TransactionAccount::try_from(&position).unwrap();
+            let expected = position.margin as i128 + account.get_profit(&price, 0).unwrap();
+
+            let settlement = position.settle(&price, position.created_at).unwrap();
+            assert_eq!(settlement.returned_margin as i128, expected);
+            assert_eq!(position.get_profit(&price, position.created_at).unwrap() as i128, expected);
+        }
+    }
+
+    #[test]
+    fn test_leverage_consistency() {
+        // `create` derives the margin from the order, which agrees with its size
+        // until the opening fee is taken out of it
+        let config = PoolConfig { taker_fee_bps: 0, ..pool_config() };
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+        let (position, _) = open_isolated(&mut config.clone(), Pubkey::default(), Pubkey::default(), 0, &args, &btc_usdc(), &clock, 0).unwrap();
+        position.check_leverage_consistency().unwrap();
+        open_long_of_three().check_leverage_consistency().unwrap();
+
+        // a margin posted for 5x on a size levered 10x, or the other way round
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, 18000_000_000);
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 90000_000_000;
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(failure(long.check_leverage_consistency()), code(ProtocolError::InvalidLeverage));
+        let mut short = open_short_of_three();
+        short.leverage = 20;
+        assert_eq!(failure(short.check_leverage_consistency()), code(ProtocolError::InvalidLeverage));
+
+        // a unit of rounding and the size tolerance pass
+        let mut long = open_long_of_three();
+        long.margin += 1;
+        long.check_leverage_consistency().unwrap();
+        long.margin += 9_000_000;
+        long.check_leverage_consistency().unwrap();
+        long.margin += 1;
+        assert!(long.check_leverage_consistency().is_err());
+    }
+
+    #[test]
+    fn test_args_leverage_consistency() {
+        let config = pool_config();
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.check_leverage_consistency().unwrap();
+        config.check_args(&mut args).unwrap();
+        let mut fractional = PositionArgs { leverage: 3, leverage_denominator: 2, ..args };
+        config.check_args(&mut fractional).unwrap();
+
+        // just under two units at 30000 buys one, half the exposure the
+        // margin is posted for at 10x
+        let mut crafted = PositionArgs { leverage_margin: 59_999, ..args };
+        assert_eq!(failure(crafted.check_leverage_consistency()), code(ProtocolError::InvalidLeverage));
+        assert_eq!(failure(config.check_args(&mut crafted)), code(ProtocolError::InvalidLeverage));
+        // less than a unit buys nothing at all
+        let mut dust = PositionArgs { leverage_margin: 29_999, ..args };
+        assert_eq!(failure(config.check_args(&mut dust)), code(ProtocolError::InvalidLeverage));
+        // nor does a reference price of zero, or one off the feed's scale
+        let mut unpriced = PositionArgs { price: 0, ..args };
+        assert_eq!(failure(config.check_args(&mut unpriced)), code(ProtocolError::InvalidPrice));
+        let mut overpriced = PositionArgs { price: u64::MAX, ..args };
+        assert_eq!(failure(config.check_args(&mut overpriced)), code(ProtocolError::InvalidPrice));
+    }
+
+    #[test]
+    fn test_open_fractional_leverage() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage = 3;
+        args.leverage_denominator = 2;
+        args.leverage_margin = 90000_000_000;
+        assert_eq!(args.margin().unwrap(), 60000_000_000);
+
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.margin, 60000_000_000);
+        assert_eq!((long.leverage, long.leverage_denominator), (3, 2));
+        // 5% of the 60000 the size needs at 1.5x
+        assert_eq!(long.maintainance_margin().unwrap(), 3000_000_000);
+        assert_eq!(long.leverage_adjusted_exposure(30000_000_000).unwrap(), 60000_000_000);
+
+        // a margin short of 1.5x is refused
+        long.margin -= 1;
+        assert_eq!(failure(long.check_initial_margin()), code(ProtocolError::InsufficientBalance));
+
+        // the pool's cap of 20x is 40 halves, and nothing opens below 1x
+        let config = pool_config();
+        config.check_fractional_leverage(40, 2, Direction::OpenLong).unwrap();
+        for (leverage, denominator) in [(41, 2), (1, 2), (3, 0)] {
+            assert_eq!(
+                failure(config.check_fractional_leverage(leverage, denominator, Direction::OpenLong)),
+                code(ProtocolError::InvalidLeverage)
+            );
+        }
+    }
+
+    #[test]
+    fn test_required_initial_margin() {
+        assert_eq!(required_initial_margin(3_000_000, 30000_000_000, 6, 10, 1).unwrap(), 9000_000_000);
+        assert_eq!(required_initial_margin(1, 30000_000_000, 6, 7, 1).unwrap(), 4286);
+        assert_eq!(
+            failure(required_initial_margin(3_000_000, 30000_000_000, 6, 0, 1)),
+            code(ProtocolError::InvalidLeverage),
+        );
+        // 90000 at 1.5x
+        assert_eq!(required_initial_margin(3_000_000, 30000_000_000, 6, 3, 2).unwrap(), 60000_000_000);
+        assert_eq!(
+            failure(required_initial_margin(3_000_000, 30000_000_000, 6, 3, 0)),
+            code(ProtocolError::InvalidLeverage),
+        );
+    }
+
+    #[test]
+    fn test_flip_adequately_margined() {
+        let mut long = open_long_of_three();
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 180000_000_000;
+
+        // 6 BTC short against 3 long leaves 3 short
+        let (posted, _) = long.reduce(&args, &flat_price(30000_000_000)).unwrap();
+        assert_eq!(posted, 9000_000_000);
+        assert_eq!(long.direction, Direction::OpenShort);
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.margin, 18000_000_000);
+        long.check_initial_margin().unwrap();
+    }
+
+    #[test]
+    fn test_flip_inadequately_margined() {
+        // a long of 2 at 1 whose margin is used up, with whole-unit sizes
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, 0);
+        long.decimals = 0;
+        long.amount = 2;
+        long.last_price = 1;
+        long.leverage = 1;
+
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.price = 1;
+        args.expo = 0;
+        args.decimals = 0;
+        args.leverage_margin = 3;
+        args.leverage = 2;
+
+        // the residual 1 gets a rounded-down share of the order's margin: 0
+        // where 1 is required
+        assert_eq!(
+            failure(long.reduce(&args, &flat_price(1))),
+            code(ProtocolError::InsufficientBalance),
+        );
+    }
+
+    #[test]
+    fn test_rounding_direction() {
+        assert_eq!(round_down_to_u64(7, 2).unwrap(), 3);
+        assert_eq!(round_up_to_u64(7, 2).unwrap(), 4);
+        assert_eq!(round_down_to_u64(8, 2).unwrap(), 4);
+        assert_eq!(round_up_to_u64(8, 2).unwrap(), 4);
+        assert_eq!(round_up_to_u64(0, 3).unwrap(), 0);
+
+        for round in [round_down_to_u64, round_up_to_u64] {
+            assert_eq!(failure(round(1, 0)), code(ProtocolError::MathOverflow));
+            assert_eq!(failure(round(u64::MAX as u128 * 2, 1)), code(ProtocolError::MathOverflow));
+        }
+        assert_eq!(round_up_to_u64(u64::MAX as u128 * 2 - 1, 2).unwrap(), u64::MAX);
+
+        let rate = Rate { numerator: 1, denominator: 10000 };
+        assert_eq!(rate.apply(15_000), Some(1));
+        assert_eq!(rate.apply_up(15_000), Some(2));
+        assert_eq!(rate.apply_up(20_000), Some(2));
+
+        // a fractional trading fee rounds in the pool's favour
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 1;
+        assert_eq!(long.trading_fee(15000_000_000, 1, Liquidity::Taker).unwrap(), 2);
+        assert_eq!(long.trading_fee(30000_000_000, 3_000_000, Liquidity::Taker).unwrap(), 9_000_000);
+    }
+
+    #[test]
+    fn test_notional() {
+        assert_eq!(notional(30000_000_000, 3_000_000, 6).unwrap(), 90000_000_000);
+        assert_eq!(notional(30000_000_000, 1, 6).unwrap(), 30000);
+        assert_eq!(notional(0, 3_000_000, 6).unwrap(), 0);
+        assert_eq!(failure(notional(i64::MAX, u64::MAX, 0)), code(ProtocolError::MathOverflow));
+    }
+
+    #[test]
+    fn test_position_notional() {
+        let long = open_long_of_three();
+        let short = open_short_of_three();
+        for (price, notional) in [
+            (30000_000_000, 90000_000_000i128),
+            (33000_000_000, 99000_000_000),
+            (21000_000_000, 63000_000_000),
+            (0, 0),
+        ] {
+            assert_eq!(long.notional(price).unwrap(), notional);
+            assert_eq!(short.notional(price).unwrap(), -notional);
+            assert_eq!(long.leverage_adjusted_exposure(price).unwrap(), notional / 10);
+            assert_eq!(short.leverage_adjusted_exposure(price).unwrap(), -notional / 10);
+        }
+
+        assert_eq!(failure(long.notional(-1)), code(ProtocolError::InvalidPrice));
+        let mut long = long;
+        long.leverage = 0;
+        assert_eq!(
+            failure(long.leverage_adjusted_exposure(30000_000_000)),
+            code(ProtocolError::InvalidLeverage),
+        );
+    }
+
+    #[test]
+    fn test_fill_price_confidence_edges() {
+        let price = |conf| pyth_sdk_solana::Price { price: 1_000, conf, expo: -6 };
+
+        // no interval, no spread
+        assert_eq!(get_fill_price(&price(0), Direction::OpenLong).unwrap(), 1_000);
+        assert_eq!(get_fill_price(&price(0), Direction::OpenShort).unwrap(), 1_000);
+        assert_eq!(get_fill_price(&flat_price(0), Direction::OpenShort).unwrap(), 0);
+
+        // an interval as wide as the price leaves no bid to short at
+        for conf in [1_000, 1_001, u64::MAX] {
+            assert_eq!(
+                failure(get_fill_price(&price(conf), Direction::OpenShort)),
+                code(ProtocolError::ConfidenceExceedsPrice),
+            );
+        }
+        assert_eq!(get_fill_price(&price(999), Direction::OpenShort).unwrap(), 1);
+        assert_eq!(get_fill_price(&price(1_000), Direction::OpenLong).unwrap(), 2_000);
+        assert_eq!(
+            failure(get_fill_price(&price(u64::MAX), Direction::OpenLong)),
+            code(ProtocolError::InvalidPrice),
+        );
+    }
+
+    #[test]
+    fn test_negative_price_does_not_wrap() {
+        assert_eq!(failure(notional(-1, 3_000_000, 6)), code(ProtocolError::InvalidPrice));
+        assert_eq!(
+            failure(get_fill_price(&flat_price(-30000_000_000), Direction::OpenLong)),
+            code(ProtocolError::InvalidPrice),
+        );
+
+        let mut long = open_long_of_three();
+        assert_eq!(
+            failure(long.get_profit(&flat_price(-30000_000_000), long.created_at)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert_eq!(
+            failure(long.unrealized_pnl(&flat_price(-1))),
+            code(ProtocolError::InvalidPrice),
+        );
+
+        long.last_price = -30000_000_000;
+        assert_eq!(failure(long.entry_notional()), code(ProtocolError::InvalidPrice));
+        assert_eq!(failure(long.charge_open_fee(0)), code(ProtocolError::InvalidPrice));
+    }
+
+    #[test]
+    fn test_negative_price_rejected_on_create_and_close() {
+        assert_eq!(price_to_u64(30000_000_000).unwrap(), 30000_000_000);
+        assert_eq!(price_to_u64(0).unwrap(), 0);
+        assert_eq!(failure(price_to_u64(-1)), code(ProtocolError::InvalidPrice));
+
+        // `create` fails before booking any open interest
+        let mut config = pool_config();
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        assert_eq!(
+            failure(open_isolated(&mut config, Pubkey::new_unique(), Pubkey::new_unique(), 0, &args, &flat_price(-30000_000_000), &clock, 0)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert_eq!(config.long_oi, 0);
+
+        // `process_position` closes through `close_at`, which would have read
+        // the wrapped price as far above any liquidation price
+        let mut long = open_long_of_three();
+        assert_eq!(
+            failure(long.close_at(&flat_price(-30000_000_000), long.created_at)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert_eq!(long.status, PositionStatus::Open);
+        assert_eq!(
+            failure(long.health(&flat_price(-30000_000_000), long.created_at)),
+            code(ProtocolError::InvalidPrice),
+        );
+
+        long.last_price = -30000_000_000;
+        assert_eq!(failure(long.set_triggers(None, Some(1))), code(ProtocolError::InvalidPrice));
+    }
+
+    #[test]
+    fn test_args_margin() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        assert_eq!(args.margin().unwrap(), 100_000_000);
+
+        // a size that isn't a multiple of the leverage rounds the margin up
+        args.leverage_margin = 1000_000_001;
+        assert_eq!(args.margin().unwrap(), 100_000_001);
+        args.leverage_margin = 9;
+        assert_eq!(args.margin().unwrap(), 1);
+
+        args.leverage = 0;
+        assert_eq!(failure(args.margin()), code(ProtocolError::InvalidLeverage));
+    }
+
+    #[test]
+    fn test_netoff_add_long() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 60000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(long.amount, 2_000_000);
+
+        args.price = 33000_000_000;
+        args.leverage_margin = 33000_000_000;
+        assert_eq!(long.add(&args, &flat_price(33000_000_000)).unwrap(), (3300_000_000, 0));
+
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.last_price, 31000_000_000);
+        assert_eq!(long.margin, 9300_000_000);
+        assert_eq!(long.bond().unwrap(), 8835_000_000);
+        assert_eq!(long.liquidation, 31000_000_000 - 8835_000_000);
+    }
+
+    #[test]
+    fn test_netoff_add_short() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 60000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+
+        args.price = 27000_000_000;
+        args.leverage_margin = 27000_000_000;
+        short.add(&args, &flat_price(27000_000_000)).unwrap();
+
+        assert_eq!(short.amount, 3_000_000);
+        assert_eq!(short.last_price, 29000_000_000);
+        assert_eq!(short.margin, 8700_000_000);
+        assert_eq!(short.bond().unwrap(), 8265_000_000);
+        assert_eq!(short.liquidation, 29000_000_000 + 8265_000_000);
+    }
+
+    #[test]
+    fn test_netoff_add_relevers() {
+        // 60000 of notional at 10x, then 33000 more at 5x
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 60000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+
+        args.price = 33000_000_000;
+        args.leverage_margin = 33000_000_000;
+        args.leverage = 5;
+        assert_eq!(long.add(&args, &flat_price(33000_000_000)).unwrap(), (6600_000_000, 0));
+
+        // 93000 over 12600 of margin is 155/21, about 7.4x
+        assert_eq!(long.margin, 12600_000_000);
+        assert_eq!((long.leverage, long.leverage_denominator), (155, 21));
+        long.check_leverage_consistency().unwrap();
+        // the maintenance margin is 5% of the combined margin, not of the
+        // 9300 the old 10x would require
+        assert_eq!(long.maintainance_margin().unwrap(), 630_000_000);
+        assert_eq!(long.liquidation, 31000_000_000 - (12600_000_000 - 630_000_000));
+    }
+
+    #[test]
+    fn test_netoff_add_overflow() {
+        let args = new_args(PositionType::Isolated, Direction::OpenLong);
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        long.amount = u64::MAX;
+        assert_eq!(
+            failure(long.add(&args, &flat_price(30000_000_000))),
+            code(ProtocolError::MathOverflow),
+        );
+    }
+
+    #[test]
+    fn test_netoff_add_rejects_opposite_direction() {
+        let args = new_args(PositionType::Isolated, Direction::OpenLong);
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        assert!(short.add(&args, &flat_price(30000_000_000)).is_err());
+    }
+
+    fn open_long_of_three() -> Position {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 90000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.margin, 9000_000_000);
+        long
+    }
+
+    fn close_args(leverage_margin: u64) -> PositionArgs {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.price = 33000_000_000;
+        args.leverage_margin = leverage_margin;
+        args
+    }
+
+    #[test]
+    fn test_netoff_reduce() {
+        let mut long = open_long_of_three();
+        assert_eq!(long.reduce(&close_args(33000_000_000), &flat_price(33000_000_000)).unwrap(), (0, 0));
+
+        assert_eq!(long.direction, Direction::OpenLong);
+        assert_eq!(long.amount, 2_000_000);
+        assert_eq!(long.last_price, 30000_000_000);
+        assert_eq!(long.margin, 9000_000_000 + 3000_000_000);
+        // the maintenance margin shrinks with the size, to 300 on 2 BTC
+        assert_eq!(long.liquidation, 30000_000_000 - 11700_000_000);
+    }
+
+    #[test]
+    fn test_netoff_exact_close() {
+        let mut long = open_long_of_three();
+        assert_eq!(long.reduce(&close_args(99000_000_000), &flat_price(33000_000_000)).unwrap(), (0, 0));
+
+        assert_eq!(long.direction, Direction::OpenLong);
+        assert_eq!(long.amount, 0);
+        assert_eq!(long.margin, 9000_000_000 + 9000_000_000);
+        assert_eq!(long.status, PositionStatus::Processed);
+        assert_eq!(failure(long.check_open()), code(ProtocolError::AlreadyProcessed));
+
+        // the whole margin is paid out, less the fees settled before the order
+        long.accrued_fee = 50_000_000;
+        let settlement = long.netted_off_settlement();
+        assert_eq!(settlement.returned_margin, 18000_000_000 - 50_000_000);
+        assert_eq!(settlement.overnight_fee, 50_000_000);
+        assert_eq!(settlement.bad_debt, 0);
+
+        // a smaller order leaves the position open
+        let mut long = open_long_of_three();
+        long.reduce(&close_args(98999_000_000), &flat_price(33000_000_000)).unwrap();
+        assert_eq!(long.status, PositionStatus::Open);
+    }
+
+    #[test]
+    fn test_netoff_flip() {
+        let mut long = open_long_of_three();
+        let args = close_args(132000_000_000);
+        assert_eq!(long.reduce(&args, &flat_price(33000_000_000)).unwrap(), (args.margin().unwrap() / 4, 0));
+
+        assert_eq!(long.direction, Direction::OpenShort);
+        assert_eq!(long.amount, 1_000_000);
+        assert_eq!(long.last_price, 33000_000_000);
+        assert_eq!(long.margin, 18000_000_000 + args.margin().unwrap() / 4);
+        assert_eq!(long.liquidation, 33000_000_000 + long.bond().unwrap());
+    }
+
+    #[test]
+    fn test_netoff_flip_checks_slippage() {
+        let mut long = open_long_of_three();
+        let mut args = close_args(132000_000_000);
+        args.price = 34000_000_000;
+        assert_eq!(
+            failure(long.reduce(&args, &flat_price(33000_000_000))),
+            code(ProtocolError::SlippageReached),
+        );
+    }
+
+    #[test]
+    fn test_netoff_taker_fee() {
+        // 10 bps of the 33000 added, out of the 3300 posted
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 60000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        long.taker_fee_bps = 10;
+        args.price = 33000_000_000;
+        args.leverage_margin = 33000_000_000;
+        assert_eq!(long.add(&args, &flat_price(33000_000_000)).unwrap(), (3300_000_000, 33_000_000));
+        assert_eq!(long.margin, 9300_000_000 - 33_000_000);
+        long.check_leverage_consistency().unwrap();
+
+        // 10 bps of the 33000 sold, out of the realized margin
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 10;
+        assert_eq!(long.reduce(&close_args(33000_000_000), &flat_price(33000_000_000)).unwrap(), (0, 33_000_000));
+        assert_eq!(long.margin, 12000_000_000 - 33_000_000);
+
+        // an exact close pays out what the fee leaves
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 10;
+        assert_eq!(long.reduce(&close_args(99000_000_000), &flat_price(33000_000_000)).unwrap(), (0, 99_000_000));
+        assert_eq!(long.netted_off_settlement().returned_margin, 18000_000_000 - 99_000_000);
+
+        // a flip pays on the whole 132000 filled, the closed 3 and the opened 1
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 10;
+        let args = close_args(132000_000_000);
+        assert_eq!(long.reduce(&args, &flat_price(33000_000_000)).unwrap(), (3300_000_000, 132_000_000));
+        assert_eq!(long.direction, Direction::OpenShort);
+        assert_eq!(long.margin, 18000_000_000 + 3300_000_000 - 132_000_000);
+        assert_eq!(long.liquidation, 33000_000_000 + long.bond().unwrap());
+    }
+
+    #[test]
+    fn test_netoff_reduce_checks_slippage() {
+        // selling a long at 31000 while expecting no worse than 1% below 32000
+        let mut long = open_long_of_three();
+        let mut args = close_args(31000_000_000);
+        args.price = 32000_000_000;
+        assert_eq!(
+            failure(long.reduce(&args, &flat_price(31000_000_000))),
+            code(ProtocolError::SlippageReached),
+        );
+        assert_eq!(long.amount, 3_000_000);
+
+        // buying back a short at 31000 while expecting no worse than 1% above 30000
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 90000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 31000_000_000;
+        assert_eq!(
+            failure(short.reduce(&args, &flat_price(31000_000_000))),
+            code(ProtocolError::SlippageReached),
+        );
+        assert_eq!(short.amount, 3_000_000);
+    }
+
+    #[test]
+    fn test_trading_fee_at_open() {
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 10;
+        let liquidation = long.liquidation;
+
+        // 10 bps of 90000 notional, taken before the liquidation price is derived
+        assert_eq!(long.charge_open_fee(0).unwrap(), 90_000_000);
+        assert_eq!(long.margin, 9000_000_000 - 90_000_000);
+        assert!(long.liquidation > liquidation);
+        assert_eq!(long.liquidation, get_liquidation(long.last_price, long.bond().unwrap(), long.direction).unwrap());
+
+        // a cross position keeps the shared collateral behind it
+        let mut cross_account = CrossAccount {
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            mint: Pubkey::default(),
+            collateral: 20000_000_000,
+            used_margin: 9000_000_000,
+            positions: Vec::new(),
+        };
+        cross_account.charge_fee(90_000_000).unwrap();
+        assert_eq!(cross_account.collateral, 20000_000_000 - 90_000_000);
+        assert_eq!(cross_account.free_collateral(), 11000_000_000);
+    }
+
+    #[test]
+    fn test_trading_fee_at_close() {
+        let mut long = open_long_of_three();
+        long.taker_fee_bps = 10;
+        long.maker_fee_bps = 4;
+
+        // 10 bps of the 99000 notional sold at 33000 goes to the fee account
+        let settlement = long.settle(&flat_price(33000_000_000), long.created_at).unwrap();
+        assert_eq!(settlement.trading_fee, 99_000_000);
+        assert_eq!(settlement.returned_margin, 9000_000_000 + 9000_000_000 - 99_000_000);
+        assert_eq!(long.get_profit(&flat_price(33000_000_000), long.created_at).unwrap(), settlement.returned_margin);
+
+        // a keeper filling a trigger charges the maker's 4 bps instead
+        let filled = long.settle_as(&flat_price(33000_000_000), long.created_at, Liquidity::Maker).unwrap();
+        assert_eq!(filled.trading_fee, 39_600_000);
+        assert_eq!(filled.returned_margin, 9000_000_000 + 9000_000_000 - 39_600_000);
+        assert_eq!(long.settle_as(&flat_price(33000_000_000), long.created_at, Liquidity::Taker).unwrap(), settlement);
+
+        // the fee account receives both fees, the owner gets the rest of margin plus PnL
+        let result = CloseResult::new(&settlement, long.margin, 33000_000_000, &signed_close(&long, Pubkey::default(), false)).unwrap();
+        assert_eq!(result.gross_pnl, 9000_000_000);
+        assert_eq!(result.trading_fee + result.returned_margin, long.margin + 9000_000_000);
+
+        // half of it on a partial close of half the size
+        let partial = long.partial_close(1_500_000, &flat_price(33000_000_000), long.created_at).unwrap();
+        assert_eq!(partial.trading_fee, 49_500_000);
+    }
+
+    #[test]
+    fn test_self_close_at_profit() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let time = long.created_at + 86400;
+
+        let settlement = long.close_at(&flat_price(31000_000_000), time).unwrap();
+        assert_eq!(long.status, PositionStatus::Processed);
+        assert_eq!(settlement.returned_margin, 9000_000_000 - 9_000_000 + 1000_000_000 * 3);
+        assert_eq!(settlement.overnight_fee, 9_000_000);
+        assert_eq!(settlement.bad_debt, 0);
+    }
+
+    #[test]
+    fn test_self_close_at_small_loss() {
+        let mut long = open_long_of_three();
+        let settlement = long.close_at(&flat_price(29500_000_000), long.created_at).unwrap();
+        assert_eq!(long.status, PositionStatus::Processed);
+        assert_eq!(settlement.returned_margin, 9000_000_000 - 500_000_000 * 3);
+    }
+
+    #[test]
+    fn test_self_close_liquidatable() {
+        // past its liquidation price a position is left to keepers
+        let mut long = open_long_of_three();
+        assert_eq!(
+            failure(long.close_at(&flat_price(21000_000_000), long.created_at)),
+            code(ProtocolError::PositionLiquidated),
+        );
+        assert_eq!(long.status, PositionStatus::Open);
+    }
+
+    #[test]
+    fn test_position_len() {
+        let mut long = open_long_of_three();
+        long.take_profit = Some(u64::MAX);
+        long.stop_loss = Some(u64::MAX);
+        long.expiry = Some(i64::MAX);
+
+        let mut data = Vec::new();
+        long.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 8 + Position::LEN);
+
+        let decoded = Position::try_deserialize(&mut data.as_slice()).unwrap();
+        let mut redata = Vec::new();
+        decoded.try_serialize(&mut redata).unwrap();
+        assert_eq!(redata, data);
+        assert_eq!(decoded.amount, long.amount);
+        assert_eq!(decoded.liquidation, long.liquidation);
+        assert_eq!(decoded.stop_loss, Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_get_liquidation_bond_exceeds_price() {
+        // at 1x a long's bond is worth more than the price itself
+        assert_eq!(get_liquidation(30000_000_000, 30000_000_000, Direction::OpenLong).unwrap(), 0);
+        assert_eq!(get_liquidation(30000_000_000, 45000_000_000, Direction::OpenLong).unwrap(), 0);
+        assert_eq!(get_liquidation(30000_000_000, 45000_000_000, Direction::OpenShort).unwrap(), 75000_000_000);
+        assert_eq!(failure(get_liquidation(1, u64::MAX, Direction::OpenShort)), code(ProtocolError::MathOverflow));
+        assert_eq!(failure(get_liquidation(-1, 0, Direction::OpenLong)), code(ProtocolError::InvalidPrice));
+    }
+
+    #[test]
+    fn test_open_long_bond_exceeds_price() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage = 1;
+        args.leverage_margin = 90000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(long.liquidation, 0);
+        assert!(!long.is_liquidated(1));
+    }
+
+    #[test]
+    fn test_liquidation_after_deposit() {
+        let mut long = open_long_of_three();
+        let preview = long.liquidation_after(1000_000_000).unwrap();
+        assert!(preview < long.liquidation);
+
+        long.margin += 1000_000_000;
+        long.liquidation = get_liquidation(long.last_price, long.bond().unwrap(), long.direction).unwrap();
+        assert_eq!(preview, long.liquidation);
+    }
+
+    #[test]
+    fn test_liquidation_after_withdrawal() {
+        let mut short = open_short_of_three();
+        let preview = short.liquidation_after(-1000_000_000).unwrap();
+        assert!(preview < short.liquidation);
+
+        short.withdraw(1000_000_000, 30000_000_000).unwrap();
+        assert_eq!(preview, short.liquidation);
+        assert_eq!(short.margin, 8000_000_000);
+    }
+
+    #[test]
+    fn test_liquidation_after_overdraw() {
+        let long = open_long_of_three();
+        assert_eq!(long.liquidation_after(-9000_000_000).unwrap(), 30000_000_000);
+        assert_eq!(
+            failure(long.liquidation_after(-9000_000_001)),
+            code(ProtocolError::InsufficientBalance),
+        );
+        assert_eq!(long.margin, 9000_000_000);
+    }
+
+    #[test]
+    fn test_set_leverage_lower() {
+        let mut long = open_long_of_three();
+        let previous_margin = long.set_leverage(5, 30000_000_000).unwrap();
+        assert_eq!(previous_margin, 9000_000_000);
+
+        // same 3 BTC, twice the margin
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.leverage, 5);
+        assert_eq!(long.margin, 18000_000_000);
+        assert_eq!(long.liquidation, 30000_000_000 - (18000_000_000 - 900_000_000));
+    }
+
+    #[test]
+    fn test_set_leverage_raise() {
+        let mut long = open_long_of_three();
+        long.set_leverage(20, 30000_000_000).unwrap();
+
+        assert_eq!(long.amount, 3_000_000);
+        assert_eq!(long.margin, 4500_000_000);
+        assert_eq!(long.liquidation, 30000_000_000 - (4500_000_000 - 225_000_000));
+
+        // the margin rounds up
+        long.set_leverage(7, 30000_000_000).unwrap();
+        assert_eq!(long.margin, 12857_142_858);
+    }
+
+    #[test]
+    fn test_set_leverage_rejected() {
+        let mut long = open_long_of_three();
+        for leverage in [0, MAX_LEVERAGE + 1] {
+            assert_eq!(failure(long.set_leverage(leverage, 30000_000_000)), code(ProtocolError::InvalidLeverage));
+        }
+
+        // fine at 10x, liquidatable straight away at 20x
+        assert!(!long.is_liquidated(25000_000_000));
+        assert_eq!(failure(long.set_leverage(20, 25000_000_000)), code(ProtocolError::PositionLiquidated));
+        assert_eq!(long.leverage, 10);
+        assert_eq!(long.margin, 9000_000_000);
+    }
+
+    #[test]
+    fn test_check_version() {
+        let mut long = open_long_of_three();
+        assert!(long.check_version().is_ok());
+
+        // an account written by an older layout
+        long.version = POSITION_VERSION - 1;
+        assert_eq!(failure(long.check_version()), code(ProtocolError::UnsupportedVersion));
+        long.version = POSITION_VERSION + 1;
+        assert_eq!(failure(long.check_version()), code(ProtocolError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn test_partial_close_half() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let liquidation = long.liquidation;
+
+        // half of 3 BTC, 10% up, after two days
+        let time = long.created_at + 2 * 86400;
+        let settlement = long.partial_close(1_500_000, &flat_price(33000_000_000), time).unwrap();
+        assert_eq!(settlement.overnight_fee, 9_000_000);
+        assert_eq!(settlement.returned_margin, 4500_000_000 - 9_000_000 + 3000_000_000 * 3 / 2);
+        assert_eq!(settlement.bad_debt, 0);
+
+        assert_eq!(long.status, PositionStatus::Open);
+        assert_eq!(long.amount, 1_500_000);
+        assert_eq!(long.margin, 4500_000_000);
+        assert_eq!(long.accrued_fee, 9_000_000);
+        assert_eq!(long.liquidation, liquidation);
+
+        // the rest closes for the remaining half of the settled fees
+        let rest = long.settle(&flat_price(33000_000_000), time).unwrap();
+        assert_eq!(rest.overnight_fee, 9_000_000);
+        assert_eq!(rest.returned_margin + settlement.returned_margin, 9000_000_000 - 18_000_000 + 3000_000_000 * 3);
+    }
+
+    #[test]
+    fn test_partial_close_invalid_amount() {
+        let mut long = open_long_of_three();
+        for close_amount in [0, 3_000_000, 4_000_000] {
+            assert_eq!(
+                failure(long.partial_close(close_amount, &flat_price(30000_000_000), long.created_at)),
+                code(ProtocolError::InvalidArgs),
+            );
+        }
+        assert_eq!(long.amount, 3_000_000);
+    }
+
+    #[test]
+    fn test_close_min_return() {
+        let long = open_long_of_three();
+        let min_return = long.get_profit(&flat_price(31000_000_000), long.created_at).unwrap();
+        assert_eq!(min_return, 12000_000_000);
+
+        let settlement = long.settle(&flat_price(31000_000_000), long.created_at).unwrap();
+        check_min_return(settlement.returned_margin, min_return).unwrap();
+
+        // the price ticks down before the close lands
+        let settlement = long.settle(&flat_price(30900_000_000), long.created_at).unwrap();
+        assert_eq!(
+            failure(check_min_return(settlement.returned_margin, min_return)),
+            code(ProtocolError::SlippageReached),
+        );
+        check_min_return(settlement.returned_margin, 0).unwrap();
+    }
+
+    fn open_short_of_three() -> Position {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 90000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        short
+    }
+
+    #[test]
+    fn test_unrealized_pnl_both_directions() {
+        let long = open_long_of_three();
+        let short = open_short_of_three();
+        for (price, long_pnl) in [
+            (33000_000_000, 9000_000_000),
+            (28000_000_000, -6000_000_000),
+            (30000_000_000, 0),
+        ] {
+            assert_eq!(long.unrealized_pnl(&flat_price(price)).unwrap(), long_pnl);
+            assert_eq!(short.unrealized_pnl(&flat_price(price)).unwrap(), -long_pnl);
+        }
+
+        // a long sells at the bid and a short buys back at the ask
+        assert_eq!(long.unrealized_pnl(&btc_usdc()).unwrap(), -15_000_000);
+        assert_eq!(short.unrealized_pnl(&btc_usdc()).unwrap(), -15_000_000);
+
+        // the close payout is the margin net of fees, plus the PnL
+        let price = flat_price(33000_000_000);
+        let fees = long.overnight_fee(long.created_at).unwrap()
+            + long.trading_fee(33000_000_000, long.amount, Liquidity::Taker).unwrap();
+        assert_eq!(
+            long.get_profit(&price, long.created_at).unwrap() as i128,
+            (long.margin - fees) as i128 + long.unrealized_pnl(&price).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_triggers_long() {
+        let mut long = open_long_of_three();
+        assert!(!long.is_triggered(&flat_price(40000_000_000)).unwrap());
+
+        long.set_triggers(Some(33000_000_000), Some(28000_000_000)).unwrap();
+        assert!(!long.is_triggered(&flat_price(30000_000_000)).unwrap());
+        // take-profit, then stop-loss
+        assert!(long.is_triggered(&flat_price(33000_000_000)).unwrap());
+        assert!(long.is_triggered(&flat_price(28000_000_000)).unwrap());
+
+        // the long exits at the bid, which is still below the take-profit
+        let price = pyth_sdk_solana::Price {
+            price: 33000_000_000,
+            conf: 5_000_000,
+            expo: -6,
+        };
+        assert!(!long.is_triggered(&price).unwrap());
+
+        // only a stop-loss
+        long.set_triggers(None, Some(28000_000_000)).unwrap();
+        assert!(!long.is_triggered(&flat_price(40000_000_000)).unwrap());
+    }
+
+    #[test]
+    fn test_open_rate_bounds() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.margin_rate_numerator = 10001;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        assert_eq!(failure(long.open(&args, &flat_price(30000_000_000), 0)), code(ProtocolError::InvalidArgs));
+
+        args.margin_rate_numerator = 10000;
+        long.overnight_fee_numerator = 10001;
+        assert_eq!(failure(long.open(&args, &flat_price(30000_000_000), 0)), code(ProtocolError::InvalidArgs));
+
+        // `create` stores the order's leverage and the pool's rates
+        let config = pool_config();
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        let (position, _) = open_isolated(&mut config.clone(), Pubkey::new_unique(), Pubkey::new_unique(), 0, &args, &btc_usdc(), &clock, 0).unwrap();
+        assert_eq!(position.leverage, args.leverage);
+        assert_eq!(position.margin_rate_numerator, config.maintenance_margin_bps);
+        assert_eq!(position.overnight_fee_numerator, config.overnight_fee_bps);
+    }
+
+    #[test]
+    fn test_create_persists_leverage_and_rates() {
+        let mut config = pool_config();
+        config.maintenance_margin_bps = 650;
+        config.overnight_fee_bps = 7;
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage = 4;
+        args.leverage_margin = 4000_000_000;
+        let args = check_isolated_args(&config, args).unwrap();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        let (short, _) = open_isolated(&mut config, Pubkey::new_unique(), Pubkey::new_unique(), 0, &args, &flat_price(30000_000_000), &clock, 0).unwrap();
+
+        assert_eq!(short.leverage, 4);
+        assert_eq!(short.margin_rate_numerator, 650);
+        assert_eq!(short.overnight_fee_numerator, 7);
+        // none of them left at zero, which would waive the maintenance
+        // margin and the overnight fee
+        assert!(short.maintainance_margin().unwrap() > 0);
+        assert!(short.overnight_fee(clock.unix_timestamp + 86400).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_cancel_position() {
+        // an account that never received its margin
+        let empty = new_position(PositionType::Isolated, Direction::OpenLong, 0);
+        empty.check_cancellable().unwrap();
+
+        let funded = open_long_of_three();
+        assert_eq!(failure(funded.check_cancellable()), code(ProtocolError::InvalidArgs));
+
+        let mut dust = empty.clone();
+        dust.margin = 1;
+        assert_eq!(failure(dust.check_cancellable()), code(ProtocolError::InvalidArgs));
+
+        // filled, with the margin since eaten by fees or funding
+        let mut drained = open_long_of_three();
+        drained.margin = 0;
+        assert_eq!(failure(drained.check_cancellable()), code(ProtocolError::InvalidArgs));
+        let mut charged = empty.clone();
+        charged.accrued_fee = 1;
+        assert_eq!(failure(charged.check_cancellable()), code(ProtocolError::InvalidArgs));
+
+        // booked in its cross account from the start
+        let cross = new_position(PositionType::Cross, Direction::OpenLong, 0);
+        assert_eq!(failure(cross.check_cancellable()), code(ProtocolError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_fee_withdrawal() {
+        // part of the balance, then what is left of it
+        assert!(check_fee_withdrawal(90_000_000, 40_000_000).is_ok());
+        assert!(check_fee_withdrawal(50_000_000, 50_000_000).is_ok());
+        assert_eq!(failure(check_fee_withdrawal(50_000_000, 50_000_001)), code(ProtocolError::InsufficientBalance));
+        assert_eq!(failure(check_fee_withdrawal(0, 1)), code(ProtocolError::InsufficientBalance));
+    }
+
+    #[cfg(feature = "debug-log")]
+    #[test]
+    fn test_debug_log() {
+        take_log();
+        let args = new_args(PositionType::Isolated, Direction::OpenLong);
+        assert!(check_slippage(31000_000_000, args).is_err());
+        assert_eq!(take_log(), vec!["slippage: reference 30000000000 fill 31000000000 allowed 100 bps"]);
+
+        quote_price(&btc_usdc(), None, 6, 1000).unwrap();
+        assert_eq!(take_log(), vec!["oracle price 30000000000 conf 5000000 expo -6"]);
+
+        // nothing is logged for a fill within the slippage
+        check_slippage(30000_000_000, args).unwrap();
+        assert!(take_log().is_empty());
+    }
+
+    #[test]
+    fn test_liquidation_moves_with_accrued_fees() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 3;
+        let opened = long.last_fee_settlement;
+        assert_eq!(long.liquidation_at(opened, 0).unwrap(), long.liquidation);
+
+        // the long's threshold climbs day by day while the stored one stays
+        let day = SECONDS_PER_DAY as i64;
+        let after_one = long.liquidation_at(opened + day, 0).unwrap();
+        let after_ten = long.liquidation_at(opened + 10 * day, 0).unwrap();
+        assert!(long.liquidation < after_one && after_one < after_ten);
+        assert!(!long.is_liquidated(after_ten));
+        assert!(long.is_liquidated_at(after_ten, opened + 10 * day, 0).unwrap());
+        assert!(!long.is_liquidated_at(after_ten, opened, 0).unwrap());
+
+        // realizing the fees brings the stored price up to it
+        long.realize_fees(opened + 10 * day, 0, 30000_000_000).unwrap();
+        assert_eq!(long.liquidation, after_ten);
+        assert_eq!(long.liquidation_at(opened + 10 * day, 0).unwrap(), after_ten);
+
+        // a short's threshold comes down instead
+        let mut short = open_short_of_three();
+        short.overnight_fee_numerator = 3;
+        assert!(short.liquidation_at(short.last_fee_settlement + 10 * day, 0).unwrap() < short.liquidation);
+    }
+
+    #[test]
+    fn test_funding_alone_liquidates() {
+        let mut long = open_long_of_three();
+        let now = long.created_at;
+        let entry = long.last_price as u64;
+        assert!(!long.is_liquidated(entry));
+
+        // 8640 of funding owed on the 90000 notional leaves 360 of margin,
+        // under the 450 of maintenance, with the price still at the entry
+        let cumulative_funding = 96_000_000;
+        assert_eq!(long.funding_owed(cumulative_funding).unwrap(), 8640_000_000);
+        assert_eq!(long.liquidation_at(now, cumulative_funding).unwrap(), entry);
+        assert!(long.is_liquidated_at(entry, now, cumulative_funding).unwrap());
+        assert!(!long.is_liquidated_at(entry, now, 0).unwrap());
+
+        // the split is of the margin the funding leaves
+        let result = long.liquidation_split(now, cumulative_funding, 2000).unwrap();
+        assert_eq!(result.returned_margin + result.keeper_bonus + result.insurance_fee, 360_000_000);
+
+        // and realizing the funding first, as `liquidate` does, agrees
+        let mut settled = long.clone();
+        assert_eq!(settled.settle_funding(cumulative_funding).unwrap(), 8640_000_000);
+        assert!(settled.is_liquidated(entry));
+        assert!(settled.is_liquidated_at(entry, now, cumulative_funding).unwrap());
+        assert_eq!(settled.liquidation_split(now, cumulative_funding, 2000).unwrap(), result);
+
+        // funding beyond the margin takes all of it instead of failing, so
+        // the position can still be liquidated
+        assert_eq!(long.settle_funding(200_000_000).unwrap(), 9000_000_000);
+        assert_eq!(long.margin, 0);
+        assert!(long.is_liquidated(entry));
+        assert_eq!(long.liquidation_split(now, 200_000_000, 2000).unwrap().returned_margin, 0);
+    }
+
+    #[test]
+    fn test_settle_at() {
+        let long = open_long_of_three();
+        let now = long.created_at;
+        let entry = long.last_price as u64;
+
+        // the 8640 of funding owed leaves it liquidatable at the entry, which
+        // the stored liquidation price doesn't show yet
+        let mut owing = long.clone();
+        assert!(!owing.is_liquidated(entry));
+        assert_eq!(failure(owing.settle_at(entry, now, 96_000_000)), code(ProtocolError::PositionLiquidated));
+        assert_eq!(owing.margin, 9000_000_000);
+
+        // 900 of funding is taken out of the margin before it changes
+        let mut settled = long.clone();
+        settled.settle_at(entry, now, 10_000_000).unwrap();
+        assert_eq!((settled.margin, settled.funding_index), (8100_000_000, 10_000_000));
+        assert_eq!(settled.liquidation, 30000_000_000 - (8100_000_000 - 450_000_000));
+
+        // a cross member leaves it for the close releasing it
+        let mut cross = Position { ptype: PositionType::Cross, ..long };
+        cross.settle_at(entry, now, 10_000_000).unwrap();
+        assert_eq!((cross.margin, cross.funding_index), (9000_000_000, 0));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut long = open_long_of_three();
+        let now = long.created_at;
+        // a position without an expiry never expires
+        assert_eq!(failure(long.check_expired(i64::MAX)), code(ProtocolError::PositionNotExpired));
+
+        assert_eq!(failure(long.set_expiry(Some(now), now)), code(ProtocolError::InvalidArgs));
+        long.set_expiry(Some(now + 86400), now).unwrap();
+        assert_eq!(failure(long.check_expired(now + 86399)), code(ProtocolError::PositionNotExpired));
+        long.check_expired(now + 86400).unwrap();
+
+        // once expired it closes like a normal close
+        let expected = long.clone().close_at(&flat_price(33000_000_000), now + 86400).unwrap();
+        let settlement = long.close_at(&flat_price(33000_000_000), now + 86400).unwrap();
+        assert_eq!(settlement, expected);
+        assert_eq!(long.status, PositionStatus::Processed);
+
+        let mut long = open_long_of_three();
+        long.set_expiry(Some(now + 60), now).unwrap();
+        long.set_expiry(None, now).unwrap();
+        assert_eq!(failure(long.check_expired(now + 60)), code(ProtocolError::PositionNotExpired));
+    }
+
+    #[test]
+    fn test_triggers_short() {
+        let mut short = open_short_of_three();
+        short.set_triggers(Some(27000_000_000), Some(32000_000_000)).unwrap();
+        assert!(!short.is_triggered(&flat_price(30000_000_000)).unwrap());
+        assert!(short.is_triggered(&flat_price(27000_000_000)).unwrap());
+        assert!(short.is_triggered(&flat_price(32000_000_000)).unwrap());
+
+        // the short exits at the ask, which is still above the take-profit
+        let price = pyth_sdk_solana::Price {
+            price: 27000_000_000,
+            conf: 5_000_000,
+            expo: -6,
+        };
+        assert!(!short.is_triggered(&price).unwrap());
+    }
+
+    #[test]
+    fn test_triggers_invalid_placement() {
+        let mut long = open_long_of_three();
+        for (take_profit, stop_loss) in [
+            (Some(29000_000_000), None),
+            (Some(30000_000_000), None),
+            (None, Some(31000_000_000)),
+            (None, Some(30000_000_000)),
+            (Some(28000_000_000), Some(33000_000_000)),
+        ] {
+            assert_eq!(
+                failure(long.set_triggers(take_profit, stop_loss)),
+                code(ProtocolError::InvalidTrigger),
+            );
+        }
+        assert_eq!(long.take_profit, None);
+
+        let mut short = open_short_of_three();
+        assert_eq!(
+            failure(short.set_triggers(Some(33000_000_000), Some(28000_000_000))),
+            code(ProtocolError::InvalidTrigger),
+        );
+        short.set_triggers(None, None).unwrap();
+    }
+
+    #[test]
+    fn test_netoff_flip_clears_triggers() {
+        let mut long = open_long_of_three();
+        long.set_triggers(Some(40000_000_000), Some(20000_000_000)).unwrap();
+        long.reduce(&close_args(132000_000_000), &flat_price(33000_000_000)).unwrap();
+        assert_eq!(long.direction, Direction::OpenShort);
+        assert_eq!((long.take_profit, long.stop_loss), (None, None));
+    }
+
+    #[test]
+    fn test_netoff_reduce_loss_beyond_margin() {
+        let mut long = open_long_of_three();
+        let mut args = close_args(99000_000_000);
+        args.price = 20000_000_000;
+        args.leverage_margin = 60000_000_000;
+        assert_eq!(
+            failure(long.reduce(&args, &flat_price(20000_000_000))),
+            code(ProtocolError::PositionLiquidated),
+        );
+    }
+
+    #[test]
+    fn test_decrease_margin() {
+        let mut long = open_long_of_three();
+        assert_eq!(long.liquidation, 30000_000_000 - 8550_000_000);
+
+        long.withdraw(1000_000_000, 30000_000_000).unwrap();
+        assert_eq!(long.margin, 8000_000_000);
+        // the maintenance margin stays at 450, the whole withdrawal comes off the bond
+        assert_eq!(long.liquidation, 30000_000_000 - 7550_000_000);
+    }
+
+    #[test]
+    fn test_decrease_margin_below_maintenance() {
+        let mut long = open_long_of_three();
+        assert_eq!(
+            failure(long.withdraw(8600_000_000, 30000_000_000)),
+            code(ProtocolError::InsufficientBalance),
+        );
+        assert_eq!(long.margin, 9000_000_000);
+    }
+
+    #[test]
+    fn test_decrease_margin_immediately_liquidatable() {
+        let mut long = open_long_of_three();
+        assert!(!long.is_liquidated(25000_000_000));
+        assert_eq!(
+            failure(long.withdraw(5000_000_000, 25000_000_000)),
+            code(ProtocolError::PositionLiquidated),
+        );
+        assert_eq!(long.liquidation, 30000_000_000 - 8550_000_000);
+    }
+
+    #[test]
+    fn test_asset_amount_keeps_fraction() {
+        // 10000 USDC buys a third of a BTC at 30000
+        let amount = get_asset_amount(10000_000_000, 30000_000_000, 6).unwrap();
+        assert_eq!(amount, 333_333);
+
+        // 1 USDC buys 0.000033 BTC, not zero
+        let amount = get_asset_amount(1_000_000, 30000_000_000, 6).unwrap();
+        assert_eq!(amount, 33);
+
+        // 0.5 SOL at 45.12 with 9 decimals
+        let amount = get_asset_amount(22_560_000_000, 45_120_000_000, 9).unwrap();
+        assert_eq!(amount, 500_000_000);
+    }
+
+    #[test]
+    fn test_asset_amount_round_trip() {
+        for (leverage_margin, price) in [
+            (10000_000_000u64, 30000_000_000u64),
+            (12345_678_901, 29999_999_999),
+            (1_000_000, 1_000_001),
+            (500_000_000, 3_141_592),
+        ] {
+            let amount = get_asset_amount(leverage_margin, price, 6).unwrap();
+            let notional = amount as u128 * price as u128 / 1_000_000;
+            // truncation loses at most one unit of size worth of notional
+            assert!(notional <= leverage_margin as u128);
+            assert!(leverage_margin as u128 - notional <= price as u128 / 1_000_000 + 1);
+        }
+    }
+
+    #[test]
+    fn test_asset_amount_consistency() {
+        let args = new_args(PositionType::Isolated, Direction::OpenLong);
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        check_asset_amount(long.amount, 30000_000_000, 6, args.leverage_margin).unwrap();
+
+        // the same price read as whole units buys no size at all
+        let mut args = args;
+        args.expo = 0;
+        args.decimals = 0;
+        args.price = 30000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.decimals = 0;
+        assert_eq!(
+            failure(long.open(&args, &flat_price(30000_000_000), 0)),
+            code(ProtocolError::InvalidArgs),
+        );
+
+        // truncating the size may only lose notional within the tolerance
+        check_asset_amount(333, 30000_000_000, 6, 10_000_000).unwrap();
+        assert_eq!(
+            failure(check_asset_amount(3, 30000_000_000, 6, 100_000)),
+            code(ProtocolError::InvalidArgs),
+        );
+    }
+
+    #[test]
+    fn test_asset_amount_zero_price() {
+        assert!(get_asset_amount(1_000_000, 0, 6).is_err());
+    }
+
+    #[test]
+    fn test_stale_feed_named() {
+        let now = 1_650_000_000;
+        check_feed_ages(now, Some(now - 60), now, 60).unwrap();
+        check_feed_ages(now - 60, None, now, 60).unwrap();
+
+        // the base feed stale behind a fresh quote, and the other way round
+        assert_eq!(failure(check_feed_ages(now - 61, Some(now), now, 60)), code(ProtocolError::PriceAStale));
+        assert_eq!(failure(check_feed_ages(now, Some(now - 61), now, 60)), code(ProtocolError::PriceBStale));
+        assert_eq!(failure(check_feed_ages(now - 61, None, now, 60)), code(ProtocolError::PriceAStale));
+        // both stale, the base feed is waited on first
+        assert_eq!(failure(check_feed_ages(now - 61, Some(now - 61), now, 60)), code(ProtocolError::PriceAStale));
+    }
+
+    #[test]
+    fn test_price_age() {
+        let now = 1_650_000_000;
+        assert!(check_price_age(now, now, 60).is_ok());
+        assert!(check_price_age(now - 60, now, 60).is_ok());
+        assert_eq!(
+            failure(check_price_age(now - 61, now, 60)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert_eq!(
+            failure(check_price_age(now - 3600, now, 60)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert!(check_price_age(now - 3600, now, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_confidence() {
+        // 5 USDC on 30000 is under 2 bps
+        assert!(check_confidence(&btc_usdc(), 100).is_ok());
+        assert!(check_confidence(&btc_usdc(), 1).is_ok());
+        assert!(check_confidence(&flat_price(30000_000_000), 0).is_ok());
+
+        // 3000 USDC on 30000 is 1000 bps
+        let blown_out = pyth_sdk_solana::Price {
+            price: 30000_000_000,
+            conf: 3000_000_000,
+            expo: -6,
+        };
+        assert!(check_confidence(&blown_out, 1000).is_ok());
+        assert_eq!(
+            failure(check_confidence(&blown_out, 999)),
+            code(ProtocolError::InvalidPrice),
+        );
+        assert_eq!(
+            failure(check_confidence(&flat_price(0), 100)),
+            code(ProtocolError::InvalidPrice),
+        );
+    }
+
+    #[test]
+    fn test_slippage_keeps_fraction() {
+        // 30000.5 quoted with expo -6; 1% slippage allows up to 30300.505
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.price = 30000_500_000;
+        check_slippage(30300_504_999, args).unwrap();
+        assert_eq!(
+            failure(check_slippage(30300_505_000, args)),
+            code(ProtocolError::SlippageReached),
+        );
+
+        // truncating the reference to 30000 would have rejected this fill
+        check_slippage(30300_100_000, args).unwrap();
+
+        args.direction = Direction::OpenShort;
+        check_slippage(29700_495_001, args).unwrap();
+        assert_eq!(
+            failure(check_slippage(29700_495_000, args)),
+            code(ProtocolError::SlippageReached),
+        );
+    }
+
+    #[test]
+    fn test_args_scale_mismatch() {
+        let config = pool_config();
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.check_scale().unwrap();
+
+        for (expo, decimals) in [(-9, 6), (-6, 8), (6, 6), (0, 6)] {
+            args.expo = expo;
+            args.decimals = decimals;
+            assert_eq!(failure(args.check_scale()), code(ProtocolError::InvalidArgs));
+            assert_eq!(failure(config.check_args(&mut args)), code(ProtocolError::InvalidArgs));
+        }
+
+        args.expo = -8;
+        args.decimals = 8;
+        config.check_args(&mut args).unwrap();
+    }
+
+    #[test]
+    fn test_slippage_rejection_reports_deviation() {
+        // a long referenced at 30000 with 1% slippage, filled at 30450
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        let rejected = slippage_rejection(30450_000_000, args).unwrap().unwrap();
+        assert_eq!(rejected.deviation_bps, 150);
+        assert_eq!(rejected.reference_price, 30000_000_000);
+        assert_eq!(rejected.oracle_price, 30450_000_000);
+        assert_eq!(rejected.direction, Direction::OpenLong);
+
+        let data = rejected.data();
+        assert_eq!(data[..8], SlippageRejected::discriminator());
+        let decoded = SlippageRejected::try_from_slice(&data[8..]).unwrap();
+        assert_eq!(decoded.deviation_bps, 150);
+        assert_eq!(decoded.expo, -6);
+        assert_eq!(decoded.decimals, 6);
+
+        // a fill within the slippage reports nothing
+        assert!(slippage_rejection(30299_999_999, args).unwrap().is_none());
+        assert!(slippage_rejection(29000_000_000, args).unwrap().is_none());
+
+        args.direction = Direction::OpenShort;
+        assert_eq!(slippage_rejection(29400_000_000, args).unwrap().unwrap().deviation_bps, 200);
+        assert!(slippage_rejection(30000_000_000, args).unwrap().is_none());
+        assert_eq!(
+            failure(check_slippage(29400_000_000, args)),
+            code(ProtocolError::SlippageReached),
+        );
+    }
+
+    #[test]
+    fn test_slippage_off_scale_reference() {
+        // a reference quoted at any scale but the fill's is refused, not
+        // compared: 3 at expo 4 and 30000_000_000_001 at expo -9 both mean
+        // about 30000
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        for (price, expo) in [(3, 4), (30000_000_000_001, -9)] {
+            args.price = price;
+            args.expo = expo;
+            assert_eq!(failure(check_slippage(30000_000_000, args)), code(ProtocolError::InvalidArgs));
+        }
+    }
+
+    #[test]
+    fn test_quote_price_positive_expo_feed() {
+        // a feed publishing 3 at expo 4 is scaled down to `-decimals`
+        let coarse = pyth_sdk_solana::Price { price: 3, conf: 0, expo: 4 };
+        let single_feed = quote_price(&coarse, None, 6, 100).unwrap();
+        assert_eq!(single_feed.price, 30000_000_000);
+        assert_eq!(single_feed.expo, -6);
+
+        let usd = pyth_sdk_solana::Price { price: 1, conf: 0, expo: 0 };
+        let two_feeds = quote_price(&coarse, Some(&usd), 6, 100).unwrap();
+        assert_eq!(two_feeds.price, single_feed.price);
+        assert_eq!(two_feeds.expo, -6);
+    }
+
+    #[test]
+    fn test_rate_apply() {
+        let rate = Rate { numerator: 300, denominator: 10000 };
+        assert_eq!(rate.apply(1_000_000), Some(30_000));
+        assert_eq!(rate.apply(0), Some(0));
+        // rounds down
+        assert_eq!(rate.apply(333), Some(9));
+
+        let rate = Rate { numerator: 3, denominator: 2 };
+        assert_eq!(rate.apply(5), Some(7));
+    }
+
+    #[test]
+    fn test_rate_denominator() {
+        // the same 5% over basis points and over parts per million
+        let bps = Rate { numerator: 500, denominator: RATE_DENOMINATOR };
+        let ppm = Rate { numerator: 50_000, denominator: 1_000_000 };
+        for value in [0, 1, 19, 1_000_000, 9000_000_000, u64::MAX / 2] {
+            assert_eq!(bps.apply(value), ppm.apply(value));
+            assert_eq!(bps.apply_up(value), ppm.apply_up(value));
+        }
+        // a finer denominator carries what basis points can't
+        let half_bps = Rate { numerator: 50, denominator: 1_000_000 };
+        assert_eq!(half_bps.apply(1_000_000_000), Some(50_000));
+
+        // every position rate is over the shared denominator
+        let long = open_long_of_three();
+        assert_eq!(long.margin_rate().denominator, RATE_DENOMINATOR);
+        assert_eq!(long.overnight_fee_rate().denominator, RATE_DENOMINATOR);
+        assert_eq!(long.maintainance_margin().unwrap(), long.margin_rate().apply(9000_000_000).unwrap());
+
+        // and a numerator past it is rejected
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.margin_rate_numerator = RATE_DENOMINATOR + 1;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        assert_eq!(failure(long.open(&args, &flat_price(30000_000_000), 0)), code(ProtocolError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_rate_zero_denominator() {
+        let rate = Rate { numerator: 300, denominator: 0 };
+        assert_eq!(rate.apply(1_000_000), None);
+        assert_eq!(rate.apply(0), None);
+    }
+
+    #[test]
+    fn test_rate_overflow() {
+        // the intermediate product doesn't overflow...
+        let rate = Rate { numerator: u64::MAX, denominator: u64::MAX };
+        assert_eq!(rate.apply(u64::MAX), Some(u64::MAX));
+
+        // ...but a result above u64::MAX does
+        let rate = Rate { numerator: 2, denominator: 1 };
+        assert_eq!(rate.apply(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_profit_long_closed_higher() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let fee = long.overnight_fee(long.created_at + 86400).unwrap();
+        assert_eq!(fee, 9_000_000);
+
+        // closing 10% higher earns the price delta on 3 BTC
+        let returned = long.get_profit(&flat_price(33000_000_000), long.created_at + 86400).unwrap();
+        assert_eq!(returned, 9000_000_000 - fee + 3000_000_000 * 3);
+
+        // the bid is what the long sells at
+        let returned = long.get_profit(&btc_usdc(), long.created_at + 86400).unwrap();
+        assert_eq!(returned, 9000_000_000 - fee - 5_000_000 * 3);
+    }
+
+    #[test]
+    fn test_profit_long_closed_lower() {
+        let long = open_long_of_three();
+        let returned = long.get_profit(&flat_price(28000_000_000), long.created_at).unwrap();
+        assert_eq!(returned, 9000_000_000 - 2000_000_000 * 3);
+
+        assert_eq!(
+            failure(long.get_profit(&flat_price(20000_000_000), long.created_at)),
+            code(ProtocolError::InvalidPrice),
+        );
+    }
+
+    #[test]
+    fn test_profit_short_closed_lower() {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 90000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(short.amount, 3_000_000);
+
+        // buying back 10% lower earns the price delta on 3 BTC
+        let returned = short.get_profit(&flat_price(27000_000_000), short.created_at).unwrap();
+        assert_eq!(returned, 9000_000_000 + 3000_000_000 * 3);
+
+        // the ask is what the short buys back at, not the bid
+        let ask = pyth_sdk_solana::Price {
+            price: 27000_000_000,
+            conf: 5_000_000,
+            expo: -6,
+        };
+        let returned = short.get_profit(&ask, short.created_at).unwrap();
+        assert_eq!(returned, 9000_000_000 + 2995_000_000 * 3);
+
+        // and closing higher loses it
+        let returned = short.get_profit(&flat_price(31000_000_000), short.created_at).unwrap();
+        assert_eq!(returned, 9000_000_000 - 1000_000_000 * 3);
+    }
+
+    #[test]
+    fn test_conf_mode_round_trip() {
+        // entering and exiting at the same oracle price and confidence
+        let price = pyth_sdk_solana::Price { price: 30000_000_000, conf: 30_000_000, expo: -6 };
+        for direction in [Direction::OpenLong, Direction::OpenShort] {
+            let mut args = new_args(PositionType::Isolated, direction);
+            args.leverage_margin = 90000_000_000;
+
+            // the midpoint fills at the oracle price both ways and returns the margin
+            let mut mid = new_position(PositionType::Isolated, direction, args.margin().unwrap());
+            mid.conf_mode = ConfMode::Mid;
+            mid.open(&args, &price, 0).unwrap();
+            assert_eq!(mid.last_price, 30000_000_000);
+            assert_eq!(mid.last_conf, 0);
+            assert_eq!(mid.amount, 3_000_000);
+            assert_eq!(mid.exit_price(&price).unwrap(), 30000_000_000);
+            assert_eq!(mid.get_profit(&price, mid.created_at).unwrap(), 9000_000_000);
+
+            // the band crosses the spread on the way in and again on the way out
+            let mut conservative = new_position(PositionType::Isolated, direction, args.margin().unwrap());
+            conservative.open(&args, &price, 0).unwrap();
+            let (entry, exit) = match direction {
+                Direction::OpenLong => (30030_000_000, 29970_000_000),
+                Direction::OpenShort => (29970_000_000, 30030_000_000),
+            };
+            assert_eq!(conservative.last_price, entry);
+            assert_eq!(conservative.exit_price(&price).unwrap(), exit);
+            assert_eq!(
+                conservative.get_profit(&price, conservative.created_at).unwrap(),
+                9000_000_000 - conservative.amount * 60,
+            );
+        }
+    }
+
+    #[test]
+    fn test_settle_within_margin() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let price = flat_price(28000_000_000);
+
+        let settlement = long.settle(&price, long.created_at + 86400).unwrap();
+        assert_eq!(settlement.returned_margin, long.get_profit(&price, long.created_at + 86400).unwrap());
+        assert_eq!(settlement.overnight_fee, 9_000_000);
+        assert_eq!(settlement.bad_debt, 0);
+    }
+
+    #[test]
+    fn test_settle_gap_past_liquidation() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        assert_eq!(long.liquidation, 21450_000_000);
+
+        // gapping to 20000 loses 30000 on a margin of 9000
+        let price = flat_price(20000_000_000);
+        assert!(long.get_profit(&price, long.created_at + 86400).is_err());
+
+        let settlement = long.settle(&price, long.created_at + 86400).unwrap();
+        assert_eq!(settlement.returned_margin, 0);
+        assert_eq!(settlement.overnight_fee, 9_000_000);
+        assert_eq!(settlement.bad_debt, 30000_000_000 - (9000_000_000 - 9_000_000));
+
+        // the same for a short gapping up
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 90000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+
+        let settlement = short.settle(&flat_price(40000_000_000), short.created_at).unwrap();
+        assert_eq!(settlement.returned_margin, 0);
+        assert_eq!(settlement.bad_debt, 30000_000_000 - 9000_000_000);
+    }
+
+    #[test]
+    fn test_settle_fee_beyond_margin() {
+        // a fee larger than the margin is capped at it, the rest of the loss is bad debt
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 10000;
+        let settlement = long.settle(&flat_price(29000_000_000), long.created_at + 86400).unwrap();
+        assert_eq!(settlement.returned_margin, 0);
+        assert_eq!(settlement.overnight_fee, long.margin);
+        assert_eq!(settlement.bad_debt, 3000_000_000);
+    }
+
+    #[test]
+    fn test_maintainance_margin_overflow() {
+        let mut position = open_long_of_three();
+        position.margin_rate_numerator = u64::MAX;
+        assert_eq!(
+            failure(position.maintainance_margin()),
+            code(ProtocolError::MathOverflow),
+        );
+
+        // without a leverage there is no basis to take it from
+        position.leverage = 0;
+        assert_eq!(
+            failure(position.maintainance_margin()),
+            code(ProtocolError::InvalidLeverage),
+        );
+    }
+
+    #[test]
+    fn test_open_already_liquidatable() {
+        // a maintenance margin as large as the margin leaves no bond at all,
+        // on a size that buys exactly at the fill
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 3000_000_000;
+        args.margin_rate_numerator = 10000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        assert_eq!(
+            failure(long.open(&args, &flat_price(30000_000_000), 0)),
+            code(ProtocolError::WouldBeImmediatelyLiquidated),
+        );
+
+        // a confidence wider than the bond fills the short below its liquidation
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.price = 27000_000_000;
+        let wide = pyth_sdk_solana::Price { price: 30000_000_000, conf: 3000_000_000, expo: -6 };
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        assert_eq!(
+            failure(short.open(&args, &wide, 0)),
+            code(ProtocolError::WouldBeImmediatelyLiquidated),
+        );
+
+        // the same order on a tight price opens
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(27000_000_000), 0).unwrap();
+        assert!(!short.is_liquidated(27000_000_000));
+    }
+
+    #[test]
+    fn test_bond_after_fees() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 100;
+        assert_eq!(long.maintainance_margin().unwrap(), 450_000_000);
+
+        // nine days of 900 leave 900 of margin; the maintenance margin doesn't move
+        long.realize_fees(long.created_at + 9 * 86400, 0, 30000_000_000).unwrap();
+        assert_eq!(long.margin, 900_000_000);
+        assert_eq!(long.maintainance_margin().unwrap(), 450_000_000);
+        assert_eq!(long.bond().unwrap(), 450_000_000);
+        assert_eq!(long.liquidation, 30000_000_000 - 450_000_000);
+
+        // fees below the maintenance margin leave no bond rather than underflowing
+        let mut eroded = long.clone();
+        eroded.margin = 400_000_000;
+        assert_eq!(eroded.bond().unwrap(), 0);
+        assert_eq!(
+            get_liquidation(eroded.last_price, eroded.bond().unwrap(), eroded.direction).unwrap(),
+            30000_000_000,
+        );
+        // and a liquidation splits only what is left
+        assert_eq!(eroded.get_liquidated_margin(eroded.last_fee_settlement, 0).unwrap(), 400_000_000);
+
+        // a tenth day takes the rest, which makes it liquidatable at entry
+        assert_eq!(
+            failure(long.realize_fees(long.created_at + 10 * 86400, 0, 30000_000_000)),
+            code(ProtocolError::PositionLiquidated),
+        );
+        assert_eq!(long.bond().unwrap(), 0);
+        assert_eq!(long.liquidation, 30000_000_000);
+    }
+
+    #[test]
+    fn test_overnight_fee_started_days() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let day = 9_000_000;
+
+        assert_eq!(long.overnight_fee(long.created_at).unwrap(), 0);
+        assert_eq!(long.overnight_fee(long.created_at + 1).unwrap(), day);
+        assert_eq!(long.overnight_fee(long.created_at + 86400).unwrap(), day);
+        assert_eq!(long.overnight_fee(long.created_at + 86401).unwrap(), 2 * day);
+    }
+
+    #[test]
+    fn test_day_rounding() {
+        assert_eq!(OVERNIGHT_FEE_ROUNDING, DayRounding::Ceil);
+        for (elapsed, ceil, floor) in [(0, 0, 0), (1, 1, 0), (86399, 1, 0), (86400, 1, 1), (86401, 2, 1)] {
+            assert_eq!(DayRounding::Ceil.days(elapsed).unwrap(), ceil);
+            assert_eq!(DayRounding::Floor.days(elapsed).unwrap(), floor);
+        }
+        assert_eq!(failure(DayRounding::Ceil.days(u64::MAX)), code(ProtocolError::MathOverflow));
+    }
+
+    #[test]
+    fn test_overnight_fee_same_block_close() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+
+        // opened and closed at the same time: no fee, not a full day's
+        let settlement = long.settle(&flat_price(30000_000_000), long.created_at).unwrap();
+        assert_eq!(settlement.overnight_fee, 0);
+        assert_eq!(settlement.returned_margin, 9000_000_000);
+    }
+
+    #[test]
+    fn test_overnight_fee_days_over_year() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 3;
+        let account = TransactionAccount::try_from(&long).unwrap();
+        let price = flat_price(30000_000_000);
+
+        // 3 bps a day on 90000 of notional is 10.95% a year
+        for (days, fee) in [(1, 27_000_000), (5, 135_000_000), (365, 9855_000_000)] {
+            let time = long.created_at + days as i64 * 86400;
+            assert_eq!(long.overnight_fee(time).unwrap(), fee);
+            assert_eq!(account.financing_fee(days).unwrap(), fee);
+
+            // the close charges the same financing `TransactionAccount` models
+            let settlement = long.settle(&price, time).unwrap();
+            assert_eq!(settlement.overnight_fee, fee.min(long.margin));
+            if fee < long.margin {
+                assert_eq!(
+                    settlement.returned_margin as i128,
+                    long.margin as i128 + account.get_profit(&price, days).unwrap(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_overnight_fee_settled_across_increase_margin() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let day = 9_000_000;
+        let unsettled = long.clone();
+
+        // margin added a day and a half in
+        long.settle_fees(long.created_at + 86400 + 43200).unwrap();
+        assert_eq!(long.accrued_fee, day);
+        assert_eq!(long.last_fee_settlement, long.created_at + 86400);
+        long.margin += 1000_000_000;
+
+        // settling again within the same day changes nothing
+        long.settle_fees(long.created_at + 86400 + 50000).unwrap();
+        assert_eq!(long.accrued_fee, day);
+
+        // the half day isn't charged twice: three days are three days
+        for time in [long.created_at + 86400 + 43200, long.created_at + 3 * 86400] {
+            assert_eq!(long.overnight_fee(time).unwrap(), unsettled.overnight_fee(time).unwrap());
+        }
+        assert_eq!(long.overnight_fee(long.created_at + 3 * 86400).unwrap(), 3 * day);
+    }
+
+    #[test]
+    fn test_realize_fees_mid_life() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let day = 9_000_000;
+        let (margin, liquidation) = (long.margin, long.liquidation);
+
+        // two and a half days in, two days come out of the margin
+        let time = long.created_at + 2 * 86400 + 43200;
+        assert_eq!(long.realize_fees(time, 0, 30000_000_000).unwrap(), 2 * day);
+        assert_eq!(long.margin, margin - 2 * day);
+        assert_eq!(long.accrued_fee, 0);
+        assert_eq!(long.last_fee_settlement, long.created_at + 2 * 86400);
+        // the bond shrinks by the whole fee and moves the long's liquidation price up
+        assert_eq!(long.liquidation, liquidation + 2 * day);
+
+        // the half day is still owed at close, not charged twice
+        assert_eq!(long.overnight_fee(long.created_at + 3 * 86400).unwrap(), long.overnight_fee_of_days(1).unwrap());
+
+        // a short's liquidation price moves down
+        let mut short = open_short_of_three();
+        short.overnight_fee_numerator = 1;
+        let liquidation = short.liquidation;
+        short.realize_fees(short.created_at + 86400, 0, 30000_000_000).unwrap();
+        assert!(short.liquidation < liquidation);
+    }
+
+    #[test]
+    fn test_realize_fees_rejects_liquidatable() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let time = long.created_at + 86400;
+
+        // just above the liquidation price before fees, at or below it after
+        let price = long.liquidation + 1;
+        assert_eq!(
+            failure(long.clone().realize_fees(time, 0, price)),
+            code(ProtocolError::PositionLiquidated),
+        );
+        long.realize_fees(time, 0, price + 9_000_000).unwrap();
+    }
+
+    #[test]
+    fn test_overnight_fee_settled_across_size_change() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+
+        // two days on 3 BTC, then the size doubles and a third day completes
+        long.settle_fees(long.created_at + 2 * 86400).unwrap();
+        long.amount *= 2;
+        assert_eq!(long.overnight_fee(long.created_at + 3 * 86400).unwrap(), 2 * 9_000_000 + 18_000_000);
+    }
+
+    #[test]
+    fn test_overnight_fee_overflow() {
+        let mut position = open_long_of_three();
+        position.overnight_fee_numerator = 1;
+
+        // a time before the position was opened
+        assert_eq!(
+            failure(position.overnight_fee(position.created_at - 2 * 86400)),
+            code(ProtocolError::MathOverflow),
+        );
+        assert_eq!(
+            failure(position.overnight_fee(i64::MIN)),
+            code(ProtocolError::MathOverflow),
+        );
+
+        // a notional that doesn't fit in a u64
+        position.amount = u64::MAX;
+        assert_eq!(
+            failure(position.overnight_fee(position.created_at)),
+            code(ProtocolError::MathOverflow),
+        );
+    }
+
+    #[test]
+    fn test_liquidated_margin_underflow() {
+        let mut position = open_long_of_three();
+        position.overnight_fee_numerator = 1;
+        assert_eq!(position.get_liquidated_margin(position.created_at + 86400, 0).unwrap(), 450_000_000 - 9_000_000);
+
+        // fees above the maintenance margin leave nothing to split
+        position.overnight_fee_numerator = 100;
+        assert_eq!(position.get_liquidated_margin(position.created_at + 86400, 0).unwrap(), 0);
+        let settlement = position.liquidated_settlement(position.created_at + 86400, 0, 1000).unwrap();
+        assert_eq!((settlement.returned_margin, settlement.insurance_fee), (0, 0));
+    }
+
+    #[test]
+    fn test_liquidate_liquidatable() {
+        let long = open_long_of_three();
+        assert_eq!(long.liquidation, 21450_000_000);
+        assert!(long.is_liquidated(21450_000_000));
+        assert!(long.is_liquidated(21000_000_000));
+
+        let result = long.liquidation_split(long.created_at, 0, 2000).unwrap();
+        assert_eq!(result.keeper_bonus, 45_000_000);
+        assert_eq!(result.insurance_fee, 45_000_000);
+        assert_eq!(result.returned_margin, 360_000_000);
+        assert_eq!(
+            result.keeper_bonus + result.insurance_fee + result.returned_margin,
+            long.maintainance_margin().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_liquidation_penalty_split() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let time = long.created_at + 86400;
+        let remaining = long.get_liquidated_margin(time, 0).unwrap();
+
+        for penalty_bps in [0, 1, 333, 2000, 5000, 9999, 10000] {
+            let result = long.liquidation_split(time, 0, penalty_bps).unwrap();
+            let penalty = remaining * penalty_bps / 10000;
+            assert_eq!(result.returned_margin, remaining - penalty);
+            assert_eq!(result.keeper_bonus, penalty * LIQUIDATION_BONUS_NUMERATOR / 10000);
+            assert_eq!(result.keeper_bonus + result.insurance_fee, penalty);
+
+            // a signed liquidation has no keeper: the insurance fund gets it all
+            let settlement = long.liquidated_settlement(time, 0, penalty_bps).unwrap();
+            assert_eq!(settlement.returned_margin, result.returned_margin);
+            assert_eq!(settlement.insurance_fee, penalty);
+            assert_eq!(
+                settlement.returned_margin + settlement.insurance_fee + settlement.overnight_fee,
+                long.maintainance_margin().unwrap(),
+            );
+        }
+
+        // no penalty hands the whole remaining margin back, as before
+        assert_eq!(long.liquidation_split(time, 0, 0).unwrap().returned_margin, remaining);
+        assert_eq!(long.liquidation_split(time, 0, 10000).unwrap().returned_margin, 0);
+    }
+
+    #[test]
+    fn test_single_feed_price() {
+        let btc = pyth_sdk_solana::Price { price: 30000_50000000, conf: 1_000_000, expo: -8 };
+        let usd = pyth_sdk_solana::Price { price: 100_000_000, conf: 0, expo: -8 };
+
+        // a quote feed at exactly 1 gives the same scaled price as none at all
+        let two_feeds = quote_price(&btc, Some(&usd), 6, 100).unwrap();
+        let single_feed = quote_price(&btc, None, 6, 100).unwrap();
+        assert_eq!(single_feed.expo, -6);
+        assert_eq!(single_feed.price, 30000_500000);
+        assert_eq!(two_feeds.price, single_feed.price);
+        assert_eq!(two_feeds.expo, single_feed.expo);
+
+        // on its own the feed keeps every digit the position can hold
+        let btc = pyth_sdk_solana::Price { price: 30000_12345678, ..btc };
+        assert_eq!(quote_price(&btc, None, 6, 100).unwrap().price, 30000_123456);
+
+        // a feed with fewer digits than the position is scaled up
+        let eth = pyth_sdk_solana::Price { price: 200_012, conf: 0, expo: -2 };
+        assert_eq!(quote_price(&eth, None, 6, 100).unwrap().price, 2000_120_000);
+
+        // a depegged quote moves the price in the collateral, which a pool
+        // without the quote feed ignores by design
+        let depegged = pyth_sdk_solana::Price { price: 98_000_000, ..usd };
+        let in_collateral = quote_price(&btc, Some(&depegged), 6, 100).unwrap();
+        // 30000.12 / 0.98, less what the feed division rounds off
+        assert!(in_collateral.price > 30612_000_000 && in_collateral.price < 30612_380_000);
+        assert_ne!(in_collateral.price, quote_price(&btc, None, 6, 100).unwrap().price);
+
+        // confidence is checked either way
+        let wide = pyth_sdk_solana::Price { conf: 600_000_000_000, ..btc };
+        assert_eq!(
+            failure(quote_price(&wide, None, 6, 100)),
+            code(ProtocolError::InvalidPrice),
+        );
+    }
+
+    #[test]
+    fn test_liquidation_source() {
+        let mut config = pool_config();
+        assert_eq!(config.liquidation_price_source(), PriceSource::Spot);
+        config.liquidate_on_ema = true;
+        assert_eq!(config.liquidation_price_source(), PriceSource::Ema);
+    }
+
+    #[test]
+    fn test_liquidate_spiky_spot_vs_ema() {
+        let long = open_long_of_three();
+        assert_eq!(long.liquidation, 21450_000_000);
+
+        // BTC/USD with expo -8 quoted in USDC/USD, scaled to 6 decimals
+        let usdc = pyth_sdk_solana::Price { price: 100_000_000, conf: 0, expo: -8 };
+        let btc = |price: i64| pyth_sdk_solana::Price { price: price * 100_000_000, conf: 0, expo: -8 };
+        // a one-tick wick to 21000 that the moving average barely registers
+        let spot = [30000, 21000, 29900];
+        let ema = [30000, 29100, 29000];
+
+        let liquidated = |series: [i64; 3]| -> Vec<bool> {
+            series
+                .iter()
+                .map(|price| {
+                    let price = quote_price(&btc(*price), Some(&usdc), long.decimals, 100).unwrap();
+                    long.is_liquidated(price.price as u64)
+                })
+                .collect()
+        };
+        assert_eq!(liquidated(spot), vec![false, true, false]);
+        assert_eq!(liquidated(ema), vec![false, false, false]);
+
+        // a sustained move still liquidates on the EMA
+        assert_eq!(liquidated([30000, 25000, 21000]), vec![false, false, true]);
+    }
+
+    #[test]
+    fn test_liquidate_not_liquidatable() {
+        let long = open_long_of_three();
+        assert!(!long.is_liquidated(21450_000_001));
+        assert!(!long.is_liquidated(30000_000_000));
+
+        let mut args = new_args(PositionType::Isolated, Direction::OpenShort);
+        args.leverage_margin = 90000_000_000;
+        let mut short = new_position(PositionType::Isolated, Direction::OpenShort, args.margin().unwrap());
+        short.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert!(!short.is_liquidated(38549_999_999));
+        assert!(short.is_liquidated(38550_000_000));
+    }
+
+    fn token_account_data(mint: Pubkey, owner: Pubkey) -> Vec<u8> {
+        use anchor_lang::solana_program::program_pack::Pack;
+        let account = anchor_spl::token::spl_token::state::Account {
+            mint,
+            owner,
+            state: anchor_spl::token::spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        };
+        let mut data = vec![0; anchor_spl::token::spl_token::state::Account::LEN];
+        account.pack_into_slice(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_release_cross_member() {
+        let mut position = open_long_of_three();
+        position.ptype = PositionType::Cross;
+        let (key, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let cross_account = CrossAccount {
+            pool: position.pool,
+            owner: position.owner,
+            mint: position.mint,
+            collateral: 15000_000_000,
+            used_margin: position.margin + 1000_000_000,
+            positions: vec![key, other],
+        };
+        let mut data = Vec::new();
+        cross_account.try_serialize(&mut data).unwrap();
+        data.resize(8 + CrossAccount::LEN, 0);
+        let (address, _) = Pubkey::find_program_address(&[b"cross", position.owner.as_ref(), position.pool.as_ref()], &crate::ID);
+        let mut lamports = 0;
+        let info = AccountInfo::new(&address, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+
+        // an isolated position has no account to settle with, a cross one
+        // can't close without it
+        let mut isolated = position.clone();
+        isolated.ptype = PositionType::Isolated;
+        let mut settlement = Settlement { bad_debt: 100, ..Settlement::default() };
+        release_cross_member(&[], &isolated, &key, isolated.margin, &mut settlement, &crate::ID).unwrap();
+        assert_eq!(settlement.bad_debt, 100);
+        assert_eq!(
+            failure(release_cross_member(&[], &position, &key, position.margin, &mut settlement, &crate::ID)),
+            code(ProtocolError::InvalidAccountData),
+        );
+
+        // a partial close gives up the margin closed and stays a member
+        let mut settlement = Settlement::default();
+        release_cross_member(std::slice::from_ref(&info), &position, &key, 1000_000_000, &mut settlement, &crate::ID).unwrap();
+        let settled = CrossAccount::try_deserialize(&mut &info.try_borrow_data().unwrap()[..]).unwrap();
+        assert_eq!(settled.positions, vec![key, other]);
+        assert_eq!((settled.collateral, settled.used_margin), (14000_000_000, position.margin));
+
+        // a full close leaves, its loss past the margin coming out of the
+        // 5000 of free collateral and the rest left to the insurance fund
+        position.status = PositionStatus::Processed;
+        let mut settlement = Settlement { bad_debt: 6500_000_000, ..Settlement::default() };
+        release_cross_member(std::slice::from_ref(&info), &position, &key, position.margin, &mut settlement, &crate::ID).unwrap();
+        assert_eq!(settlement.bad_debt, 1500_000_000);
+        let released = CrossAccount::try_deserialize(&mut &info.try_borrow_data().unwrap()[..]).unwrap();
+        assert_eq!(released.positions, vec![other]);
+        assert_eq!((released.collateral, released.used_margin), (0, 0));
+
+        // once released it is no longer a member to close
+        assert_eq!(
+            failure(release_cross_member(&[info], &position, &key, position.margin, &mut Settlement::default(), &crate::ID)),
+            code(ProtocolError::InvalidArgs),
+        );
+    }
+
+    #[test]
+    fn test_cross_liquidation_worst_first() {
+        let mut long = open_long_of_three();
+        let mut short = open_short_of_three();
+        long.ptype = PositionType::Cross;
+        short.ptype = PositionType::Cross;
+
+        // at 20000 the long is the one underwater
+        let price = flat_price(20000_000_000);
+        CrossAccount::check_worst_member(&[long.clone(), short.clone()], &price).unwrap();
+        assert_eq!(
+            failure(CrossAccount::check_worst_member(&[short.clone(), long.clone()], &price)),
+            code(ProtocolError::PositionNotLiquidated),
+        );
+        // and at 40000 the short
+        let price = flat_price(40000_000_000);
+        CrossAccount::check_worst_member(&[short.clone(), long.clone()], &price).unwrap();
+        assert!(CrossAccount::check_worst_member(&[long.clone(), short], &price).is_err());
+        CrossAccount::check_worst_member(&[long], &price).unwrap();
+        assert_eq!(failure(CrossAccount::check_worst_member(&[], &price)), code(ProtocolError::InvalidAccountData));
+
+        // the worst member closes at its own PnL: the long has lost 30000 on
+        // 9000 of margin, and the rest of the loss is the account's to cover
+        let mut long = open_long_of_three();
+        long.ptype = PositionType::Cross;
+        let settlement = long.settle(&flat_price(20000_000_000), long.created_at).unwrap();
+        assert_eq!(settlement.returned_margin, 0);
+        let mut cross_account = CrossAccount {
+            pool: Pubkey::default(),
+            owner: Pubkey::default(),
+            mint: Pubkey::default(),
+            collateral: 40000_000_000,
+            used_margin: long.margin,
+            positions: vec![Pubkey::default()],
+        };
+        let bad_debt = settlement.bad_debt;
+        assert!(bad_debt > 20000_000_000);
+        assert_eq!(cross_account.release(&Pubkey::default(), long.margin, bad_debt).unwrap(), 0);
+        assert_eq!(cross_account.collateral, 40000_000_000 - long.margin - bad_debt);
+        assert_eq!(cross_account.used_margin, 0);
+    }
+
+    #[test]
+    fn test_payout_recipient() {
+        let mint = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        // a recipient that is neither the owner nor the payer
+        let recipient_owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = token_account_data(mint, recipient_owner);
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &token::ID, false, 0);
+
+        assert!(payout_recipient(&[], &mint, None).unwrap().is_none());
+        let recipient = payout_recipient(std::slice::from_ref(&info), &mint, None).unwrap().unwrap();
+        assert_eq!(recipient.key(), key);
+        assert_eq!(recipient.owner, recipient_owner);
+
+        // a cross position's account is passed alongside, in either order
+        let cross_key = Pubkey::new_unique();
+        let mut cross_lamports = 0;
+        let mut cross_data = Vec::new();
+        let cross_info = AccountInfo::new(&cross_key, false, true, &mut cross_lamports, &mut cross_data, &crate::ID, false, 0);
+        assert!(payout_recipient(std::slice::from_ref(&cross_info), &mint, Some(cross_key)).unwrap().is_none());
+        for remaining in [[cross_info.clone(), info.clone()], [info.clone(), cross_info]] {
+            assert_eq!(payout_recipient(&remaining, &mint, Some(cross_key)).unwrap().unwrap().key(), key);
+        }
+
+        assert_eq!(
+            failure(payout_recipient(std::slice::from_ref(&info), &Pubkey::new_unique(), None)),
+            code(ProtocolError::InvalidAccountData),
+        );
+
+        let mut read_only = info;
+        read_only.is_writable = false;
+        assert_eq!(
+            failure(payout_recipient(&[read_only], &mint, None)),
+            code(ProtocolError::InvalidAccountData),
+        );
+    }
+
+    #[test]
+    fn test_position_opened_event_decodes() {
+        let long = open_long_of_three();
+        let event = PositionOpened {
+            position: Pubkey::new_unique(),
+            owner: long.owner,
+            direction: long.direction,
+            entry_price: long.last_price,
+            amount: long.amount,
+            margin: long.margin,
+        };
+
+        let data = event.data();
+        assert_eq!(data[..8], PositionOpened::discriminator());
+        let decoded = PositionOpened::try_from_slice(&data[8..]).unwrap();
+        assert_eq!(decoded.position, event.position);
+        assert_eq!(decoded.direction, Direction::OpenLong);
+        assert_eq!(decoded.entry_price, 30000_000_000);
+        assert_eq!(decoded.amount, 3_000_000);
+        assert_eq!(decoded.margin, 9000_000_000);
+    }
+
+    #[test]
+    fn test_margin_changed_events_in_order() {
+        let key = Pubkey::new_unique();
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 3;
+        let day = long.created_at + 86400;
+        let mut logged = Vec::new();
+
+        // each change made the way its handler makes it, logging what it emits
+        let ((), event) = long.change_margin(key, MarginChangeReason::Deposit, |position| position.deposit(1000_000_000)).unwrap();
+        logged.push(event.data());
+        let ((), event) = long.change_margin(key, MarginChangeReason::Withdraw, |position| position.withdraw(500_000_000, 30000_000_000)).unwrap();
+        logged.push(event.data());
+        let (fee, event) = long.change_margin(key, MarginChangeReason::Fee, |position| position.realize_fees(day, 0, 30000_000_000)).unwrap();
+        logged.push(event.data());
+        let (_, event) = long.change_margin(key, MarginChangeReason::RealizedPnl, |position| position.partial_close(1_000_000, &flat_price(30000_000_000), day)).unwrap();
+        logged.push(event.data());
+
+        let decoded: Vec<MarginChanged> = logged
+            .iter()
+            .map(|data| {
+                assert_eq!(data[..8], MarginChanged::discriminator());
+                MarginChanged::try_from_slice(&data[8..]).unwrap()
+            })
+            .collect();
+        assert!(decoded.iter().all(|event| event.position == key));
+        let reasons: Vec<_> = decoded.iter().map(|event| event.reason).collect();
+        assert_eq!(reasons, [
+            MarginChangeReason::Deposit,
+            MarginChangeReason::Withdraw,
+            MarginChangeReason::Fee,
+            MarginChangeReason::RealizedPnl,
+        ]);
+        assert_eq!((decoded[0].old_margin, decoded[0].new_margin), (9000_000_000, 10000_000_000));
+        assert_eq!((decoded[1].old_margin, decoded[1].new_margin), (10000_000_000, 9500_000_000));
+        assert!(fee > 0);
+        assert_eq!((decoded[2].old_margin, decoded[2].new_margin), (9500_000_000, 9500_000_000 - fee));
+        // a third of the size releases a third of the margin
+        let after_fee = 9500_000_000 - fee;
+        assert_eq!((decoded[3].old_margin, decoded[3].new_margin), (after_fee, after_fee - after_fee / 3));
+        assert_eq!(decoded[3].new_margin, long.margin);
+
+        // a change that fails logs nothing and leaves the margin alone
+        assert!(long.change_margin(key, MarginChangeReason::Withdraw, |position| position.withdraw(u64::MAX, 30000_000_000)).is_err());
+        assert_eq!(long.margin, decoded[3].new_margin);
+    }
+
+    #[test]
+    fn test_position_processed_event_decodes() {
+        let long = open_long_of_three();
+        let returned_margin = long.get_profit(&flat_price(33000_000_000), long.created_at).unwrap();
+        let event = PositionProcessed {
+            position: Pubkey::new_unique(),
+            owner: long.owner,
+            direction: long.direction,
+            entry_price: long.last_price,
+            margin: long.margin,
+            returned_margin,
+            pnl: realized_pnl(long.margin, returned_margin).unwrap(),
+        };
+
+        let data = event.data();
+        assert_eq!(data[..8], PositionProcessed::discriminator());
+        let decoded = PositionProcessed::try_from_slice(&data[8..]).unwrap();
+        assert_eq!(decoded.returned_margin, 18000_000_000);
+        assert_eq!(decoded.pnl, 9000_000_000);
+        assert_eq!(realized_pnl(9000_000_000, 8000_000_000).unwrap(), -1000_000_000);
+    }
+
+    #[test]
+    fn test_collateral_mode() {
+        let mut config = pool_config();
+        config.check_collateral_mode(CollateralMode::Token).unwrap();
+        assert_eq!(
+            failure(config.check_collateral_mode(CollateralMode::Native)),
+            code(ProtocolError::CollateralModeMismatch),
+        );
+
+        // `set` leaves the mode as created
+        let args = PoolConfigArgs {
+            max_leverage: config.max_leverage,
+            max_leverage_long: config.max_leverage_long,
+            max_leverage_short: config.max_leverage_short,
+            maintenance_margin_bps: config.maintenance_margin_bps,
+            max_slippage_bps: config.max_slippage_bps,
+            overnight_fee_bps: config.overnight_fee_bps,
+            maker_fee_bps: config.maker_fee_bps,
+            taker_fee_bps: config.taker_fee_bps,
+            max_long_oi: config.max_long_oi,
+            max_short_oi: config.max_short_oi,
+            max_notional: config.max_notional,
+            funding_rate_bps: config.funding_rate_bps,
+            min_margin: config.min_margin,
+            min_notional: config.min_notional,
+            min_hold_seconds: config.min_hold_seconds,
+            liquidation_penalty_bps: config.liquidation_penalty_bps,
+            collateral_mode: CollateralMode::Native,
+            conf_mode: config.conf_mode,
+            paused: false,
+            liquidate_on_ema: false,
+            permissionless_liquidation: true,
+            max_payout_multiple: config.max_payout_multiple,
+            max_price_age: config.max_price_age,
+            max_conf_bps: config.max_conf_bps,
+            oracle_authority: config.oracle_authority,
+        };
+        config.set(&args);
+        assert_eq!(config.collateral_mode, CollateralMode::Token);
+    }
+
+    #[test]
+    fn test_native_position_open_and_close() {
+        let mut config = pool_config();
+        config.max_long_oi = u64::MAX;
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+
+        // 9 SOL of margin at 10x on a SOL/USD-quoted pool, with lamport decimals
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 90_000_000_000;
+        args.price = 30_000_000_000;
+        args.expo = -9;
+        args.decimals = 9;
+
+        // only a native pool takes lamports, and only for isolated positions
+        assert_eq!(failure(check_native_args(&config, args)), code(ProtocolError::CollateralModeMismatch));
+        config.collateral_mode = CollateralMode::Native;
+        assert_eq!(
+            failure(check_native_args(&config, PositionArgs { ptype: PositionType::Cross, ..args })),
+            code(ProtocolError::InvalidArgs),
+        );
+        let args = check_native_args(&config, args).unwrap();
+
+        // `create_native` opens it as any isolated position, under the pool's
+        // rates and limits, with the opening fee paid out of the margin
+        let entry = pyth_sdk_solana::Price { price: 30_000_000_000, conf: 0, expo: -9 };
+        let (mut long, trading_fee) = open_isolated(&mut config, Pubkey::new_unique(), NATIVE_MINT, 0, &args, &entry, &clock, 0).unwrap();
+        assert_eq!(long.mint, NATIVE_MINT);
+        assert_eq!(long.amount, 3_000_000_000);
+        assert_eq!(trading_fee, 90_000_000);
+        assert_eq!(long.margin, 9_000_000_000 - 90_000_000);
+        assert_eq!((long.max_price_age, long.max_conf_bps), (config.max_price_age, config.max_conf_bps));
+        assert_eq!(config.long_oi, 3_000_000_000);
+
+        // `process_position_native` closes it on signed data, here at a 10% gain
+        let key = Pubkey::new_unique();
+        let signed = signed_close(&long, key, false);
+        let price = pyth_sdk_solana::Price { price: 33_000_000_000, conf: 0, expo: -9 };
+        let expected = long.settle(&price, signed.data.time).unwrap();
+        let (settlement, status) = close_signed(&mut long, &key, &config, &signed, 1234, signed.data.time, |_| Ok(price)).unwrap();
+        assert_eq!(status, BatchStatus::Closed);
+        assert_eq!(settlement, expected);
+        assert!(settlement.returned_margin > long.margin);
+        assert_eq!(settlement.bad_debt, 0);
+
+        // the vault pays it out only from what it holds above its rent-exempt minimum
+        let rent = 890_880;
+        let owed = settlement.returned_margin + settlement.overnight_fee + settlement.trading_fee;
+        check_native_payout(&settlement, rent + owed, rent, rent).unwrap();
+        assert_eq!(
+            failure(check_native_payout(&settlement, rent + owed - 1, rent, rent)),
+            code(ProtocolError::InsufficientVaultBalance),
+        );
+
+        // bad debt is covered by the insurance fund, into the vault, first
+        let underwater = Settlement {
+            returned_margin: 0,
+            overnight_fee: 500,
+            trading_fee: 500,
+            bad_debt: 1_000,
+            insurance_fee: 0,
+        };
+        check_native_payout(&underwater, rent, rent + 1_000, rent).unwrap();
+        assert_eq!(
+            failure(check_native_payout(&underwater, rent, rent + 999, rent)),
+            code(ProtocolError::InsufficientInsuranceFund),
+        );
+        assert_eq!(
+            failure(check_native_payout(&Settlement { overnight_fee: 501, ..underwater }, rent, rent + 1_000, rent)),
+            code(ProtocolError::InsufficientVaultBalance),
+        );
+
+        assert_eq!(spendable_lamports(1_000_890_880, 890_880), 1_000_000_000);
+        assert_eq!(spendable_lamports(890_000, 890_880), 0);
+    }
+
+    #[test]
+    fn test_position_counter() {
+        let mut counter = PositionCounter {
+            owner: Pubkey::new_unique(),
+            next_index: 0,
+        };
+
+        // indexes are handed out in turn
+        for index in 0..3 {
+            assert_eq!(counter.claim(index).unwrap(), index);
+        }
+        assert_eq!(counter.next_index, 3);
+
+        // a taken index, or one ahead of the counter, is refused
+        for index in [2, 4] {
+            assert_eq!(failure(counter.claim(index)), code(ProtocolError::PositionIndexInUse));
+        }
+        assert_eq!(counter.next_index, 3);
+
+        counter.next_index = u32::MAX;
+        assert_eq!(failure(counter.claim(u32::MAX)), code(ProtocolError::MathOverflow));
+    }
+
+    #[test]
+    fn test_check_pool() {
+        let mut long = open_long_of_three();
+        long.pool = Pubkey::new_unique();
+        assert!(long.check_pool(&long.pool).is_ok());
+        assert_eq!(failure(long.check_pool(&Pubkey::new_unique())), code(ProtocolError::PoolMismatch));
+    }
+
+    #[test]
+    fn test_close_result_decodes() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let time = long.created_at + 86400;
+        let price = flat_price(33000_000_000);
+
+        let mut signed = signed_close(&long, Pubkey::new_unique(), false);
+        signed.authority = Pubkey::new_unique();
+
+        let settlement = long.settle(&price, time).unwrap();
+        let result = CloseResult::new(&settlement, long.margin, long.exit_price(&price).unwrap(), &signed).unwrap();
+        let decoded = CloseResult::try_from_slice(&result.try_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded, CloseResult {
+            returned_margin: 18000_000_000 - 9_000_000,
+            gross_pnl: 9000_000_000,
+            overnight_fee: 9_000_000,
+            trading_fee: 0,
+            exit_price: 33000_000_000,
+            was_liquidated: false,
+            authenticated: signed,
+        });
+
+        // a liquidation loses the margin above maintenance, at the signed price
+        let returned_margin = long.get_liquidated_margin(time, 0).unwrap();
+        let settlement = Settlement {
+            returned_margin,
+            overnight_fee: long.overnight_fee(time).unwrap(),
+            trading_fee: 0,
+            bad_debt: 0,
+            insurance_fee: 0,
+        };
+        signed.data.is_liquidated = true;
+        let result = CloseResult::new(&settlement, long.margin, 21000_000_000, &signed).unwrap();
+        let decoded = CloseResult::try_from_slice(&result.try_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded, CloseResult {
+            returned_margin: 450_000_000 - 9_000_000,
+            gross_pnl: 450_000_000 - 9000_000_000,
+            overnight_fee: 9_000_000,
+            trading_fee: 0,
+            exit_price: 21000_000_000,
+            was_liquidated: true,
+            authenticated: signed,
+        });
+        // the verified signer and flag can be read back on their own
+        let decoded = AuthenticatedData::try_from_slice(&decoded.authenticated.try_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.authority, signed.authority);
+        assert!(decoded.data.is_liquidated);
+
+        // bad debt counts towards the loss
+        signed.data.is_liquidated = false;
+        let settlement = long.settle(&flat_price(20000_000_000), time).unwrap();
+        let result = CloseResult::new(&settlement, long.margin, 20000_000_000, &signed).unwrap();
+        assert_eq!(result.gross_pnl, -30000_000_000);
+    }
+
+    fn open_long_of_one() -> Position {
+        let mut args = new_args(PositionType::Isolated, Direction::OpenLong);
+        args.leverage_margin = 30000_000_000;
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &flat_price(30000_000_000), 0).unwrap();
+        assert_eq!(long.amount, 1_000_000);
+        assert_eq!(long.margin, 3000_000_000);
+        assert_eq!(long.liquidation, 27150_000_000);
+        long
+    }
+
+    #[test]
+    fn test_health_healthy() {
+        let long = open_long_of_one();
+        let health = long.health(&flat_price(30000_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, 3000 * 10000 / 150);
+        assert_eq!(health.liquidation_distance, 2850_000_000);
+
+        let health = long.health(&flat_price(31000_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, 4000 * 10000 / 150);
+    }
+
+    #[test]
+    fn test_health_near_liquidation() {
+        let long = open_long_of_one();
+        let health = long.health(&flat_price(27200_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, 200 * 10000 / 150);
+        assert_eq!(health.liquidation_distance, 50_000_000);
+
+        // past the liquidation price
+        let health = long.health(&flat_price(26000_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, 0);
+        assert_eq!(health.liquidation_distance, 0);
+
+        // and past the margin, which leaves bad debt rather than an error
+        let health = long.health(&flat_price(20000_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, 0);
+    }
+
+    #[test]
+    fn test_health_without_maintenance_margin() {
+        let mut long = open_long_of_one();
+        long.margin_rate_numerator = 0;
+        assert_eq!(long.maintainance_margin().unwrap(), 0);
+        let health = long.health(&flat_price(30000_000_000), long.created_at).unwrap();
+        assert_eq!(health.margin_ratio_bps, u64::MAX);
+
+        // an error pricing the close isn't read as zero equity
+        long.leverage = 0;
+        assert_eq!(
+            failure(long.health(&flat_price(30000_000_000), long.created_at)),
+            code(ProtocolError::MathOverflow),
+        );
+    }
+
+    fn ed25519_data(authority: &Pubkey, message: &[u8]) -> Vec<u8> {
+        let sig_offset = ED25519_HEADER_LEN as u16;
+        let pubkey_offset = sig_offset + 64;
+        let data_offset = pubkey_offset + 32;
+
+        let mut data = vec![1, 0];
+        for value in [
+            sig_offset,
+            u16::MAX,
+            pubkey_offset,
+            u16::MAX,
+            data_offset,
+            message.len() as u16,
+            u16::MAX,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(message);
+        data
+    }
+
+    fn signed_close(position: &Position, key: Pubkey, is_liquidated: bool) -> AuthenticatedData {
+        AuthenticatedData {
+            data: LiquidatedData {
+                position: key,
+                is_liquidated,
+                price: 21000_000_000,
+                time: position.created_at + 86400,
+                slot: 1234,
+            },
+            authority: oracle(),
+        }
+    }
+
+    #[test]
+    fn test_batch_close_mixed() {
+        let config = pool_config();
+        let mut positions = [open_long_of_three(), open_short_of_three(), open_long_of_three()];
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let signed = [
+            signed_close(&positions[0], keys[0], false),
+            signed_close(&positions[1], keys[1], false),
+            signed_close(&positions[2], keys[2], true),
+        ];
+        let time = positions[0].created_at + 86400;
+        let price = flat_price(31000_000_000);
+
+        // the long profits, the short loses and the last is liquidated
+        let expected = [
+            positions[0].settle(&price, time).unwrap(),
+            positions[1].settle(&price, time).unwrap(),
+            positions[2].liquidated_settlement(time, config.cumulative_funding, config.liquidation_penalty_bps).unwrap(),
+        ];
+        assert!(expected[0].returned_margin > positions[0].margin);
+        assert!(expected[1].returned_margin < positions[1].margin);
+
+        let (total, statuses) = run_batch(positions.iter_mut().zip(keys.iter().zip(signed.iter())), false, |(position, (key, signed))| {
+            close_signed(position, key, &config, signed, 1234, time, |_| Ok(price))
+        })
+        .unwrap();
+        assert_eq!(statuses, vec![BatchStatus::Closed, BatchStatus::Closed, BatchStatus::Liquidated]);
+        assert_eq!(total, expected.iter().fold(Settlement::default(), |total, s| total.checked_add(s).unwrap()));
+        assert!(positions.iter().all(|position| position.status == PositionStatus::Processed));
+    }
+
+    #[test]
+    fn test_signed_close_authority() {
+        // a position is stamped with the pool's oracle key as it opens
+        let mut config = pool_config();
+        let clock = Clock { slot: 1234, unix_timestamp: 1_650_000_000, ..Clock::default() };
+        let args = check_isolated_args(&config, new_args(PositionType::Isolated, Direction::OpenLong)).unwrap();
+        let (mut long, _) = open_isolated(&mut config, Pubkey::new_unique(), Pubkey::new_unique(), 0, &args, &btc_usdc(), &clock, 0).unwrap();
+        assert_eq!(long.authority, oracle());
+        let key = Pubkey::new_unique();
+        let time = long.created_at + 86400;
+        let price = flat_price(31000_000_000);
+
+        // data signed by any other key is refused
+        let mut forged = signed_close(&long, key, false);
+        forged.authority = Pubkey::new_unique();
+        assert_eq!(
+            failure(close_signed(&mut long.clone(), &key, &config, &forged, 1234, time, |_| Ok(price))),
+            code(ProtocolError::InvalidAuthority),
+        );
+
+        // and a position opened without a key can't be closed by the zero key
+        let mut unsigned = long.clone();
+        unsigned.authority = Pubkey::default();
+        forged.authority = Pubkey::default();
+        assert_eq!(
+            failure(close_signed(&mut unsigned, &key, &config, &forged, 1234, time, |_| Ok(price))),
+            code(ProtocolError::InvalidAuthority),
+        );
+
+        let signed = signed_close(&long, key, false);
+        close_signed(&mut long, &key, &config, &signed, 1234, time, |_| Ok(price)).unwrap();
+        assert_eq!(long.status, PositionStatus::Processed);
+    }
+
+    #[test]
+    fn test_process_twice() {
+        let config = pool_config();
+        let mut long = open_long_of_three();
+        let key = Pubkey::new_unique();
+        let signed = signed_close(&long, key, false);
+        let time = long.created_at + 86400;
+        let price = flat_price(31000_000_000);
+        close_signed(&mut long, &key, &config, &signed, 1234, time, |_| Ok(price)).unwrap();
+        assert_eq!(long.status, PositionStatus::Processed);
+
+        // the same signed close again pays out nothing
+        assert_eq!(
+            failure(close_signed(&mut long, &key, &config, &signed, 1234, time, |_| Ok(price))),
+            code(ProtocolError::AlreadyProcessed)
+        );
+        let liquidated = signed_close(&long, key, true);
+        assert_eq!(
+            failure(close_signed(&mut long, &key, &config, &liquidated, 1234, time, |_| Ok(price))),
+            code(ProtocolError::AlreadyProcessed)
+        );
+    }
+
+    #[test]
+    fn test_batch_close_best_effort() {
+        let config = pool_config();
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let time = open_long_of_three().created_at + 86400;
+        let price = flat_price(31000_000_000);
+        let batch = |best_effort: bool| {
+            let mut positions = [open_long_of_three(), open_short_of_three(), open_long_of_three()];
+            let mut signed = [
+                signed_close(&positions[0], keys[0], false),
+                signed_close(&positions[1], keys[1], false),
+                signed_close(&positions[2], keys[2], true),
+            ];
+            // signed by someone else, and signed for another position
+            signed[1].authority = Pubkey::new_unique();
+            signed[2].data.position = keys[0];
+            let result = run_batch(positions.iter_mut().zip(keys.iter().zip(signed.iter())), best_effort, |(position, (key, signed))| {
+                close_signed(position, key, &config, signed, 1234, time, |_| Ok(price))
+            });
+            (result, positions)
+        };
+
+        let (result, positions) = batch(true);
+        let (total, statuses) = result.unwrap();
+        assert_eq!(statuses, vec![BatchStatus::Closed, BatchStatus::Skipped, BatchStatus::Skipped]);
+        assert_eq!(total, positions[0].settle(&price, time).unwrap());
+        assert_eq!(positions[1].status, PositionStatus::Open);
+        assert_eq!(positions[2].status, PositionStatus::Open);
+
+        // without it the first bad position fails the batch
+        assert_eq!(failure(batch(false).0), code(ProtocolError::InvalidAuthority));
+    }
+
+    fn liquidated_data() -> LiquidatedData {
+        LiquidatedData {
+            position: Pubkey::default(),
+            is_liquidated: true,
+            price: 21000_000_000,
+            time: 1_650_000_000,
+            slot: 1234,
+        }
+    }
+
+    fn domain() -> SignedDomain {
+        SignedDomain::new(Pubkey::new_from_array([7; 32]))
+    }
+
+    fn signed_message(domain: &SignedDomain, data: &LiquidatedData) -> Vec<u8> {
+        domain.message(data)
+    }
+
+    /// Serializes `instructions` the way the runtime lays out the instructions
+    /// sysvar, with `current` as the executing one.
+    fn instructions_sysvar(instructions: &[anchor_lang::solana_program::instruction::Instruction], current: u16) -> Vec<u8> {
+        let mut data = (instructions.len() as u16).to_le_bytes().to_vec();
+        let mut bodies = Vec::new();
+        for instruction in instructions {
+            let offset = 2 + 2 * instructions.len() + bodies.len();
+            data.extend_from_slice(&(offset as u16).to_le_bytes());
+            bodies.extend_from_slice(&(instruction.accounts.len() as u16).to_le_bytes());
+            for meta in &instruction.accounts {
+                bodies.push(meta.is_signer as u8 | (meta.is_writable as u8) << 1);
+                bodies.extend_from_slice(meta.pubkey.as_ref());
+            }
+            bodies.extend_from_slice(instruction.program_id.as_ref());
+            bodies.extend_from_slice(&(instruction.data.len() as u16).to_le_bytes());
+            bodies.extend_from_slice(&instruction.data);
+        }
+        data.extend_from_slice(&bodies);
+        data.extend_from_slice(&current.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_build_ed25519_instruction_round_trip() {
+        use anchor_lang::solana_program::{instruction::Instruction, sysvar};
+
+        let authority = Pubkey::new_unique();
+        let pool = domain().pool;
+        let signature = [9; 64];
+        let ed25519_ix = build_ed25519_instruction(&authority, &pool, &liquidated_data(), &signature);
+        assert_eq!(ed25519_ix.data[ED25519_HEADER_LEN..ED25519_HEADER_LEN + 64], signature);
+        let process_ix = Instruction {
+            program_id: crate::ID,
+            accounts: vec![],
+            data: vec![],
+        };
+
+        let key = sysvar::instructions::id();
+        let owner = sysvar::id();
+        let mut lamports = 0;
+        let mut data = instructions_sysvar(&[ed25519_ix, process_ix], 1);
+        let account = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+
+        let authenticated = verify_and_extract(&account, &pool).unwrap();
+        assert_eq!(authenticated.authority, authority);
+        assert_eq!(authenticated.data, liquidated_data());
+
+        // the same instruction doesn't verify for another pool
+        assert_eq!(
+            failure(verify_and_extract(&account, &Pubkey::new_unique())),
+            code(ProtocolError::InvalidSignature),
+        );
+    }
+
+    #[test]
+    fn test_extract_ed25519_data_domain() {
+        let authority = Pubkey::new_unique();
+        let data = ed25519_data(&authority, &signed_message(&domain(), &liquidated_data()));
+        assert_eq!(extract_ed25519_data(&data, &domain()).unwrap().data, liquidated_data());
+
+        // tagged for another program, another market or another layout
+        for tag in [
+            SignedDomain { program_id: Pubkey::new_unique(), ..domain() },
+            SignedDomain { pool: Pubkey::new_unique(), ..domain() },
+            SignedDomain { version: SIGNED_DATA_VERSION + 1, ..domain() },
+        ] {
+            let data = ed25519_data(&authority, &signed_message(&tag, &liquidated_data()));
+            assert_eq!(
+                failure(extract_ed25519_data(&data, &domain())),
+                code(ProtocolError::InvalidSignature),
+            );
+        }
+
+        // an untagged message doesn't parse
+        let data = ed25519_data(&authority, &liquidated_data().try_to_vec().unwrap());
+        assert_eq!(
+            failure(extract_ed25519_data(&data, &domain())),
+            code(ProtocolError::InvalidEd25519Instruction),
+        );
+    }
+
+    #[test]
+    fn test_extract_ed25519_data() {
+        let authority = Pubkey::new_unique();
+        let data = ed25519_data(&authority, &signed_message(&domain(), &liquidated_data()));
+
+        let authenticated = extract_ed25519_data(&data, &domain()).unwrap();
+        assert_eq!(authenticated.authority, authority);
+        assert_eq!(authenticated.data, liquidated_data());
+    }
+
+    #[test]
+    fn test_extract_ed25519_data_truncated() {
+        let authority = Pubkey::new_unique();
+        let data = ed25519_data(&authority, &signed_message(&domain(), &liquidated_data()));
+
+        // every truncation fails cleanly, from an empty header to a partial message
+        for len in 0..data.len() {
+            assert_eq!(
+                failure(extract_ed25519_data(&data[..len], &domain())),
+                code(ProtocolError::InvalidEd25519Instruction),
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_ed25519_data_out_of_bounds_offsets() {
+        let authority = Pubkey::new_unique();
+        let message = signed_message(&domain(), &liquidated_data());
+
+        let mut data = ed25519_data(&authority, &message);
+        data[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(
+            failure(extract_ed25519_data(&data, &domain())),
+            code(ProtocolError::InvalidEd25519Instruction),
+        );
+
+        let mut data = ed25519_data(&authority, &message);
+        data[12..14].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(
+            failure(extract_ed25519_data(&data, &domain())),
+            code(ProtocolError::InvalidEd25519Instruction),
+        );
+    }
+
+    #[test]
+    fn test_extract_ed25519_data_over_length() {
+        let authority = Pubkey::new_unique();
+        let mut message = signed_message(&domain(), &liquidated_data());
+
+        // trailing instruction data outside the message is ignored
+        let mut data = ed25519_data(&authority, &message);
+        data.extend_from_slice(&[0; 16]);
+        assert!(extract_ed25519_data(&data, &domain()).is_ok());
+
+        // but a message longer than a tagged `LiquidatedData` is rejected
+        message.extend_from_slice(&[0; 16]);
+        let data = ed25519_data(&authority, &message);
+        assert_eq!(
+            failure(extract_ed25519_data(&data, &domain())),
+            code(ProtocolError::InvalidEd25519Instruction),
+        );
+    }
+
+    #[test]
+    fn test_signed_data_fresh() {
+        let data = liquidated_data();
+        assert!(check_signed_data(&data, &data.position, data.slot).is_ok());
+        assert!(check_signed_data(&data, &data.position, data.slot + MAX_SIGNATURE_AGE_SLOTS).is_ok());
+    }
+
+    #[test]
+    fn test_signed_data_replay() {
+        let data = liquidated_data();
+        assert_eq!(
+            failure(check_signed_data(&data, &data.position, data.slot + MAX_SIGNATURE_AGE_SLOTS + 1)),
+            code(ProtocolError::StaleSignature),
+        );
+        // signed for a slot that hasn't happened yet
+        assert_eq!(
+            failure(check_signed_data(&data, &data.position, data.slot - 1)),
+            code(ProtocolError::StaleSignature),
+        );
+    }
+
+    #[test]
+    fn test_signed_data_wrong_position() {
+        let data = liquidated_data();
+        assert_eq!(
+            failure(check_signed_data(&data, &Pubkey::new_unique(), data.slot)),
+            code(ProtocolError::StaleSignature),
+        );
+    }
+
+    #[test]
+    fn test_close_payout_accounts_for_margin() {
+        let mut long = open_long_of_three();
+        long.overnight_fee_numerator = 1;
+        let fee = long.overnight_fee(long.created_at + 86400).unwrap();
+
+        // profit: the owner and the fee account together get margin plus PnL
+        let returned = long.get_profit(&flat_price(33000_000_000), long.created_at + 86400).unwrap();
+        assert_eq!(returned + fee, long.margin + 9000_000_000);
+
+        // loss: what isn't paid out stays in the vault
+        let returned = long.get_profit(&flat_price(29000_000_000), long.created_at + 86400).unwrap();
+        assert_eq!(returned + fee + 3000_000_000, long.margin);
+
+        // liquidation: the maintenance margin is split between owner, keeper and fees
+        let result = long.liquidation_split(long.created_at + 86400, 0, 2000).unwrap();
+        assert_eq!(
+            result.returned_margin + result.keeper_bonus + result.insurance_fee + fee,
+            long.maintainance_margin().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_isolated_open_uses_own_bond() {
+        let args = new_args(PositionType::Isolated, Direction::OpenLong);
+        let mut long = new_position(PositionType::Isolated, Direction::OpenLong, args.margin().unwrap());
+        long.open(&args, &btc_usdc(), 0).unwrap();
+        assert_eq!(long.liquidation, 30005_000_000 - 95_000_117);
+    }
+
+    /// Invariants of the PnL and fee math over generated positions and prices.
+    mod properties {
+        use super::{flat_price, new_position};
+        use crate::{Direction, Liquidity, Position, PositionType};
+        use proptest::prelude::*;
+
+        /// a million per unit at 6 decimals
+        const MAX_PRICE: i64 = 1_000_000_000_000;
+        /// a million units at 6 decimals; with `MAX_PRICE` twice over the
+        /// notional still fits in a `u64`
+        const MAX_AMOUNT: u64 = 1_000_000_000_000;
+
+        fn direction(long: bool) -> Direction {
+            if long {
+                Direction::OpenLong
+            } else {
+                Direction::OpenShort
+            }
+        }
+
+        fn position_at(direction: Direction, entry: i64, amount: u64, margin: u64) -> Position {
+            let mut position = new_position(PositionType::Isolated, direction, margin);
+            position.leverage = 10;
+            position.last_price = entry;
+            position.amount = amount;
+            position
+        }
+
+        /// What the position can make at `price` before fees, rounded like its PnL.
+        fn max_gain(position: &Position, price: i64) -> u128 {
+            let diff = match position.direction {
+                Direction::OpenLong => price as i128 - position.last_price as i128,
+                Direction::OpenShort => position.last_price as i128 - price as i128,
+            };
+            (diff.max(0) as u128 * position.amount as u128) / 1_000_000
+        }
+
+        proptest! {
+            #[test]
+            fn prop_close_at_entry_returns_margin(
+                long in any::<bool>(),
+                entry in 1..=MAX_PRICE,
+                amount in 1..=MAX_AMOUNT,
+                margin in 0..=u64::MAX / 2,
+            ) {
+                let position = position_at(direction(long), entry, amount, margin);
+                let price = flat_price(entry);
+                prop_assert_eq!(position.get_profit(&price, position.created_at).unwrap(), margin);
+                prop_assert_eq!(position.settle(&price, position.created_at).unwrap().returned_margin, margin);
+            }
+
+            #[test]
+            fn prop_long_and_short_mirror(
+                entry in 1..=MAX_PRICE,
+                move_by in -MAX_PRICE..=MAX_PRICE,
+                amount in 1..=MAX_AMOUNT,
+                margin in 0..=u64::MAX / 2,
+            ) {
+                // the long at `entry + move_by` against the short at `entry - move_by`
+                let move_by = move_by.clamp(-entry, entry);
+                let long = position_at(Direction::OpenLong, entry, amount, margin);
+                let short = position_at(Direction::OpenShort, entry, amount, margin);
+                let up = flat_price(entry + move_by);
+                let down = flat_price(entry - move_by);
+
+                prop_assert_eq!(long.unrealized_pnl(&up).unwrap(), short.unrealized_pnl(&down).unwrap());
+                prop_assert_eq!(long.get_profit(&up, long.created_at).ok(), short.get_profit(&down, short.created_at).ok());
+                prop_assert_eq!(long.settle(&up, long.created_at).unwrap(), short.settle(&down, short.created_at).unwrap());
+            }
+
+            #[test]
+            fn prop_payout_within_margin_and_gain(
+                long in any::<bool>(),
+                entry in 1..=MAX_PRICE,
+                price in 0..=2 * MAX_PRICE,
+                conf in 0..=1_000_000_000u64,
+                amount in 1..=MAX_AMOUNT,
+                margin in 0..=u64::MAX / 2,
+                maker_fee_bps in 0..=10000u64,
+                taker_fee_bps in 0..=10000u64,
+                overnight_fee_numerator in 0..=10000u64,
+                held in 0..=10 * 365 * 86400i64,
+            ) {
+                let mut position = position_at(direction(long), entry, amount, margin);
+                position.maker_fee_bps = maker_fee_bps;
+                position.taker_fee_bps = taker_fee_bps;
+                position.overnight_fee_numerator = overnight_fee_numerator;
+                let current_price = pyth_sdk_solana::Price { conf, ..flat_price(price) };
+                let time = position.created_at + held;
+                let bound = margin as u128 + max_gain(&position, price);
+
+                // a bid below zero or years of fees that don't fit fail instead
+                if let Ok(returned) = position.get_profit(&current_price, time) {
+                    prop_assert!(returned as u128 <= bound);
+                }
+                if let Ok(settlement) = position.settle(&current_price, time) {
+                    prop_assert!(settlement.returned_margin as u128 <= bound);
+                    // nothing more than the margin is ever taken in fees
+                    prop_assert!(settlement.overnight_fee + settlement.trading_fee <= margin);
+                    // a short makes at most its entry notional, the price going to zero
+                    if !long {
+                        prop_assert!(settlement.returned_margin as u128 <= margin as u128 + max_gain(&position, 0));
+                    }
+                }
+            }
+
+            #[test]
+            fn prop_no_panic(
+                long in any::<bool>(),
+                entry in any::<i64>(),
+                last_conf in any::<u64>(),
+                decimals in any::<u8>(),
+                leverage_denominator in any::<u64>(),
+                price in any::<i64>(),
+                conf in any::<u64>(),
+                amount in any::<u64>(),
+                margin in any::<u64>(),
+                leverage in any::<u64>(),
+                maker_fee_bps in 0..=10000u64,
+                taker_fee_bps in 0..=10000u64,
+                overnight_fee_numerator in 0..=10000u64,
+                time in any::<i64>(),
+            ) {
+                let mut position = position_at(direction(long), entry, amount, margin);
+                position.last_conf = last_conf;
+                position.decimals = decimals;
+                position.leverage = leverage;
+                position.leverage_denominator = leverage_denominator;
+                position.maker_fee_bps = maker_fee_bps;
+                position.taker_fee_bps = taker_fee_bps;
+                position.overnight_fee_numerator = overnight_fee_numerator;
+                let current_price = pyth_sdk_solana::Price { conf, ..flat_price(price) };
+
+                // errors are fine, panics aren't
+                let _ = position.exit_price(&current_price);
+                let _ = position.unrealized_pnl(&current_price);
+                let _ = position.get_profit(&current_price, time);
+                let _ = position.settle(&current_price, time);
+                let _ = position.health(&current_price, time);
+                let _ = position.maintainance_margin();
+                let _ = position.settle_as(&current_price, time, Liquidity::Maker);
+                let _ = position.liquidation_at(time, 0);
+                let _ = position.liquidation_split(time, 0, 2000);
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Liquidation math on plain integers. Nothing here depends on Anchor or
+//! Solana, so that keepers can build it off-chain and run the exact predicate
+//! the program liquidates on. Prices, margins and fees share the position's
+//! `10^decimals` scale; `is_long` stands for `Direction::OpenLong`.
+
+/// The price at which a position entered at `entry_price` is liquidated,
+/// `bond` below it for a long and `bond` above it for a short. A long's
+/// floors at zero; `None` when a short's overflows.
+pub fn liquidation_price(entry_price: u64, bond: u64, is_long: bool) -> Option<u64> {
+    if is_long {
+        Some(entry_price.saturating_sub(bond))
+    } else {
+        entry_price.checked_add(bond)
+    }
+}
+
+/// Whether `price` has reached `liquidation`, from above for a long and
+/// from below for a short. Reaching it exactly liquidates.
+pub fn is_liquidated(price: u64, liquidation: u64, is_long: bool) -> bool {
+    if is_long {
+        price <= liquidation
+    } else {
+        price >= liquidation
+    }
+}
+
+/// The margin left above `maintenance_margin` once `overnight_fee` and the
+/// `funding_owed` are taken out of it, which is what the liquidation price
+/// is `bond` away from. Funding owed to the position, negative, adds to it.
+pub fn fee_adjusted_bond(margin: u64, maintenance_margin: u64, overnight_fee: u64, funding_owed: i64) -> u64 {
+    funded_margin(margin, funding_owed)
+        .saturating_sub(maintenance_margin)
+        .saturating_sub(overnight_fee)
+}
+
+/// `margin` once `funding_owed` is paid out of it or, when negative, into
+/// it; floors at zero when the funding owed is more than the margin.
+pub fn funded_margin(margin: u64, funding_owed: i64) -> u64 {
+    (margin as i128 - funding_owed as i128).clamp(0, u64::MAX as i128) as u64
+}
+
+/// `liquidation_price` with the overnight fees and funding owed taken out of
+/// the bond, as `Position::liquidation_at` computes it.
+pub fn fee_adjusted_liquidation_price(entry_price: u64, margin: u64, maintenance_margin: u64, overnight_fee: u64, funding_owed: i64, is_long: bool) -> Option<u64> {
+    liquidation_price(entry_price, fee_adjusted_bond(margin, maintenance_margin, overnight_fee, funding_owed), is_long)
+}
+
+/// Replays `prices` against a fixed `liquidation` price and returns the
+/// index of the first one that liquidates, as a keeper watching the feed
+/// would see it.
+#[cfg(any(test, feature = "client"))]
+pub fn simulate_liquidation_price_series(prices: &[u64], liquidation: u64, is_long: bool) -> Option<usize> {
+    prices
+        .iter()
+        .position(|price| is_liquidated(*price, liquidation, is_long))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_liquidation_boundary() {
+        let liquidation = liquidation_price(30000_000_000, 1000_000_000, true).unwrap();
+        assert_eq!(liquidation, 29000_000_000);
+        assert!(is_liquidated(liquidation, liquidation, true));
+        assert!(!is_liquidated(liquidation + 1, liquidation, true));
+        assert!(is_liquidated(liquidation - 1, liquidation, true));
+    }
+
+    #[test]
+    fn test_short_liquidation_boundary() {
+        let liquidation = liquidation_price(30000_000_000, 1000_000_000, false).unwrap();
+        assert_eq!(liquidation, 31000_000_000);
+        assert!(is_liquidated(liquidation, liquidation, false));
+        assert!(is_liquidated(liquidation + 1, liquidation, false));
+        assert!(!is_liquidated(liquidation - 1, liquidation, false));
+    }
+
+    #[test]
+    fn test_liquidation_price_bounds() {
+        // a long with more bond than price can only go to zero
+        assert_eq!(liquidation_price(1000, 5000, true), Some(0));
+        assert!(is_liquidated(0, 0, true));
+        assert_eq!(liquidation_price(u64::MAX, 1, false), None);
+        assert_eq!(liquidation_price(u64::MAX - 1, 1, false), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_fee_adjusted_boundary() {
+        // 3000 of margin, 1500 of it maintenance and 100 owed in fees
+        assert_eq!(fee_adjusted_bond(3000_000_000, 1500_000_000, 100_000_000, 0), 1400_000_000);
+        assert_eq!(fee_adjusted_bond(3000_000_000, 1500_000_000, 2000_000_000, 0), 0);
+        assert_eq!(fee_adjusted_bond(1000_000_000, 1500_000_000, 0, 0), 0);
+
+        let long = fee_adjusted_liquidation_price(30000_000_000, 3000_000_000, 1500_000_000, 100_000_000, 0, true).unwrap();
+        assert_eq!(long, 28600_000_000);
+        assert!(is_liquidated(long, long, true));
+        assert!(!is_liquidated(long + 1, long, true));
+        assert!(is_liquidated(long - 1, long, true));
+
+        let short = fee_adjusted_liquidation_price(30000_000_000, 3000_000_000, 1500_000_000, 100_000_000, 0, false).unwrap();
+        assert_eq!(short, 31400_000_000);
+        assert!(is_liquidated(short, short, false));
+        assert!(is_liquidated(short + 1, short, false));
+        assert!(!is_liquidated(short - 1, short, false));
+
+        // the fees bring the liquidation price closer than the bare bond does
+        assert!(long > liquidation_price(30000_000_000, 1500_000_000, true).unwrap());
+        assert!(short < liquidation_price(30000_000_000, 1500_000_000, false).unwrap());
+    }
+
+    #[test]
+    fn test_funding_adjusted_boundary() {
+        // funding owed comes out of the bond like a fee, funding received adds to it
+        assert_eq!(fee_adjusted_bond(3000_000_000, 1500_000_000, 100_000_000, 400_000_000), 1000_000_000);
+        assert_eq!(fee_adjusted_bond(3000_000_000, 1500_000_000, 100_000_000, -400_000_000), 1800_000_000);
+
+        // funding alone can leave nothing above maintenance, or no margin at all
+        assert_eq!(fee_adjusted_bond(3000_000_000, 1500_000_000, 0, 1500_000_000), 0);
+        assert_eq!(funded_margin(3000_000_000, 5000_000_000), 0);
+        assert_eq!(funded_margin(u64::MAX, i64::MIN), u64::MAX);
+        let long = fee_adjusted_liquidation_price(30000_000_000, 3000_000_000, 1500_000_000, 0, 1500_000_000, true).unwrap();
+        assert_eq!(long, 30000_000_000);
+        assert!(is_liquidated(30000_000_000, long, true));
+    }
+
+    #[test]
+    fn test_simulate_liquidation_price_series() {
+        let series = [30000_000_000, 29500_000_000, 29000_000_001, 29000_000_000, 28000_000_000];
+        assert_eq!(simulate_liquidation_price_series(&series, 29000_000_000, true), Some(3));
+        assert_eq!(simulate_liquidation_price_series(&series, 28000_000_000, true), Some(4));
+        assert_eq!(simulate_liquidation_price_series(&series, 27000_000_000, true), None);
+        assert_eq!(simulate_liquidation_price_series(&series, 30000_000_000, false), Some(0));
+        assert_eq!(simulate_liquidation_price_series(&[], 29000_000_000, true), None);
+    }
+}
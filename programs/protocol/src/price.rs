@@ -1,13 +1,14 @@
 use crate::Direction;
-use crate::PositionType;
+use crate::Position;
+use crate::ProtocolError;
 use crate::Rate;
+use crate::round_up_to_u64;
 
-use std::convert::From;
+use std::convert::{From, TryFrom};
 
 pub struct ProtocolPrice {
     pub price: i64,
     pub conf: u64,
-    pub expo: i32,
 }
 
 impl From<pyth_sdk_solana::Price> for ProtocolPrice {
@@ -15,21 +16,56 @@ impl From<pyth_sdk_solana::Price> for ProtocolPrice {
         Self {
             price: pyth_price.price,
             conf: pyth_price.conf,
-            expo: pyth_price.expo,
         }
     }
 }
 
 pub struct TransactionAccount {
     pub direction: Direction,
-    pub ptype: PositionType,
     pub initial_shares_price: ProtocolPrice,
     pub asset_decimals: u32,
     pub shares_with_decimals: u64,
     pub leverage: u64,
+    pub leverage_denominator: u64,
     pub financing_rate: Rate,
 }
 
+impl TryFrom<&Position> for TransactionAccount {
+    type Error = anchor_lang::error::Error;
+
+    /// Fails with `MathOverflow` when the mid price or the yearly financing
+    /// rate doesn't fit.
+    fn try_from(position: &Position) -> Result<Self, Self::Error> {
+        // the position stores its fill, the account the mid price it was filled around
+        let conf = i64::try_from(position.last_conf)
+            .map_err(|_| ProtocolError::MathOverflow)?;
+        let mid = match position.direction {
+            Direction::OpenLong => position.last_price.checked_sub(conf),
+            Direction::OpenShort => position.last_price.checked_add(conf),
+        }
+        .ok_or(ProtocolError::MathOverflow)?;
+        let financing_numerator = position.overnight_fee_numerator
+            .checked_mul(365)
+            .ok_or(ProtocolError::MathOverflow)?;
+        Ok(Self {
+            direction: position.direction,
+            initial_shares_price: ProtocolPrice {
+                price: mid,
+                conf: position.last_conf,
+            },
+            asset_decimals: position.decimals as u32,
+            shares_with_decimals: position.amount,
+            leverage: position.leverage,
+            leverage_denominator: position.leverage_denominator,
+            // the overnight fee is charged per day, the financing rate per year
+            financing_rate: Rate {
+                numerator: financing_numerator,
+                denominator: 10000,
+            },
+        })
+    }
+}
+
 impl TransactionAccount {
 
     pub fn initial_price(&self) -> i64 {
@@ -45,11 +81,28 @@ impl TransactionAccount {
     }
 
     pub fn initial_margin(&self) -> Option<u64> {
-        self.shares()
-            .checked_mul(self.initial_price() as u64)?
-            .checked_div(self.leverage)?
-            .checked_div(10u64.pow(self.asset_decimals as u32))
+        let price = u64::try_from(self.initial_price()).ok()?;
+        (self.shares() as u128)
+            .checked_mul(price as u128)?
+            .checked_mul(self.leverage_denominator as u128)?
+            .checked_div(self.leverage as u128)?
+            .checked_div(10u128.checked_pow(self.asset_decimals)?)?
+            .try_into()
+            .ok()
+    }
 
+    /// Financing owed after `days`: the yearly `financing_rate` on the
+    /// leveraged initial margin, prorated over 365 days. Divided once at the
+    /// end and rounded up, as a fee.
+    pub fn financing_fee(&self, days: u64) -> Option<u64> {
+        let owed = (self.initial_margin()? as u128)
+            .checked_mul(self.leverage as u128)?
+            .checked_mul(self.financing_rate.numerator as u128)?
+            .checked_mul(days as u128)?;
+        let divisor = (self.financing_rate.denominator as u128)
+            .checked_mul(365)?
+            .checked_mul(self.leverage_denominator as u128)?;
+        round_up_to_u64(owed, divisor).ok()
     }
 
     pub fn buy_to_open_price(&self) -> Option<i64> {
@@ -95,11 +148,10 @@ impl TransactionAccount {
             let diff = (price.price)
                 .checked_sub(price.conf as i64)?
                 .checked_sub(self.buy_to_open_price()?)?;
-            dbg!(diff);
+            // floored, so a fractional loss is charged in full
             return (self.shares() as i128)
                 .checked_mul(diff as i128)?
-                .checked_div(10i128.pow(self.asset_decimals));
-                
+                .checked_div_euclid(10i128.checked_pow(self.asset_decimals)?);
         }
         None
     }
@@ -125,34 +177,125 @@ impl TransactionAccount {
                 )?;
             return (self.shares() as i128)
                 .checked_mul(diff as i128)?
-                .checked_div(10i128.pow(self.asset_decimals));
+                .checked_div_euclid(10i128.checked_pow(self.asset_decimals)?);
         }
         None
     }
 
-    pub fn get_profit(&self, price: &pyth_sdk_solana::Price, days: u64) -> Option<i128> {
-        let gross = match self.direction {
-            Direction::OpenLong => self.sell_to_close_profit(price)?,
-            Direction::OpenShort => self.buy_to_close_profit(price)?,
-        };
+    /// PnL of closing at `price` before financing. `None` on a negative
+    /// oracle price.
+    pub fn gross_profit(&self, price: &pyth_sdk_solana::Price) -> Option<i128> {
+        if price.price < 0 {
+            return None;
+        }
+        match self.direction {
+            Direction::OpenLong => self.sell_to_close_profit(price),
+            Direction::OpenShort => self.buy_to_close_profit(price),
+        }
+    }
 
-        gross
-            .checked_sub(
-                self.initial_margin()?
-                    .checked_mul(self.leverage)?
-                    .checked_mul(self.financing_rate.numerator)?
-                    .checked_div(self.financing_rate.denominator)?
-                    .checked_mul(days)?
-                    .checked_div(365)?
-                    .try_into()
-                    .ok()?
-            )
+    #[cfg(test)]
+    pub fn get_profit(&self, price: &pyth_sdk_solana::Price, days: u64) -> Option<i128> {
+        self.gross_profit(price)?
+            .checked_sub(self.financing_fee(days)? as i128)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tests::{code, failure};
+    use crate::PositionType;
+
+    #[test]
+    fn test_from_position() {
+        let position = Position {
+            version: 1,
+            pool: Default::default(),
+            owner: Default::default(),
+            authority: Default::default(),
+            mint: Default::default(),
+            price_a: Default::default(),
+            price_b: Default::default(),
+            index: 0,
+            status: crate::PositionStatus::Open,
+            ptype: PositionType::Isolated,
+            direction: Direction::OpenLong,
+            decimals: 6,
+            leverage: 100,
+            last_price: 30005_000_000,
+            last_conf: 5_000_000,
+            margin: 300_000_000,
+            margin_rate_numerator: 500,
+            overnight_fee_numerator: 1,
+            taker_fee_bps: 0,
+            liquidation: 0,
+            created_at: 0,
+            slot: 0,
+            amount: 1_000_000,
+            max_price_age: 60,
+            max_conf_bps: 100,
+            last_fee_settlement: 0,
+            accrued_fee: 0,
+            take_profit: None,
+            stop_loss: None,
+            funding_index: 0,
+            conf_mode: crate::ConfMode::Conservative,
+            expiry: None,
+            leverage_denominator: 1,
+            collateral_price: Default::default(),
+            collateral_decimals: 0,
+            maker_fee_bps: 0,
+        };
+
+        let account = TransactionAccount::try_from(&position).unwrap();
+
+        assert!(account.direction == position.direction);
+        assert!(account.initial_price() == 30000_000_000);
+        assert!(account.buy_to_open_price() == Some(position.last_price));
+        assert!(account.shares() == position.amount);
+        assert!(account.financing_rate == Rate { numerator: 365, denominator: 10000 });
+
+        // a mid price or a yearly rate out of range is an error, not a wrap
+        for position in [
+            Position { last_price: i64::MIN, ..position.clone() },
+            Position { direction: Direction::OpenShort, last_price: i64::MAX, ..position.clone() },
+            Position { last_conf: u64::MAX, ..position.clone() },
+            Position { overnight_fee_numerator: u64::MAX, ..position.clone() },
+        ] {
+            assert_eq!(failure(TransactionAccount::try_from(&position)), code(ProtocolError::MathOverflow));
+        }
+    }
+
+    #[test]
+    fn test_rounding_direction() {
+        let price = |price| ProtocolPrice { price, conf: 0 };
+        let account = |direction| TransactionAccount {
+            direction,
+            initial_shares_price: price(30000_000_000),
+            asset_decimals: 6,
+            shares_with_decimals: 1,
+            leverage: 10,
+            leverage_denominator: 1,
+            financing_rate: Rate { numerator: 1, denominator: 10000 },
+        };
+        let close = |price| pyth_sdk_solana::Price { price, conf: 0, expo: -6 };
+
+        // a millionth of a unit moving by 0.5: the gain rounds down, the loss
+        // rounds away from zero, both against the owner
+        let long = account(Direction::OpenLong);
+        assert_eq!(long.gross_profit(&close(30000_500_000)), Some(0));
+        assert_eq!(long.gross_profit(&close(29999_500_000)), Some(-1));
+        let short = account(Direction::OpenShort);
+        assert_eq!(short.gross_profit(&close(29999_500_000)), Some(0));
+        assert_eq!(short.gross_profit(&close(30000_500_000)), Some(-1));
+
+        // 3000 units of margin at 10x, financed at 1bp a year for a day, owe
+        // under a unit, charged as a whole one
+        assert_eq!(long.initial_margin(), Some(3000));
+        assert_eq!(long.financing_fee(1), Some(1));
+        assert_eq!(long.financing_fee(0), Some(0));
+    }
 
     #[test]
     fn test_protocol_price() {
@@ -208,11 +351,11 @@ mod tests {
 
         let account = TransactionAccount {
             direction: Direction::OpenLong,
-            ptype: PositionType::Isolated,
             initial_shares_price: price_day1.into(),
             asset_decimals: 6,
             shares_with_decimals: 1000000,
             leverage: 100,
+            leverage_denominator: 1,
             financing_rate: Rate { numerator: 300, denominator: 10000 },
         };
 
@@ -273,11 +416,11 @@ mod tests {
 
         let account = TransactionAccount {
             direction: Direction::OpenShort,
-            ptype: PositionType::Isolated,
             initial_shares_price: price_day1.into(),
             asset_decimals: 6,
             shares_with_decimals: 1000000,
             leverage: 100,
+            leverage_denominator: 1,
             financing_rate: Rate { numerator: 300, denominator: 10000 },
         };
 
@@ -341,11 +484,11 @@ mod tests {
 
         let account = TransactionAccount {
             direction: Direction::OpenLong,
-            ptype: PositionType::Isolated,
             initial_shares_price: price_day1.into(),
             asset_decimals: 6,
             shares_with_decimals: 1000000,
             leverage: 100,
+            leverage_denominator: 1,
             financing_rate: Rate { numerator: 300, denominator: 10000 },
         };
 
@@ -373,11 +516,11 @@ mod tests {
 
         let account = TransactionAccount {
             direction: Direction::OpenShort,
-            ptype: PositionType::Isolated,
             initial_shares_price: price_day1.into(),
             asset_decimals: 6,
             shares_with_decimals: 1000000,
             leverage: 100,
+            leverage_denominator: 1,
             financing_rate: Rate { numerator: 300, denominator: 10000 },
         };
 
@@ -0,0 +1,1164 @@
+//! Runs positions through the deployed program end to end: a pool is set up
+//! against a hand-written Pyth price account, then a position is opened,
+//! topped up, partly withdrawn and closed at a moved price, opened on
+//! collateral converted through a second feed and paid back out in it,
+//! opened against a cross-margin account and closed back out of it, on its
+//! own or on a trigger, closed on data signed by the pool's oracle alone or
+//! in a batch, or opened and closed on margin paid in native SOL.
+
+// prices are written as whole units, then their six decimals
+#![allow(clippy::inconsistent_digit_grouping)]
+
+use std::cell::RefCell;
+use std::mem;
+use std::sync::Once;
+
+use anchor_lang::solana_program::account_info::AccountInfo;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::sysvar::{self, clock::Clock, rent};
+use anchor_lang::{system_program, AccountDeserialize, AccountSerialize, InstructionData, ToAccountMetas};
+use anchor_spl::token::spl_token;
+use protocol::{Admin, CollateralMode, ConfMode, CrossAccount, Direction, FUNDING_INTERVAL, LiquidatedData, PoolConfigArgs, Position, PositionArgs, PositionStatus, PositionType, SignedDomain};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::account::{Account, AccountSharedData};
+use solana_sdk::ed25519_instruction::new_ed25519_instruction;
+use solana_sdk::instruction::{AccountMeta, Instruction};
+use solana_sdk::program_stubs::{self, SyscallStubs};
+use solana_sdk::program_utils::limited_deserialize;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{keypair_from_seed, Keypair, Signer};
+use solana_sdk::system_instruction::{self, SystemInstruction};
+use solana_sdk::transaction::Transaction;
+
+/// Size of a Pyth v2 price account with its 32 publisher slots.
+const PYTH_PRICE_ACCOUNT_LEN: usize = 3312;
+const DECIMALS: u8 = 6;
+const USDC: u64 = 1_000_000;
+const BTC_PRICE: i64 = 30000_000_000;
+const STARTING_BALANCE: u64 = 100_000 * USDC;
+const POOL_LIQUIDITY: u64 = 10_000 * USDC;
+
+#[derive(Clone, Copy)]
+struct TestPool {
+    pool: Pubkey,
+    mint: Pubkey,
+    price_a: Pubkey,
+    payer_tokens: Pubkey,
+}
+
+impl TestPool {
+    fn pda(&self, seed: &[u8]) -> Pubkey {
+        Pubkey::find_program_address(&[seed, self.pool.as_ref()], &protocol::ID).0
+    }
+
+    /// The pool's token account for `seed`, one of vault, fee or insurance.
+    fn token_pda(&self, seed: &[u8]) -> Pubkey {
+        Pubkey::find_program_address(&[seed, self.pool.as_ref(), self.mint.as_ref()], &protocol::ID).0
+    }
+
+    fn pool_config(&self) -> Pubkey {
+        self.pda(b"pool_config")
+    }
+
+    fn position(&self, owner: &Pubkey, index: u32) -> Pubkey {
+        Pubkey::find_program_address(&[b"protocol", owner.as_ref(), &index.to_le_bytes()], &protocol::ID).0
+    }
+
+    fn cross_account(&self, owner: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"cross", owner.as_ref(), self.pool.as_ref()], &protocol::ID).0
+    }
+}
+
+fn put(data: &mut [u8], offset: usize, bytes: &[u8]) {
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// A trading Pyth price account quoting `price` at `10^expo`, published at
+/// `timestamp` in `slot`, with no confidence interval.
+fn pyth_price_account(price: i64, expo: i32, timestamp: i64, slot: u64) -> Account {
+    let mut data = vec![0u8; PYTH_PRICE_ACCOUNT_LEN];
+    put(&mut data, 0, &0xa1b2_c3d4u32.to_le_bytes()); // magic
+    put(&mut data, 4, &2u32.to_le_bytes()); // version
+    put(&mut data, 8, &3u32.to_le_bytes()); // account type: price
+    put(&mut data, 12, &(PYTH_PRICE_ACCOUNT_LEN as u32).to_le_bytes());
+    put(&mut data, 16, &1u32.to_le_bytes()); // price type: price
+    put(&mut data, 20, &expo.to_le_bytes());
+    put(&mut data, 32, &slot.to_le_bytes()); // last_slot
+    put(&mut data, 40, &slot.to_le_bytes()); // valid_slot
+    put(&mut data, 48, &price.to_le_bytes()); // ema_price
+    put(&mut data, 96, &timestamp.to_le_bytes());
+    put(&mut data, 208, &price.to_le_bytes()); // agg.price
+    put(&mut data, 216, &0u64.to_le_bytes()); // agg.conf
+    put(&mut data, 224, &1u32.to_le_bytes()); // agg.status: trading
+    put(&mut data, 232, &slot.to_le_bytes()); // agg.pub_slot
+    Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: Pubkey::new_unique(),
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+fn token_program_account<T: Pack>(state: T) -> Account {
+    let mut data = vec![0u8; T::LEN];
+    state.pack_into_slice(&mut data);
+    Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: spl_token::ID,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+fn add_mint(context: &mut ProgramTestContext, address: Pubkey, decimals: u8, supply: u64) {
+    let mint = spl_token::state::Mint {
+        mint_authority: COption::None,
+        supply,
+        decimals,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    };
+    context.set_account(&address, &AccountSharedData::from(token_program_account(mint)));
+}
+
+/// A token account of the payer holding `amount` of `mint`.
+fn add_token_account(context: &mut ProgramTestContext, address: Pubkey, mint: Pubkey, amount: u64) {
+    let account = spl_token::state::Account {
+        mint,
+        owner: context.payer.pubkey(),
+        amount,
+        delegate: COption::None,
+        state: spl_token::state::AccountState::Initialized,
+        is_native: COption::None,
+        delegated_amount: 0,
+        close_authority: COption::None,
+    };
+    context.set_account(&address, &AccountSharedData::from(token_program_account(account)));
+}
+
+async fn send(context: &mut ProgramTestContext, instruction: Instruction) {
+    assert!(try_send(context, instruction, &[]).await);
+}
+
+/// Sends `instruction` paid for by the context's payer and also signed by
+/// `signers`, and reports whether it succeeded.
+async fn try_send(context: &mut ProgramTestContext, instruction: Instruction, signers: &[&Keypair]) -> bool {
+    try_send_all(context, &[instruction], signers).await
+}
+
+/// Like `try_send`, for several instructions in one transaction.
+async fn try_send_all(context: &mut ProgramTestContext, instructions: &[Instruction], signers: &[&Keypair]) -> bool {
+    let blockhash = context.banks_client.get_latest_blockhash().await.unwrap();
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(instructions, Some(&context.payer.pubkey()), &all_signers, blockhash);
+    context.banks_client.process_transaction(transaction).await.is_ok()
+}
+
+async fn token_balance(context: &mut ProgramTestContext, address: Pubkey) -> u64 {
+    let account = context.banks_client.get_account(address).await.unwrap().unwrap();
+    spl_token::state::Account::unpack(&account.data).unwrap().amount
+}
+
+async fn position_state(context: &mut ProgramTestContext, address: Pubkey) -> Option<Position> {
+    let account = context.banks_client.get_account(address).await.unwrap()?;
+    Some(Position::try_deserialize(&mut account.data.as_slice()).unwrap())
+}
+
+async fn cross_account_state(context: &mut ProgramTestContext, address: Pubkey) -> CrossAccount {
+    let account = context.banks_client.get_account(address).await.unwrap().unwrap();
+    CrossAccount::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+/// Moves the mock feed to `price`, published at the current clock.
+async fn set_price(context: &mut ProgramTestContext, price_a: Pubkey, price: i64) {
+    let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let account = pyth_price_account(price, -(DECIMALS as i32), clock.unix_timestamp, clock.slot);
+    context.set_account(&price_a, &AccountSharedData::from(account));
+}
+
+fn pool_config_args() -> PoolConfigArgs {
+    PoolConfigArgs {
+        max_leverage: 20,
+        max_leverage_long: 0,
+        max_leverage_short: 0,
+        maintenance_margin_bps: 500,
+        max_slippage_bps: 200,
+        overnight_fee_bps: 0,
+        maker_fee_bps: 0,
+        taker_fee_bps: 0,
+        max_long_oi: u64::MAX,
+        max_short_oi: u64::MAX,
+        max_notional: u64::MAX,
+        funding_rate_bps: 0,
+        min_margin: 0,
+        min_notional: 0,
+        min_hold_seconds: 0,
+        liquidation_penalty_bps: 0,
+        collateral_mode: CollateralMode::Token,
+        conf_mode: ConfMode::Conservative,
+        paused: false,
+        liquidate_on_ema: false,
+        permissionless_liquidation: true,
+        max_payout_multiple: 0,
+        max_price_age: 60,
+        max_conf_bps: 100,
+        oracle_authority: oracle().pubkey(),
+    }
+}
+
+/// The key the pool's positions are opened under, whose signed data closes
+/// them through `process_position`.
+fn oracle() -> Keypair {
+    keypair_from_seed(&[7; 32]).unwrap()
+}
+
+/// The Ed25519 instruction carrying `signer`'s signature over the data
+/// closing `position` at the current clock, liquidated or not.
+async fn signed_close_instruction(context: &mut ProgramTestContext, test_pool: &TestPool, position: Pubkey, is_liquidated: bool, signer: &Keypair) -> Instruction {
+    let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let data = LiquidatedData {
+        position,
+        is_liquidated,
+        price: BTC_PRICE as u64,
+        time: clock.unix_timestamp,
+        slot: clock.slot,
+    };
+    let signer = ed25519_dalek::Keypair::from_bytes(&signer.to_bytes()).unwrap();
+    new_ed25519_instruction(&signer, &SignedDomain::new(test_pool.pool).message(&data))
+}
+
+/// 1 BTC at 30000 USDC on 10x leverage.
+fn position_args(direction: Direction) -> PositionArgs {
+    PositionArgs {
+        price: BTC_PRICE as u64,
+        expo: -(DECIMALS as i32),
+        decimals: DECIMALS,
+        leverage_margin: BTC_PRICE as u64,
+        leverage: 10,
+        leverage_denominator: 1,
+        ptype: PositionType::Isolated,
+        direction,
+        slippage_numerator: 100,
+        margin_rate_numerator: 0,
+    }
+}
+
+fn admin_address() -> Pubkey {
+    Pubkey::find_program_address(&[b"admin"], &protocol::ID).0
+}
+
+/// Writes the governance account held by `authority` directly: the program
+/// runs as a builtin here, with no ProgramData for `init_admin` to check.
+fn add_admin(context: &mut ProgramTestContext, authority: Pubkey) {
+    let mut data = Vec::new();
+    Admin { authority }.try_serialize(&mut data).unwrap();
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: protocol::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    context.set_account(&admin_address(), &AccountSharedData::from(account));
+}
+
+fn init_pool_config_instruction(payer: Pubkey, test_pool: &TestPool, args: PoolConfigArgs) -> Instruction {
+    let accounts = protocol::accounts::InitPoolConfig {
+        payer,
+        admin: admin_address(),
+        pool: test_pool.pool,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        quote_mint: test_pool.mint,
+        pool_config: test_pool.pool_config(),
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::InitPoolConfig { args }.data();
+    Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))
+}
+
+thread_local! {
+    /// The accounts of the instruction the program is running natively, for
+    /// `CpiStubs` to find the invoked program among.
+    static ACCOUNTS: RefCell<Vec<AccountInfo<'static>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `protocol::entry` with its accounts kept aside for `CpiStubs`.
+fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    // SAFETY: the accounts outlive the call, and are dropped before it returns
+    let kept = accounts.iter().map(|account| unsafe { mem::transmute::<AccountInfo, AccountInfo<'static>>(account.clone()) }).collect();
+    ACCOUNTS.with(|accounts| *accounts.borrow_mut() = kept);
+    let result = protocol::entry(program_id, accounts, data);
+    ACCOUNTS.with(|accounts| accounts.borrow_mut().clear());
+    result
+}
+
+/// The test runtime's syscalls, with two gaps of its native CPI filled in:
+/// it looks the invoked program up among the accounts, which Anchor's CPI
+/// contexts leave out, and it can't grow an account's data, so an account
+/// is created by funding it and assigning it to the program, which then
+/// sizes it and hands it on to its owner as the deployed program couldn't.
+struct CpiStubs(Box<dyn SyscallStubs>);
+
+/// Gives the program's account at `key` `space` zeroed bytes of data and
+/// hands it to `owner`.
+fn allocate(account_infos: &[AccountInfo], key: &Pubkey, space: u64, owner: &Pubkey) {
+    let account = account_infos.iter().find(|account| account.key == key).unwrap();
+    *account.data.borrow_mut() = Box::leak(vec![0; space as usize].into_boxed_slice());
+    // SAFETY: the owner lives in the runtime's copy of the account, which
+    // the test runtime itself rewrites this way when the System Program
+    // assigns it
+    #[allow(mutable_transmutes)]
+    let account_owner = unsafe { mem::transmute::<&Pubkey, &mut Pubkey>(account.owner) };
+    *account_owner = *owner;
+}
+
+/// Holds the runtime's place while `CpiStubs` takes it over.
+struct NoStubs;
+
+impl SyscallStubs for NoStubs {}
+
+impl SyscallStubs for CpiStubs {
+    fn sol_log(&self, message: &str) {
+        self.0.sol_log(message)
+    }
+    fn sol_log_compute_units(&self) {
+        self.0.sol_log_compute_units()
+    }
+    fn sol_invoke_signed(&self, instruction: &Instruction, account_infos: &[AccountInfo], signers_seeds: &[&[&[u8]]]) -> ProgramResult {
+        let mut account_infos = account_infos.to_vec();
+        if !account_infos.iter().any(|account| account.key == &instruction.program_id) {
+            let program = ACCOUNTS.with(|accounts| accounts.borrow().iter().find(|account| account.key == &instruction.program_id).cloned());
+            // SAFETY: the program's account outlives the instruction invoking it
+            account_infos.extend(program.map(|program| unsafe { mem::transmute::<AccountInfo<'static>, AccountInfo>(program) }));
+        }
+        if instruction.program_id == system_program::ID {
+            if let Ok(SystemInstruction::CreateAccount { lamports, space, owner }) = limited_deserialize(&instruction.data) {
+                let (from, to) = (&instruction.accounts[0].pubkey, &instruction.accounts[1].pubkey);
+                self.0.sol_invoke_signed(&system_instruction::transfer(from, to, lamports), &account_infos, signers_seeds)?;
+                self.0.sol_invoke_signed(&system_instruction::assign(to, &protocol::ID), &account_infos, signers_seeds)?;
+                allocate(&account_infos, to, space, &owner);
+                return Ok(());
+            }
+        }
+        self.0.sol_invoke_signed(instruction, &account_infos, signers_seeds)
+    }
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_clock_sysvar(var_addr)
+    }
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_fees_sysvar(var_addr)
+    }
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_rent_sysvar(var_addr)
+    }
+    unsafe fn sol_memcpy(&self, dst: *mut u8, src: *const u8, n: usize) {
+        self.0.sol_memcpy(dst, src, n)
+    }
+    unsafe fn sol_memmove(&self, dst: *mut u8, src: *const u8, n: usize) {
+        self.0.sol_memmove(dst, src, n)
+    }
+    unsafe fn sol_memcmp(&self, s1: *const u8, s2: *const u8, n: usize, result: *mut i32) {
+        self.0.sol_memcmp(s1, s2, n, result)
+    }
+    unsafe fn sol_memset(&self, s: *mut u8, c: u8, n: usize) {
+        self.0.sol_memset(s, c, n)
+    }
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        self.0.sol_get_return_data()
+    }
+    fn sol_set_return_data(&self, data: &[u8]) {
+        self.0.sol_set_return_data(data)
+    }
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        self.0.sol_log_data(fields)
+    }
+    fn sol_get_processed_sibling_instruction(&self, index: usize) -> Option<Instruction> {
+        self.0.sol_get_processed_sibling_instruction(index)
+    }
+    fn sol_get_stack_height(&self) -> u64 {
+        self.0.sol_get_stack_height()
+    }
+}
+
+/// Starts the program with the payer as governance, a USDC mint, a funded
+/// payer token account and a BTC feed at 30000, then creates the pool's
+/// vaults and config and seeds the vault with liquidity to pay profits from.
+async fn setup() -> (ProgramTestContext, TestPool) {
+    let mut program_test = ProgramTest::new("protocol", protocol::ID, processor!(process_instruction));
+    // natively too, for `CpiStubs` to run the program's transfers through
+    program_test.add_program("spl_token", spl_token::ID, processor!(spl_token::processor::Processor::process));
+    setup_program(program_test).await
+}
+
+/// `setup` against `program_test`, to run the pool on the built program
+/// rather than natively.
+async fn setup_program(program_test: ProgramTest) -> (ProgramTestContext, TestPool) {
+    let mut context = program_test.start_with_context().await;
+    // the runtime installs its syscalls on its first start
+    static CPI_STUBS: Once = Once::new();
+    CPI_STUBS.call_once(|| {
+        let runtime = program_stubs::set_syscall_stubs(Box::new(CpiStubs(Box::new(NoStubs))));
+        program_stubs::set_syscall_stubs(Box::new(CpiStubs(runtime)));
+    });
+    let payer = context.payer.pubkey();
+    add_admin(&mut context, payer);
+
+    let test_pool = TestPool {
+        pool: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        price_a: Pubkey::new_unique(),
+        payer_tokens: Pubkey::new_unique(),
+    };
+    add_mint(&mut context, test_pool.mint, DECIMALS, STARTING_BALANCE);
+    add_token_account(&mut context, test_pool.payer_tokens, test_pool.mint, STARTING_BALANCE);
+    set_price(&mut context, test_pool.price_a, BTC_PRICE).await;
+    init_vault(&mut context, &test_pool).await;
+
+    send(&mut context, init_pool_config_instruction(payer, &test_pool, pool_config_args())).await;
+
+    seed_vault(&mut context, &test_pool, POOL_LIQUIDITY).await;
+
+    (context, test_pool)
+}
+
+/// Creates the pool's vault, fee account and insurance fund for its `mint`.
+async fn init_vault(context: &mut ProgramTestContext, test_pool: &TestPool) {
+    let instruction = init_vault_instruction(context.payer.pubkey(), test_pool);
+    send(context, instruction).await;
+}
+
+fn init_vault_instruction(payer: Pubkey, test_pool: &TestPool) -> Instruction {
+    let accounts = protocol::accounts::InitVault {
+        payer,
+        admin: admin_address(),
+        pool: test_pool.pool,
+        mint: test_pool.mint,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        insurance_fund: test_pool.token_pda(b"insurance"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+        rent: rent::ID,
+    };
+    Instruction::new_with_bytes(protocol::ID, &protocol::instruction::InitVault {}.data(), accounts.to_account_metas(None))
+}
+
+/// An instruction of the pool config's admin, signed by `admin`.
+fn pool_admin_instruction(admin: Pubkey, test_pool: &TestPool, data: Vec<u8>) -> Instruction {
+    let accounts = protocol::accounts::UpdatePoolConfig {
+        admin,
+        pool_config: test_pool.pool_config(),
+    };
+    Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))
+}
+
+/// Moves `amount` of the payer's tokens into the vault, to pay profits from.
+async fn seed_vault(context: &mut ProgramTestContext, test_pool: &TestPool, amount: u64) {
+    let payer = context.payer.pubkey();
+    let seed = spl_token::instruction::transfer(&spl_token::ID, &test_pool.payer_tokens, &test_pool.token_pda(b"vault"), &payer, &[], amount).unwrap();
+    send(context, seed).await;
+}
+
+/// The instruction's accounts, followed by the feed converting the
+/// position's collateral if there is one.
+fn with_collateral_price(mut metas: Vec<AccountMeta>, collateral_price: Option<Pubkey>) -> Vec<AccountMeta> {
+    metas.extend(collateral_price.map(|price| AccountMeta::new_readonly(price, false)));
+    metas
+}
+
+async fn create(context: &mut ProgramTestContext, test_pool: &TestPool, index: u32, args: PositionArgs, collateral_price: Option<Pubkey>) -> Pubkey {
+    let payer = context.payer.pubkey();
+    send(context, create_instruction(payer, test_pool, index, args, collateral_price)).await;
+    test_pool.position(&payer, index)
+}
+
+fn create_instruction(payer: Pubkey, test_pool: &TestPool, index: u32, args: PositionArgs, collateral_price: Option<Pubkey>) -> Instruction {
+    let accounts = protocol::accounts::Create {
+        payer,
+        pool: test_pool.pool,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        pool_config: test_pool.pool_config(),
+        mint: test_pool.mint,
+        payer_token_account: test_pool.payer_tokens,
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        position: test_pool.position(&payer, index),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::Create { index, args }.data();
+    Instruction::new_with_bytes(protocol::ID, &data, with_collateral_price(accounts.to_account_metas(None), collateral_price))
+}
+
+async fn increase_margin(context: &mut ProgramTestContext, test_pool: &TestPool, position: Pubkey, amount: u64, collateral_price: Option<Pubkey>) {
+    let accounts = protocol::accounts::IncreaseMargin {
+        payer: context.payer.pubkey(),
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: test_pool.pool_config(),
+        payer_token_account: test_pool.payer_tokens,
+        vault: test_pool.token_pda(b"vault"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::IncreaseMargin { amount }.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, with_collateral_price(accounts.to_account_metas(None), collateral_price))).await;
+}
+
+fn decrease_margin_instruction(payer: Pubkey, test_pool: &TestPool, position: Pubkey, amount: u64) -> Instruction {
+    let accounts = protocol::accounts::DecreaseMargin {
+        payer,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: test_pool.pool_config(),
+        payer_token_account: test_pool.payer_tokens,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::DecreaseMargin { amount }.data();
+    Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))
+}
+
+async fn decrease_margin(context: &mut ProgramTestContext, test_pool: &TestPool, position: Pubkey, amount: u64) {
+    let instruction = decrease_margin_instruction(context.payer.pubkey(), test_pool, position, amount);
+    send(context, instruction).await;
+}
+
+/// Creates the payer's cross-margin account in the pool and deposits `amount`.
+async fn deposit_cross_margin(context: &mut ProgramTestContext, test_pool: &TestPool, amount: u64) -> Pubkey {
+    let payer = context.payer.pubkey();
+    let cross_account = test_pool.cross_account(&payer);
+    let accounts = protocol::accounts::InitCrossAccount {
+        payer,
+        pool: test_pool.pool,
+        mint: test_pool.mint,
+        cross_account,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::InitCrossAccount {}.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+
+    let accounts = protocol::accounts::DepositCrossMargin {
+        payer,
+        cross_account,
+        payer_token_account: test_pool.payer_tokens,
+        vault: test_pool.token_pda(b"vault"),
+        token_program: spl_token::ID,
+    };
+    let data = protocol::instruction::DepositCrossMargin { amount }.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+    cross_account
+}
+
+async fn create_cross(context: &mut ProgramTestContext, test_pool: &TestPool, index: u32, args: PositionArgs) -> Pubkey {
+    let payer = context.payer.pubkey();
+    let position = test_pool.position(&payer, index);
+    let accounts = protocol::accounts::CreateCross {
+        payer,
+        pool: test_pool.pool,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        pool_config: test_pool.pool_config(),
+        cross_account: test_pool.cross_account(&payer),
+        position,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::CreateCross { index, args }.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+    position
+}
+
+/// Closes `position`, passing `remaining_accounts` after the instruction's
+/// own: a collateral feed, or a cross position's `CrossAccount`.
+async fn close_position(context: &mut ProgramTestContext, test_pool: &TestPool, position: Pubkey, min_return: u64, remaining_accounts: Vec<AccountMeta>) {
+    let accounts = protocol::accounts::ClosePosition {
+        owner: context.payer.pubkey(),
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: test_pool.pool_config(),
+        owner_token_account: test_pool.payer_tokens,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        insurance_fund: test_pool.token_pda(b"insurance"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let mut metas = accounts.to_account_metas(None);
+    metas.extend(remaining_accounts);
+    let data = protocol::instruction::ClosePosition { min_return }.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, metas)).await;
+}
+
+async fn set_take_profit(context: &mut ProgramTestContext, position: Pubkey, take_profit: u64) {
+    let accounts = protocol::accounts::SetTriggers {
+        owner: context.payer.pubkey(),
+        position,
+    };
+    let data = protocol::instruction::SetTriggers { take_profit: Some(take_profit), stop_loss: None }.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+}
+
+/// Closes `position` on its trigger as a keeper would, passing
+/// `remaining_accounts` after the instruction's own.
+async fn execute_trigger(context: &mut ProgramTestContext, test_pool: &TestPool, position: Pubkey, remaining_accounts: Vec<AccountMeta>) {
+    let accounts = protocol::accounts::ExecuteTrigger {
+        keeper: context.payer.pubkey(),
+        owner: context.payer.pubkey(),
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: test_pool.pool_config(),
+        owner_token_account: test_pool.payer_tokens,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        insurance_fund: test_pool.token_pda(b"insurance"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+    };
+    let mut metas = accounts.to_account_metas(None);
+    metas.extend(remaining_accounts);
+    let data = protocol::instruction::ExecuteTrigger {}.data();
+    send(context, Instruction::new_with_bytes(protocol::ID, &data, metas)).await;
+}
+
+/// Closes `close_amount` of `position` on the signed data of the
+/// instruction before it.
+fn process_position_instruction(payer: Pubkey, test_pool: &TestPool, position: Pubkey, min_return: u64, close_amount: u64) -> Instruction {
+    let accounts = protocol::accounts::ProcessPosition {
+        payer,
+        pool: test_pool.pool,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: test_pool.pool_config(),
+        owner_token_account: test_pool.payer_tokens,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        insurance_fund: test_pool.token_pda(b"insurance"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+        instruction_sysvar_account_info: sysvar::instructions::ID,
+    };
+    let data = protocol::instruction::ProcessPosition { min_return, close_amount }.data();
+    Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))
+}
+
+/// Closes `positions` in full on the signed data of the instructions right
+/// before it, one each and in the same order.
+fn process_positions_instruction(payer: Pubkey, test_pool: &TestPool, positions: &[Pubkey], min_return: u64) -> Instruction {
+    let accounts = protocol::accounts::ProcessPositions {
+        payer,
+        pool: test_pool.pool,
+        mint: test_pool.mint,
+        price_a: test_pool.price_a,
+        price_b: Pubkey::default(),
+        pool_config: test_pool.pool_config(),
+        owner_token_account: test_pool.payer_tokens,
+        vault_authority: test_pool.pda(b"vault_authority"),
+        vault: test_pool.token_pda(b"vault"),
+        fee_account: test_pool.token_pda(b"fee"),
+        insurance_fund: test_pool.token_pda(b"insurance"),
+        token_program: spl_token::ID,
+        system_program: system_program::ID,
+        instruction_sysvar_account_info: sysvar::instructions::ID,
+    };
+    let mut metas = accounts.to_account_metas(None);
+    metas.extend(positions.iter().map(|position| AccountMeta::new(*position, false)));
+    let data = protocol::instruction::ProcessPositions { min_return, best_effort: false }.data();
+    Instruction::new_with_bytes(protocol::ID, &data, metas)
+}
+
+/// Opens 1 BTC in `direction`, adds 1000 USDC of margin and withdraws 500 of
+/// it, moves the price to `exit_price` and closes, checking the position and
+/// the balances at each step. `min_return` is the expected payout, so the
+/// close also fails if the program returns less.
+async fn run_lifecycle(direction: Direction, exit_price: i64, expected_return: u64) {
+    let (mut context, test_pool) = setup().await;
+    let vault = test_pool.token_pda(b"vault");
+    let payer = context.payer.pubkey();
+
+    let position = create(&mut context, &test_pool, 0, position_args(direction), None).await;
+    let opened = position_state(&mut context, position).await.unwrap();
+    assert_eq!(opened.owner, payer);
+    assert_eq!(opened.pool, test_pool.pool);
+    assert_eq!(opened.status, PositionStatus::Open);
+    assert_eq!(opened.direction, direction);
+    assert_eq!(opened.amount, 1_000_000);
+    assert_eq!(opened.margin, 3000 * USDC);
+    assert_eq!(opened.last_price, BTC_PRICE);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 3000 * USDC);
+
+    increase_margin(&mut context, &test_pool, position, 1000 * USDC, None).await;
+    let topped_up = position_state(&mut context, position).await.unwrap();
+    assert_eq!(topped_up.margin, 4000 * USDC);
+    assert_eq!(topped_up.amount, opened.amount);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 4000 * USDC);
+
+    decrease_margin(&mut context, &test_pool, position, 500 * USDC).await;
+    assert_eq!(position_state(&mut context, position).await.unwrap().margin, 3500 * USDC);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 3500 * USDC);
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY - 3500 * USDC);
+
+    set_price(&mut context, test_pool.price_a, exit_price).await;
+    close_position(&mut context, &test_pool, position, expected_return, vec![]).await;
+    assert!(position_state(&mut context, position).await.is_none());
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY - 3500 * USDC + expected_return);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 3500 * USDC - expected_return);
+}
+
+#[tokio::test]
+async fn long_lifecycle() {
+    // 1 BTC up 1000 USDC on top of 3500 USDC of margin
+    run_lifecycle(Direction::OpenLong, 31000_000_000, 4500 * USDC).await;
+}
+
+#[tokio::test]
+async fn short_lifecycle() {
+    run_lifecycle(Direction::OpenShort, 29000_000_000, 4500 * USDC).await;
+}
+
+/// Deposits 5000 USDC into a cross-margin account, opens 1 BTC long against
+/// 3000 of it and closes it with BTC up 1000: the 4000 returned leaves the
+/// vault and the account keeps the 2000 never allocated.
+#[tokio::test]
+async fn signed_close() {
+    let (mut context, test_pool) = setup().await;
+    let payer = context.payer.pubkey();
+    let position = create(&mut context, &test_pool, 0, position_args(Direction::OpenLong), None).await;
+    assert_eq!(position_state(&mut context, position).await.unwrap().authority, oracle().pubkey());
+    set_price(&mut context, test_pool.price_a, 31000_000_000).await;
+
+    // data signed by anyone but the pool's oracle closes nothing
+    let forged = signed_close_instruction(&mut context, &test_pool, position, false, &Keypair::new()).await;
+    let process = process_position_instruction(payer, &test_pool, position, 0, u64::MAX);
+    assert!(!try_send_all(&mut context, &[forged, process], &[]).await);
+    assert_eq!(position_state(&mut context, position).await.unwrap().status, PositionStatus::Open);
+
+    // the oracle's closes it at the live price: 3000 USDC of margin and 1000 of profit
+    let signed = signed_close_instruction(&mut context, &test_pool, position, false, &oracle()).await;
+    let process = process_position_instruction(payer, &test_pool, position, 4000 * USDC, u64::MAX);
+    assert!(try_send_all(&mut context, &[signed, process], &[]).await);
+    assert!(position_state(&mut context, position).await.is_none());
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY + 1000 * USDC);
+    assert_eq!(token_balance(&mut context, test_pool.token_pda(b"vault")).await, POOL_LIQUIDITY - 1000 * USDC);
+}
+
+#[tokio::test]
+async fn batch_close() {
+    let (mut context, test_pool) = setup().await;
+    let payer = context.payer.pubkey();
+    let long = create(&mut context, &test_pool, 0, position_args(Direction::OpenLong), None).await;
+    let short = create(&mut context, &test_pool, 1, position_args(Direction::OpenShort), None).await;
+    set_price(&mut context, test_pool.price_a, 31000_000_000).await;
+
+    // the long's 1000 USDC of profit is the short's loss, paid out as one
+    // transfer of both margins
+    let signed_long = signed_close_instruction(&mut context, &test_pool, long, false, &oracle()).await;
+    let signed_short = signed_close_instruction(&mut context, &test_pool, short, false, &oracle()).await;
+    let process = process_positions_instruction(payer, &test_pool, &[long, short], 6000 * USDC);
+    assert!(try_send_all(&mut context, &[signed_long, signed_short, process], &[]).await);
+    assert!(position_state(&mut context, long).await.is_none());
+    assert!(position_state(&mut context, short).await.is_none());
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY);
+    assert_eq!(token_balance(&mut context, test_pool.token_pda(b"vault")).await, POOL_LIQUIDITY);
+}
+
+#[tokio::test]
+async fn cross_lifecycle() {
+    let (mut context, test_pool) = setup().await;
+    let vault = test_pool.token_pda(b"vault");
+    let payer = context.payer.pubkey();
+
+    let cross_account = deposit_cross_margin(&mut context, &test_pool, 5000 * USDC).await;
+    let deposited = cross_account_state(&mut context, cross_account).await;
+    assert_eq!(deposited.owner, payer);
+    assert_eq!((deposited.collateral, deposited.used_margin), (5000 * USDC, 0));
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 5000 * USDC);
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY - 5000 * USDC);
+
+    let args = PositionArgs { ptype: PositionType::Cross, ..position_args(Direction::OpenLong) };
+    let position = create_cross(&mut context, &test_pool, 0, args).await;
+    assert_eq!(position_state(&mut context, position).await.unwrap().margin, 3000 * USDC);
+    let allocated = cross_account_state(&mut context, cross_account).await;
+    assert_eq!((allocated.collateral, allocated.used_margin), (5000 * USDC, 3000 * USDC));
+    assert_eq!(allocated.positions, vec![position]);
+    // the margin was already in the vault
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 5000 * USDC);
+
+    set_price(&mut context, test_pool.price_a, 31000_000_000).await;
+    close_position(&mut context, &test_pool, position, 4000 * USDC, vec![AccountMeta::new(cross_account, false)]).await;
+    assert!(position_state(&mut context, position).await.is_none());
+    let released = cross_account_state(&mut context, cross_account).await;
+    assert_eq!((released.collateral, released.used_margin), (2000 * USDC, 0));
+    assert!(released.positions.is_empty());
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 1000 * USDC);
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY - 1000 * USDC);
+}
+
+/// Opens two cross longs of 1 BTC on 10000 USDC of shared collateral and
+/// closes one on its take-profit with BTC up 1000: the account gives up the
+/// 3000 it had booked and stops backing it, while the other stays a member.
+#[tokio::test]
+async fn cross_trigger_releases_member() {
+    let (mut context, test_pool) = setup().await;
+    let vault = test_pool.token_pda(b"vault");
+
+    let cross_account = deposit_cross_margin(&mut context, &test_pool, 10000 * USDC).await;
+    let args = PositionArgs { ptype: PositionType::Cross, ..position_args(Direction::OpenLong) };
+    let triggered = create_cross(&mut context, &test_pool, 0, args).await;
+    let other = create_cross(&mut context, &test_pool, 1, args).await;
+    let opened = cross_account_state(&mut context, cross_account).await;
+    assert_eq!(opened.positions, vec![triggered, other]);
+    assert_eq!((opened.collateral, opened.used_margin), (10000 * USDC, 6000 * USDC));
+
+    set_take_profit(&mut context, triggered, 30500_000_000).await;
+    set_price(&mut context, test_pool.price_a, 31000_000_000).await;
+    execute_trigger(&mut context, &test_pool, triggered, vec![AccountMeta::new(cross_account, false)]).await;
+
+    assert!(position_state(&mut context, triggered).await.is_none());
+    assert_eq!(position_state(&mut context, other).await.unwrap().status, PositionStatus::Open);
+    let released = cross_account_state(&mut context, cross_account).await;
+    assert_eq!(released.positions, vec![other]);
+    assert_eq!((released.collateral, released.used_margin), (7000 * USDC, 3000 * USDC));
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 10000 * USDC - 4000 * USDC);
+    assert_eq!(token_balance(&mut context, test_pool.payer_tokens).await, STARTING_BALANCE - POOL_LIQUIDITY - 10000 * USDC + 4000 * USDC);
+}
+
+/// A pool beside `usdc_pool`, seeded the same, charging 1% of the notional
+/// an hour in funding while the book is one-sided.
+async fn funded_pool(context: &mut ProgramTestContext, usdc_pool: TestPool) -> TestPool {
+    let payer = context.payer.pubkey();
+    let funded_pool = TestPool {
+        pool: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    init_vault(context, &funded_pool).await;
+    let args = PoolConfigArgs {
+        funding_rate_bps: 100,
+        ..pool_config_args()
+    };
+    send(context, init_pool_config_instruction(payer, &funded_pool, args)).await;
+    seed_vault(context, &funded_pool, POOL_LIQUIDITY).await;
+    funded_pool
+}
+
+/// Moves the clock `seconds` on, with the slot where it was.
+async fn advance_clock(context: &mut ProgramTestContext, seconds: i64) {
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    clock.unix_timestamp += seconds;
+    context.set_sysvar(&clock);
+}
+
+/// Funding taken out of a cross member's margin before its trigger fires
+/// still releases the whole 3000 the account booked for it: the 300 paid
+/// leaves with it, not as collateral the account never had.
+#[tokio::test]
+async fn cross_trigger_after_funding() {
+    let (mut context, usdc_pool) = setup().await;
+    let funded_pool = funded_pool(&mut context, usdc_pool).await;
+    let vault = funded_pool.token_pda(b"vault");
+
+    let cross_account = deposit_cross_margin(&mut context, &funded_pool, 10000 * USDC).await;
+    let args = PositionArgs { ptype: PositionType::Cross, ..position_args(Direction::OpenLong) };
+    let triggered = create_cross(&mut context, &funded_pool, 0, args).await;
+    let other = create_cross(&mut context, &funded_pool, 1, args).await;
+    set_take_profit(&mut context, triggered, 30500_000_000).await;
+
+    // an hour with only longs open accrues 1% of the 30000 notional
+    advance_clock(&mut context, FUNDING_INTERVAL as i64).await;
+    set_price(&mut context, funded_pool.price_a, 31000_000_000).await;
+    execute_trigger(&mut context, &funded_pool, triggered, vec![AccountMeta::new(cross_account, false)]).await;
+
+    let released = cross_account_state(&mut context, cross_account).await;
+    assert_eq!(released.positions, vec![other]);
+    assert_eq!((released.collateral, released.used_margin), (7000 * USDC, 3000 * USDC));
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 10000 * USDC - 3700 * USDC);
+}
+
+/// A withdrawal counts the funding owed since the last settlement: of the
+/// 3000 posted, the 300 an hour accrues and the 150 of maintenance margin
+/// leave 2550 to take, not the 2850 the stale margin shows.
+#[tokio::test]
+async fn withdraw_after_funding() {
+    let (mut context, usdc_pool) = setup().await;
+    let funded_pool = funded_pool(&mut context, usdc_pool).await;
+    let vault = funded_pool.token_pda(b"vault");
+    let position = create(&mut context, &funded_pool, 0, position_args(Direction::OpenLong), None).await;
+
+    advance_clock(&mut context, FUNDING_INTERVAL as i64).await;
+    set_price(&mut context, funded_pool.price_a, BTC_PRICE).await;
+    let payer = context.payer.pubkey();
+    assert!(!try_send(&mut context, decrease_margin_instruction(payer, &funded_pool, position, 2600 * USDC), &[]).await);
+
+    decrease_margin(&mut context, &funded_pool, position, 2000 * USDC).await;
+    let withdrawn = position_state(&mut context, position).await.unwrap();
+    assert_eq!(withdrawn.margin, 3000 * USDC - 300 * USDC - 2000 * USDC);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 3000 * USDC - 2000 * USDC);
+}
+
+/// A take-profit hit at a price doubled in a blink pays out no more than the
+/// pool's `max_payout_multiple` of the margin; the rest stays in the vault.
+#[tokio::test]
+async fn trigger_payout_capped() {
+    let (mut context, usdc_pool) = setup().await;
+    let payer = context.payer.pubkey();
+    let capped_pool = TestPool {
+        pool: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    init_vault(&mut context, &capped_pool).await;
+    let args = PoolConfigArgs {
+        max_payout_multiple: 2,
+        ..pool_config_args()
+    };
+    send(&mut context, init_pool_config_instruction(payer, &capped_pool, args)).await;
+    seed_vault(&mut context, &capped_pool, POOL_LIQUIDITY).await;
+    let vault = capped_pool.token_pda(b"vault");
+
+    let position = create(&mut context, &capped_pool, 0, position_args(Direction::OpenLong), None).await;
+    set_take_profit(&mut context, position, 30500_000_000).await;
+    let balance = token_balance(&mut context, capped_pool.payer_tokens).await;
+
+    // 33000 uncapped: the 3000 of margin and 30000 of profit
+    set_price(&mut context, capped_pool.price_a, 2 * BTC_PRICE).await;
+    execute_trigger(&mut context, &capped_pool, position, vec![]).await;
+    assert!(position_state(&mut context, position).await.is_none());
+    assert_eq!(token_balance(&mut context, capped_pool.payer_tokens).await, balance + 6000 * USDC);
+    assert_eq!(token_balance(&mut context, vault).await, POOL_LIQUIDITY + 3000 * USDC - 6000 * USDC);
+}
+
+/// Opens 1 BTC long on SOL posted at 100 USDC and tops it up by 500 USDC
+/// worth, then closes it with BTC up 1000 and SOL up to 125: the 4500 USDC
+/// returned is paid as 36 SOL. SOL is refused until the pool lists it.
+#[tokio::test]
+async fn collateral_lifecycle() {
+    const SOL: u64 = 1_000_000_000;
+    let (mut context, usdc_pool) = setup().await;
+    let sol_pool = TestPool {
+        mint: Pubkey::new_unique(),
+        payer_tokens: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    let sol_price = Pubkey::new_unique();
+    add_mint(&mut context, sol_pool.mint, 9, 100 * SOL);
+    add_token_account(&mut context, sol_pool.payer_tokens, sol_pool.mint, 100 * SOL);
+    set_price(&mut context, sol_price, 100_000_000).await;
+    init_vault(&mut context, &sol_pool).await;
+    seed_vault(&mut context, &sol_pool, 50 * SOL).await;
+
+    let unlisted = create_instruction(context.payer.pubkey(), &sol_pool, 1, position_args(Direction::OpenLong), Some(sol_price));
+    assert!(!try_send(&mut context, unlisted, &[]).await);
+
+    let accounts = protocol::accounts::UpdatePoolConfig {
+        admin: context.payer.pubkey(),
+        pool_config: sol_pool.pool_config(),
+    };
+    let data = protocol::instruction::AddCollateralFeed { mint: sol_pool.mint, price: sol_price }.data();
+    send(&mut context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+
+    // the 3000 USDC of margin is taken as 30 SOL, and kept in the quote
+    let vault = sol_pool.token_pda(b"vault");
+    let position = create(&mut context, &sol_pool, 0, position_args(Direction::OpenLong), Some(sol_price)).await;
+    let opened = position_state(&mut context, position).await.unwrap();
+    assert_eq!(opened.mint, sol_pool.mint);
+    assert_eq!(opened.collateral_price, sol_price);
+    assert_eq!(opened.collateral_decimals, 9);
+    assert_eq!(opened.margin, 3000 * USDC);
+    assert_eq!(token_balance(&mut context, vault).await, 80 * SOL);
+    assert_eq!(token_balance(&mut context, sol_pool.payer_tokens).await, 20 * SOL);
+
+    // 500 USDC more of margin is 5 SOL
+    increase_margin(&mut context, &sol_pool, position, 500 * USDC, Some(sol_price)).await;
+    assert_eq!(position_state(&mut context, position).await.unwrap().margin, 3500 * USDC);
+    assert_eq!(token_balance(&mut context, vault).await, 85 * SOL);
+    assert_eq!(token_balance(&mut context, sol_pool.payer_tokens).await, 15 * SOL);
+
+    set_price(&mut context, usdc_pool.price_a, 31000_000_000).await;
+    set_price(&mut context, sol_price, 125_000_000).await;
+    close_position(&mut context, &sol_pool, position, 36 * SOL, vec![AccountMeta::new_readonly(sol_price, false)]).await;
+    assert!(position_state(&mut context, position).await.is_none());
+    assert_eq!(token_balance(&mut context, sol_pool.payer_tokens).await, 51 * SOL);
+    assert_eq!(token_balance(&mut context, vault).await, 49 * SOL);
+    // the quote vault was never touched
+    assert_eq!(token_balance(&mut context, usdc_pool.token_pda(b"vault")).await, POOL_LIQUIDITY);
+}
+
+/// The compute units `create` may use. Only the built program is metered, so
+/// this runs against `target/deploy/protocol.so`: `anchor build`, then
+/// `cargo test -- --ignored`.
+const CREATE_COMPUTE_UNITS: u64 = 200_000;
+
+/// Opening a position fits in `CREATE_COMPUTE_UNITS`.
+#[tokio::test]
+#[ignore]
+async fn create_compute_units() {
+    let mut program_test = ProgramTest::new("protocol", protocol::ID, None);
+    program_test.set_compute_max_units(CREATE_COMPUTE_UNITS);
+    let (mut context, test_pool) = setup_program(program_test).await;
+    let position = create(&mut context, &test_pool, 0, position_args(Direction::OpenLong), None).await;
+    assert_eq!(position_state(&mut context, position).await.unwrap().status, PositionStatus::Open);
+}
+
+/// Only the governance authority may create a pool's config.
+#[tokio::test]
+async fn init_pool_config_requires_admin() {
+    let (mut context, usdc_pool) = setup().await;
+    let stranger = Keypair::new();
+    let transfer = anchor_lang::solana_program::system_instruction::transfer(&context.payer.pubkey(), &stranger.pubkey(), 1_000_000_000);
+    send(&mut context, transfer).await;
+
+    let pool = TestPool {
+        pool: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    let instruction = init_pool_config_instruction(stranger.pubkey(), &pool, pool_config_args());
+    assert!(!try_send(&mut context, instruction, &[&stranger]).await);
+    assert!(context.banks_client.get_account(pool.pool_config()).await.unwrap().is_none());
+
+    let payer = context.payer.pubkey();
+    assert!(try_send(&mut context, init_pool_config_instruction(payer, &pool, pool_config_args()), &[]).await);
+}
+
+/// Only the governance authority may create a pool's vaults, and only the
+/// pool's admin may change its config or hand it over.
+#[tokio::test]
+async fn admin_instructions_reject_strangers() {
+    let (mut context, usdc_pool) = setup().await;
+    let stranger = Keypair::new();
+    let transfer = anchor_lang::solana_program::system_instruction::transfer(&context.payer.pubkey(), &stranger.pubkey(), 1_000_000_000);
+    send(&mut context, transfer).await;
+
+    let pool = TestPool {
+        pool: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    assert!(!try_send(&mut context, init_vault_instruction(stranger.pubkey(), &pool), &[&stranger]).await);
+    assert!(context.banks_client.get_account(pool.token_pda(b"vault")).await.unwrap().is_none());
+
+    let update = protocol::instruction::UpdatePoolConfig {
+        args: PoolConfigArgs { paused: true, ..pool_config_args() },
+    }
+    .data();
+    let handover = protocol::instruction::TransferAdmin { new_admin: stranger.pubkey() }.data();
+    for data in [update.clone(), handover.clone()] {
+        assert!(!try_send(&mut context, pool_admin_instruction(stranger.pubkey(), &usdc_pool, data), &[&stranger]).await);
+    }
+    let config_account = context.banks_client.get_account(usdc_pool.pool_config()).await.unwrap().unwrap();
+    let config = protocol::PoolConfig::try_deserialize(&mut &config_account.data[..]).unwrap();
+    assert!(!config.paused);
+    assert_eq!(config.pending_admin, Pubkey::default());
+
+    // the admin itself goes through
+    let payer = context.payer.pubkey();
+    for data in [update, handover] {
+        assert!(try_send(&mut context, pool_admin_instruction(payer, &usdc_pool, data), &[]).await);
+    }
+    let config_account = context.banks_client.get_account(usdc_pool.pool_config()).await.unwrap().unwrap();
+    let config = protocol::PoolConfig::try_deserialize(&mut &config_account.data[..]).unwrap();
+    assert!(config.paused);
+    assert_eq!(config.pending_admin, stranger.pubkey());
+}
+
+/// A pool margined in native SOL: `create_native` takes the margin and the
+/// opening fee in lamports into the pool's native vaults.
+#[tokio::test]
+async fn native_lifecycle() {
+    let (mut context, usdc_pool) = setup().await;
+    let payer = context.payer.pubkey();
+    let sol_pool = TestPool {
+        pool: Pubkey::new_unique(),
+        ..usdc_pool
+    };
+    let args = PoolConfigArgs {
+        collateral_mode: CollateralMode::Native,
+        taker_fee_bps: 10,
+        ..pool_config_args()
+    };
+    send(&mut context, init_pool_config_instruction(payer, &sol_pool, args)).await;
+
+    let (native_vault, native_fee) = (sol_pool.pda(b"native_vault"), sol_pool.pda(b"native_fee"));
+    let accounts = protocol::accounts::InitNativeVault {
+        payer,
+        pool: sol_pool.pool,
+        pool_config: sol_pool.pool_config(),
+        native_vault,
+        native_fee,
+        native_insurance: sol_pool.pda(b"native_insurance"),
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::InitNativeVault {}.data();
+    send(&mut context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+    send(&mut context, system_instruction::transfer(&payer, &native_vault, POOL_LIQUIDITY)).await;
+    let vault_before = context.banks_client.get_balance(native_vault).await.unwrap();
+    let fee_before = context.banks_client.get_balance(native_fee).await.unwrap();
+
+    let position = sol_pool.position(&payer, 0);
+    let accounts = protocol::accounts::CreateNative {
+        payer,
+        pool: sol_pool.pool,
+        price_a: sol_pool.price_a,
+        price_b: Pubkey::default(),
+        pool_config: sol_pool.pool_config(),
+        native_vault,
+        native_fee,
+        position,
+        system_program: system_program::ID,
+    };
+    let data = protocol::instruction::CreateNative { index: 0, args: position_args(Direction::OpenLong) }.data();
+    send(&mut context, Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None))).await;
+
+    // 10 bps of the 30000 notional is taken out of the 3000 of margin
+    let opened = position_state(&mut context, position).await.unwrap();
+    assert_eq!(opened.mint, protocol::NATIVE_MINT);
+    assert_eq!(opened.status, PositionStatus::Open);
+    assert_eq!(opened.margin, 2970 * USDC);
+    assert_eq!(context.banks_client.get_balance(native_vault).await.unwrap() - vault_before, 2970 * USDC);
+    assert_eq!(context.banks_client.get_balance(native_fee).await.unwrap() - fee_before, 30 * USDC);
+
+    // closed on the oracle's data up 1000, less 10 bps of the 31000 notional
+    set_price(&mut context, sol_pool.price_a, 31000_000_000).await;
+    let signed = signed_close_instruction(&mut context, &sol_pool, position, false, &oracle()).await;
+    let accounts = protocol::accounts::ProcessPositionNative {
+        payer,
+        pool: sol_pool.pool,
+        price_a: sol_pool.price_a,
+        price_b: Pubkey::default(),
+        position,
+        pool_config: sol_pool.pool_config(),
+        native_vault,
+        native_fee,
+        native_insurance: sol_pool.pda(b"native_insurance"),
+        system_program: system_program::ID,
+        instruction_sysvar_account_info: sysvar::instructions::ID,
+    };
+    let data = protocol::instruction::ProcessPositionNative { min_return: 3939 * USDC, close_amount: u64::MAX }.data();
+    let process = Instruction::new_with_bytes(protocol::ID, &data, accounts.to_account_metas(None));
+    assert!(try_send_all(&mut context, &[signed, process], &[]).await);
+    assert!(position_state(&mut context, position).await.is_none());
+    // the vault pays out 3939 and moves the 31 of fee across
+    assert_eq!(vault_before - context.banks_client.get_balance(native_vault).await.unwrap(), 1000 * USDC);
+    assert_eq!(context.banks_client.get_balance(native_fee).await.unwrap() - fee_before, 61 * USDC);
+}